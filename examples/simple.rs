@@ -7,7 +7,7 @@ use std::fs;
 use std::io;
 
 
-fn get_resource() -> Result<fs::File, Box<Error>>
+fn get_resource() -> Result<fs::File, Box<dyn Error>>
 {
     // Where shall we store our cache data?
     let cache_path = env::temp_dir().join("static_http_cache");
@@ -17,19 +17,14 @@ fn get_resource() -> Result<fs::File, Box<Error>>
         .recursive(true)
         .create(&cache_path)?;
 
-    // What URL should we download?
-    let url = reqwest::Url::parse(
-        "https://static.rust-lang.org/dist/channel-rust-stable.toml",
-    )?;
-
     // Create the cache data structure we need on disk.
     let mut cache = static_http_cache::Cache::new(
         cache_path,
-        reqwest::Client::new(),
+        reqwest::blocking::Client::new(),
     )?;
 
     // Actually retrieve the URL if needed.
-    cache.get(url)
+    Ok(cache.get("https://static.rust-lang.org/dist/channel-rust-stable.toml")?)
 }
 
 
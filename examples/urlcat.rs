@@ -10,7 +10,7 @@ use std::path;
 
 
 fn parse_args<T: Iterator<Item=String>>(mut args: T)
-    -> Result<fs::File, Box<Error>>
+    -> Result<fs::File, Box<dyn Error>>
 {
     let cache_path = args.next()
         .map(|x| Ok(path::PathBuf::from(x)))
@@ -22,16 +22,14 @@ fn parse_args<T: Iterator<Item=String>>(mut args: T)
         .create(&cache_path)?;
 
     let raw_url = args.next()
-        .map(|x| Ok(x))
+        .map(Ok)
         .unwrap_or(Err("URL argument required"))?;
-    let url = reqwest::Url::parse(&raw_url)?;
-
     let mut cache = static_http_cache::Cache::new(
         cache_path,
-        reqwest::Client::new(),
+        reqwest::blocking::Client::new(),
     )?;
 
-    cache.get(url)
+    Ok(cache.get(raw_url)?)
 }
 
 
@@ -0,0 +1,156 @@
+//! Exporting a cache to, and importing it from, a portable archive.
+//!
+//! An archive is a tar file holding an `index.tsv` file followed by the content files it refers to.
+//! Each line of the index describes one entry, as tab-separated fields:
+//! its URL, the path of its content in the archive, its `Last-Modified` and `ETag` values, the Unix time it's fresh until,
+//! and `no-cache` if it must always be revalidated.
+//! Missing values are empty, and content paths are relative to the archive, so it can be imported under any root.
+use {fehler::throws, anyhow::Error, std::{collections::{HashMap, HashSet}, io, path, time}, log::{info, warn}, crate::{db, reqwest_mock, Cache, Storage}};
+
+const INDEX: &str = "index.tsv";
+
+#[throws] fn field(value: &str) -> &str {
+    if value.contains(&['\t', '\n'][..]) { anyhow::bail!("Can't export {:?}, which contains a tab or newline", value) }
+    value
+}
+
+impl<C: reqwest_mock::Client, S: Storage> Cache<C, S> {
+    /// Writes every cached entry, with its content, to `w` as a portable archive.
+    ///
+    /// Interrupted downloads are skipped, and access counts are not exported.
+    ///
+    /// # Errors
+    ///   - the cache metadata is corrupt
+    ///   - a content file cannot be read
+    ///   - `w` cannot be written to
+    #[throws] pub fn export(&self, w: impl io::Write) {
+        let records: Vec<_> = self.db.records()?.into_iter().filter(|(_, record)| record.complete).collect();
+        let mut index = String::new();
+        for (url, record) in &records {
+            let fresh_until = record.fresh_until.map(|time| time.duration_since(time::UNIX_EPOCH).map_or(0, |d| d.as_secs()).to_string());
+            index += &[
+                field(url.as_str())?,
+                field(&record.path)?,
+                field(record.last_modified.as_deref().unwrap_or_default())?,
+                field(&db::ETag::join(&record.etags))?,
+                fresh_until.as_deref().unwrap_or_default(),
+                if record.no_cache { "no-cache" } else { "" },
+            ].join("\t");
+            index.push('\n');
+        }
+        let mut archive = tar::Builder::new(w);
+        let mut header = tar::Header::new_gnu();
+        header.set_size(index.len() as u64);
+        header.set_mode(0o644);
+        header.set_cksum();
+        archive.append_data(&mut header, INDEX, index.as_bytes())?;
+        for (_, record) in &records {
+            let path = self.root.join(&record.path);
+            let mut header = tar::Header::new_gnu();
+            header.set_mode(0o644);
+            if !record.compressed {
+                header.set_size(self.storage.len(&path)?);
+                header.set_cksum();
+                archive.append_data(&mut header, &record.path, self.storage.open(&path)?)?;
+                continue
+            }
+            // Archives hold content as the server sent it, whether or not either cache compresses it.
+            let mut content = tempfile::tempfile()?;
+            let size = io::copy(&mut flate2::read::MultiGzDecoder::new(self.storage.open(&path)?), &mut content)?;
+            io::Seek::seek(&mut content, io::SeekFrom::Start(0))?;
+            header.set_size(size);
+            header.set_cksum();
+            archive.append_data(&mut header, &record.path, content)?;
+        }
+        archive.finish()?;
+        info!("Exported {} entries", records.len());
+    }
+
+    /// Adds the entries in an archive written by [`Cache::export`] to this cache.
+    ///
+    /// Content files are copied under this cache's root, and all the entries are recorded in a single transaction.
+    /// Entries replace any existing entries for the same URLs, whose content is removed.
+    /// If the import fails, nothing is recorded, and the content files copied so far are removed.
+    ///
+    /// # Errors
+    ///   - the cache is read-only
+    ///   - `r` cannot be read, or isn't a valid archive
+    ///   - content files cannot be written
+    ///   - we can't update the cache metadata
+    #[throws] pub fn import(&mut self, r: impl io::Read) {
+        self.check_writable()?;
+        // Whatever happens, content files that end up without an entry are removed.
+        let mut copied = vec![];
+        let result = self.import_entries(r, &mut copied);
+        let kept: HashSet<_> = result.as_ref().map(|(records, _)| records.iter().map(|(_, record)| self.root.join(&record.path)).collect()).unwrap_or_default();
+        for path in copied.iter().filter(|path| !kept.contains(*path)) { self.storage.remove(path).unwrap_or_else(|err| warn!("Failed to remove {:?}: {}", path, err)); }
+        let (records, replaced) = result?;
+        for record in replaced {
+            let path = self.root.join(&record.path);
+            self.storage.remove(&path).unwrap_or_else(|err| warn!("Failed to remove {:?}: {}", path, err));
+        }
+        info!("Imported {} entries", records.len());
+    }
+
+    /// Copies the content in archive `r` under the root, adding the path of each file to `copied` as soon as it's created,
+    /// then records the entries, returning them and the entries they replaced.
+    #[throws] fn import_entries(&mut self, r: impl io::Read, copied: &mut Vec<path::PathBuf>) -> (Vec<(reqwest::Url, db::CacheRecord)>, Vec<db::CacheRecord>) {
+        let mut index = None;
+        let mut paths = HashMap::new();
+        for entry in tar::Archive::new(r).entries()? {
+            let mut entry = entry?;
+            let name = entry.path()?.to_str().ok_or_else(|| anyhow::anyhow!("Archive path is not UTF-8: {:?}", entry.path()))?.to_owned();
+            if name == INDEX {
+                let mut text = String::new();
+                io::Read::read_to_string(&mut entry, &mut text)?;
+                index = Some(text);
+            } else {
+                let (mut handle, path) = self.create_file("content")?;
+                copied.push(path.clone());
+                io::copy(&mut entry, &mut handle)?;
+                // If the archive has several files of the same name, the last one wins, and the others are left unused.
+                paths.insert(name, path);
+            }
+        }
+        let index = index.ok_or_else(|| anyhow::anyhow!("Archive has no {}", INDEX))?;
+        // An index with several lines for the same URL keeps the last one.
+        let mut records = HashMap::new();
+        for line in index.lines() {
+            let fields: Vec<_> = line.split('\t').collect();
+            let (url, name, last_modified, etag, fresh_until, no_cache) = match fields[..] {
+                [url, name, last_modified, etag, fresh_until, no_cache] => (url, name, last_modified, etag, fresh_until, no_cache),
+                _ => anyhow::bail!("Malformed archive index line: {:?}", line),
+            };
+            let path = match paths.remove(name) { Some(path) => path, None => { warn!("Archive has no content for {}, skipping it", url); continue } };
+            let fresh_until = match fresh_until {
+                "" => None,
+                secs => Some(time::UNIX_EPOCH.checked_add(time::Duration::from_secs(secs.parse()?)).ok_or_else(|| anyhow::anyhow!("Invalid freshness in archive index line: {:?}", line))?),
+            };
+            records.insert(url.parse::<reqwest::Url>()?, db::CacheRecord {
+                path: path.strip_prefix(&self.root)?.to_str().unwrap().into(),
+                last_modified: Some(last_modified).filter(|date| !date.is_empty()).map(ToOwned::to_owned),
+                etags: db::ETag::parse_list(etag),
+                complete: true,
+                fresh_until,
+                no_cache: no_cache == "no-cache",
+                status: None,
+                fetched_at: None,
+                validated_at: None,
+                declared_length: None,
+                response_date: None,
+                compressed: false,
+            });
+        }
+        let mut replaced = vec![];
+        for url in records.keys() {
+            match self.db.get(url.clone()) {
+                Ok(record) => replaced.push(record),
+                Err(db::DbError::NotFound(_)) => {},
+                Err(err) => Err(err)?,
+            }
+        }
+        let records: Vec<_> = records.into_iter().collect();
+        self.db.set_many(records.clone())?.commit()?;
+        (records, replaced)
+    }
+}
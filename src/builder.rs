@@ -0,0 +1,342 @@
+//! Configuration for creating a [`Cache`](../struct.Cache.html).
+use {fehler::{throw, throws}, anyhow::Error, std::{fs, path, sync::Arc, time}, reqwest::header::*, crate::{db, reqwest_mock, shared, Cache, CacheError, Clock, FreshnessPolicy, FsStorage, RevalidationPolicy, SharedCache, Storage, Validator}};
+
+/// Settings that change how a [`Cache`] behaves.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct Options {
+    pub validate_with_head: bool,
+    pub read_only: bool,
+    pub ignore_query_params: Vec<String>,
+    pub normalize_urls: bool,
+    pub sharded: bool,
+    pub revalidation: RevalidationPolicy,
+    /// What decides whether cached data is fresh, replacing `revalidation` if set.
+    pub freshness: Option<Arc<dyn FreshnessPolicy>>,
+    pub validator: Validator,
+    pub evict_when_gone: bool,
+    pub max_retry_wait: Option<time::Duration>,
+    pub heuristic_freshness: Option<f64>,
+    pub max_file_size: Option<u64>,
+    pub free_space_reserve: Option<u64>,
+    pub max_stale: Option<time::Duration>,
+    /// How many redirections to follow ourselves, or leave them to the client if `None`.
+    pub max_redirects: Option<usize>,
+    pub access_debounce: Option<time::Duration>,
+    pub stale_while_revalidate: Option<time::Duration>,
+    pub strict_length: bool,
+    pub expected_size: Option<u64>,
+    pub compress_at_rest: bool,
+    /// Which response headers to store for [`Cache::get_headers`], or the defaults if `None`.
+    pub stored_headers: Option<Vec<HeaderName>>,
+    /// Where to get the current time from, or the system clock if `None`.
+    pub clock: Option<Arc<dyn Clock>>,
+    pub db: db::DbOptions,
+}
+
+impl Options {
+    /// The response headers to store for [`Cache::get_headers`].
+    pub fn headers_to_store(&self) -> Vec<HeaderName> {
+        self.stored_headers.clone().unwrap_or_else(|| vec![CONTENT_TYPE, CONTENT_DISPOSITION, CONTENT_ENCODING, CONTENT_LANGUAGE, CACHE_CONTROL])
+    }
+}
+
+/// Configures and creates a [`Cache`].
+///
+/// Obtain one from [`Cache::builder`].
+/// [`Cache::new`] is a shortcut for a builder with every option left at its default.
+#[derive(Debug)]
+pub struct CacheBuilder<C: reqwest_mock::Client> {
+    pub(crate) root: path::PathBuf,
+    pub(crate) client: C,
+    pub(crate) options: Options,
+}
+
+impl<C: reqwest_mock::Client> CacheBuilder<C> {
+    pub(crate) fn new(root: path::PathBuf, client: C) -> Self {
+        CacheBuilder{root, client, options: Default::default()}
+    }
+
+    /// Whether to validate cached data with a `HEAD` request before any `GET`.
+    ///
+    /// When enabled, a cached URL is first checked with a `HEAD` request.
+    /// If the `ETag`, `Last-Modified` and `Content-Length` headers it returns agree with what's cached, the cached data is used without sending a `GET` at all.
+    /// Otherwise, the cache falls back to its usual conditional `GET`.
+    ///
+    /// This costs an extra round-trip when the resource has changed, so it's only worthwhile for very large resources on servers that don't handle conditional requests well.
+    /// Defaults to `false`.
+    pub fn validate_with_head(mut self, enabled: bool) -> Self { self.options.validate_with_head = enabled; self }
+
+    /// Whether to open the cache without ever modifying it.
+    ///
+    /// See [`Cache::open_read_only`] for details.
+    /// Defaults to `false`.
+    pub fn read_only(mut self, enabled: bool) -> Self { self.options.read_only = enabled; self.options.db.read_only = enabled; self }
+
+    /// Query parameters to ignore when deciding whether two URLs are the same resource.
+    ///
+    /// Each entry is either the name of a parameter, or a prefix followed by `*` (like `"utm_*"`).
+    /// Matching parameters are removed from a URL before it is looked up or stored in the cache,
+    /// so `http://example.com/?utm_source=a` and `http://example.com/?utm_source=b` share the entry for `http://example.com/`.
+    /// The server is still sent the URL you asked for.
+    ///
+    /// Like the rest of the cache, this only ever sees URLs with their fragment already removed, unless [`CacheBuilder::keep_fragment`] is enabled.
+    /// Defaults to no parameters.
+    pub fn ignore_query_params(mut self, names: Vec<String>) -> Self { self.options.ignore_query_params = names; self }
+
+    /// Whether to normalize percent-encoding in URLs before looking them up or storing them.
+    ///
+    /// Parsing a URL already lower-cases its scheme and host, removes the default port, and resolves `.` and `..` path segments.
+    /// When this is enabled, percent-encoded unreserved characters in the path and query are also decoded (so `%7E` becomes `~`),
+    /// and other percent-encodings are upper-cased (so `%2f` becomes `%2F`), as described in [RFC 3986 section 6.2.2].
+    /// This lets URLs that differ only in those ways share a cache entry.
+    /// The server is still sent the URL you asked for.
+    ///
+    /// Defaults to `false`.
+    ///
+    /// [RFC 3986 section 6.2.2]: https://tools.ietf.org/html/rfc3986#section-6.2.2
+    pub fn normalize_urls(mut self, enabled: bool) -> Self { self.options.normalize_urls = enabled; self }
+
+    /// Whether to spread downloaded files across subdirectories, rather than keeping them all in one directory.
+    ///
+    /// Some filesystems slow down badly with hundreds of thousands of files in one directory.
+    /// When this is enabled, each new file is put in a subdirectory named after the first two characters of its name,
+    /// like `content/ab/cdef…`.
+    /// Entries stored before this was enabled keep working, as does disabling it again later.
+    ///
+    /// Defaults to `false`.
+    pub fn sharded(mut self, enabled: bool) -> Self { self.options.sharded = enabled; self }
+
+    /// When to revalidate cached data with the server.
+    ///
+    /// See [`Cache::set_revalidation`], which can also change this later.
+    /// Defaults to [`RevalidationPolicy::Always`].
+    pub fn revalidation(mut self, policy: RevalidationPolicy) -> Self { self.options.revalidation = policy; self }
+
+    /// What decides whether cached data is fresh enough to use without revalidating it.
+    ///
+    /// Once set, this replaces the [`RevalidationPolicy`], whether it was set with [`CacheBuilder::revalidation`] or [`Cache::set_revalidation`].
+    /// [`DefaultPolicy`](struct.DefaultPolicy.html) and [`AlwaysRevalidate`](struct.AlwaysRevalidate.html) behave like
+    /// [`RevalidationPolicy::WhenStale`] and [`RevalidationPolicy::Always`]; implement [`FreshnessPolicy`] for other rules.
+    /// Defaults to `None`, following the revalidation policy.
+    pub fn freshness_policy(mut self, policy: impl FreshnessPolicy + 'static) -> Self { self.options.freshness = Some(Arc::new(policy)); self }
+
+    /// Which conditional headers to send when revalidating cached data that has both an `ETag` and a `Last-Modified` date.
+    ///
+    /// Data with only one of them is always revalidated with that one.
+    /// Defaults to [`Validator::Both`], sending both `If-None-Match` and `If-Modified-Since`.
+    pub fn prefer_validator(mut self, validator: Validator) -> Self { self.options.validator = validator; self }
+
+    /// Whether to remove a cached URL when revalidating it finds it no longer exists.
+    ///
+    /// When enabled, a `404 Not Found` or `410 Gone` response to a revalidation removes the entry,
+    /// and [`Cache::get`] returns [`CacheError::Gone`](enum.CacheError.html#variant.Gone).
+    /// Otherwise, the cached copy keeps being used, as it is whenever the server can't be reached.
+    ///
+    /// Either way, other unexpected responses (like `204 No Content`) never replace cached data.
+    /// Defaults to `false`.
+    pub fn evict_when_gone(mut self, enabled: bool) -> Self { self.options.evict_when_gone = enabled; self }
+
+    /// Whether to retry a request the server answers with `503 Service Unavailable`, and for how long we're willing to wait.
+    ///
+    /// When set, if the response has a `Retry-After` header, the cache waits as long as it asks, but no longer than `max_wait`,
+    /// then sends the request once more.
+    /// Otherwise, or if the server is still unavailable, the error is a [`CacheError::Unavailable`](enum.CacheError.html#variant.Unavailable)
+    /// saying how long the server asked us to wait.
+    ///
+    /// This only applies to downloads: when cached data can't be revalidated, it is used straight away.
+    /// Defaults to `None`, never retrying.
+    pub fn retry_unavailable(mut self, max_wait: Option<time::Duration>) -> Self { self.options.max_retry_wait = max_wait; self }
+
+    /// Whether to guess how long responses that don't say how long they're fresh for stay fresh, and by how much.
+    ///
+    /// When set, a response with no `Cache-Control: max-age` or `Expires` header is considered fresh for `fraction`
+    /// of the time it had gone unchanged when it was downloaded: since its `Last-Modified` date, or if it has none, since its `Date`.
+    /// [RFC 7234](https://tools.ietf.org/html/rfc7234#section-4.2.2) suggests a fraction of `0.1`, that is 10%.
+    /// This lets resources that rarely change be served without a round trip for a while, even when they have no validators.
+    ///
+    /// Like explicit freshness lifetimes, this only has an effect with [`RevalidationPolicy::WhenStale`],
+    /// and never applies to responses marked `Cache-Control: no-cache`.
+    /// `fraction` must be between 0 and 1; anything else makes building the cache fail.
+    /// Defaults to `None`, only trusting explicit freshness lifetimes.
+    pub fn heuristic_freshness(mut self, fraction: Option<f64>) -> Self { self.options.heuristic_freshness = fraction; self }
+
+    /// The largest response body, in bytes, the cache will download.
+    ///
+    /// See [`Cache::set_max_file_size`], which can also change this later.
+    /// Defaults to `None`, no limit.
+    pub fn max_file_size(mut self, bytes: Option<u64>) -> Self { self.options.max_file_size = bytes; self }
+
+    /// How many redirections the cache follows for each request, failing with [`CacheError::TooManyRedirects`] after that.
+    ///
+    /// See [`Cache::set_max_redirects`], which can also change this later.
+    /// Defaults to `None`, leaving redirections to the client.
+    pub fn max_redirects(mut self, max: Option<usize>) -> Self { self.options.max_redirects = max; self }
+
+    /// Whether to check there's room for a download before starting it, and how many bytes to leave free if so.
+    ///
+    /// When this is `Some(reserve)` and a response announces its `Content-Length`,
+    /// the download fails with [`CacheError::OutOfSpace`](enum.CacheError.html#variant.OutOfSpace) before writing anything
+    /// unless the filesystem holding the cache would still have `reserve` bytes free afterwards.
+    /// See also [`Cache::has_room_for`].
+    /// Defaults to `None`, no check.
+    pub fn reserve_free_space(mut self, reserve: Option<u64>) -> Self { self.options.free_space_reserve = reserve; self }
+
+    /// How often to record accesses to each URL, at most.
+    ///
+    /// Every retrieval records the time it happened, for [`Cache::most_accessed`] and [`Cache::prune_expired`],
+    /// which means a write to the metadata database even when the content is served from the cache.
+    /// With `Some(interval)`, an access less than `interval` after the last recorded one for the same URL isn't recorded,
+    /// so frequently used URLs don't cause a write on every retrieval.
+    /// Access times are then only accurate to within `interval`, and access counts only count recorded accesses.
+    /// Defaults to `None`, recording every access.
+    pub fn access_debounce(mut self, interval: Option<time::Duration>) -> Self { self.options.access_debounce = interval; self }
+
+    /// How long after cached data goes stale a [`SharedCache`] may keep serving it while revalidating it in the background.
+    ///
+    /// With `Some(window)`, when [`SharedCache::get`] finds data that needs revalidating
+    /// but went stale less than `window` ago according to its `max-age` or `Expires`,
+    /// it returns the cached copy straight away, and revalidates it on another thread so that later retrievals get the current version.
+    /// Only one background revalidation of each URL runs at a time.
+    /// Data marked `Cache-Control: no-cache`, data without a freshness lifetime, and data older than [`Cache::set_max_stale`] allows
+    /// are always revalidated before they're served.
+    /// A [`Cache`] can't revalidate in the background, so this only affects [`SharedCache`].
+    /// Defaults to `None`, always revalidating before serving.
+    pub fn stale_while_revalidate(mut self, window: Option<time::Duration>) -> Self { self.options.stale_while_revalidate = window; self }
+
+    /// Whether a download whose length differs from its `Content-Length` header fails.
+    ///
+    /// Such a mismatch usually means the transfer was cut short.
+    /// When enabled, the download is discarded, and [`Cache::get`] returns [`CacheError::LengthMismatch`](enum.CacheError.html#variant.LengthMismatch).
+    /// Otherwise, the mismatch is only logged.
+    /// Defaults to `false`.
+    pub fn strict_length(mut self, enabled: bool) -> Self { self.options.strict_length = enabled; self }
+
+    /// Which response headers to store with each download, so [`Cache::get_headers`] can return them later.
+    ///
+    /// This is for re-serving cached content with the headers it was originally served with.
+    /// Leave out hop-by-hop headers like `Connection` and `Transfer-Encoding`, which only describe the original connection.
+    /// The validators and `Content-Length` are stored anyway, and needn't be listed.
+    /// Defaults to `Content-Type`, `Content-Disposition`, `Content-Encoding`, `Content-Language` and `Cache-Control`.
+    pub fn store_headers(mut self, names: Vec<HeaderName>) -> Self { self.options.stored_headers = Some(names); self }
+
+    /// How many bytes the cache is expected to need, so that a lack of room is noticed when it's created rather than during a download.
+    ///
+    /// When set, [`CacheBuilder::build`] fails with [`CacheError::InsufficientSpace`](enum.CacheError.html#variant.InsufficientSpace)
+    /// unless the filesystem holding the cache has that many bytes free,
+    /// on top of the reserve set with [`CacheBuilder::reserve_free_space`], if any.
+    /// Data already in the cache doesn't count towards it, so this is best suited to a cache that's about to be filled.
+    /// A read-only cache isn't checked.
+    /// Defaults to `None`, no check.
+    pub fn expected_size(mut self, bytes: Option<u64>) -> Self { self.options.expected_size = bytes; self }
+
+    /// Whether to gzip-compress downloaded content as it's stored, to save disk space.
+    ///
+    /// Compressed content is decompressed whenever it's retrieved, into a scratch directory that is removed when the [`Cache`] is dropped,
+    /// so this suits large, compressible resources like logs and JSON documents, at the cost of extra work on every retrieval.
+    /// Each entry records whether it's compressed, so entries stored with and without this setting can be mixed.
+    /// Sizes on disk, as [`Cache::trim`] counts them, are the compressed sizes.
+    /// A compressed download that's interrupted is discarded rather than resumed.
+    /// Defaults to `false`.
+    pub fn compress_at_rest(mut self, enabled: bool) -> Self { self.options.compress_at_rest = enabled; self }
+
+    /// Where the cache gets the current time from.
+    ///
+    /// Every decision that depends on the time, like whether a response is still fresh or when a URL was last accessed, asks `clock`.
+    /// Pass a [`TestClock`](struct.TestClock.html) to test such behaviour without waiting.
+    /// Defaults to [`SystemClock`](struct.SystemClock.html).
+    /// A [`MemoryStorage`](struct.MemoryStorage.html) should be given the same clock, with [`MemoryStorage::with_clock`](struct.MemoryStorage.html#method.with_clock).
+    pub fn clock(mut self, clock: impl Clock + 'static) -> Self { self.options.clock = Some(Arc::new(clock)); self }
+
+    /// Whether to keep the fragment (the part after `#`) of a URL, so that URLs differing only in their fragment get separate cache entries.
+    ///
+    /// By default the fragment is removed before a URL is looked up, stored, or requested,
+    /// since it only identifies a part of the resource and is never sent to the server.
+    /// Enable this when the fragment selects different content, for example for an API that encodes a version there.
+    /// HTTP clients still don't send the fragment, so the server sees the same request either way.
+    ///
+    /// Defaults to `false`.
+    pub fn keep_fragment(mut self, enabled: bool) -> Self { self.options.db.keep_fragment = enabled; self }
+
+    /// Whether to store a hash of each URL longer than `threshold` bytes as its key, rather than the URL itself.
+    ///
+    /// Very long URLs, like signed S3 URLs with many query parameters, make looking up entries slow.
+    /// When this is set, such URLs are looked up by a fixed-size hash instead.
+    /// The full URL is still stored alongside it, so it can be listed, trimmed, exported and invalidated by prefix as usual.
+    ///
+    /// Hashing doesn't keep a URL out of the metadata database: the full URL is stored alongside its hash,
+    /// as is the URL each entry was requested from, so that [`Cache::refresh_all`] can request it again.
+    /// Credentials in query strings, like the signatures in signed URLs, are therefore stored in plain text,
+    /// so protect the cache directory accordingly, or prefer credentials that expire soon.
+    ///
+    /// Entries stored under a different threshold may not be found by this one.
+    /// Defaults to `None`, storing every URL as it is.
+    pub fn hash_long_urls(mut self, threshold: Option<usize>) -> Self { self.options.db.hash_urls_longer_than = threshold; self }
+
+    /// Whether to start a new metadata database when the existing one is corrupt.
+    ///
+    /// The corrupt database is renamed to `cache.db.corrupt`, so it can be inspected later, and a warning is logged.
+    /// The downloaded files it described are left where they are, but will no longer be found.
+    /// Otherwise, opening a cache with a corrupt database fails with [`DbError::Corrupt`](enum.DbError.html#variant.Corrupt).
+    /// A read-only cache never recreates its database.
+    ///
+    /// Defaults to `false`.
+    pub fn recreate_on_corruption(mut self, enabled: bool) -> Self { self.options.db.recreate_on_corruption = enabled; self }
+
+    /// SQLite `PRAGMA` settings to apply to the metadata database whenever it's opened, as (name, value) pairs.
+    ///
+    /// Each pair runs `PRAGMA name = value;` before anything else uses the database,
+    /// so this can tune its trade-offs between speed and durability.
+    /// The metadata database uses write-ahead logging, so `("synchronous", "NORMAL")` is safe,
+    /// and faster than the default `FULL`: a power failure may lose the latest changes, but won't corrupt the database.
+    /// `cache_size` and `mmap_size` trade memory for speed.
+    /// Names and values must be plain identifiers or integers; anything else makes opening the cache fail.
+    /// Defaults to no extra pragmas.
+    pub fn db_pragmas(mut self, pragmas: Vec<(String, String)>) -> Self { self.options.db.pragmas = pragmas; self }
+
+    /// How long to wait for another [`Cache`] sharing the same metadata database to finish writing, before giving up.
+    ///
+    /// Defaults to 5 seconds.
+    pub fn busy_timeout(mut self, timeout: time::Duration) -> Self { self.options.db.busy_timeout = timeout; self }
+
+    /// Creates the configured [`Cache`].
+    ///
+    /// # Errors
+    ///   - `root` cannot be created, or cannot be written to
+    ///   - the filesystem holding `root` has less free space than [`CacheBuilder::expected_size`] asks for
+    ///   - the [`CacheBuilder::heuristic_freshness`] fraction isn't between 0 and 1
+    ///   - the metadata database cannot be created or cannot be written to
+    ///   - the metadata database is corrupt
+    #[throws] pub fn build(self) -> Cache<C> { self.build_with_storage(FsStorage)? }
+
+    /// Creates the configured [`Cache`], keeping content in `storage` rather than in files under the root.
+    ///
+    /// The metadata database and download locks are still kept under the root.
+    /// A cache using another storage can't return content as a `std::fs::File` or a path,
+    /// so it's retrieved with [`Cache::get_bytes`], [`Cache::get_string`] or [`Cache::get_range`] instead.
+    ///
+    /// # Errors
+    /// The same as [`CacheBuilder::build`].
+    #[throws] pub fn build_with_storage<S: Storage>(self, storage: S) -> Cache<C, S> {
+        let CacheBuilder{root, client, options} = self;
+        if let Some(fraction) = options.heuristic_freshness.filter(|fraction| !(0. ..=1.).contains(fraction)) {
+            anyhow::bail!("The heuristic freshness fraction must be between 0 and 1, not {}", fraction)
+        }
+        if !options.read_only { fs::DirBuilder::new().recursive(true).create(&root)?; }
+        let root = root.canonicalize()?;
+        if let Some(needed) = options.expected_size.filter(|_| !options.read_only) {
+            let (room, available) = crate::room_for(&root, needed, options.free_space_reserve.unwrap_or(0))?;
+            if !room { throw!(CacheError::InsufficientSpace{root, needed, available}) }
+        }
+        let db = db::CacheDB::open(root.join("cache.db"), &options.db)?;
+        Cache::from_parts(root, db, client, options, storage)
+    }
+
+    /// Creates a [`SharedCache`] with the configured options, which can be used from several threads at once.
+    ///
+    /// # Errors
+    /// The same as [`CacheBuilder::build`].
+    #[throws] pub fn build_shared(self) -> SharedCache<C> where C: Clone {
+        let Cache{root, client, options, ..} = self.build()?;
+        SharedCache{root, client, options, refreshing: Default::default(), in_flight: Default::default(), pool: shared::Pool::new()}
+    }
+}
@@ -0,0 +1,44 @@
+//! Where a cache gets the current time from.
+use std::{fmt, sync::{Arc, Mutex}, time};
+
+/// A source of the current time, used for every freshness and timestamp decision a [`Cache`](../struct.Cache.html) makes.
+///
+/// See [`CacheBuilder::clock`](../struct.CacheBuilder.html#method.clock).
+pub trait Clock: fmt::Debug + Send + Sync {
+    /// Returns the current time.
+    fn now(&self) -> time::SystemTime;
+}
+
+/// The system's clock, which caches use by default.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> time::SystemTime { time::SystemTime::now() }
+}
+
+/// A clock that only moves when told to, for testing time-dependent behaviour deterministically.
+///
+/// Clones share the same time, so you can keep one to advance after giving another to a cache.
+#[derive(Clone, Debug)]
+pub struct TestClock(Arc<Mutex<time::SystemTime>>);
+
+impl TestClock {
+    /// Returns a clock stopped at `now`.
+    pub fn new(now: time::SystemTime) -> TestClock { TestClock(Arc::new(Mutex::new(now))) }
+
+    /// Moves the clock forward by `duration`.
+    pub fn advance(&self, duration: time::Duration) { *self.0.lock().unwrap() += duration; }
+
+    /// Sets the clock to `now`, which may be earlier than its current time.
+    pub fn set(&self, now: time::SystemTime) { *self.0.lock().unwrap() = now; }
+}
+
+impl Default for TestClock {
+    /// Returns a clock stopped at the current system time.
+    fn default() -> Self { TestClock::new(time::SystemTime::now()) }
+}
+
+impl Clock for TestClock {
+    fn now(&self) -> time::SystemTime { *self.0.lock().unwrap() }
+}
@@ -1,39 +1,382 @@
-use {fehler::throws, anyhow::Error, std::{cmp, error, ffi, fmt, iter, path}, log::{warn, debug}, sqlite, reqwest};
-
-const SCHEMA_SQL: &str = "
+use {fehler::{throw, throws}, anyhow::Error, std::{cell, cmp, error, ffi, fmt, fs, io, iter, path, time}, log::{warn, debug}};
+
+/// The steps that build the database schema, oldest first.
+///
+/// The database's `user_version` records how many of these have been applied,
+/// so new steps must only ever be appended to this list.
+const MIGRATIONS: &[&str] = &[
+    // 1: The original schema.
+    "
     CREATE TABLE urls (
     	url TEXT NOT NULL UNIQUE,
     	path TEXT NOT NULL,
     	last_modified TEXT,
     	etag TEXT
     );
-";
+    ",
+    // 2: Usage tracking.
+    "
+    ALTER TABLE urls ADD COLUMN access_count INTEGER NOT NULL DEFAULT 0;
+    ALTER TABLE urls ADD COLUMN last_accessed INTEGER NOT NULL DEFAULT 0;
+    UPDATE urls SET last_accessed = CAST(strftime('%s', 'now') AS INTEGER);
+    ",
+    // 3: Resumable downloads.
+    "
+    ALTER TABLE urls ADD COLUMN complete INTEGER NOT NULL DEFAULT 1;
+    ",
+    // 4: Freshness lifetimes.
+    "
+    ALTER TABLE urls ADD COLUMN fresh_until INTEGER;
+    ",
+    // 5: The URL each entry's content was requested from, when that isn't the URL it's stored under.
+    "
+    ALTER TABLE urls ADD COLUMN source_url TEXT;
+    ",
+    // 6: Cache-Control: no-cache.
+    "
+    ALTER TABLE urls ADD COLUMN no_cache INTEGER NOT NULL DEFAULT 0;
+    ",
+    // 7: Response status codes.
+    "
+    ALTER TABLE urls ADD COLUMN status INTEGER;
+    ",
+    // 8: Download times.
+    "
+    ALTER TABLE urls ADD COLUMN fetched_at INTEGER;
+    ",
+    // 9: Revalidation times.
+    "
+    ALTER TABLE urls ADD COLUMN validated_at INTEGER;
+    ",
+    // 10: Content lengths.
+    "
+    ALTER TABLE urls ADD COLUMN declared_length INTEGER;
+    ",
+    // 11: Response dates.
+    "
+    ALTER TABLE urls ADD COLUMN response_date INTEGER;
+    ",
+    // 12: Pinned entries.
+    "
+    ALTER TABLE urls ADD COLUMN pinned INTEGER NOT NULL DEFAULT 0;
+    ",
+    // 13: Response headers to replay, one "name: value" per line.
+    "
+    ALTER TABLE urls ADD COLUMN headers TEXT;
+    ",
+    // 14: The full URL of entries whose `url` is a hash of it.
+    "
+    ALTER TABLE urls ADD COLUMN full_url TEXT;
+    ",
+    // 15: Content compressed at rest.
+    "
+    ALTER TABLE urls ADD COLUMN compressed INTEGER NOT NULL DEFAULT 0;
+    ",
+];
+
+/// The URL an entry is stored under, whether or not its `url` column holds a hash of it.
+const FULL_URL: &str = "coalesce(full_url, url)";
+
+/// Matches URLs starting with the string `?2`, given `?1` is that string escaped for `LIKE` by [`prefix_params`].
+///
+/// `LIKE` ignores ASCII case, so the match is confirmed with an exact comparison; `LIKE` narrows it down first.
+const PREFIX_MATCH: &str = "coalesce(full_url, url) LIKE ?1 || '%' ESCAPE '\\' AND substr(coalesce(full_url, url), 1, length(?2)) = ?2";
+
+/// The parameters for [`PREFIX_MATCH`].
+fn prefix_params(prefix: &str) -> [sqlite::Value; 2] {
+    let escaped = prefix.replace('\\', "\\\\").replace('%', "\\%").replace('_', "\\_");
+    [sqlite::Value::String(escaped), sqlite::Value::String(prefix.into())]
+}
+
+/// The columns [`record_from_row`] expects, in order.
+const RECORD_COLUMNS: &str = "path, last_modified, etag, complete, fresh_until, no_cache, status, fetched_at, validated_at, declared_length, response_date, compressed";
 
 /// All the information we have about a given URL.
+///
+/// Only the cache makes these, so that fields can be added without breaking code that reads them.
 #[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[non_exhaustive]
 pub struct CacheRecord {
     /// The path to the cached response body on disk.
     pub path: String,
     /// The value of the Last-Modified header in the original response.
     pub last_modified: Option<String>,
-    /// The value of the Etag header in the original response.
-    pub etag: Option<String>,
+    /// The entity tags the original response was identified by, if any.
+    ///
+    /// Usually there's at most one, but a server may send several `ETag` headers.
+    pub etags: Vec<ETag>,
+    /// Whether the whole response body has been written to `path`.
+    ///
+    /// If not, the download was interrupted, and `path` holds a prefix of the body that may be resumed.
+    pub complete: bool,
+    /// Until when the original response said it could be used without revalidation, if it said.
+    pub fresh_until: Option<time::SystemTime>,
+    /// Whether the original response said it must be revalidated before every use, with `Cache-Control: no-cache`.
+    pub no_cache: bool,
+    /// The status code of the most recent response from the server about this URL, if any.
+    ///
+    /// This is usually `200 OK`, or `304 Not Modified` after a successful revalidation.
+    pub status: Option<u16>,
+    /// When the cached content was downloaded, if it was.
+    ///
+    /// This isn't changed when the server confirms the content is unchanged.
+    pub fetched_at: Option<time::SystemTime>,
+    /// When the server last said the cached content was current, either by sending it or by confirming it's unchanged.
+    pub validated_at: Option<time::SystemTime>,
+    /// The length of the response body, as given by the `Content-Length` header of the response that was downloaded.
+    ///
+    /// This is known as soon as the download starts, so it's available for interrupted downloads too.
+    pub declared_length: Option<u64>,
+    /// When the server says it generated the downloaded response, from its `Date` header, if it had a valid one.
+    ///
+    /// Comparing this with `fetched_at` shows how far the server's clock is behind ours, or how long the response sat in other caches.
+    pub response_date: Option<time::SystemTime>,
+    /// Whether the content at `path` is gzip-compressed, rather than stored as the server sent it.
+    ///
+    /// See [`CacheBuilder::compress_at_rest`](struct.CacheBuilder.html#method.compress_at_rest).
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub compressed: bool,
+}
+
+/// An entity tag, as sent in an `ETag` header.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ETag {
+    /// The opaque tag, including its quotes if the server sent any.
+    pub tag: String,
+    /// Whether this is a weak validator, marked with a `W/` prefix.
+    pub weak: bool,
+}
+
+impl ETag {
+    /// Parse the value of an `ETag` header.
+    pub fn parse(value: &str) -> ETag {
+        match value.strip_prefix("W/") {
+            Some(tag) => ETag { tag: tag.into(), weak: true },
+            None => ETag { tag: value.into(), weak: false },
+        }
+    }
+
+    /// Parse a comma-separated list of entity tags, as sent in `If-None-Match`.
+    pub fn parse_list(value: &str) -> Vec<ETag> {
+        let mut tags = vec![];
+        let (mut start, mut quoted) = (0, false);
+        for (i, c) in value.char_indices() {
+            match c {
+                '"' => quoted = !quoted,
+                ',' if !quoted => { tags.push(&value[start..i]); start = i + 1; }
+                _ => {}
+            }
+        }
+        tags.push(&value[start..]);
+        tags.into_iter().map(str::trim).filter(|tag| !tag.is_empty()).map(ETag::parse).collect()
+    }
+
+    /// Format a list of entity tags the way `If-None-Match` expects.
+    pub fn join(tags: &[ETag]) -> String {
+        tags.iter().map(ToString::to_string).collect::<Vec<_>>().join(", ")
+    }
+}
+
+impl From<&str> for ETag {
+    fn from(value: &str) -> ETag { ETag::parse(value) }
+}
+
+impl fmt::Display for ETag {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.weak { f.write_str("W/")? }
+        f.write_str(&self.tag)
+    }
+}
+
+/// The ways a database operation can fail.
+#[derive(Debug)]
+pub enum DbError {
+    /// The URL is not in the cache.
+    NotFound(reqwest::Url),
+    /// The database contains data we can't make sense of.
+    Corrupt(String),
+    /// A content path isn't a plain relative path, so it may name a file outside the cache root.
+    ///
+    /// The cache never stores such paths itself, so a database holding one was written by something else.
+    InvalidPath(String),
+    /// SQLite reported an error.
+    Sqlite(sqlite::Error),
+}
+
+impl fmt::Display for DbError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DbError::NotFound(url) => write!(f, "URL not found in cache: {:?}", url.as_str()),
+            DbError::Corrupt(message) => f.write_str(message),
+            DbError::InvalidPath(path) => write!(f, "Content path {:?} leads outside the cache root", path),
+            DbError::Sqlite(err) => write!(f, "SQLite error: {}", err),
+        }
+    }
+}
+
+impl error::Error for DbError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            DbError::Sqlite(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<sqlite::Error> for DbError {
+    fn from(err: sqlite::Error) -> DbError { DbError::Sqlite(err) }
+}
+
+/// Whether `err` is SQLite complaining that a file is damaged, or not a database at all.
+fn is_corruption(err: &Error) -> bool {
+    const SQLITE_CORRUPT: isize = 11;
+    const SQLITE_NOTADB: isize = 26;
+    let err = match (err.downcast_ref::<sqlite::Error>(), err.downcast_ref::<DbError>()) {
+        (Some(err), _) | (None, Some(DbError::Sqlite(err))) => err,
+        _ => return false,
+    };
+    matches!(err.code, Some(SQLITE_CORRUPT) | Some(SQLITE_NOTADB))
+}
+
+/// Convert a timestamp to whole seconds since the Unix epoch, as stored in the database.
+fn unix_time(time: time::SystemTime) -> i64 {
+    time.duration_since(time::UNIX_EPOCH).map_or(0, |d| d.as_secs() as i64)
+}
+
+fn from_unix_time(secs: i64) -> time::SystemTime {
+    time::UNIX_EPOCH + time::Duration::from_secs(cmp::max(secs, 0) as u64)
+}
+
+/// Whether `path` is a relative path made only of plain names, so that it stays inside whatever directory it's joined to.
+fn is_contained(path: &str) -> bool {
+    let path = path::Path::new(path);
+    !path.as_os_str().is_empty() && path.components().all(|component| matches!(component, path::Component::Normal(_)))
+}
+
+/// Decode a row of [`RECORD_COLUMNS`] into a record.
+fn record_from_row(row: Vec<sqlite::Value>) -> Result<CacheRecord, DbError> {
+    let mut cols = row.into_iter();
+
+    let path = match cols.next().unwrap() {
+        sqlite::Value::String(s) => Ok(s),
+        other => Err(DbError::Corrupt(format!("Path had wrong type: {:?}", other))),
+    }?;
+    if !is_contained(&path) { return Err(DbError::InvalidPath(path)) }
+
+    let last_modified = match cols.next().unwrap() {
+        sqlite::Value::String(s) => Some(s),
+        sqlite::Value::Null => None,
+        other => {
+            warn!(
+                "last_modified contained weird type: {:?}",
+                other,
+            );
+            None
+        },
+    };
+
+    let etags = match cols.next().unwrap() {
+        sqlite::Value::String(s) => ETag::parse_list(&s),
+        sqlite::Value::Null => vec![],
+        other => {
+            warn!("etag contained weird type: {:?}", other);
+            vec![]
+        },
+    };
+
+    let complete = match cols.next().unwrap() {
+        sqlite::Value::Integer(complete) => Ok(complete != 0),
+        other => Err(DbError::Corrupt(format!("complete had wrong type: {:?}", other))),
+    }?;
+
+    let fresh_until = match cols.next().unwrap() {
+        sqlite::Value::Integer(secs) => Some(from_unix_time(secs)),
+        sqlite::Value::Null => None,
+        other => {
+            warn!("fresh_until contained weird type: {:?}", other);
+            None
+        },
+    };
+
+    let no_cache = match cols.next().unwrap() {
+        sqlite::Value::Integer(no_cache) => Ok(no_cache != 0),
+        other => Err(DbError::Corrupt(format!("no_cache had wrong type: {:?}", other))),
+    }?;
+
+    let status = match cols.next().unwrap() {
+        sqlite::Value::Integer(status) => Some(status as u16),
+        sqlite::Value::Null => None,
+        other => {
+            warn!("status contained weird type: {:?}", other);
+            None
+        },
+    };
+
+    let fetched_at = match cols.next().unwrap() {
+        sqlite::Value::Integer(secs) => Some(from_unix_time(secs)),
+        sqlite::Value::Null => None,
+        other => {
+            warn!("fetched_at contained weird type: {:?}", other);
+            None
+        },
+    };
+
+    let validated_at = match cols.next().unwrap() {
+        sqlite::Value::Integer(secs) => Some(from_unix_time(secs)),
+        sqlite::Value::Null => None,
+        other => {
+            warn!("validated_at contained weird type: {:?}", other);
+            None
+        },
+    };
+
+    let declared_length = match cols.next().unwrap() {
+        sqlite::Value::Integer(length) => Some(length as u64),
+        sqlite::Value::Null => None,
+        other => {
+            warn!("declared_length contained weird type: {:?}", other);
+            None
+        },
+    };
+
+    let response_date = match cols.next().unwrap() {
+        sqlite::Value::Integer(secs) => Some(from_unix_time(secs)),
+        sqlite::Value::Null => None,
+        other => {
+            warn!("response_date contained weird type: {:?}", other);
+            None
+        },
+    };
+
+    let compressed = match cols.next().unwrap() {
+        sqlite::Value::Integer(compressed) => Ok(compressed != 0),
+        other => Err(DbError::Corrupt(format!("compressed had wrong type: {:?}", other))),
+    }?;
+
+    Ok(CacheRecord{path, last_modified, etags, complete, fresh_until, no_cache, status, fetched_at, validated_at, declared_length, response_date, compressed})
+}
+
+/// Decode a row of `url` followed by [`RECORD_COLUMNS`].
+fn url_and_record_from_row(mut row: Vec<sqlite::Value>) -> Result<(reqwest::Url, CacheRecord), Error> {
+    match row.remove(0) {
+        sqlite::Value::String(url) => Ok((url.parse::<reqwest::Url>()?, record_from_row(row)?)),
+        other => Err(anyhow::anyhow!("URL had wrong type: {:?}", other)),
+    }
 }
 
 /// Represents the rows returned by a query.
 struct Rows<'a>(sqlite::Cursor<'a>);
 
 impl<'a> iter::Iterator for Rows<'a> {
-    type Item = Vec<sqlite::Value>;
+    type Item = Result<Vec<sqlite::Value>, sqlite::Error>;
 
     fn next(&mut self) -> Option<Self::Item> {
         self.0
             .next()
-            .unwrap_or_else(|err| {
-                warn!("Failed to get next row from SQLite: {}", err);
-                None
-            })
-            .map(|values| values.to_vec())
+            .transpose()
+            .map(|values| values.map(|values| values.to_vec()))
     }
 }
 
@@ -41,20 +384,24 @@ impl<'a> iter::Iterator for Rows<'a> {
 #[must_use]
 pub struct Transaction<'a> {
     connection: &'a sqlite::Connection,
+    open: &'a cell::Cell<bool>,
     committed: bool,
 }
 
 impl<'a> Transaction<'a> {
-    fn new(connection: &'a sqlite::Connection) -> Transaction<'a> {
+    fn new(connection: &'a sqlite::Connection, open: &'a cell::Cell<bool>) -> Transaction<'a> {
+        open.set(true);
         Transaction {
-            connection: connection,
+            connection,
+            open,
             committed: false,
         }
     }
 
-    pub fn commit(mut self) -> Result<(), sqlite::Error> {
+    pub fn commit(mut self) -> Result<(), DbError> {
         debug!("Attempting to commit changes...");
         self.committed = true;
+        self.open.set(false);
 
         self.connection.execute("COMMIT;").map_err(|err| {
             debug!("Failed to commit changes: {}", err);
@@ -82,11 +429,16 @@ impl<'a> Drop for Transaction<'a> {
             debug!("Attempting to rollback changes...");
             self.connection.execute("ROLLBACK;").unwrap_or_else(|err| {
                 debug!("Failed to rollback changes: {}", err)
-            })
+            });
+            self.open.set(false);
         }
     }
 }
 
+/// How many times each database has been opened, to check that connections are reused.
+#[cfg(test)]
+pub(crate) static OPENED: std::sync::Mutex<Option<std::collections::HashMap<path::PathBuf, usize>>> = std::sync::Mutex::new(None);
+
 #[throws] fn canonicalize_db_path(path: path::PathBuf) -> path::PathBuf {
     let mem_path: ffi::OsString = ":memory:".into();
 
@@ -105,28 +457,171 @@ impl<'a> Drop for Transaction<'a> {
     }
 }
 
+/// Settings for opening a [`CacheDB`].
+#[derive(Clone, Debug)]
+pub struct DbOptions {
+    /// How long to wait for another connection to release its lock before giving up.
+    pub busy_timeout: time::Duration,
+    /// Whether to refuse all modifications to the database.
+    pub read_only: bool,
+    /// Whether URLs keep their fragment, rather than having it removed before they're looked up or stored.
+    pub keep_fragment: bool,
+    /// Whether to move a corrupt database aside and start a new one, rather than fail with [`DbError::Corrupt`].
+    pub recreate_on_corruption: bool,
+    /// Extra `PRAGMA` statements to run on the connection after opening it, as (name, value) pairs.
+    pub pragmas: Vec<(String, String)>,
+    /// How long, in bytes, a URL may be before a hash of it is stored as its key instead, if there's a limit.
+    pub hash_urls_longer_than: Option<usize>,
+}
+
+impl Default for DbOptions {
+    fn default() -> Self {
+        DbOptions { busy_timeout: time::Duration::from_secs(5), read_only: false, keep_fragment: false, recreate_on_corruption: false, pragmas: Vec::new(), hash_urls_longer_than: None }
+    }
+}
+
 /// Represents the database that describes the contents of the cache.
 pub struct CacheDB {
     path: path::PathBuf,
     connection: sqlite::Connection,
+    keep_fragment: bool,
+    hash_urls_longer_than: Option<usize>,
+    /// Whether a [`Transaction`] has begun and not yet been committed or dropped.
+    in_transaction: cell::Cell<bool>,
 }
 
 impl CacheDB {
     /// Create a cache database in the given file.
-    #[throws] pub fn new(path: path::PathBuf) -> Self {
+    #[cfg(test)] #[throws] pub fn new(path: path::PathBuf) -> Self { Self::open(path, &Default::default())? }
+
+    /// Create a cache database in the given file, with the given settings.
+    ///
+    /// If the file exists but isn't a valid database, this fails with [`DbError::Corrupt`],
+    /// unless `options.recreate_on_corruption` is set,
+    /// in which case the file is renamed with a `.corrupt` suffix and a new database created in its place.
+    #[throws] pub fn open(path: path::PathBuf, options: &DbOptions) -> Self {
         let path = canonicalize_db_path(path)?;
+        #[cfg(test)]
+        { *OPENED.lock().unwrap().get_or_insert_with(Default::default).entry(path.clone()).or_default() += 1; }
+        match Self::connect(path.clone(), options) {
+            Err(err) if is_corruption(&err) => {
+                if !options.recreate_on_corruption || options.read_only {
+                    throw!(DbError::Corrupt(format!("Cache DB {:?} is corrupt: {}", path, err)));
+                }
+                let mut aside = path.clone().into_os_string();
+                aside.push(".corrupt");
+                warn!("Cache DB {:?} is corrupt ({}), moving it to {:?} and starting again", path, err, aside);
+                // The journal files belong to the corrupt database, and would confuse a new one.
+                for suffix in &["", "-wal", "-shm"] {
+                    let (mut from, mut to) = (path.clone().into_os_string(), aside.clone());
+                    from.push(suffix);
+                    to.push(suffix);
+                    match fs::rename(&from, &to) {
+                        Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+                        other => other?,
+                    }
+                }
+                Self::connect(path, options)?
+            }
+            other => other?,
+        }
+    }
+
+    #[throws] fn connect(path: path::PathBuf, options: &DbOptions) -> Self {
         debug!("Creating cache metadata in {:?}", path);
-        let connection = sqlite::Connection::open(&path)?;
-        let db = CacheDB { path, connection };
-        let rows: Vec<_> = db.query("SELECT COUNT(*) FROM sqlite_master;", &[])?.collect();
-        if let sqlite::Value::Integer(0) = rows[0][0] {
-            debug!("No tables in the cache DB, loading schema.");
-            db.connection.execute(SCHEMA_SQL)?
+        let flags = if options.read_only { sqlite::OpenFlags::new().set_read_only() } else { sqlite::OpenFlags::new().set_create().set_read_write() };
+        let mut connection = sqlite::Connection::open_with_flags(&path, flags)?;
+        // Write-ahead logging lets readers carry on while another
+        // connection is writing, and the timeout makes writers queue up
+        // rather than fail immediately with "database is locked".
+        connection.set_busy_timeout(options.busy_timeout.as_millis() as usize)?;
+        if options.read_only {
+            connection.execute("PRAGMA query_only = ON;")?;
+        } else {
+            connection.execute("PRAGMA journal_mode = WAL;")?;
+        }
+        for (name, value) in &options.pragmas {
+            // Neither can be passed as a parameter, so make sure they can't smuggle in other SQL.
+            let identifier = |text: &str| text.split('.').all(|part| part.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_') && part.chars().all(|c| c.is_ascii_alphanumeric() || c == '_'));
+            let number = |text: &str| text.strip_prefix('-').unwrap_or(text).parse::<u64>().is_ok();
+            if !identifier(name) { anyhow::bail!("Invalid pragma name: {:?}", name) }
+            if !identifier(value) && !number(value) { anyhow::bail!("Invalid value for pragma {}: {:?}", name, value) }
+            connection.execute(format!("PRAGMA {} = {};", name, value))?;
         }
+        let db = CacheDB { path, connection, keep_fragment: options.keep_fragment, hash_urls_longer_than: options.hash_urls_longer_than, in_transaction: cell::Cell::new(false) };
+        db.migrate(options.read_only)?;
         db
     }
 
-    fn query<'a, T: AsRef<str>+std::fmt::Debug>(&'a self, query: T, params: &[sqlite::Value]) -> sqlite::Result<Rows> {
+    /// Remove `url`'s fragment, unless we were told to keep it.
+    fn strip_fragment(&self, url: &mut reqwest::Url) {
+        if !self.keep_fragment { url.set_fragment(None) }
+    }
+
+    /// Whether `url` is too long to be stored as its own key.
+    fn is_hashed(&self, url: &reqwest::Url) -> bool {
+        self.hash_urls_longer_than.map_or(false, |limit| url.as_str().len() > limit)
+    }
+
+    /// The value of the `url` column for `url`: the URL itself, or a hash of it if it's too long.
+    ///
+    /// A hash is never a valid URL, so it can't collide with one stored as it is.
+    fn key(&self, url: &reqwest::Url) -> sqlite::Value {
+        if !self.is_hashed(url) { return sqlite::Value::String(url.as_str().into()) }
+        sqlite::Value::String(format!("sha256:{}", crypto_hash::hex_digest(crypto_hash::Algorithm::SHA256, url.as_str().as_bytes())))
+    }
+
+    /// The value of the `full_url` column for `url`, which is only needed when its key is a hash.
+    fn full_url(&self, url: &reqwest::Url) -> sqlite::Value {
+        if self.is_hashed(url) { sqlite::Value::String(url.as_str().into()) } else { sqlite::Value::Null }
+    }
+
+    /// Bring the schema up to date, one migration at a time.
+    ///
+    /// A read-only database can't be migrated, so it must already be up to date.
+    #[throws] fn migrate(&self, read_only: bool) {
+        let version = match self.query("PRAGMA user_version;", &[])?.next().transpose()?.as_deref() {
+            Some([sqlite::Value::Integer(version)]) => *version as usize,
+            other => anyhow::bail!("user_version had wrong type: {:?}", other),
+        };
+        // Databases created before we tracked versions have version 0,
+        // but already contain the original schema.
+        let tables = self.query("SELECT COUNT(*) FROM sqlite_master WHERE name = 'urls';", &[])?.collect::<Result<Vec<_>, _>>()?;
+        let version = if version == 0 && tables[0][0] == sqlite::Value::Integer(1) { 1 } else { version };
+        if version > MIGRATIONS.len() {
+            anyhow::bail!("Cache DB has schema version {}, but we only understand up to {}", version, MIGRATIONS.len());
+        }
+        if read_only && version < MIGRATIONS.len() {
+            anyhow::bail!("Cache DB has schema version {}, and can't be upgraded to {} while read-only", version, MIGRATIONS.len());
+        }
+        for (index, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+            debug!("Migrating cache DB schema to version {}", index + 1);
+            let transaction = self.begin()?;
+            self.connection.execute(migration)?;
+            self.connection.execute(format!("PRAGMA user_version = {};", index + 1))?;
+            transaction.commit()?;
+        }
+    }
+
+    /// Start a new transaction, and return the value that will clean it up
+    /// when necessary.
+    fn begin(&self) -> Result<Transaction<'_>, DbError> {
+        if self.in_transaction.get() {
+            // If somebody mem::forget()s a Transaction, its BEGIN is never
+            // matched by a COMMIT or ROLLBACK, and nesting is an error.
+            // Throw away whatever that transaction did, and start afresh.
+            warn!("A transaction on cache DB {:?} was never committed or rolled back, discarding its uncommitted changes", self.path);
+            self.connection.execute("ROLLBACK;")?;
+            self.in_transaction.set(false);
+        }
+        self.connection.execute("BEGIN;")?;
+        Ok(Transaction::new(&self.connection, &self.in_transaction))
+    }
+
+    /// Return the canonicalized path of the database file.
+    pub fn path(&self) -> &path::Path { &self.path }
+
+    fn query<T: AsRef<str>+std::fmt::Debug>(&self, query: T, params: &[sqlite::Value]) -> sqlite::Result<Rows<'_>> {
         debug!("Executing query: {:?} with values {:?}", query, params);
         let mut cur = self.connection.prepare(query)?.cursor();
         cur.bind(params)?;
@@ -137,103 +632,472 @@ impl CacheDB {
     pub fn get(
         &self,
         mut url: reqwest::Url,
-    ) -> Result<CacheRecord, Box<dyn error::Error>> {
-        url.set_fragment(None);
+    ) -> Result<CacheRecord, DbError> {
+        self.strip_fragment(&mut url);
 
         let mut rows = self.query(
-            "
-            SELECT path, last_modified, etag
-            FROM urls
-            WHERE url = ?1
-            ",
-            &[sqlite::Value::String(url.as_str().into())],
+            format!("SELECT {} FROM urls WHERE url = ?1", RECORD_COLUMNS),
+            &[self.key(&url)],
         )?;
 
-        rows.next()
-            .map_or(
-                Err(format!("URL not found in cache: {:?}", url)),
-                |x| Ok(x),
-            )
-            .map(|row| -> Result<CacheRecord, Box<dyn error::Error>> {
-                let mut cols = row.into_iter();
-
-                let path = match cols.next().unwrap() {
-                    sqlite::Value::String(s) => Ok(s),
-                    other => Err(format!("Path had wrong type: {:?}", other)),
-                }?;
-
-                let last_modified = match cols.next().unwrap() {
-                    sqlite::Value::String(s) => Some(s),
-                    sqlite::Value::Null => None,
-                    other => {
-                        warn!(
-                            "last_modified contained weird type: {:?}",
-                            other,
-                        );
-                        None
-                    },
-                };
-
-                let etag = match cols.next().unwrap() {
-                    sqlite::Value::String(s) => Some(s),
-                    sqlite::Value::Null => None,
-                    other => {
-                        warn!("etag contained weird type: {:?}", other);
-                        None
-                    },
-                };
-
-                debug!("Cache says URL {:?} content is at {:?}, etag {:?}, last modified at {:?}", url, path, etag, last_modified);
-
-                Ok(CacheRecord{path, last_modified, etag})
-            })?
+        let row = rows.next().transpose()?.ok_or_else(|| DbError::NotFound(url.clone()))?;
+        let record = record_from_row(row)?;
+        debug!("Cache says URL {:?} content is at {:?}, etag {:?}, last modified at {:?}", url, record.path, record.etags, record.last_modified);
+        Ok(record)
     }
 
     /// Record information about this information in the database.
     pub fn set(
         &mut self,
-        mut url: reqwest::Url,
+        url: reqwest::Url,
         record: CacheRecord,
-    ) -> Result<Transaction, sqlite::Error> {
-        url.set_fragment(None);
+    ) -> Result<Transaction<'_>, DbError> {
+        self.set_many(iter::once((url, record)))
+    }
 
-        // TODO: Consider using the "pre-poop-your-pants" pattern to
-        // ensure the transaction gets cleaned up even if somebody calls
-        // mem::forget() on the Transaction object.
+    /// Record information about several URLs in the database, in a single transaction.
+    ///
+    /// This is much faster than calling [`CacheDB::set`] for each of them.
+    pub fn set_many(
+        &mut self,
+        records: impl IntoIterator<Item = (reqwest::Url, CacheRecord)>,
+    ) -> Result<Transaction<'_>, DbError> {
+        let res = self.begin()?;
 
-        // Start a new transaction...
-        self.connection.execute("BEGIN;")?;
+        for (url, record) in records { self.upsert(url, record)?; }
 
-        // ...and immediately construct the value that will clean up
-        // the transaction when necessary.
-        let res = Transaction::new(&self.connection);
+        Ok(res)
+    }
+
+    /// Record a response to a request for `source`, with the `headers` to replay from it, as what's stored for `url`, in a single transaction.
+    pub fn set_response(
+        &mut self,
+        url: reqwest::Url,
+        source: &reqwest::Url,
+        record: CacheRecord,
+        headers: &reqwest::header::HeaderMap,
+    ) -> Result<Transaction<'_>, DbError> {
+        let res = self.begin()?;
+        self.upsert(url.clone(), record)?;
+        self.set_source(url.clone(), source)?;
+        self.set_headers(url, headers)?;
+        Ok(res)
+    }
+
+    /// Insert or replace the record for a URL, within the current transaction.
+    fn upsert(&self, mut url: reqwest::Url, record: CacheRecord) -> Result<(), DbError> {
+        self.strip_fragment(&mut url);
+        if !is_contained(&record.path) { return Err(DbError::InvalidPath(record.path)) }
 
         let rows = self.query(
             "
-            INSERT OR REPLACE INTO urls
-                (url, path, last_modified, etag)
+            INSERT INTO urls
+                (url, path, last_modified, etag, complete, fresh_until, no_cache, status, fetched_at, validated_at, declared_length, response_date, full_url, compressed, last_accessed)
             VALUES
-                (?1, ?2, ?3, ?4);
+                (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, CAST(strftime('%s', 'now') AS INTEGER))
+            ON CONFLICT (url) DO UPDATE SET
+                path = excluded.path,
+                last_modified = excluded.last_modified,
+                etag = excluded.etag,
+                complete = excluded.complete,
+                fresh_until = excluded.fresh_until,
+                no_cache = excluded.no_cache,
+                status = excluded.status,
+                fetched_at = excluded.fetched_at,
+                validated_at = excluded.validated_at,
+                declared_length = excluded.declared_length,
+                response_date = excluded.response_date,
+                full_url = excluded.full_url,
+                compressed = excluded.compressed,
+                -- Stored headers describe the content, so they don't outlive it.
+                headers = CASE WHEN path = excluded.path THEN headers END;
             ",
             &[
-                sqlite::Value::String(url.as_str().into()),
+                self.key(&url),
                 sqlite::Value::String(record.path),
                 record
                     .last_modified
-                    .map(|date| sqlite::Value::String(date))
+                    .map(sqlite::Value::String)
+                    .unwrap_or(sqlite::Value::Null),
+                if record.etags.is_empty() {
+                    sqlite::Value::Null
+                } else {
+                    sqlite::Value::String(ETag::join(&record.etags))
+                },
+                sqlite::Value::Integer(record.complete as i64),
+                record
+                    .fresh_until
+                    .map(|time| sqlite::Value::Integer(unix_time(time)))
+                    .unwrap_or(sqlite::Value::Null),
+                sqlite::Value::Integer(record.no_cache as i64),
+                record
+                    .status
+                    .map(|status| sqlite::Value::Integer(status.into()))
+                    .unwrap_or(sqlite::Value::Null),
+                record
+                    .fetched_at
+                    .map(|time| sqlite::Value::Integer(unix_time(time)))
+                    .unwrap_or(sqlite::Value::Null),
+                record
+                    .validated_at
+                    .map(|time| sqlite::Value::Integer(unix_time(time)))
+                    .unwrap_or(sqlite::Value::Null),
+                record
+                    .declared_length
+                    .map(|length| sqlite::Value::Integer(length as i64))
                     .unwrap_or(sqlite::Value::Null),
                 record
-                    .etag
-                    .map(|etag| sqlite::Value::String(etag))
+                    .response_date
+                    .map(|time| sqlite::Value::Integer(unix_time(time)))
                     .unwrap_or(sqlite::Value::Null),
+                self.full_url(&url),
+                sqlite::Value::Integer(record.compressed as i64),
             ],
         )?;
 
         // Exhaust the row iterator to ensure the query is executed.
-        for _ in rows {}
+        for row in rows { row?; }
+
+        Ok(())
+    }
+}
+
+impl CacheDB {
+    /// Count one more successful retrieval of a URL, at the given time.
+    pub fn record_access(&self, mut url: reqwest::Url, now: time::SystemTime) -> Result<(), DbError> {
+        self.strip_fragment(&mut url);
+
+        let rows = self.query(
+            "
+            UPDATE urls
+            SET access_count = access_count + 1, last_accessed = ?2
+            WHERE url = ?1;
+            ",
+            &[
+                self.key(&url),
+                sqlite::Value::Integer(unix_time(now)),
+            ],
+        )?;
+        for row in rows { row?; }
+
+        Ok(())
+    }
+
+    /// Record that a URL was last used at the given time, without counting a retrieval.
+    pub fn touch(&self, mut url: reqwest::Url, now: time::SystemTime) -> Result<(), DbError> {
+        self.strip_fragment(&mut url);
+
+        let rows = self.query(
+            "UPDATE urls SET last_accessed = ?2 WHERE url = ?1;",
+            &[
+                self.key(&url),
+                sqlite::Value::Integer(unix_time(now)),
+            ],
+        )?;
+        for row in rows { row?; }
+
+        Ok(())
+    }
+
+    /// Like [`CacheDB::record_access`], but does nothing if the last recorded access was less than `interval` before `now`.
+    ///
+    /// Skipped accesses aren't counted, so this keeps access times roughly up to date without writing to the database on every access.
+    pub fn record_access_debounced(&self, mut url: reqwest::Url, now: time::SystemTime, interval: time::Duration) -> Result<(), DbError> {
+        self.strip_fragment(&mut url);
+
+        let rows = self.query(
+            "
+            UPDATE urls
+            SET access_count = access_count + 1, last_accessed = ?2
+            WHERE url = ?1 AND last_accessed <= ?3;
+            ",
+            &[
+                self.key(&url),
+                sqlite::Value::Integer(unix_time(now)),
+                sqlite::Value::Integer(unix_time(now) - interval.as_secs() as i64),
+            ],
+        )?;
+        for row in rows { row?; }
+
+        Ok(())
+    }
+
+    /// Record that the whole response body for a URL has been written to `path`.
+    pub fn set_complete(&self, mut url: reqwest::Url, path: &str) -> Result<(), DbError> {
+        self.strip_fragment(&mut url);
+
+        let rows = self.query(
+            "UPDATE urls SET path = ?2, complete = 1 WHERE url = ?1;",
+            &[
+                self.key(&url),
+                sqlite::Value::String(path.into()),
+            ],
+        )?;
+        for row in rows { row?; }
+
+        Ok(())
+    }
+
+    /// Record whether a URL is pinned, so that it's never removed to make room.
+    pub fn set_pinned(&self, mut url: reqwest::Url, pinned: bool) -> Result<(), DbError> {
+        self.strip_fragment(&mut url);
+
+        let rows = self.query(
+            "UPDATE urls SET pinned = ?2 WHERE url = ?1;",
+            &[
+                self.key(&url),
+                sqlite::Value::Integer(pinned.into()),
+            ],
+        )?;
+        for row in rows { row?; }
+
+        Ok(())
+    }
+
+    /// Return every pinned URL, with what we know about it.
+    #[throws] pub fn pinned(&self) -> Vec<(reqwest::Url, CacheRecord)> {
+        self.query(format!("SELECT {0}, {1} FROM urls WHERE pinned ORDER BY {0}", FULL_URL, RECORD_COLUMNS), &[])?
+        .map(|row| url_and_record_from_row(row?))
+        .collect::<Result<_, Error>>()?
+    }
+
+    /// Record the status code of the latest response from the server about a URL.
+    pub fn set_status(&self, mut url: reqwest::Url, status: u16) -> Result<(), DbError> {
+        self.strip_fragment(&mut url);
+
+        let rows = self.query(
+            "UPDATE urls SET status = ?2 WHERE url = ?1;",
+            &[
+                self.key(&url),
+                sqlite::Value::Integer(status.into()),
+            ],
+        )?;
+        for row in rows { row?; }
+
+        Ok(())
+    }
+
+    /// Record that a URL's content is requested from `source`.
+    pub fn set_source(&self, mut url: reqwest::Url, source: &reqwest::Url) -> Result<(), DbError> {
+        self.strip_fragment(&mut url);
+
+        let rows = self.query(
+            "UPDATE urls SET source_url = ?2 WHERE url = ?1;",
+            &[
+                self.key(&url),
+                // Only worth storing when it differs.
+                if *source == url { sqlite::Value::Null } else { sqlite::Value::String(source.as_str().into()) },
+            ],
+        )?;
+        for row in rows { row?; }
+
+        Ok(())
+    }
+
+    /// Return the URL to request a URL's content from, as recorded with [`CacheDB::set_source`], or the URL itself if none was.
+    pub fn source(&self, mut url: reqwest::Url) -> Result<reqwest::Url, DbError> {
+        self.strip_fragment(&mut url);
+
+        let mut rows = self.query("SELECT source_url FROM urls WHERE url = ?1", &[self.key(&url)])?;
+        match rows.next().transpose()?.ok_or_else(|| DbError::NotFound(url.clone()))?.pop() {
+            Some(sqlite::Value::String(source)) => reqwest::Url::parse(&source).map_err(|err| DbError::Corrupt(format!("source_url {:?} is invalid: {}", source, err))),
+            Some(sqlite::Value::Null) => Ok(url),
+            other => Err(DbError::Corrupt(format!("source_url had wrong type: {:?}", other))),
+        }
+    }
+
+    /// Record the response headers to replay for a URL, replacing any recorded before.
+    ///
+    /// Values that aren't plain text are left out.
+    pub fn set_headers(&self, mut url: reqwest::Url, headers: &reqwest::header::HeaderMap) -> Result<(), DbError> {
+        self.strip_fragment(&mut url);
+
+        let lines: Vec<_> = headers.iter()
+            .filter_map(|(name, value)| Some(format!("{}: {}", name, value.to_str().ok()?)))
+            .collect();
+        let rows = self.query(
+            "UPDATE urls SET headers = ?2 WHERE url = ?1;",
+            &[
+                self.key(&url),
+                if lines.is_empty() { sqlite::Value::Null } else { sqlite::Value::String(lines.join("\n")) },
+            ],
+        )?;
+        for row in rows { row?; }
+
+        Ok(())
+    }
+
+    /// Return the response headers recorded for a URL with [`CacheDB::set_headers`], which may be none.
+    pub fn headers(&self, mut url: reqwest::Url) -> Result<reqwest::header::HeaderMap, DbError> {
+        self.strip_fragment(&mut url);
+
+        let mut rows = self.query("SELECT headers FROM urls WHERE url = ?1", &[self.key(&url)])?;
+        let text = match rows.next().transpose()?.ok_or_else(|| DbError::NotFound(url.clone()))?.pop() {
+            Some(sqlite::Value::String(text)) => text,
+            Some(sqlite::Value::Null) => return Ok(Default::default()),
+            other => return Err(DbError::Corrupt(format!("headers had wrong type: {:?}", other))),
+        };
+        text.lines().map(|line| {
+            let (name, value) = line.split_once(": ").ok_or_else(|| DbError::Corrupt(format!("header {:?} has no value", line)))?;
+            match (reqwest::header::HeaderName::from_bytes(name.as_bytes()), reqwest::header::HeaderValue::from_str(value)) {
+                (Ok(name), Ok(value)) => Ok((name, value)),
+                _ => Err(DbError::Corrupt(format!("header {:?} is invalid", line))),
+            }
+        }).collect()
+    }
+
+    /// Return the `n` URLs with the highest access counts, most popular first.
+    #[throws] pub fn most_accessed(&self, n: usize) -> Vec<(reqwest::Url, u64)> {
+        self.query(
+            "
+            SELECT coalesce(full_url, url), access_count
+            FROM urls
+            ORDER BY access_count DESC, coalesce(full_url, url)
+            LIMIT ?1
+            ",
+            &[sqlite::Value::Integer(n as i64)],
+        )?
+        .map(|row| match &row?[..] {
+            [sqlite::Value::String(url), sqlite::Value::Integer(count)] => Ok((url.parse::<reqwest::Url>()?, *count as u64)),
+            other => Err(anyhow::anyhow!("Access count row had wrong types: {:?}", other)),
+        })
+        .collect::<Result<_, Error>>()?
+    }
+
+    /// Return how many URLs the database knows about.
+    #[throws] pub fn len(&self) -> usize {
+        match self.query("SELECT COUNT(*) FROM urls", &[])?.next().transpose()?.as_deref() {
+            Some([sqlite::Value::Integer(count)]) => *count as usize,
+            other => anyhow::bail!("Count had wrong type: {:?}", other),
+        }
+    }
+
+    /// Return the URL whose content is stored at `path`, relative to the cache root, if any.
+    #[throws] pub fn url_for_path(&self, path: &str) -> Option<reqwest::Url> {
+        let mut rows = self.query(format!("SELECT {} FROM urls WHERE path = ?1", FULL_URL), &[sqlite::Value::String(path.into())])?;
+        match rows.next().transpose()?.as_deref() {
+            Some([sqlite::Value::String(url)]) => Some(url.parse::<reqwest::Url>()?),
+            None => None,
+            Some(other) => anyhow::bail!("URL had wrong type: {:?}", other),
+        }
+    }
+
+    /// Forget everything about a URL.
+    ///
+    /// Nothing is actually removed until the returned transaction is committed.
+    pub fn remove(&mut self, mut url: reqwest::Url) -> Result<Transaction<'_>, DbError> {
+        self.strip_fragment(&mut url);
+
+        let res = self.begin()?;
+        let rows = self.query(
+            "DELETE FROM urls WHERE url = ?1;",
+            &[self.key(&url)],
+        )?;
+        for row in rows { row?; }
 
         Ok(res)
     }
+
+    /// Move everything we know about `old` to `new`, returning what we knew about `new` before, which is forgotten.
+    ///
+    /// Nothing is actually changed until the returned transaction is committed.
+    #[throws] pub fn rename(&mut self, mut old: reqwest::Url, mut new: reqwest::Url) -> (Option<CacheRecord>, Transaction<'_>) {
+        self.strip_fragment(&mut old);
+        self.strip_fragment(&mut new);
+
+        let transaction = self.begin()?;
+        self.get(old.clone())?;
+        // Both are the same entry, which stays as it is.
+        if self.key(&old) == self.key(&new) { return (None, transaction) }
+        let replaced = match self.get(new.clone()) {
+            Ok(record) => Some(record),
+            Err(DbError::NotFound(_)) => None,
+            Err(err) => Err(err)?,
+        };
+
+        let (key, full_url) = (self.key(&new), self.full_url(&new));
+        for row in self.query("DELETE FROM urls WHERE url = ?1;", std::slice::from_ref(&key))? { row?; }
+        for row in self.query("UPDATE urls SET url = ?2, full_url = ?3 WHERE url = ?1;", &[self.key(&old), key, full_url])? { row?; }
+
+        (replaced, transaction)
+    }
+
+    /// Return every URL in the database, with what we know about it.
+    #[throws] pub fn records(&self) -> Vec<(reqwest::Url, CacheRecord)> {
+        self.query(format!("SELECT {0}, {1} FROM urls ORDER BY {0}", FULL_URL, RECORD_COLUMNS), &[])?
+        .map(|row| url_and_record_from_row(row?))
+        .collect::<Result<_, Error>>()?
+    }
+
+    /// Remove every URL last retrieved before `cutoff`, except pinned ones and ones still fresh at `now`, returning what was removed.
+    ///
+    /// Nothing is actually removed until the returned transaction is committed.
+    #[throws] pub fn remove_accessed_before(&mut self, cutoff: time::SystemTime, now: time::SystemTime) -> (Vec<(reqwest::Url, CacheRecord)>, Transaction<'_>) {
+        let transaction = self.begin()?;
+
+        const EXPIRED: &str = "last_accessed < ?1 AND NOT pinned AND (fresh_until IS NULL OR fresh_until <= ?2)";
+        let params = [sqlite::Value::Integer(unix_time(cutoff)), sqlite::Value::Integer(unix_time(now))];
+        let removed = self.query(
+            format!("SELECT {}, {} FROM urls WHERE {}", FULL_URL, RECORD_COLUMNS, EXPIRED),
+            &params,
+        )?
+        .map(|row| url_and_record_from_row(row?))
+        .collect::<Result<_, Error>>()?;
+
+        for row in self.query(format!("DELETE FROM urls WHERE {};", EXPIRED), &params)? { row?; }
+
+        (removed, transaction)
+    }
+
+    /// Remove the least recently accessed URLs, returning what was removed.
+    ///
+    /// `choose` is given every URL that isn't pinned, with what we know about it, least recently accessed first, and returns how many of them to remove.
+    /// Nothing is actually removed until the returned transaction is committed.
+    #[throws] pub fn remove_least_recently_accessed(&mut self, choose: impl FnOnce(&[(reqwest::Url, CacheRecord)]) -> usize) -> (Vec<(reqwest::Url, CacheRecord)>, Transaction<'_>) {
+        let transaction = self.begin()?;
+        let mut entries: Vec<_> = self.query(format!("SELECT {0}, {1} FROM urls WHERE NOT pinned ORDER BY last_accessed, {0}", FULL_URL, RECORD_COLUMNS), &[])?
+            .map(|row| url_and_record_from_row(row?))
+            .collect::<Result<_, Error>>()?;
+        entries.truncate(choose(&entries));
+        for (url, _) in &entries {
+            for row in self.query("DELETE FROM urls WHERE url = ?1;", &[self.key(url)])? { row?; }
+        }
+        (entries, transaction)
+    }
+
+    /// Return every URL in the database that starts with `prefix`, with what we know about it.
+    #[throws] pub fn entries_with_prefix(&self, prefix: &str) -> Vec<(reqwest::Url, CacheRecord)> {
+        self.query(format!("SELECT {0}, {1} FROM urls WHERE {2} ORDER BY {0}", FULL_URL, RECORD_COLUMNS, PREFIX_MATCH), &prefix_params(prefix))?
+        .map(|row| url_and_record_from_row(row?))
+        .collect::<Result<_, Error>>()?
+    }
+
+    /// Remove every URL that starts with `prefix`, returning what was removed.
+    ///
+    /// Nothing is actually removed until the returned transaction is committed.
+    #[throws] pub fn remove_with_prefix(&mut self, prefix: &str) -> (Vec<(reqwest::Url, CacheRecord)>, Transaction<'_>) {
+        let transaction = self.begin()?;
+        let removed = self.entries_with_prefix(prefix)?;
+        for row in self.query(format!("DELETE FROM urls WHERE {};", PREFIX_MATCH), &prefix_params(prefix))? { row?; }
+        (removed, transaction)
+    }
+
+    /// Rebuild the database file to reclaim unused space, and refresh the statistics SQLite plans queries with.
+    ///
+    /// `VACUUM` can't run inside a transaction; taking `&mut self` ensures none of ours is open.
+    pub fn optimize(&mut self) -> Result<(), DbError> {
+        self.connection.execute("VACUUM;")?;
+        self.connection.execute("PRAGMA optimize;")?;
+        Ok(())
+    }
+
+    /// Check the database for corruption, logging any problems found.
+    #[throws] pub fn check_integrity(&self) -> bool {
+        let rows = self.query("PRAGMA integrity_check;", &[])?.collect::<Result<Vec<_>, _>>()?;
+        let ok = match &rows[..] { [row] => row[..] == [sqlite::Value::String("ok".into())], _ => false };
+        if !ok { for row in rows { warn!("Cache DB integrity check: {:?}", row) } }
+        ok
+    }
 }
 
 impl fmt::Debug for CacheDB {
@@ -253,10 +1117,10 @@ impl cmp::Eq for CacheDB {}
 #[cfg(test)]
 mod tests {
     extern crate tempdir;
-    use reqwest;
-    use sqlite;
+    use log::debug;
 
     use std::path;
+    use std::time;
 
     #[test]
     fn create_fresh_db() {
@@ -269,35 +1133,319 @@ mod tests {
                 &[sqlite::Value::String("table".into())],
             )
             .unwrap()
-            .collect();
+            .collect::<Result<_, _>>()
+            .unwrap();
+
+        assert_eq!(rows, vec![vec![sqlite::Value::String("urls".into())]]);
+    }
+
+    #[test]
+    fn reopen_existing_db() {
+        let root = tempdir::TempDir::new("cachedb-test").unwrap().into_path();
+        let db_path = root.join("cache.db");
 
+        let db1 = super::CacheDB::new(db_path.clone()).unwrap();
+        let rows: Vec<_> = db1
+            .query(
+                "SELECT name FROM sqlite_master WHERE TYPE = ?1",
+                &[sqlite::Value::String("table".into())],
+            )
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
         assert_eq!(rows, vec![vec![sqlite::Value::String("urls".into())]]);
+
+        let db2 = super::CacheDB::new(db_path.clone()).unwrap();
+        let rows: Vec<_> = db2
+            .query(
+                "SELECT name FROM sqlite_master WHERE TYPE = ?1",
+                &[sqlite::Value::String("table".into())],
+            )
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(rows, vec![vec![sqlite::Value::String("urls".into())]]);
+    }
+
+    #[test]
+    fn migrate_unversioned_db() {
+        let root = tempdir::TempDir::new("cachedb-test").unwrap().into_path();
+        let db_path = root.join("cache.db");
+
+        // Create a database the way the first release did.
+        let connection = sqlite::Connection::open(&db_path).unwrap();
+        connection.execute(super::MIGRATIONS[0]).unwrap();
+        connection
+            .execute(
+                "
+            INSERT INTO urls
+                ( url
+                , path
+                , last_modified
+                , etag
+                )
+            VALUES
+                ( 'http://example.com/'
+                , 'path/to/data'
+                , NULL
+                , 'some-etag'
+                )
+            ;
+        ",
+            )
+            .unwrap();
+        drop(connection);
+
+        let db = super::CacheDB::new(db_path).unwrap();
+
+        let rows: Vec<_> =
+            db.query("PRAGMA user_version;", &[])
+                .unwrap()
+                .collect::<Result<_, _>>()
+                .unwrap();
+        assert_eq!(
+            rows,
+            vec![vec![sqlite::Value::Integer(
+                super::MIGRATIONS.len() as i64
+            )]]
+        );
+
+        // The old data is still readable...
+        assert_eq!(
+            db.get("http://example.com/".parse().unwrap()).unwrap(),
+            super::CacheRecord {
+                path: "path/to/data".into(),
+                last_modified: None,
+                etags: vec!["some-etag".into()],
+                complete: true,
+                fresh_until: None,
+                no_cache: false,
+                status: None,
+                fetched_at: None,
+                validated_at: None,
+                declared_length: None,
+                response_date: None,
+                compressed: false,
+            }
+        );
+
+        // ...and the new columns are usable.
+        db.record_access(
+            "http://example.com/".parse().unwrap(),
+            time::SystemTime::now(),
+        )
+        .unwrap();
+        assert_eq!(
+            db.most_accessed(1).unwrap(),
+            vec![("http://example.com/".parse().unwrap(), 1)]
+        );
+    }
+
+    #[test]
+    fn interleave_two_connections() {
+        let root = tempdir::TempDir::new("cachedb-test").unwrap().into_path();
+        let db_path = root.join("cache.db");
+        let url: reqwest::Url = "http://example.com/".parse().unwrap();
+        let record = super::CacheRecord {
+            path: "path/to/data".into(),
+            last_modified: None,
+            etags: vec![],
+            complete: true,
+            fresh_until: None,
+            no_cache: false,
+            status: None,
+            fetched_at: None,
+            validated_at: None,
+            declared_length: None,
+            response_date: None,
+            compressed: false,
+        };
+
+        let mut db1 = super::CacheDB::new(db_path.clone()).unwrap();
+        let mut db2 = super::CacheDB::new(db_path.clone()).unwrap();
+
+        let rows: Vec<_> = db1
+            .query("PRAGMA journal_mode;", &[])
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(rows, vec![vec![sqlite::Value::String("wal".into())]]);
+
+        // A reader isn't blocked by an in-progress write...
+        let trans = db1.set(url.clone(), record.clone()).unwrap();
+        assert!(matches!(
+            db2.get(url.clone()),
+            Err(super::DbError::NotFound(_))
+        ));
+        trans.commit().unwrap();
+
+        // ...and sees it once it's committed.
+        assert_eq!(db2.get(url.clone()).unwrap(), record);
+
+        // Writes can alternate between connections.
+        db2.set(url.clone(), record.clone())
+            .unwrap()
+            .commit()
+            .unwrap();
+        db1.set(url.clone(), record.clone())
+            .unwrap()
+            .commit()
+            .unwrap();
+        assert_eq!(db2.get(url).unwrap(), record);
+    }
+
+    #[test]
+    fn corrupt_db_is_reported() {
+        let root = tempdir::TempDir::new("cachedb-test").unwrap().into_path();
+        let db_path = root.join("cache.db");
+        std::fs::write(&db_path, vec![b'x'; 4096]).unwrap();
+
+        let err = super::CacheDB::new(db_path.clone()).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<super::DbError>(),
+            Some(super::DbError::Corrupt(_))
+        ));
+        // Without being asked to, we leave the file alone.
+        assert_eq!(std::fs::read(&db_path).unwrap(), vec![b'x'; 4096]);
+    }
+
+    #[test]
+    fn corrupt_db_can_be_recreated() {
+        let root = tempdir::TempDir::new("cachedb-test").unwrap().into_path();
+        let db_path = root.join("cache.db");
+        std::fs::write(&db_path, vec![b'x'; 4096]).unwrap();
+
+        let mut db = super::CacheDB::open(
+            db_path.clone(),
+            &super::DbOptions {
+                recreate_on_corruption: true,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+
+        assert_eq!(
+            std::fs::read(root.join("cache.db.corrupt")).unwrap(),
+            vec![b'x'; 4096]
+        );
+        db.set(
+            "http://example.com/".parse().unwrap(),
+            super::CacheRecord {
+                path: "path/to/data".into(),
+                last_modified: None,
+                etags: vec![],
+                complete: true,
+                fresh_until: None,
+                no_cache: false,
+                status: None,
+                fetched_at: None,
+                validated_at: None,
+                declared_length: None,
+                response_date: None,
+                compressed: false,
+            },
+        )
+        .unwrap()
+        .commit()
+        .unwrap();
+    }
+
+    #[test]
+    #[cfg(feature = "serde")]
+    fn cache_record_round_trips_through_json() {
+        let record = super::CacheRecord {
+            path: "content/abcd".into(),
+            last_modified: Some("Thu, 01 Jan 1970 00:00:00 GMT".into()),
+            etags: vec![super::ETag::parse("W/\"abcd\"")],
+            complete: true,
+            fresh_until: Some(time::UNIX_EPOCH + time::Duration::from_secs(60)),
+            no_cache: false,
+            status: Some(200),
+            fetched_at: Some(time::UNIX_EPOCH),
+            validated_at: Some(time::UNIX_EPOCH),
+            declared_length: Some(5),
+            response_date: None,
+            compressed: false,
+        };
+
+        let json = serde_json::to_string(&record).unwrap();
+        assert_eq!(
+            serde_json::from_str::<super::CacheRecord>(&json).unwrap(),
+            record
+        );
+    }
+
+    #[test]
+    fn pragmas_are_applied() {
+        let db = super::CacheDB::open(
+            path::PathBuf::new().join(":memory:"),
+            &super::DbOptions {
+                pragmas: vec![("cache_size".into(), "-4000".into())],
+                ..Default::default()
+            },
+        )
+        .unwrap();
+
+        let rows = db
+            .query("PRAGMA cache_size;", &[])
+            .unwrap()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!(rows, vec![vec![sqlite::Value::Integer(-4000)]]);
+    }
+
+    #[test]
+    fn pragmas_must_be_simple() {
+        for (name, value) in &[
+            ("cache_size; DROP TABLE urls", "1"),
+            ("cache_size", "1; DROP TABLE urls"),
+            ("cache_size", "'1'"),
+        ] {
+            assert!(super::CacheDB::open(
+                path::PathBuf::new().join(":memory:"),
+                &super::DbOptions {
+                    pragmas: vec![(name.to_string(), value.to_string())],
+                    ..Default::default()
+                },
+            )
+            .is_err());
+        }
     }
 
     #[test]
-    fn reopen_existing_db() {
+    fn read_only_db_refuses_writes() {
         let root = tempdir::TempDir::new("cachedb-test").unwrap().into_path();
         let db_path = root.join("cache.db");
 
-        let db1 = super::CacheDB::new(db_path.clone()).unwrap();
-        let rows: Vec<_> = db1
-            .query(
-                "SELECT name FROM sqlite_master WHERE TYPE = ?1",
-                &[sqlite::Value::String("table".into())],
-            )
-            .unwrap()
-            .collect();
-        assert_eq!(rows, vec![vec![sqlite::Value::String("urls".into())]]);
+        super::CacheDB::new(db_path.clone()).unwrap();
+        let mut db = super::CacheDB::open(
+            db_path,
+            &super::DbOptions {
+                read_only: true,
+                ..Default::default()
+            },
+        )
+        .unwrap();
 
-        let db2 = super::CacheDB::new(db_path.clone()).unwrap();
-        let rows: Vec<_> = db2
-            .query(
-                "SELECT name FROM sqlite_master WHERE TYPE = ?1",
-                &[sqlite::Value::String("table".into())],
+        assert!(db
+            .set(
+                "http://example.com/".parse().unwrap(),
+                super::CacheRecord {
+                    path: "path/to/data".into(),
+                    last_modified: None,
+                    etags: vec![],
+                    complete: true,
+                    fresh_until: None,
+                    no_cache: false,
+                    status: None,
+                    fetched_at: None,
+                    validated_at: None,
+                    declared_length: None,
+                    response_date: None,
+                    compressed: false,
+                },
             )
-            .unwrap()
-            .collect();
-        assert_eq!(rows, vec![vec![sqlite::Value::String("urls".into())]]);
+            .is_err());
     }
 
     #[test]
@@ -305,7 +1453,7 @@ mod tests {
         let res =
             super::CacheDB::new(path::PathBuf::new().join("does/not/exist"));
 
-        assert_eq!(res.is_err(), true);
+        assert!(res.is_err());
     }
 
     #[test]
@@ -315,8 +1463,9 @@ mod tests {
 
         let err = db.get("http://example.com/".parse().unwrap()).unwrap_err();
 
+        assert!(matches!(err, super::DbError::NotFound(_)));
         assert_eq!(
-            err.description(),
+            err.to_string(),
             "URL not found in cache: \"http://example.com/\""
         );
     }
@@ -331,7 +1480,16 @@ mod tests {
             super::CacheRecord {
                 path: "path/to/data".into(),
                 last_modified: None,
-                etag: None,
+                etags: vec![],
+                complete: true,
+                fresh_until: None,
+                no_cache: false,
+                status: None,
+                fetched_at: None,
+                validated_at: None,
+                declared_length: None,
+                response_date: None,
+                compressed: false,
             },
         )
         .unwrap()
@@ -342,8 +1500,9 @@ mod tests {
             .get("http://example.com/two".parse().unwrap())
             .unwrap_err();
 
+        assert!(matches!(err, super::DbError::NotFound(_)));
         assert_eq!(
-            err.description(),
+            err.to_string(),
             "URL not found in cache: \"http://example.com/two\""
         );
     }
@@ -356,7 +1515,16 @@ mod tests {
         let orig_record = super::CacheRecord {
             path: "path/to/data".into(),
             last_modified: None,
-            etag: None,
+            etags: vec![],
+            complete: true,
+            fresh_until: None,
+            no_cache: false,
+            status: None,
+            fetched_at: None,
+            validated_at: None,
+            declared_length: None,
+            response_date: None,
+            compressed: false,
         };
 
         db.set("http://example.com/".parse().unwrap(), orig_record.clone())
@@ -378,7 +1546,89 @@ mod tests {
         let orig_record = super::CacheRecord {
             path: "path/to/data".into(),
             last_modified: Some("Thu, 01 Jan 1970 00:00:00 GMT".into()),
-            etag: Some("some-etag".into()),
+            etags: vec!["some-etag".into()],
+            complete: true,
+            fresh_until: None,
+            no_cache: false,
+            status: None,
+            fetched_at: None,
+            validated_at: None,
+            declared_length: None,
+            response_date: None,
+            compressed: false,
+        };
+
+        db.set("http://example.com/".parse().unwrap(), orig_record.clone())
+            .unwrap()
+            .commit()
+            .unwrap();
+
+        let new_record =
+            db.get("http://example.com/".parse().unwrap()).unwrap();
+
+        assert_eq!(new_record, orig_record);
+    }
+
+    #[test]
+    fn get_known_url_with_weak_etag() {
+        let mut db =
+            super::CacheDB::new(path::PathBuf::new().join(":memory:")).unwrap();
+
+        let orig_record = super::CacheRecord {
+            path: "path/to/data".into(),
+            last_modified: None,
+            etags: vec!["W/\"some-etag\"".into()],
+            complete: true,
+            fresh_until: None,
+            no_cache: false,
+            status: None,
+            fetched_at: None,
+            validated_at: None,
+            declared_length: None,
+            response_date: None,
+            compressed: false,
+        };
+        assert_eq!(
+            orig_record.etags,
+            vec![super::ETag {
+                tag: "\"some-etag\"".into(),
+                weak: true,
+            }]
+        );
+
+        db.set("http://example.com/".parse().unwrap(), orig_record.clone())
+            .unwrap()
+            .commit()
+            .unwrap();
+
+        let new_record =
+            db.get("http://example.com/".parse().unwrap()).unwrap();
+
+        assert_eq!(new_record, orig_record);
+        assert_eq!(
+            new_record.etags[0].to_string(),
+            "W/\"some-etag\""
+        );
+    }
+
+    #[test]
+    fn get_known_url_with_several_etags() {
+        let mut db =
+            super::CacheDB::new(path::PathBuf::new().join(":memory:")).unwrap();
+
+        let orig_record = super::CacheRecord {
+            path: "path/to/data".into(),
+            last_modified: None,
+            etags: vec!["\"one,two\"".into(), "W/\"three\"".into()],
+            complete: true,
+            fresh_until: None,
+            no_cache: false,
+            status: None,
+            fetched_at: None,
+            validated_at: None,
+            declared_length: None,
+            response_date: None,
+            compressed: false,
         };
 
         db.set("http://example.com/".parse().unwrap(), orig_record.clone())
@@ -390,6 +1640,10 @@ mod tests {
             db.get("http://example.com/".parse().unwrap()).unwrap();
 
         assert_eq!(new_record, orig_record);
+        assert_eq!(
+            super::ETag::join(&new_record.etags),
+            "\"one,two\", W/\"three\""
+        );
     }
 
     #[test]
@@ -419,12 +1673,67 @@ mod tests {
 
         let err = db.get("http://example.com/".parse().unwrap()).unwrap_err();
 
+        assert!(matches!(err, super::DbError::Corrupt(_)));
         assert_eq!(
-            err.description(),
+            err.to_string(),
             "Path had wrong type: Binary([97, 98, 99])"
         );
     }
 
+    #[test]
+    fn paths_outside_the_root_are_rejected() {
+        let mut db =
+            super::CacheDB::new(path::PathBuf::new().join(":memory:")).unwrap();
+
+        db.connection
+            .execute(
+                "
+            INSERT INTO urls
+                ( url
+                , path
+                , last_modified
+                , etag
+                )
+            VALUES
+                ( 'http://example.com/'
+                , '../../etc/passwd'
+                , NULL
+                , NULL
+                )
+            ;
+        ",
+            )
+            .unwrap();
+
+        let err = db.get("http://example.com/".parse().unwrap()).unwrap_err();
+        assert!(matches!(err, super::DbError::InvalidPath(_)));
+        assert_eq!(
+            err.to_string(),
+            "Content path \"../../etc/passwd\" leads outside the cache root"
+        );
+
+        for path in &["/etc/passwd", "content/../../x", ""] {
+            let record = super::CacheRecord {
+                path: path.to_string(),
+                last_modified: None,
+                etags: vec![],
+                complete: true,
+                fresh_until: None,
+                no_cache: false,
+                status: None,
+                fetched_at: None,
+                validated_at: None,
+                declared_length: None,
+                response_date: None,
+                compressed: false,
+            };
+            assert!(matches!(
+                db.set("http://example.com/other".parse().unwrap(), record),
+                Err(super::DbError::InvalidPath(_))
+            ));
+        }
+    }
+
     #[test]
     fn get_url_with_invalid_last_modified_and_etag() {
         let db =
@@ -459,11 +1768,46 @@ mod tests {
                 // We expect TEXT or NULL; if we get a BLOB value we
                 // treat it as NULL.
                 last_modified: None,
-                etag: None,
+                etags: vec![],
+                complete: true,
+                fresh_until: None,
+                no_cache: false,
+                status: None,
+                fetched_at: None,
+                validated_at: None,
+                declared_length: None,
+                response_date: None,
+                compressed: false,
             }
         );
     }
 
+    #[test]
+    fn query_errors_are_not_end_of_rows() {
+        let db =
+            super::CacheDB::new(path::PathBuf::new().join(":memory:")).unwrap();
+
+        // This statement prepares fine, but fails when stepped.
+        let mut rows = db
+            .query("SELECT abs(?1);", &[sqlite::Value::Integer(i64::MIN)])
+            .unwrap();
+
+        assert!(rows.next().unwrap().is_err());
+    }
+
+    #[test]
+    fn sqlite_errors_keep_their_source() {
+        use std::error::Error;
+
+        let err = super::DbError::from(sqlite::Error {
+            code: Some(1),
+            message: Some("boom".into()),
+        });
+
+        assert_eq!(err.to_string(), "SQLite error: boom (code 1)");
+        assert_eq!(err.source().unwrap().to_string(), "boom (code 1)");
+    }
+
     #[test]
     fn get_ignores_fragments() {
         let mut db =
@@ -472,7 +1816,16 @@ mod tests {
         let orig_record = super::CacheRecord {
             path: "path/to/data".into(),
             last_modified: None,
-            etag: None,
+            etags: vec![],
+            complete: true,
+            fresh_until: None,
+            no_cache: false,
+            status: None,
+            fetched_at: None,
+            validated_at: None,
+            declared_length: None,
+            response_date: None,
+            compressed: false,
         };
 
         db.set("http://example.com/".parse().unwrap(), orig_record.clone())
@@ -492,7 +1845,16 @@ mod tests {
         let record = super::CacheRecord {
             path: "path/to/data".into(),
             last_modified: None,
-            etag: None,
+            etags: vec![],
+            complete: true,
+            fresh_until: None,
+            no_cache: false,
+            status: None,
+            fetched_at: None,
+            validated_at: None,
+            declared_length: None,
+            response_date: None,
+            compressed: false,
         };
 
         let mut db =
@@ -507,20 +1869,121 @@ mod tests {
         }
 
         let rows: Vec<_> =
-            db.query("SELECT * FROM urls;", &[]).unwrap().collect();
+            db.query("SELECT * FROM urls;", &[])
+                .unwrap()
+                .collect::<Result<_, _>>()
+                .unwrap();
         debug!("Table content: {:?}", rows);
 
         // Did our data make it into the DB?
         assert_eq!(db.get(url).unwrap(), record);
     }
 
+    #[test]
+    fn long_urls_are_stored_as_hashes() {
+        let url: reqwest::Url =
+            format!("http://example.com/?signature={}", "a".repeat(3000))
+                .parse()
+                .unwrap();
+        let record = super::CacheRecord {
+            path: "path/to/data".into(),
+            last_modified: None,
+            etags: vec![],
+            complete: true,
+            fresh_until: None,
+            no_cache: false,
+            status: None,
+            fetched_at: None,
+            validated_at: None,
+            declared_length: None,
+            response_date: None,
+            compressed: false,
+        };
+
+        let mut db = super::CacheDB::open(
+            path::PathBuf::new().join(":memory:"),
+            &super::DbOptions {
+                hash_urls_longer_than: Some(2048),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        db.set(url.clone(), record.clone()).unwrap().commit().unwrap();
+
+        // The key is a fixed-size hash, not the URL.
+        let keys: Vec<_> = db
+            .query("SELECT url FROM urls;", &[])
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        match &keys[..] {
+            [row] => match &row[..] {
+                [sqlite::Value::String(key)] => {
+                    assert!(key.starts_with("sha256:"));
+                    assert!(key.len() < 100);
+                }
+                other => panic!("Unexpected row {:?}", other),
+            },
+            other => panic!("Unexpected rows {:?}", other),
+        }
+
+        // But the full URL round-trips.
+        assert_eq!(db.get(url.clone()).unwrap(), record);
+        assert_eq!(db.records().unwrap(), vec![(url.clone(), record.clone())]);
+        assert_eq!(
+            db.entries_with_prefix("http://example.com/?signature=aaa")
+                .unwrap(),
+            vec![(url.clone(), record)]
+        );
+
+        let new_url: reqwest::Url = "http://example.com/short".parse().unwrap();
+        db.rename(url.clone(), new_url.clone()).unwrap().1.commit().unwrap();
+        assert!(db.get(url).is_err());
+        assert_eq!(db.records().unwrap()[0].0, new_url);
+    }
+
+    #[test]
+    fn url_for_path_finds_the_entry() {
+        let url: reqwest::Url = "http://example.com/".parse().unwrap();
+        let record = super::CacheRecord {
+            path: "content/abcd".into(),
+            last_modified: None,
+            etags: vec![],
+            complete: true,
+            fresh_until: None,
+            no_cache: false,
+            status: None,
+            fetched_at: None,
+            validated_at: None,
+            declared_length: None,
+            response_date: None,
+            compressed: false,
+        };
+
+        let mut db =
+            super::CacheDB::new(path::PathBuf::new().join(":memory:")).unwrap();
+        db.set(url.clone(), record).unwrap().commit().unwrap();
+
+        assert_eq!(db.url_for_path("content/abcd").unwrap(), Some(url));
+        assert_eq!(db.url_for_path("content/efgh").unwrap(), None);
+    }
+
     #[test]
     fn insert_data_with_all_fields() {
         let url: reqwest::Url = "http://example.com/".parse().unwrap();
         let record = super::CacheRecord {
             path: "path/to/data".into(),
             last_modified: Some("Thu, 01 Jan 1970 00:00:00 GMT".into()),
-            etag: Some("some-etag".into()),
+            etags: vec!["some-etag".into()],
+            complete: true,
+            fresh_until: None,
+            no_cache: false,
+            status: None,
+            fetched_at: None,
+            validated_at: None,
+            declared_length: None,
+            response_date: None,
+            compressed: false,
         };
 
         let mut db =
@@ -543,7 +2006,16 @@ mod tests {
         let record = super::CacheRecord {
             path: "path/to/data".into(),
             last_modified: None,
-            etag: None,
+            etags: vec![],
+            complete: true,
+            fresh_until: None,
+            no_cache: false,
+            status: None,
+            fetched_at: None,
+            validated_at: None,
+            declared_length: None,
+            response_date: None,
+            compressed: false,
         };
 
         let mut db =
@@ -559,11 +2031,52 @@ mod tests {
 
         // Did our data make it into the DB?
         assert_eq!(
-            db.get(url).unwrap_err().description(),
+            db.get(url).unwrap_err().to_string(),
             "URL not found in cache: \"http://example.com/\""
         );
     }
 
+    #[test]
+    fn insert_data_after_leaked_transaction() {
+        let url: reqwest::Url = "http://example.com/".parse().unwrap();
+        let record = super::CacheRecord {
+            path: "path/to/data".into(),
+            last_modified: None,
+            etags: vec![],
+            complete: true,
+            fresh_until: None,
+            no_cache: false,
+            status: None,
+            fetched_at: None,
+            validated_at: None,
+            declared_length: None,
+            response_date: None,
+            compressed: false,
+        };
+
+        let mut db =
+            super::CacheDB::new(path::PathBuf::new().join(":memory:")).unwrap();
+
+        // Start a transaction, and never finish it.
+        std::mem::forget(
+            db.set("http://example.com/leaked".parse().unwrap(), record.clone())
+                .unwrap(),
+        );
+
+        // The next transaction should still work...
+        db.set(url.clone(), record.clone())
+            .unwrap()
+            .commit()
+            .unwrap();
+        assert_eq!(db.get(url).unwrap(), record);
+
+        // ...but the leaked one was rolled back.
+        assert!(matches!(
+            db.get("http://example.com/leaked".parse().unwrap()),
+            Err(super::DbError::NotFound(_))
+        ));
+    }
+
     #[test]
     fn overwrite_data() {
         let url: reqwest::Url = "http://example.com/".parse().unwrap();
@@ -571,13 +2084,31 @@ mod tests {
         let record_one = super::CacheRecord {
             path: "path/to/data/one".into(),
             last_modified: None,
-            etag: Some("one".into()),
+            etags: vec!["one".into()],
+            complete: true,
+            fresh_until: None,
+            no_cache: false,
+            status: None,
+            fetched_at: None,
+            validated_at: None,
+            declared_length: None,
+            response_date: None,
+            compressed: false,
         };
 
         let record_two = super::CacheRecord {
             path: "path/to/data/two".into(),
             last_modified: None,
-            etag: Some("two".into()),
+            etags: vec!["two".into()],
+            complete: true,
+            fresh_until: None,
+            no_cache: false,
+            status: None,
+            fetched_at: None,
+            validated_at: None,
+            declared_length: None,
+            response_date: None,
+            compressed: false,
         };
 
         let mut db =
@@ -607,13 +2138,31 @@ mod tests {
         let record_one = super::CacheRecord {
             path: "path/to/data/one".into(),
             last_modified: None,
-            etag: Some("one".into()),
+            etags: vec!["one".into()],
+            complete: true,
+            fresh_until: None,
+            no_cache: false,
+            status: None,
+            fetched_at: None,
+            validated_at: None,
+            declared_length: None,
+            response_date: None,
+            compressed: false,
         };
 
         let record_two = super::CacheRecord {
             path: "path/to/data/two".into(),
             last_modified: None,
-            etag: Some("two".into()),
+            etags: vec!["two".into()],
+            complete: true,
+            fresh_until: None,
+            no_cache: false,
+            status: None,
+            fetched_at: None,
+            validated_at: None,
+            declared_length: None,
+            response_date: None,
+            compressed: false,
         };
 
         let mut db =
@@ -673,6 +2222,241 @@ mod tests {
         );
     }
 
+    #[test]
+    fn insert_many_in_one_transaction() {
+        let mut db =
+            super::CacheDB::new(path::PathBuf::new().join(":memory:")).unwrap();
+
+        let record = |i| super::CacheRecord {
+            path: format!("path/to/data/{}", i),
+            last_modified: None,
+            etags: vec![],
+            complete: true,
+            fresh_until: None,
+            no_cache: false,
+            status: None,
+            fetched_at: None,
+            validated_at: None,
+            declared_length: None,
+            response_date: None,
+            compressed: false,
+        };
+        let url = |i| {
+            format!("http://example.com/{}#fragment", i)
+                .parse::<reqwest::Url>()
+                .unwrap()
+        };
+
+        db.set_many((0..100).map(|i| (url(i), record(i))))
+            .unwrap()
+            .commit()
+            .unwrap();
+
+        for i in 0..100 {
+            assert_eq!(db.get(url(i)).unwrap(), record(i));
+        }
+        assert_eq!(db.records().unwrap().len(), 100);
+        // Fragments are stripped before storing.
+        assert!(db
+            .records()
+            .unwrap()
+            .iter()
+            .all(|(url, _)| url.fragment().is_none()));
+    }
+
+    #[test]
+    fn entries_with_prefix_matches_exactly() {
+        let mut db =
+            super::CacheDB::new(path::PathBuf::new().join(":memory:")).unwrap();
+
+        let record = super::CacheRecord {
+            path: "path/to/data".into(),
+            last_modified: None,
+            etags: vec![],
+            complete: true,
+            fresh_until: None,
+            no_cache: false,
+            status: None,
+            fetched_at: None,
+            validated_at: None,
+            declared_length: None,
+            response_date: None,
+            compressed: false,
+        };
+        let urls = [
+            "http://example.com/assets/a",
+            "http://example.com/assets/b",
+            "http://example.com/Assets/c",
+            "http://example.com/assetsXd",
+            "http://example.com/other/e",
+            "http://example.com/100%25/f",
+            "http://example.com/100x25/g",
+        ];
+        db.set_many(
+            urls.iter().map(|url| (url.parse().unwrap(), record.clone())),
+        )
+        .unwrap()
+        .commit()
+        .unwrap();
+
+        let matching = |prefix| {
+            db.entries_with_prefix(prefix)
+                .unwrap()
+                .into_iter()
+                .map(|(url, _)| url.to_string())
+                .collect::<Vec<_>>()
+        };
+        // Case matters, and _ isn't a wildcard.
+        assert_eq!(
+            matching("http://example.com/assets/"),
+            vec!["http://example.com/assets/a", "http://example.com/assets/b"]
+        );
+        assert_eq!(matching("http://example.com/assets_"), Vec::<String>::new());
+        // Nor is %.
+        assert_eq!(
+            matching("http://example.com/100%"),
+            vec!["http://example.com/100%25/f"]
+        );
+
+        let (removed, transaction) =
+            db.remove_with_prefix("http://example.com/assets/").unwrap();
+        transaction.commit().unwrap();
+        assert_eq!(removed.len(), 2);
+        assert_eq!(db.records().unwrap().len(), urls.len() - 2);
+    }
+
+    #[test]
+    fn overwrite_data_keeps_access_count() {
+        let url: reqwest::Url = "http://example.com/".parse().unwrap();
+        let record = super::CacheRecord {
+            path: "path/to/data".into(),
+            last_modified: None,
+            etags: vec![],
+            complete: true,
+            fresh_until: None,
+            no_cache: false,
+            status: None,
+            fetched_at: None,
+            validated_at: None,
+            declared_length: None,
+            response_date: None,
+            compressed: false,
+        };
+
+        let mut db =
+            super::CacheDB::new(path::PathBuf::new().join(":memory:")).unwrap();
+
+        let now = time::SystemTime::now();
+
+        db.set(url.clone(), record.clone()).unwrap().commit().unwrap();
+        db.record_access(url.clone(), now).unwrap();
+        db.record_access(url.clone(), now).unwrap();
+
+        // Updating the cached content doesn't reset its popularity.
+        db.set(url.clone(), record).unwrap().commit().unwrap();
+        db.record_access(url.clone(), now).unwrap();
+
+        assert_eq!(db.most_accessed(10).unwrap(), vec![(url, 3)]);
+    }
+
+    #[test]
+    fn debounced_accesses_are_recorded_at_most_once_per_interval() {
+        let url: reqwest::Url = "http://example.com/".parse().unwrap();
+        let mut db =
+            super::CacheDB::new(path::PathBuf::new().join(":memory:")).unwrap();
+        let minute = time::Duration::from_secs(60);
+        let now = time::SystemTime::now() + 2 * minute;
+
+        db.set(
+            url.clone(),
+            super::CacheRecord {
+                path: "path/to/data".into(),
+                last_modified: None,
+                etags: vec![],
+                complete: true,
+                fresh_until: None,
+                no_cache: false,
+                status: None,
+                fetched_at: None,
+                validated_at: None,
+                declared_length: None,
+                response_date: None,
+                compressed: false,
+            },
+        )
+        .unwrap()
+        .commit()
+        .unwrap();
+        db.record_access_debounced(url.clone(), now, minute).unwrap();
+        db.record_access_debounced(url.clone(), now, minute).unwrap();
+        db.record_access_debounced(url.clone(), now + minute / 2, minute)
+            .unwrap();
+        assert_eq!(db.most_accessed(10).unwrap(), vec![(url.clone(), 1)]);
+
+        db.record_access_debounced(url.clone(), now + minute, minute)
+            .unwrap();
+        assert_eq!(db.most_accessed(10).unwrap(), vec![(url, 2)]);
+    }
+
+    #[test]
+    fn remove_only_entries_accessed_before_cutoff() {
+        let old_url: reqwest::Url = "http://example.com/old".parse().unwrap();
+        let new_url: reqwest::Url = "http://example.com/new".parse().unwrap();
+        let old_record = super::CacheRecord {
+            path: "path/to/old".into(),
+            last_modified: None,
+            etags: vec![],
+            complete: true,
+            fresh_until: None,
+            no_cache: false,
+            status: None,
+            fetched_at: None,
+            validated_at: None,
+            declared_length: None,
+            response_date: None,
+            compressed: false,
+        };
+        let new_record = super::CacheRecord {
+            path: "path/to/new".into(),
+            last_modified: None,
+            etags: vec![],
+            complete: true,
+            fresh_until: None,
+            no_cache: false,
+            status: None,
+            fetched_at: None,
+            validated_at: None,
+            declared_length: None,
+            response_date: None,
+            compressed: false,
+        };
+
+        let mut db =
+            super::CacheDB::new(path::PathBuf::new().join(":memory:")).unwrap();
+
+        let now = time::SystemTime::now();
+        let hour = time::Duration::from_secs(60 * 60);
+
+        db.set(old_url.clone(), old_record.clone())
+            .unwrap()
+            .commit()
+            .unwrap();
+        db.record_access(old_url.clone(), now - 2 * hour).unwrap();
+        db.set(new_url.clone(), new_record.clone())
+            .unwrap()
+            .commit()
+            .unwrap();
+        db.record_access(new_url.clone(), now).unwrap();
+
+        let (removed, trans) =
+            db.remove_accessed_before(now - hour, now).unwrap();
+        assert_eq!(removed, vec![(old_url.clone(), old_record)]);
+        trans.commit().unwrap();
+
+        assert!(db.get(old_url).is_err());
+        assert_eq!(db.get(new_url).unwrap(), new_record);
+    }
+
     #[test]
     fn dbs_are_equal_if_paths_are_equal() {
         let root = tempdir::TempDir::new("cachedb-test").unwrap().into_path();
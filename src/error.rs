@@ -0,0 +1,77 @@
+//! Errors specific to this crate.
+//!
+//! Methods on [`Cache`](../struct.Cache.html) return `anyhow::Error`;
+//! when the failure is one of these, you can recover it with `downcast_ref`.
+//!
+//! Errors from the HTTP client are passed on as they are, not flattened to strings,
+//! so with a `reqwest::blocking::Client` you can `downcast_ref::<reqwest::Error>()`
+//! to ask whether it was a timeout or which status the server answered with.
+use std::{error, fmt, path, time};
+
+/// A reason a [`Cache`](../struct.Cache.html) operation failed, other than an underlying I/O, database or HTTP error.
+#[derive(Debug, PartialEq, Eq)]
+pub enum CacheError {
+    /// The cache was opened read-only, and the operation would modify it.
+    ReadOnly,
+    /// The server said a cached URL no longer exists, so it was removed from the cache.
+    ///
+    /// See [`CacheBuilder::evict_when_gone`](../struct.CacheBuilder.html#method.evict_when_gone).
+    Gone { url: reqwest::Url, status: reqwest::StatusCode },
+    /// The server answered `503 Service Unavailable`.
+    ///
+    /// `retry_after` is how long its `Retry-After` header asked us to wait before trying again, if it sent one.
+    /// See [`CacheBuilder::retry_unavailable`](../struct.CacheBuilder.html#method.retry_unavailable) to have the cache wait and retry by itself.
+    Unavailable { url: reqwest::Url, retry_after: Option<time::Duration> },
+    /// The response body was larger than the `limit` in bytes set with [`Cache::set_max_file_size`](../struct.Cache.html#method.set_max_file_size).
+    TooLarge { url: reqwest::Url, limit: u64 },
+    /// The URL is already cached, and the operation would replace its entry.
+    AlreadyCached { url: reqwest::Url },
+    /// We received a different number of bytes than the response's `Content-Length` header declared.
+    ///
+    /// See [`CacheBuilder::strict_length`](../struct.CacheBuilder.html#method.strict_length).
+    LengthMismatch { url: reqwest::Url, declared: u64, received: u64 },
+    /// The response's `Content-Length` was `needed` bytes, but the cache's filesystem only had `available` bytes to spare.
+    ///
+    /// See [`CacheBuilder::reserve_free_space`](../struct.CacheBuilder.html#method.reserve_free_space).
+    OutOfSpace { url: reqwest::Url, needed: u64, available: u64 },
+    /// The cache was expected to need `needed` bytes, but the filesystem holding `root` only had `available` bytes to spare.
+    ///
+    /// See [`CacheBuilder::expected_size`](../struct.CacheBuilder.html#method.expected_size).
+    InsufficientSpace { root: path::PathBuf, needed: u64, available: u64 },
+    /// The byte range from `start` up to `end` isn't within the `len` bytes of `url`'s content.
+    ///
+    /// See [`Cache::get_range`](../struct.Cache.html#method.get_range).
+    InvalidRange { url: reqwest::Url, start: u64, end: u64, len: u64 },
+    /// Requesting `url` was redirected more than `max` times.
+    ///
+    /// See [`Cache::set_max_redirects`](../struct.Cache.html#method.set_max_redirects).
+    TooManyRedirects { url: reqwest::Url, max: usize },
+    /// Writing the content of `url` to the cache failed, say because the disk is full.
+    ///
+    /// The partially written file is removed.
+    /// If it was to replace the content of a complete entry for `url`, that entry is kept as it was;
+    /// otherwise, the entry is removed too.
+    /// The error from the filesystem is the [`anyhow::Error`](https://docs.rs/anyhow/1/anyhow/struct.Error.html)'s source.
+    StorageFailed { url: reqwest::Url },
+}
+
+impl fmt::Display for CacheError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CacheError::ReadOnly => f.write_str("The cache is read-only"),
+            CacheError::LengthMismatch{url, declared, received} => write!(f, "{} declared a length of {} bytes, but we received {}", url, declared, received),
+            CacheError::OutOfSpace{url, needed, available} => write!(f, "{} needs {} bytes, but only {} are available", url, needed, available),
+            CacheError::InsufficientSpace{root, needed, available} => write!(f, "The cache in {:?} expects to need {} bytes, but only {} are available", root, needed, available),
+            CacheError::InvalidRange{url, start, end, len} => write!(f, "Bytes {} to {} are not within the {} bytes of {}", start, end, len, url),
+            CacheError::TooManyRedirects{url, max} => write!(f, "{} was redirected more than {} times", url, max),
+            CacheError::StorageFailed{url} => write!(f, "Failed to store the content of {}", url),
+            CacheError::AlreadyCached{url} => write!(f, "{} is already cached", url),
+            CacheError::Gone{url, status} => write!(f, "{} is gone from the server ({})", url, status),
+            CacheError::Unavailable{url, retry_after: None} => write!(f, "{} is temporarily unavailable", url),
+            CacheError::TooLarge{url, limit} => write!(f, "{} is larger than the limit of {} bytes", url, limit),
+            CacheError::Unavailable{url, retry_after: Some(delay)} => write!(f, "{} is temporarily unavailable, retry after {} seconds", url, delay.as_secs()),
+        }
+    }
+}
+
+impl error::Error for CacheError {}
@@ -0,0 +1,30 @@
+//! Structured descriptions of the decisions a cache makes.
+
+/// Something that happened while a [`Cache`](../struct.Cache.html) retrieved a URL.
+///
+/// See [`Cache::set_event_handler`](../struct.Cache.html#method.set_event_handler).
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum CacheEvent {
+    /// The request was answered with locally cached data.
+    Hit { url: reqwest::Url },
+    /// The URL was not cached at all.
+    Miss { url: reqwest::Url },
+    /// A response body of `bytes` bytes was downloaded.
+    Download { url: reqwest::Url, bytes: u64 },
+    /// The server was asked whether cached data was still valid; `changed` is whether it wasn't.
+    Revalidated { url: reqwest::Url, changed: bool },
+    /// The server couldn't be asked whether cached data was still valid, so it was used anyway.
+    FallbackToCache { url: reqwest::Url, error: String },
+}
+
+/// How [`Cache::get_with_status`](../struct.Cache.html#method.get_with_status) obtained its local copy.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum CacheStatus {
+    /// The cached data was used without asking the server, including when the server couldn't be reached.
+    Fresh,
+    /// The server confirmed the cached data was still valid.
+    Revalidated,
+    /// The content was downloaded.
+    Downloaded,
+}
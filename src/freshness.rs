@@ -0,0 +1,346 @@
+//! Deciding whether cached data can be used without asking the server.
+use {std::{fmt, time}, reqwest::header::*, crate::db::CacheRecord};
+
+/// When a [`Cache`](../struct.Cache.html) should ask the server whether cached data is still valid.
+///
+/// See [`Cache::set_revalidation`](../struct.Cache.html#method.set_revalidation).
+///
+/// Whatever the policy, a response marked `Cache-Control: no-store` is never cached,
+/// and one marked `Cache-Control: no-cache` is revalidated before every use.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum RevalidationPolicy {
+    /// Revalidate cached data on every retrieval.
+    #[default]
+    Always,
+    /// Only revalidate cached data once the freshness lifetime given by the original response's
+    /// `Cache-Control: max-age` or `Expires` header has passed.
+    /// Data without a freshness lifetime is always revalidated.
+    WhenStale,
+    /// Never revalidate cached data; only URLs that aren't cached at all are downloaded.
+    Never,
+}
+
+/// Decides whether cached data is fresh enough to use without asking the server.
+///
+/// Installing one with [`CacheBuilder::freshness_policy`](../struct.CacheBuilder.html#method.freshness_policy)
+/// replaces the [`RevalidationPolicy`], so rules the built-in policies don't cover can be tested on their own.
+/// Data marked `Cache-Control: no-cache`, or validated longer ago than [`Cache::set_max_stale`](../struct.Cache.html#method.set_max_stale) allows,
+/// is revalidated whatever the policy says.
+pub trait FreshnessPolicy: fmt::Debug + Send + Sync {
+    /// Returns whether `record` may be used at `now` without revalidating it.
+    fn is_fresh(&self, record: &CacheRecord, now: time::SystemTime) -> bool;
+}
+
+/// Data is fresh until the lifetime given by its `Cache-Control: max-age` or `Expires` header has passed,
+/// or the heuristic lifetime if [`CacheBuilder::heuristic_freshness`](../struct.CacheBuilder.html#method.heuristic_freshness) is set.
+///
+/// This is the same as [`RevalidationPolicy::WhenStale`].
+#[derive(Clone, Copy, Debug, Default)]
+pub struct DefaultPolicy;
+
+impl FreshnessPolicy for DefaultPolicy {
+    fn is_fresh(&self, record: &CacheRecord, now: time::SystemTime) -> bool {
+        !record.no_cache && record.fresh_until.map_or(false, |fresh_until| fresh_until > now)
+    }
+}
+
+/// Data is never fresh, so it's revalidated on every retrieval.
+///
+/// This is the same as [`RevalidationPolicy::Always`].
+#[derive(Clone, Copy, Debug, Default)]
+pub struct AlwaysRevalidate;
+
+impl FreshnessPolicy for AlwaysRevalidate {
+    fn is_fresh(&self, _: &CacheRecord, _: time::SystemTime) -> bool { false }
+}
+
+/// Which of the stored validators a [`Cache`](../struct.Cache.html) sends when revalidating cached data.
+///
+/// Some servers only honour one of `If-None-Match` and `If-Modified-Since`, and answer `200 OK` when sent both.
+/// See [`CacheBuilder::prefer_validator`](../struct.CacheBuilder.html#method.prefer_validator).
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Validator {
+    /// Send `If-None-Match` if the response had an `ETag`, and otherwise `If-Modified-Since`.
+    ETag,
+    /// Send `If-Modified-Since` if the response had a `Last-Modified` date, and otherwise `If-None-Match`.
+    LastModified,
+    /// Send both, whenever the response had them.
+    #[default]
+    Both,
+}
+
+/// Returns the value of the `Cache-Control` directive `name`, if present.
+///
+/// Directives without a value, like `no-store`, have an empty value.
+pub(crate) fn cache_control<'h>(headers: &'h HeaderMap, name: &str) -> Option<&'h str> {
+    headers.get_all(CACHE_CONTROL).iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .find_map(|directive| {
+            let mut parts = directive.trim().splitn(2, '=');
+            if parts.next()?.eq_ignore_ascii_case(name) { Some(parts.next().unwrap_or_default().trim_matches('"')) } else { None }
+        })
+}
+
+/// Parses an HTTP date in any of the three formats [RFC 7231 section 7.1.1.1] asks us to accept:
+/// the preferred IMF-fixdate (as in RFC 1123), the obsolete RFC 850 format, and C's asctime format.
+///
+/// [RFC 7231 section 7.1.1.1]: https://tools.ietf.org/html/rfc7231#section-7.1.1.1
+pub(crate) fn parse_http_date(text: &str) -> Option<time::SystemTime> { httpdate::parse_http_date(text.trim()).ok() }
+
+/// Returns the HTTP date `text` in the IMF-fixdate format we must send, or unchanged if it isn't a valid date.
+pub(crate) fn canonical_http_date(text: &str) -> String {
+    parse_http_date(text).map_or_else(|| text.to_owned(), httpdate::fmt_http_date)
+}
+
+/// Returns the time the server says it generated a response with `headers`, if it sent a valid `Date` header.
+pub(crate) fn response_date(headers: &HeaderMap) -> Option<time::SystemTime> {
+    parse_http_date(headers.get(DATE)?.to_str().ok()?)
+}
+
+/// The largest number of seconds we accept in `max-age` or `Age`: larger values, however large, mean the same,
+/// as [RFC 7234 section 1.2.1] asks.
+///
+/// [RFC 7234 section 1.2.1]: https://tools.ietf.org/html/rfc7234#section-1.2.1
+const MAX_DELTA_SECONDS: u64 = 1 << 31;
+
+/// Parses a number of seconds, as in `max-age` or `Age`, clamped to [`MAX_DELTA_SECONDS`].
+fn delta_seconds(text: &str) -> Option<u64> {
+    if text.is_empty() || !text.bytes().all(|b| b.is_ascii_digit()) { return None }
+    Some(text.parse().unwrap_or(MAX_DELTA_SECONDS).min(MAX_DELTA_SECONDS))
+}
+
+/// Returns `time + duration`, or as late a time as can be represented if that's too late, so that it's never stale.
+fn saturating_add(time: time::SystemTime, duration: time::Duration) -> time::SystemTime {
+    std::iter::successors(Some(duration), |duration| Some(*duration / 2)).find_map(|duration| time.checked_add(duration)).unwrap()
+}
+
+/// Returns how old a response received at `now` with `headers` already was, following [RFC 7234 section 4.2.3]:
+/// the larger of how far its `Date` is behind `now`, and its `Age` header.
+///
+/// A `Date` ahead of `now` counts as no age at all, so a server whose clock is ahead of ours doesn't make responses look older.
+/// Without a valid `Date`, the response is taken to have been generated when we received it.
+///
+/// [RFC 7234 section 4.2.3]: https://tools.ietf.org/html/rfc7234#section-4.2.3
+pub(crate) fn initial_age(headers: &HeaderMap, now: time::SystemTime) -> time::Duration {
+    let apparent_age = response_date(headers).and_then(|date| now.duration_since(date).ok()).unwrap_or_default();
+    let age = headers.get(AGE).and_then(|age| delta_seconds(age.to_str().ok()?.trim())).map(time::Duration::from_secs).unwrap_or_default();
+    apparent_age.max(age)
+}
+
+/// Returns until when a response received at `now` with `headers` may be used without revalidation, if the headers say.
+///
+/// The response's freshness lifetime is reduced by its [`initial_age`].
+pub(crate) fn fresh_until(headers: &HeaderMap, now: time::SystemTime) -> Option<time::SystemTime> {
+    let lifetime = match cache_control(headers, "max-age").and_then(delta_seconds) {
+        Some(max_age) => time::Duration::from_secs(max_age),
+        None => {
+            // An invalid Expires header, like "0", means the response is already stale.
+            let expires = match parse_http_date(headers.get(EXPIRES)?.to_str().unwrap_or_default()) { Some(expires) => expires, None => return Some(now) };
+            // Measure the lifetime against the server's clock, in case ours disagrees.
+            expires.duration_since(response_date(headers).unwrap_or(now)).unwrap_or_default()
+        }
+    };
+    Some(saturating_add(now, lifetime.checked_sub(initial_age(headers, now)).unwrap_or_default()))
+}
+
+/// Returns until when a response received at `now` with `headers` may be used without revalidation, guessing from its age.
+///
+/// This is for responses that don't say how long they're fresh for,
+/// following the heuristic of [RFC 7234 section 4.2.2]:
+/// a resource that hasn't changed for a long time probably won't change soon.
+/// It's fresh for `fraction` of the time between its `Last-Modified` date, or its `Date` if it has none, and `now`.
+///
+/// [RFC 7234 section 4.2.2]: https://tools.ietf.org/html/rfc7234#section-4.2.2
+pub(crate) fn heuristic_fresh_until(headers: &HeaderMap, now: time::SystemTime, fraction: f64) -> Option<time::SystemTime> {
+    let date = |name| parse_http_date(headers.get(name)?.to_str().ok()?);
+    let unchanged_since = date(LAST_MODIFIED).or_else(|| date(DATE))?;
+    let age = now.duration_since(unchanged_since).unwrap_or_default();
+    // Out of range fractions are clamped, so they can't make a duration that doesn't exist.
+    Some(saturating_add(now, time::Duration::from_secs_f64(age.as_secs_f64() * fraction.max(0.).min(1.))))
+}
+
+#[cfg(test)]
+mod tests {
+    use reqwest::header::*;
+    use std::time;
+
+    fn headers(pairs: &[(HeaderName, &'static str)]) -> HeaderMap {
+        pairs
+            .iter()
+            .map(|(name, value)| (name.clone(), HeaderValue::from_static(value)))
+            .collect()
+    }
+
+    #[test]
+    fn max_age_overrides_expires() {
+        let now = time::UNIX_EPOCH + time::Duration::from_secs(1000);
+        assert_eq!(
+            super::fresh_until(
+                &headers(&[
+                    (CACHE_CONTROL, "public, Max-Age=60"),
+                    (EXPIRES, "Thu, 01 Jan 1970 00:00:00 GMT"),
+                ]),
+                now,
+            ),
+            Some(now + time::Duration::from_secs(60))
+        );
+    }
+
+    #[test]
+    fn expires_is_relative_to_date() {
+        let now = time::UNIX_EPOCH + time::Duration::from_secs(1000);
+        // The server's clock is an hour ahead of ours.
+        assert_eq!(
+            super::fresh_until(
+                &headers(&[
+                    (DATE, "Thu, 01 Jan 1970 01:16:40 GMT"),
+                    (EXPIRES, "Thu, 01 Jan 1970 01:17:40 GMT"),
+                ]),
+                now,
+            ),
+            Some(now + time::Duration::from_secs(60))
+        );
+    }
+
+    #[test]
+    fn date_behind_our_clock_is_age() {
+        let now = time::UNIX_EPOCH + time::Duration::from_secs(1000);
+        // Generated 100 seconds before we received it.
+        assert_eq!(
+            super::fresh_until(
+                &headers(&[
+                    (CACHE_CONTROL, "max-age=600"),
+                    (DATE, "Thu, 01 Jan 1970 00:15:00 GMT"),
+                ]),
+                now,
+            ),
+            Some(now + time::Duration::from_secs(500))
+        );
+        assert_eq!(
+            super::fresh_until(
+                &headers(&[
+                    (DATE, "Thu, 01 Jan 1970 00:15:00 GMT"),
+                    (EXPIRES, "Thu, 01 Jan 1970 00:16:00 GMT"),
+                ]),
+                now,
+            ),
+            Some(now)
+        );
+        // An Age header larger than the apparent age wins.
+        assert_eq!(
+            super::fresh_until(
+                &headers(&[
+                    (CACHE_CONTROL, "max-age=600"),
+                    (DATE, "Thu, 01 Jan 1970 00:15:00 GMT"),
+                    (AGE, "200"),
+                ]),
+                now,
+            ),
+            Some(now + time::Duration::from_secs(400))
+        );
+        // An unparseable Date is ignored.
+        assert_eq!(
+            super::fresh_until(
+                &headers(&[(CACHE_CONTROL, "max-age=600"), (DATE, "yesterday")]),
+                now,
+            ),
+            Some(now + time::Duration::from_secs(600))
+        );
+    }
+
+    #[test]
+    fn huge_max_age_is_clamped() {
+        let now = time::UNIX_EPOCH + time::Duration::from_secs(1000);
+        let clamped = Some(now + time::Duration::from_secs(1 << 31));
+        assert_eq!(
+            super::fresh_until(
+                &headers(&[(CACHE_CONTROL, "max-age=18446744073709551615")]),
+                now,
+            ),
+            clamped
+        );
+        assert_eq!(
+            super::fresh_until(
+                &headers(&[(
+                    CACHE_CONTROL,
+                    "max-age=99999999999999999999999999999999"
+                )]),
+                now,
+            ),
+            clamped
+        );
+    }
+
+    #[test]
+    fn invalid_expires_is_already_stale() {
+        let now = time::UNIX_EPOCH + time::Duration::from_secs(1000);
+        assert_eq!(
+            super::fresh_until(&headers(&[(EXPIRES, "0")]), now),
+            Some(now)
+        );
+        assert_eq!(super::fresh_until(&HeaderMap::new(), now), None);
+    }
+
+    #[test]
+    fn all_http_date_formats_are_understood() {
+        let date = time::UNIX_EPOCH + time::Duration::from_secs(784111777);
+        for text in &[
+            "Sun, 06 Nov 1994 08:49:37 GMT",
+            "Sunday, 06-Nov-94 08:49:37 GMT",
+            "Sun Nov  6 08:49:37 1994",
+        ] {
+            assert_eq!(super::parse_http_date(text), Some(date), "{}", text);
+            assert_eq!(
+                super::canonical_http_date(text),
+                "Sun, 06 Nov 1994 08:49:37 GMT"
+            );
+        }
+        assert_eq!(super::parse_http_date("yesterday"), None);
+        assert_eq!(super::canonical_http_date("yesterday"), "yesterday");
+    }
+
+    #[test]
+    fn heuristic_is_a_fraction_of_the_age() {
+        let now = time::UNIX_EPOCH + time::Duration::from_secs(1000);
+        let last_modified = headers(&[
+            (DATE, "Thu, 01 Jan 1970 00:16:00 GMT"),
+            (LAST_MODIFIED, "Thu, 01 Jan 1970 00:00:00 GMT"),
+        ]);
+        assert_eq!(
+            super::heuristic_fresh_until(&last_modified, now, 0.1),
+            Some(now + time::Duration::from_secs(100))
+        );
+
+        // Without a Last-Modified date, the response has been unchanged since its Date.
+        let date = headers(&[(DATE, "Thu, 01 Jan 1970 00:15:00 GMT")]);
+        assert_eq!(
+            super::heuristic_fresh_until(&date, now, 0.1),
+            Some(now + time::Duration::from_secs(10))
+        );
+
+        assert_eq!(
+            super::heuristic_fresh_until(&HeaderMap::new(), now, 0.1),
+            None
+        );
+    }
+
+    #[test]
+    fn heuristic_fraction_is_clamped() {
+        let now = time::UNIX_EPOCH + time::Duration::from_secs(1000);
+        let date = headers(&[(DATE, "Thu, 01 Jan 1970 00:15:00 GMT")]);
+        for (fraction, lifetime) in &[
+            (f64::NAN, 0),
+            (-1., 0),
+            (f64::INFINITY, 100),
+            (f64::MAX, 100),
+        ] {
+            assert_eq!(
+                super::heuristic_fresh_until(&date, now, *fraction),
+                Some(now + time::Duration::from_secs(*lifetime)),
+                "{}",
+                fraction
+            );
+        }
+    }
+}
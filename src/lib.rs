@@ -5,11 +5,17 @@
 //! Whenever you ask it for the contents of a URL, it will re-use a previously-downloaded copy if the resource has not changed on the server.
 //! Otherwise, it will download the new version and use that instead.
 //!
-//! Because it only supports static resources, `static_http_cache` only sends HTTP `GET` requests.
+//! Because it only supports static resources, `static_http_cache` only sends HTTP `GET` requests (and, if you ask it to, `HEAD` requests).
 //!
 //! `static_http_cache` uses the `reqwest` crate for HTTP operations, so it should properly handle HTTPS negotiation and use the operating-system's certificate store.
+//! By default `reqwest` uses its default TLS implementation;
+//! to use `rustls` instead, disable this crate's default features and enable `rustls-tls`.
+//! The `native-tls` feature likewise forwards to `reqwest`'s feature of the same name,
+//! and with none of them, only plain HTTP URLs can be fetched.
 //!
-//! Currently, `static_http_cache` only uses the `Last-Modified` and `ETag` HTTP headers to determine when its cached data is out of date.
+//! Currently, `static_http_cache` mostly uses the `Last-Modified` and `ETag` HTTP headers to determine when its cached data is out of date.
+//! It honours the `no-store` and `no-cache` directives of the `Cache-Control` header,
+//! and can be told to trust `max-age` and `Expires` with [`RevalidationPolicy::WhenStale`].
 //! Therefore, it's not suitable for general-purpose HTTP caching; it's best suited for static content like Amazon S3 data, or Apache or nginx serving up a filesystem directory.
 //!
 //! # Capabilities
@@ -18,8 +24,12 @@
 //!
 //! Although `static_http_cache` is designed to work with the `reqwest` library, //! it will accept any type that implements //! the traits in the [`reqwest_mock`] module.
 //! If you want to use it with an alternative HTTP backend, or if you need to stub out network access for testing purposes, you can do that.
+//! For tests, [`reqwest_mock::FixtureClient`] serves responses from files,
+//! and enabling this crate's `testing` feature provides the fake clients in [`reqwest_mock::testing`].
 //!
 //! [`reqwest_mock`]: reqwest_mock/index.html
+//! [`reqwest_mock::FixtureClient`]: reqwest_mock/struct.FixtureClient.html
+//! [`reqwest_mock::testing`]: reqwest_mock/testing/index.html
 //!
 //! ## Concurrent cache sharing
 //!
@@ -27,33 +37,280 @@
 //!
 //! Note that while it's *safe* to have multiple things managing the same cache, it's not necessarily performant:
 //! a [`Cache`] instance that's downloading a new or updated file is likely to stall other cache reads or writes until it's complete.
+//!
+//! When several instances miss on the same URL at once, only one of them downloads it:
+//! the others wait for it to finish (using a lock file under the cache root) and then use its copy.
+//!
+//! ## Storage
+//!
+//! By default, content is stored as files under the cache root, so [`Cache::get`] can return a `std::fs::File` and [`Cache::get_path`] a path.
+//! [`CacheBuilder::build_with_storage`] makes a cache that keeps its content in any other [`Storage`], such as a [`MemoryStorage`],
+//! and serves it with [`Cache::get_bytes`], [`Cache::get_string`] and [`Cache::get_range`].
+//! Either way, the metadata database and lock files are files under the root.
+//! For a cache that doesn't outlive the program, [`Cache::in_memory`] keeps its metadata in memory and its content in a temporary directory.
 
 pub mod reqwest_mock;
 mod db;
-use {fehler::throws, std::{fs,io,path}, log::{info}, reqwest::header::*};
+mod stats;
+mod builder;
+mod error;
+mod event;
+mod freshness;
+mod archive;
+mod shared;
+mod clock;
+mod trim;
+mod repair;
+mod storage;
+pub use {stats::{CacheStats, RefreshSummary}, builder::CacheBuilder, db::{CacheRecord, DbError, ETag}, error::CacheError, event::{CacheEvent, CacheStatus}, freshness::{AlwaysRevalidate, DefaultPolicy, FreshnessPolicy, RevalidationPolicy, Validator}, shared::SharedCache, clock::{Clock, SystemClock, TestClock}, trim::{TrimPolicy, TrimSummary}, repair::RepairPolicy, storage::{FsStorage, MemoryStorage, MemoryWriter, Storage}};
+use {fehler::{throw, throws}, std::{fmt,fs,io,path}, log::{info, warn}, reqwest::header::*};
 
-#[throws(std::io::Error)] fn make_random_file<P: AsRef<path::Path>>(parent: P) -> (fs::File, path::PathBuf) {
+/// Creates new content with a random name in `parent` in `storage`,
+/// or if `sharded`, in a subdirectory of `parent` named after the first two characters of the name, holding the rest.
+#[throws(std::io::Error)] fn make_random_file<S: Storage>(storage: &S, parent: &path::Path, sharded: bool) -> (S::Writer, path::PathBuf) {
     std::iter::repeat_with(|| {
         use rand::Rng/*sample*/;
-        let path = parent.as_ref().join(std::iter::repeat_with(|| rand::thread_rng().sample(rand::distributions::Alphanumeric)).take(20).collect::<String>());
-        fs::OpenOptions::new().create_new(true).write(true).open(&path).map(|file| (file, path))
+        let name = std::iter::repeat_with(|| rand::thread_rng().sample(rand::distributions::Alphanumeric)).take(20).collect::<String>();
+        let path = if sharded { parent.join(&name[..2]).join(&name[2..]) } else { parent.join(name) };
+        storage.create_new(&path).map(|file| (file, path))
     })
-    .filter(|r| r.as_ref().map_or_else(|e| e.kind() != io::ErrorKind::AlreadyExists, |_| true))
-    .next().unwrap()?
+    .find(|r| r.as_ref().map_or_else(|e| e.kind() != io::ErrorKind::AlreadyExists, |_| true)).unwrap()?
+}
+
+/// Something that names a URL to retrieve, such as a `reqwest::Url` or a string to be parsed as one.
+///
+/// This plays the part of `reqwest::IntoUrl`, whose conversion method can't be called from outside `reqwest`.
+pub trait IntoUrl {
+    /// Returns the URL, or an error if this isn't a valid one.
+    fn into_url(self) -> Result<reqwest::Url, Error>;
+}
+
+impl IntoUrl for reqwest::Url {
+    fn into_url(self) -> Result<reqwest::Url, Error> { Ok(self) }
+}
+
+impl IntoUrl for &reqwest::Url {
+    fn into_url(self) -> Result<reqwest::Url, Error> { Ok(self.clone()) }
+}
+
+impl IntoUrl for &str {
+    fn into_url(self) -> Result<reqwest::Url, Error> {
+        use anyhow::Context;
+        reqwest::Url::parse(self).with_context(|| format!("Invalid URL {:?}", self))
+    }
+}
+
+impl IntoUrl for &String {
+    fn into_url(self) -> Result<reqwest::Url, Error> { self.as_str().into_url() }
+}
+
+impl IntoUrl for String {
+    fn into_url(self) -> Result<reqwest::Url, Error> { self.as_str().into_url() }
+}
+
+/// Opens the local copy of some content at `path` in `storage`, saying which file it was if that fails.
+#[throws] fn open<S: Storage>(storage: &S, path: &path::Path) -> S::Reader {
+    use anyhow::Context;
+    storage.open(path).with_context(|| format!("Opening cached file {:?}", path))?
+}
+
+/// Sends `request`, returning the response whatever its status.
+///
+/// If `max_redirects` is set, we follow up to that many redirections ourselves, failing with [`CacheError::TooManyRedirects`] after that.
+#[throws] fn send<C: reqwest_mock::Client>(client: &C, mut request: reqwest::blocking::Request, max_redirects: Option<usize>) -> C::Response {
+    use {reqwest::StatusCode, reqwest_mock::HttpResponse};
+    let original = request.url().clone();
+    let mut redirects = 0;
+    loop {
+        // Our requests have no body, so they're easy to copy.
+        let (method, mut headers, url) = (request.method().clone(), request.headers().clone(), request.url().clone());
+        info!("HTTP request: {:?}", request);
+        let response = client.execute(request)?;
+        info!("HTTP response: {:?}", response);
+        let max = match max_redirects { Some(max) => max, None => return response };
+        if ![StatusCode::MOVED_PERMANENTLY, StatusCode::FOUND, StatusCode::SEE_OTHER, StatusCode::TEMPORARY_REDIRECT, StatusCode::PERMANENT_REDIRECT].contains(&response.status()) { return response }
+        let location = match response.headers().get(LOCATION).and_then(|location| url.join(location.to_str().ok()?).ok()) { Some(location) => location, None => return response };
+        if redirects == max { throw!(CacheError::TooManyRedirects{url: original, max}) }
+        redirects += 1;
+        // Like reqwest, don't hand credentials to another site.
+        if location.origin() != url.origin() { for name in &[AUTHORIZATION, COOKIE, PROXY_AUTHORIZATION, WWW_AUTHENTICATE] { headers.remove(name); } }
+        info!("Following redirection from {} to {}", url, location);
+        request = reqwest::blocking::Request::new(method, location);
+        *request.headers_mut() = headers;
+    }
+}
+
+/// Sends `request` with [`send`], treating a client or server error status as an error, unless it's one of `accept`.
+///
+/// `503 Service Unavailable` is reported as [`CacheError::Unavailable`], with any delay the server asked us to wait before retrying.
+#[throws] fn execute<C: reqwest_mock::Client>(client: &C, request: reqwest::blocking::Request, clock: &dyn Clock, max_redirects: Option<usize>, accept: &[reqwest::StatusCode]) -> C::Response {
+    use reqwest_mock::HttpResponse;
+    let url = request.url().clone();
+    let response = send(client, request, max_redirects)?;
+    if response.status() == reqwest::StatusCode::SERVICE_UNAVAILABLE {
+        throw!(CacheError::Unavailable{url, retry_after: retry_after(response.headers(), clock.now())});
+    }
+    if accept.contains(&response.status()) { return response }
+    response.error_for_status()?
+}
+
+/// Returns how long the `Retry-After` header in `headers` asks us to wait from `now`, if there is one.
+///
+/// It may give either a number of seconds, or an HTTP date.
+fn retry_after(headers: &HeaderMap, now: std::time::SystemTime) -> Option<std::time::Duration> {
+    let value = headers.get(RETRY_AFTER)?.to_str().ok()?.trim();
+    match value.parse() {
+        Ok(seconds) => Some(std::time::Duration::from_secs(seconds)),
+        Err(_) => Some(freshness::parse_http_date(value)?.duration_since(now).unwrap_or_default()),
+    }
+}
+
+/// Returns the length of the body announced by the `Content-Length` header in `headers`, if there is one.
+fn content_length(headers: &HeaderMap) -> Option<u64> {
+    headers.get(CONTENT_LENGTH)?.to_str().ok()?.trim().parse().ok()
+}
+
+/// Whether a response with `status` to a conditional `GET` carries new content to replace what we have.
+fn replaces_content(status: reqwest::StatusCode) -> bool {
+    use reqwest::StatusCode;
+    status.is_success() && ![StatusCode::NO_CONTENT, StatusCode::RESET_CONTENT, StatusCode::PARTIAL_CONTENT].contains(&status)
+}
+
+/// Makes `request` conditional on the resource having changed since `record` was stored, using the `preferred` validators.
+#[throws] fn make_conditional(request: &mut reqwest::blocking::Request, record: &db::CacheRecord, preferred: Validator) {
+    let (etag, last_modified) = (!record.etags.is_empty(), record.last_modified.is_some());
+    let (etag, last_modified) = match preferred {
+        Validator::ETag => (etag, last_modified && !etag),
+        Validator::LastModified => (etag && !last_modified, last_modified),
+        Validator::Both => (etag, last_modified),
+    };
+    // Insert rather than append, so these override any default headers of the same name.
+    // We store Last-Modified as the server sent it, but must send dates in the preferred format, whichever one it used.
+    if let Some(date) = record.last_modified.as_ref().filter(|_| last_modified) { request.headers_mut().insert(IF_MODIFIED_SINCE, HeaderValue::from_str(&freshness::canonical_http_date(date))?); }
+    if etag { request.headers_mut().insert(IF_NONE_MATCH, HeaderValue::from_str(&db::ETag::join(&record.etags))?); }
+}
+
+/// Returns the URL that `url`'s content is stored under when there's no [`Cache::set_key_fn`], following `options`.
+///
+/// `url` should already have had its fragment removed.
+fn default_key(options: &builder::Options, url: &reqwest::Url) -> reqwest::Url {
+    let mut key = url.clone();
+    let ignored = &options.ignore_query_params;
+    let ignore = |name: &str| ignored.iter().any(|pattern| match pattern.strip_suffix('*') { Some(prefix) => name.starts_with(prefix), None => name == pattern });
+    if !ignored.is_empty() && url.query_pairs().any(|(name, _)| ignore(name.as_ref())) {
+        let kept: Vec<_> = url.query_pairs().filter(|(name, _)| !ignore(name.as_ref())).map(|(name, value)| (name.into_owned(), value.into_owned())).collect();
+        if kept.is_empty() { key.set_query(None) } else { key.query_pairs_mut().clear().extend_pairs(kept); }
+    }
+    if options.normalize_urls {
+        key.set_path(&normalize_percent_encoding(url.path()));
+        if let Some(query) = key.query().map(normalize_percent_encoding) { key.set_query(Some(&query)) }
+    }
+    key
+}
+
+/// Applies the RFC 3986 normalizations that parsing a URL doesn't already do:
+/// percent-encoded unreserved characters are decoded, and other percent-encodings are upper-cased.
+fn normalize_percent_encoding(text: &str) -> String {
+    let bytes = text.as_bytes();
+    let mut normalized = String::with_capacity(text.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' && i + 2 < bytes.len() && bytes[i + 1].is_ascii_hexdigit() && bytes[i + 2].is_ascii_hexdigit() {
+            let byte = u8::from_str_radix(&text[i + 1..i + 3], 16).unwrap();
+            if byte.is_ascii_alphanumeric() || b"-._~".contains(&byte) { normalized.push(byte as char) } else { normalized.push_str(&format!("%{:02X}", byte)) }
+            i += 3;
+        } else {
+            normalized.push(bytes[i] as char);
+            i += 1;
+        }
+    }
+    normalized
+}
+
+/// Returns whether the filesystem holding `path` has room for `bytes` more, while keeping `reserve` bytes free, and how much it has.
+fn room_for(path: &path::Path, bytes: u64, reserve: u64) -> io::Result<(bool, u64)> {
+    let available = fs2::available_space(path)?;
+    Ok((bytes.saturating_add(reserve) <= available, available))
 }
 
+/// Takes an exclusive lock on downloading `url` into the cache at `root`, blocking until it's available.
+///
+/// URLs are hashed into one of 256 lock files, so they don't pile up as entries come and go;
+/// two URLs that share a lock file just can't be downloaded at the same time.
+/// The lock is released when the returned file is closed.
+#[throws] fn lock_url(root: &path::Path, url: &reqwest::Url) -> fs::File {
+    use fs2::FileExt;
+    let locks_dir = root.join("locks");
+    fs::DirBuilder::new().recursive(true).create(&locks_dir)?;
+    let mut name = crypto_hash::hex_digest(crypto_hash::Algorithm::SHA256, url.as_str().as_bytes());
+    name.truncate(2);
+    let file = fs::OpenOptions::new().create(true).truncate(false).write(true).open(locks_dir.join(name))?;
+    file.lock_exclusive()?;
+    file
+}
+
+/// A writer that remembers whether writing to it failed, to tell storage failures from network ones when copying a response body.
+struct TrackedWriter<W> {
+    inner: W,
+    failed: bool,
+}
+
+impl<W: io::Write> io::Write for TrackedWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(buf);
+        // Writing nothing means there's no room left; `io::copy` turns it into a `WriteZero` error.
+        if written.as_ref().map_or(true, |&count| count == 0 && !buf.is_empty()) { self.failed = true; }
+        written
+    }
+    fn flush(&mut self) -> io::Result<()> { self.inner.flush().map_err(|err| { self.failed = true; err }) }
+}
+
+/// See [`Cache::set_key_fn`].
+type KeyFn = Box<dyn Fn(&reqwest::Url) -> reqwest::Url>;
+/// See [`Cache::set_interceptor`].
+type Interceptor = Box<dyn Fn(&mut reqwest::blocking::Request)>;
+/// See [`Cache::set_eviction_handler`].
+type EvictionHandler = Box<dyn FnMut(&reqwest::Url, &CacheRecord)>;
+
 /// Represents a local cache of HTTP resources.
 ///
 /// Whenever you ask it for the contents of a URL, it will re-use a previously-downloaded copy if the resource has not changed on the server.
 /// Otherwise, it will download the new version and use that instead.
 ///
-#[derive(Debug, PartialEq, Eq)]
-pub struct Cache<C: reqwest_mock::Client> {
+pub struct Cache<C: reqwest_mock::Client, S: Storage = FsStorage> {
     root: path::PathBuf,
+    storage: S,
     db: db::CacheDB,
     client: C,
+    options: builder::Options,
+    /// Shared with the threads [`Cache::get_many`] retrieves URLs on.
+    stats: std::sync::Arc<stats::Stats>,
+    default_headers: HeaderMap,
+    /// Headers for the requests of the current call only, see [`Cache::get_with_headers`].
+    call_headers: HeaderMap,
+    header_provider: Option<Box<dyn Fn() -> HeaderMap>>,
+    key_fn: Option<KeyFn>,
+    interceptor: Option<Interceptor>,
+    event_handler: Option<Box<dyn FnMut(CacheEvent)>>,
+    eviction_handler: Option<EvictionHandler>,
+    /// Where downloads go when they can't be recorded in the cache.
+    scratch: Option<tempfile::TempDir>,
+    /// The temporary directory that is `root`, for a cache made with [`Cache::in_memory`].
+    ephemeral: Option<tempfile::TempDir>,
+}
+
+impl<C: reqwest_mock::Client + fmt::Debug, S: Storage> fmt::Debug for Cache<C, S> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Cache").field("root", &self.root).field("db", &self.db).field("client", &self.client).field("stats", &self.stats).field("default_headers", &self.default_headers).finish()
+    }
 }
 
+impl<C: reqwest_mock::Client + PartialEq, S: Storage> PartialEq for Cache<C, S> {
+    fn eq(&self, other: &Self) -> bool { self.root == other.root && self.db == other.db && self.client == other.client }
+}
+
+impl<C: reqwest_mock::Client + Eq, S: Storage> Eq for Cache<C, S> {}
+
 use anyhow::Error;
 impl<C: reqwest_mock::Client> Cache<C> {
     /// Returns a Cache that wraps `client` and caches data in `root`.
@@ -62,6 +319,8 @@ impl<C: reqwest_mock::Client> Cache<C> {
     /// If multiple instances share the same `root` (concurrently or in series), each instance will be able to re-use resources downloaded by the others.
     ///
     /// `client` should almost certainly be a `reqwest::Client`, but you can use any type that implements [`reqwest_mock::Client`] if you want to use a different HTTP client library.
+    /// Every request the cache makes, including revalidations, is sent with the client's `execute` method,
+    /// so however it's configured (with proxies, a redirect policy, default headers or timeouts) applies to all of them.
     ///
     /// [`reqwest_mock::Client`]: reqwest_mock/trait.Client.html
     ///
@@ -69,24 +328,41 @@ impl<C: reqwest_mock::Client> Cache<C> {
     ///   - `root` cannot be created, or cannot be written to
     ///   - the metadata database cannot be created or cannot be written to
     ///   - the metadata database is corrupt
-    #[throws] pub fn new(root: path::PathBuf, client: C) -> Cache<C> {
-        fs::DirBuilder::new().recursive(true).create(&root)?;
-        let db = db::CacheDB::new(root.join("cache.db"))?;
-        Cache{root, db, client}
-    }
+    #[throws] pub fn new(root: path::PathBuf, client: C) -> Cache<C> { Self::builder(root, client).build()? }
 
-    #[throws] fn record_response(&mut self, url: reqwest::Url, response: &impl reqwest_mock::HttpResponse) -> (fs::File, path::PathBuf, db::Transaction) {
-        let content_dir = self.root.join("content");
-        fs::DirBuilder::new().recursive(true).create(&content_dir)?;
-        let (handle, path) = make_random_file(&content_dir)?;
-        let transaction = self.db.set(url, db::CacheRecord {
-            path: path.strip_prefix(&self.root)?.to_str().unwrap().into(),
-            last_modified: response.headers().get(&LAST_MODIFIED).map(HeaderValue::to_str).transpose()?.map(ToOwned::to_owned),
-            etag: response.headers().get(&ETAG).map(HeaderValue::to_str).transpose()?.map(ToOwned::to_owned),
-        })?;
-        (handle, path, transaction)
+    /// Returns a Cache that serves data from `root`, but never modifies it.
+    ///
+    /// This is for processes that may read from a cache that some other process maintains.
+    /// The metadata database is opened read-only, and
+    /// anything [`Cache::get`] has to download is saved to a temporary directory instead of being recorded in the cache.
+    /// Methods that would otherwise modify the cache return [`CacheError::ReadOnly`].
+    ///
+    /// # Errors
+    ///   - `root` does not exist, or cannot be read
+    ///   - the metadata database cannot be read, or needs upgrading to a newer schema
+    #[throws] pub fn open_read_only(root: path::PathBuf, client: C) -> Cache<C> { Self::builder(root, client).read_only(true).build()? }
+
+    /// Returns a Cache that wraps `client`, and keeps what it caches only until it's dropped.
+    ///
+    /// The metadata is kept in an in-memory database, and content in a temporary directory that is removed when the Cache is dropped,
+    /// so repeated requests in one run are still served from the cache, but nothing persists, and no other instance can share it.
+    ///
+    /// # Errors
+    ///   - the temporary directory cannot be created
+    #[throws] pub fn in_memory(client: C) -> Cache<C> {
+        let dir = tempfile::Builder::new().prefix("http-cache").tempdir()?;
+        let options = builder::Options::default();
+        let db = db::CacheDB::open(":memory:".into(), &options.db)?;
+        let mut cache = Cache::from_parts(dir.path().canonicalize()?, db, client, options, FsStorage);
+        cache.ephemeral = Some(dir);
+        cache
     }
 
+    /// Returns a [`CacheBuilder`] for a Cache that wraps `client` and caches data in `root`, with non-default options.
+    ///
+    /// See [`Cache::new`] for the meaning of the arguments.
+    pub fn builder(root: path::PathBuf, client: C) -> CacheBuilder<C> { CacheBuilder::new(root, client) }
+
     /// Retrieve the content of the given URL.
     ///
     /// If we've never seen this URL before, we will try to retrieve it (with a `GET` request) and store its data locally.
@@ -105,478 +381,7171 @@ impl<C: reqwest_mock::Client> Cache<C> {
     ///   - we can't update the cache metadata
     ///   - the cache metadata points to a local file that no longer exists
     ///
+    /// Errors from the client, including error statuses, keep their original type, so they can be recovered with `downcast_ref`.
+    ///
     /// After returning a network-related or disk I/O-related error, this `Cache` instance should be OK and you may keep using it.
-    #[throws] pub fn get(&mut self, mut url: reqwest::Url) -> fs::File {
-        use {reqwest::StatusCode, reqwest_mock::HttpResponse};
-        url.set_fragment(None);
-        let mut request = reqwest::blocking::Request::new(reqwest::Method::GET, url.clone());
-        #[throws] fn execute(client: &impl reqwest_mock::Client, request: reqwest::blocking::Request) -> impl reqwest_mock::HttpResponse {
-            info!("HTTP request: {:?}", request);
-            let response = client.execute(request)?.error_for_status()?;
-            info!("HTTP response: {:?}", response);
-            response
-        }
-        let mut response = match self.db.get(url.clone()) {
-            Ok(db::CacheRecord{path, last_modified, etag}) => {
-                let path = self.root.join(path);
-                let day = std::time::Duration::new(24*60*60, 0);
-                if std::time::SystemTime::now().duration_since(fs::metadata(&path)?.modified()?)? > day { return fs::File::open(&path)? }
-                if let Some(last_modified) = last_modified { request.headers_mut().append(IF_MODIFIED_SINCE, HeaderValue::from_str(&last_modified)?); }
-                if let Some(etag) = etag { request.headers_mut().append(IF_NONE_MATCH, HeaderValue::from_str(&etag)?); }
-                let response = execute(&self.client, request)?;
-                if response.status() == StatusCode::NOT_MODIFIED { return fs::File::open(&path)? }
-                response
-            },
-            Err(_) => execute(&self.client, request)?,
-        };
-        let (mut handle, path, transaction) = self.record_response(url.clone(), &response)?;
-        let count = io::copy(&mut response, &mut handle)?;
-        info!("Downloaded {} bytes", count);
-        transaction.commit()?;
-        fs::File::open(&path)?
+    #[throws] pub fn get(&mut self, url: impl IntoUrl) -> fs::File {
+        let path = self.get_path(url)?;
+        open(&self.storage, &path)?
     }
-}
 
-#[cfg(test)]
-mod tests {
-    extern crate env_logger;
-    extern crate tempdir;
+    /// Retrieve the content of the given URL, returning the absolute path of the local copy instead of opening it.
+    ///
+    /// This performs the same checks and downloads as [`Cache::get`],
+    /// and is useful when you need a path rather than a file-handle, for example to memory-map a large file.
+    ///
+    /// The path stays valid until the entry is updated (by a later retrieval finding the resource changed) or removed from the cache.
+    /// Updated content is always written to a new file, so a file you already opened keeps its old content.
+    /// For a read-only cache, or a response marked `Cache-Control: no-store`,
+    /// the content is downloaded to a scratch directory that is removed when the `Cache` is dropped.
+    /// So is content stored compressed, with [`CacheBuilder::compress_at_rest`], which is decompressed there.
+    ///
+    /// # Errors
+    /// The same as [`Cache::get`].
+    #[throws] pub fn get_path(&mut self, url: impl IntoUrl) -> path::PathBuf { self.retrieve_uncompressed(url.into_url()?)?.0 }
 
-    use reqwest;
-    use reqwest::header as rh;
+    /// Retrieve the content of the given URL, accepting a cached copy that went stale up to `tolerance` ago.
+    ///
+    /// This is like the `max-stale` directive of a request's `Cache-Control` header:
+    /// a cached copy that would otherwise be revalidated is served as it is if it's no more than `tolerance` past its freshness lifetime,
+    /// as given by `max-age` or `Expires`, or past when it was last validated if the server didn't say.
+    /// Otherwise, this behaves exactly like [`Cache::get`].
+    /// It only relaxes this call; [`Cache::set_max_stale`] still limits how long ago a copy may have been validated.
+    ///
+    /// # Errors
+    /// The same as [`Cache::get`].
+    #[throws] pub fn get_max_stale(&mut self, url: impl IntoUrl, tolerance: std::time::Duration) -> fs::File {
+        let url = url.into_url()?;
+        match self.stale_copy(url.clone(), tolerance, true)? {
+            Some(path) => open(&self.storage, &path)?,
+            None => self.get(url)?,
+        }
+    }
 
-    use std::io;
+    /// Retrieve the content of the given URL, adding `extra` headers to the requests sent for this call only.
+    ///
+    /// This behaves exactly like [`Cache::get`], with `extra` replacing any default or provided headers of the same name.
+    /// The cache's own conditional headers, like `If-None-Match`, still take precedence over `extra`.
+    /// Nothing about `extra` is stored: entries are keyed by URL alone,
+    /// so if a header like `Accept-Language` changes the content, the new content replaces the old,
+    /// and a later [`Cache::get`] may be served either.
+    ///
+    /// # Errors
+    /// The same as [`Cache::get`].
+    #[throws] pub fn get_with_headers(&mut self, url: impl IntoUrl, extra: HeaderMap) -> fs::File {
+        self.call_headers = extra;
+        let file = self.get(url);
+        self.call_headers.clear();
+        file?
+    }
 
-    use std::io::Read;
+    /// Retrieve the content of the given URL, and say whether it needed the network.
+    ///
+    /// This behaves exactly like [`Cache::get`], but also returns a [`CacheStatus`] describing how the local copy was obtained.
+    ///
+    /// # Errors
+    /// The same as [`Cache::get`].
+    #[throws] pub fn get_with_status(&mut self, url: impl IntoUrl) -> (fs::File, CacheStatus) {
+        let (path, status) = self.retrieve_uncompressed(url.into_url()?)?;
+        (open(&self.storage, &path)?, status)
+    }
 
-    use super::reqwest_mock::tests as rmt;
+    /// Retrieve the content of each of the given URLs, returning the results in the same order.
+    ///
+    /// Each URL is retrieved as by [`Cache::get`], and failing to retrieve one doesn't stop the others.
+    /// They're retrieved on up to 8 threads at once, each with its own clone of the client and connection to the cache metadata.
+    /// A key function, header provider, interceptor, event handler or eviction handler can't be used from other threads,
+    /// so a Cache with any of them, or one made with [`Cache::in_memory`], retrieves every URL on this thread, one at a time.
+    pub fn get_many<U: IntoUrl>(&mut self, urls: impl IntoIterator<Item = U>) -> Vec<Result<fs::File, anyhow::Error>> where C: Clone + Send {
+        let urls: Vec<_> = urls.into_iter().map(IntoUrl::into_url).collect();
+        if self.key_fn.is_some() || self.header_provider.is_some() || self.interceptor.is_some() || self.event_handler.is_some() || self.eviction_handler.is_some() || self.ephemeral.is_some() {
+            return urls.into_iter().map(|url| url.and_then(|url| self.get(url))).collect()
+        }
+        let valid: Vec<_> = urls.iter().enumerate().filter_map(|(index, url)| Some((index, url.as_ref().ok()?.clone()))).collect();
+        // Invalid URLs fail right away.
+        let mut results: Vec<_> = urls.into_iter().map(|url| url.err().map(Err)).collect();
+        self.get_concurrently(valid.clone(), &mut results);
+        for (index, url) in valid { results[index].get_or_insert_with(|| Err(anyhow::anyhow!("Retrieving {} panicked", url))); }
+        results.into_iter().map(Option::unwrap).collect()
+    }
 
-    const DATE_ZERO: &str = "Thu, 01 Jan 1970 00:00:00 GMT";
-    const DATE_ONE: &str = "Thu, 01 Jan 1970 00:00:00 GMT";
+    /// Retrieves each of `urls`, the index of a URL in `results` and the URL itself, on a pool of threads, and fills in its result.
+    fn get_concurrently(&self, urls: Vec<(usize, reqwest::Url)>, results: &mut [Option<Result<fs::File, Error>>]) where C: Clone + Send {
+        let workers = std::cmp::min(urls.len(), shared::MAX_WORKERS);
+        let next = std::sync::Mutex::new(urls.into_iter());
+        let (sender, receiver) = std::sync::mpsc::channel();
+        std::thread::scope(|scope| {
+            let handles: Vec<_> = (0..workers).map(|_| {
+                let (next, sender) = (&next, sender.clone());
+                let (root, db_path, client, options) = (self.root.clone(), self.db_path().to_owned(), self.client.clone(), self.options.clone());
+                let (default_headers, stats) = (self.default_headers.clone(), self.stats.clone());
+                scope.spawn(move || {
+                    // SQLite connections can't move between threads, so each opens its own.
+                    let mut cache = db::CacheDB::open(db_path, &options.db).map(|db| Cache{default_headers, stats, ..Cache::from_parts(root, db, client, options, FsStorage)});
+                    loop {
+                        let item = next.lock().unwrap().next();
+                        let (index, url) = match item { Some(item) => item, None => break };
+                        let result = match &mut cache { Ok(cache) => cache.get(url), Err(err) => Err(anyhow::anyhow!("Could not open the cache metadata: {}", err)) };
+                        sender.send((index, result)).unwrap();
+                    }
+                })
+            }).collect();
+            drop(sender);
+            for (index, result) in receiver { results[index] = Some(result); }
+            for handle in handles { handle.join().unwrap_or_else(|_| warn!("A get_many worker thread panicked")); }
+        });
+    }
+}
 
-    fn make_test_cache(
-        client: rmt::FakeClient,
-    ) -> super::Cache<rmt::FakeClient> {
-        super::Cache::new(
-            tempdir::TempDir::new("http-cache-test")
-                .unwrap()
-                .into_path(),
-            client,
-        )
-        .unwrap()
+impl<C: reqwest_mock::Client, S: Storage> Cache<C, S> {
+    /// Returns a Cache that caches data in `root`, which must already be canonical, using the already-open `db`.
+    pub(crate) fn from_parts(root: path::PathBuf, db: db::CacheDB, client: C, options: builder::Options, storage: S) -> Cache<C, S> {
+        Cache{root, storage, db, client, options, stats: Default::default(), default_headers: HeaderMap::new(), call_headers: HeaderMap::new(), header_provider: None, key_fn: None, interceptor: None, event_handler: None, eviction_handler: None, scratch: None, ephemeral: None}
     }
 
-    #[test]
-    fn initial_request_success() {
-        let _ = env_logger::try_init();
+    /// Returns the directory where this cache stores its data.
+    ///
+    /// This is the `root` passed to [`Cache::new`], canonicalized.
+    pub fn root(&self) -> &path::Path { &self.root }
 
-        let url_text = "http://example.com/";
-        let url: reqwest::Url = url_text.parse().unwrap();
+    /// Returns the path to the metadata database inside [`Cache::root`].
+    pub fn db_path(&self) -> &path::Path { self.db.path() }
 
-        let body = b"hello world";
+    /// Returns a snapshot of the hit/miss counters accumulated by [`Cache::get`].
+    pub fn stats(&self) -> CacheStats { self.stats.snapshot() }
 
-        let mut c = make_test_cache(rmt::FakeClient::new(
-            url.clone(),
-            HeaderMap::new(),
-            rmt::FakeResponse {
-                status: reqwest::StatusCode::OK,
-                headers: HeaderMap::new(),
-                body: io::Cursor::new(body.as_ref().into()),
-            },
-        ));
+    /// Returns the fraction of the latest `window` requests answered from the cache rather than by downloading a body.
+    ///
+    /// Only the latest 1024 outcomes are remembered, so larger windows are capped at that.
+    /// Returns `0.0` if there haven't been any requests since the cache was created or [`Cache::reset_stats`] was called.
+    /// A sudden drop may mean the server stopped honouring our validators.
+    pub fn recent_hit_ratio(&self, window: usize) -> f64 { self.stats.recent_hit_ratio(window) }
 
-        // We should get a file-handle containing the body bytes.
-        let mut res = c.get(url).unwrap();
-        let mut buf = vec![];
-        res.read_to_end(&mut buf).unwrap();
-        assert_eq!(&buf, body);
-        c.client.assert_called();
-    }
+    /// Resets all the counters reported by [`Cache::stats`] to zero.
+    pub fn reset_stats(&mut self) { self.stats = Default::default(); }
 
-    #[test]
-    fn initial_request_failure() {
-        let _ = env_logger::try_init();
+    /// Sets headers to be sent with every request this cache makes.
+    ///
+    /// These are merged into both the initial download and any revalidation request.
+    /// If one of them collides with a conditional header the cache adds itself (`If-Modified-Since` or `If-None-Match`), the conditional header wins.
+    pub fn set_default_headers(&mut self, headers: HeaderMap) { self.default_headers = headers; }
 
-        let url: reqwest::Url = "http://example.com/".parse().unwrap();
-        let mut c = make_test_cache(rmt::FakeClient::new(
-            url.clone(),
-            HeaderMap::new(),
-            rmt::FakeResponse {
-                status: reqwest::StatusCode::INTERNAL_SERVER_ERROR,
-                headers: HeaderMap::new(),
-                body: io::Cursor::new(vec![]),
-            },
-        ));
+    /// Sets the `User-Agent` header sent with every request this cache makes.
+    ///
+    /// This is one of the default headers, so a later call to [`Cache::set_default_headers`] replaces it.
+    ///
+    /// # Errors
+    /// Returns an error if `user_agent` isn't a valid header value, for example if it contains a newline.
+    #[throws] pub fn set_user_agent(&mut self, user_agent: &str) { self.default_headers.insert(USER_AGENT, HeaderValue::from_str(user_agent)?); }
 
-        let err = c.get(url).expect_err("Got a response??");
-        assert_eq!(format!("{}", err), "FakeError");
-        c.client.assert_called();
+    /// Sets a function that supplies headers for each request, such as a short-lived `Authorization` token.
+    ///
+    /// `provider` is called immediately before every request the cache makes, both for initial downloads and revalidations.
+    /// Its headers are merged the same way as [`Cache::set_default_headers`], overriding any default header of the same name.
+    pub fn set_header_provider(&mut self, provider: impl Fn() -> HeaderMap + 'static) { self.header_provider = Some(Box::new(provider)); }
+
+    /// Sets a function that may inspect and modify each request just before it's sent, for instance to sign it.
+    ///
+    /// `interceptor` sees the request exactly as it will be sent, with the default headers, the header provider's headers,
+    /// and any conditional headers the cache added to revalidate cached data.
+    /// It is called for initial downloads, revalidations, `HEAD` requests, and each retry, but not for `file:` URLs.
+    pub fn set_interceptor(&mut self, interceptor: impl Fn(&mut reqwest::blocking::Request) + 'static) { self.interceptor = Some(Box::new(interceptor)); }
+
+    /// Passes `request` to the function set with [`Cache::set_interceptor`], if any.
+    fn intercept(&self, mut request: reqwest::blocking::Request) -> reqwest::blocking::Request {
+        if let Some(interceptor) = &self.interceptor { interceptor(&mut request) }
+        request
     }
 
-    #[test]
-    fn ignore_fragment_in_url() {
-        let _ = env_logger::try_init();
+    /// Sets the function that decides which URL each requested URL's content is stored under.
+    ///
+    /// URLs that `key_fn` maps to the same key share one cache entry, which is useful when several URLs name the same resource,
+    /// for instance mirrors, or URLs that differ only in an authentication parameter.
+    /// Requests still go to the URL asked for; only the metadata database sees the key.
+    /// The key is used by every method that looks up or stores an entry, and replaces
+    /// [`CacheBuilder::ignore_query_params`] and [`CacheBuilder::normalize_urls`], which it should reimplement if needed.
+    /// By default, the key is the requested URL without its fragment.
+    ///
+    /// Entries stored under a different key function aren't found by this one,
+    /// so changing it effectively invalidates what's already cached.
+    pub fn set_key_fn(&mut self, key_fn: impl Fn(&reqwest::Url) -> reqwest::Url + 'static) { self.key_fn = Some(Box::new(key_fn)); }
 
-        let url_fragment: reqwest::Url =
-            "http://example.com/#frag".parse().unwrap();
+    /// Sets a function to be called with a [`CacheEvent`] at each decision [`Cache::get`] makes.
+    ///
+    /// This reports the same things as this crate's `log` messages, but in a machine-readable form that doesn't depend on a global logger.
+    pub fn set_event_handler(&mut self, handler: impl FnMut(CacheEvent) + 'static) { self.event_handler = Some(Box::new(handler)); }
 
-        let mut network_url = url_fragment.clone();
-        network_url.set_fragment(None);
+    /// Sets a function to be called with the URL and metadata of each entry the cache removes, just before it's removed.
+    ///
+    /// This is called once for each entry removed by [`Cache::invalidate_prefix`], [`Cache::prune_expired`] and [`Cache::trim`],
+    /// and for an entry evicted because the server says it's gone, or because its download failed.
+    /// The URL is the one the entry is stored under, as [`Cache::metadata`] would look it up.
+    /// Entries replaced by new content, or moved with [`Cache::rename_url`], aren't removed, so this isn't called for them.
+    pub fn set_eviction_handler(&mut self, handler: impl FnMut(&reqwest::Url, &CacheRecord) + 'static) { self.eviction_handler = Some(Box::new(handler)); }
 
-        let mut c = make_test_cache(rmt::FakeClient::new(
-            // We expect the cache to request the URL without the fragment.
-            network_url,
-            HeaderMap::new(),
-            rmt::FakeResponse {
-                status: reqwest::StatusCode::OK,
-                headers: HeaderMap::new(),
-                body: io::Cursor::new(b"hello world"[..].into()),
-            },
-        ));
+    /// Sets when cached data should be revalidated with the server.
+    ///
+    /// The default, [`RevalidationPolicy::Always`], revalidates on every retrieval.
+    pub fn set_revalidation(&mut self, policy: RevalidationPolicy) { self.options.revalidation = policy; }
 
-        // Ask for the URL with the fragment.
-        c.get(url_fragment).unwrap();
-    }
+    /// Sets the largest response body, in bytes, the cache will download.
+    ///
+    /// A download that would be larger fails with [`CacheError::TooLarge`], leaving nothing behind.
+    /// It fails before anything is downloaded if the response's `Content-Length` says it's too large,
+    /// and otherwise as soon as the limit is passed.
+    /// There's no limit by default.
+    pub fn set_max_file_size(&mut self, bytes: u64) { self.options.max_file_size = Some(bytes); }
 
-    #[test]
-    fn use_cache_data_if_not_modified_since() {
-        let _ = env_logger::try_init();
+    /// Sets the longest cached data may be used without asking the server whether it's still valid.
+    ///
+    /// Once this long has passed since the server last sent or confirmed an entry, it is revalidated before its next use,
+    /// even if the response said it would still be fresh, or the revalidation policy is [`RevalidationPolicy::Never`].
+    /// This can only make the cache revalidate more often, never less.
+    /// Entries added with [`Cache::insert_local`] or [`Cache::import`] haven't been validated, so this doesn't apply to them until they are.
+    /// There's no limit by default.
+    pub fn set_max_stale(&mut self, max_stale: std::time::Duration) { self.options.max_stale = Some(max_stale); }
 
-        let url: reqwest::Url = "http://example.com/".parse().unwrap();
-        let body = b"hello world";
+    /// Sets how many redirections the cache follows for each request, failing with [`CacheError::TooManyRedirects`] after that.
+    ///
+    /// Redirections are otherwise left to the client, following its redirect policy.
+    /// A `reqwest` client's policy can't be changed once it's built, so for this limit to apply,
+    /// build it with `.redirect(reqwest::redirect::Policy::none())` and let the cache follow them instead.
+    /// Like `reqwest`, the cache doesn't send `Authorization` or `Cookie` headers on to another site.
+    /// Content is cached under the URL that was asked for, not the one it was redirected to.
+    ///
+    /// See [`CacheBuilder::max_redirects`], which can set this before the cache is first used.
+    pub fn set_max_redirects(&mut self, max: usize) { self.options.max_redirects = Some(max); }
 
-        // We send a request, and the server responds with the data,
-        // and a "Last-Modified" header.
-        let mut response_headers = HeaderMap::new();
-        response_headers
-            .append(LAST_MODIFIED, HeaderValue::from_static(DATE_ZERO));
+    /// Returns whether the filesystem holding the cache has room for `bytes` more.
+    ///
+    /// This leaves room for the reserve set with [`CacheBuilder::reserve_free_space`], if any.
+    /// Other processes may use or free space at any time, so the answer is only a guide.
+    pub fn has_room_for(&self, bytes: u64) -> io::Result<bool> {
+        Ok(room_for(&self.root, bytes, self.options.free_space_reserve.unwrap_or(0))?.0)
+    }
 
-        let mut c = make_test_cache(rmt::FakeClient::new(
-            url.clone(),
-            HeaderMap::new(),
-            rmt::FakeResponse {
-                status: reqwest::StatusCode::OK,
-                headers: response_headers.clone(),
-                body: io::Cursor::new(body.as_ref().into()),
-            },
-        ));
+    /// Sets where the cache gets the current time from, for freshness and access times.
+    ///
+    /// See [`CacheBuilder::clock`], which can set this before the cache is first used.
+    pub fn set_clock(&mut self, clock: impl Clock + 'static) { self.options.clock = Some(std::sync::Arc::new(clock)); }
 
-        // The response and its last-modified date should now be recorded
-        // in the cache.
-        c.get(url.clone()).unwrap();
-        c.client.assert_called();
+    fn clock(&self) -> &dyn Clock { self.options.clock.as_deref().unwrap_or(&SystemClock) }
 
-        // For the next request, we expect the request to include the
-        // modified date in the "if modified since" header, and we'll give
-        // the "no, it hasn't been modified" response.
-        let mut second_request = HeaderMap::new();
-        second_request.append(
-            IF_MODIFIED_SINCE,
-            HeaderValue::from_static(DATE_ZERO),
-        );
+    fn emit(&mut self, event: CacheEvent) { if let Some(handler) = &mut self.event_handler { handler(event) } }
 
-        c.client = rmt::FakeClient::new(
-            url.clone(),
-            second_request,
-            rmt::FakeResponse {
-                status: reqwest::StatusCode::NOT_MODIFIED,
-                headers: response_headers,
-                body: io::Cursor::new(b""[..].into()),
-            },
-        );
+    fn request_headers(&self) -> HeaderMap {
+        let mut headers = self.default_headers.clone();
+        if let Some(provider) = &self.header_provider { headers.extend(provider()); }
+        headers.extend(self.call_headers.clone());
+        headers
+    }
 
-        // Now when we make the request, even though the actual response
-        // did not include a body, we should get the complete body from
-        // the local cache.
-        let mut res = c.get(url).unwrap();
-        let mut buf = vec![];
-        res.read_to_end(&mut buf).unwrap();
-        assert_eq!(&buf, body);
-        c.client.assert_called();
+    /// Creates a new file with a random name in the directory `dir` under the cache root.
+    ///
+    /// Complete content lives in `content`, and interrupted downloads in `partial`.
+    /// With [`CacheBuilder::sharded`], the file is put in a subdirectory of `dir`.
+    #[throws] fn create_file(&self, dir: &str) -> (S::Writer, path::PathBuf) {
+        use anyhow::Context;
+        let dir = self.root.join(dir);
+        make_random_file(&self.storage, &dir, self.options.sharded).with_context(|| format!("Creating a file in cache directory {:?}", dir))?
     }
 
-    #[test]
-    fn update_cache_if_modified_since() {
-        let _ = env_logger::try_init();
+    /// Records `response`, to a request for `source`, as the content of `url`, pending a download to the returned file.
+    ///
+    /// Once the download is complete, it should be moved into place with [`Cache::finish_download`].
+    #[throws] fn record_response(&mut self, url: reqwest::Url, source: &reqwest::Url, response: &impl reqwest_mock::HttpResponse) -> (S::Writer, path::PathBuf, db::Transaction<'_>) {
+        let (handle, path) = self.create_file("partial")?;
+        let (record, headers) = self.response_record(&path, response)?;
+        let transaction = self.db.set_response(url, source, record, &headers)?;
+        (handle, path, transaction)
+    }
 
-        let url: reqwest::Url = "http://example.com/".parse().unwrap();
+    /// Describes `response`, whose content is (or will be) at `path`, as an incomplete entry, along with the headers to store with it.
+    #[throws] fn response_record(&self, path: &path::Path, response: &impl reqwest_mock::HttpResponse) -> (db::CacheRecord, HeaderMap) {
+        let now = self.clock().now();
+        let no_cache = freshness::cache_control(response.headers(), "no-cache").is_some();
+        let heuristic = self.options.heuristic_freshness.filter(|_| !no_cache);
+        let mut headers = HeaderMap::new();
+        for name in self.options.headers_to_store() {
+            for value in response.headers().get_all(&name) { headers.append(name.clone(), value.clone()); }
+        }
+        let record = db::CacheRecord {
+            path: path.strip_prefix(&self.root)?.to_str().unwrap().into(),
+            last_modified: response.headers().get(&LAST_MODIFIED).map(HeaderValue::to_str).transpose()?.map(ToOwned::to_owned),
+            etags: response.headers().get_all(&ETAG).iter().map(|etag| etag.to_str().map(db::ETag::parse)).collect::<Result<_, _>>()?,
+            complete: false,
+            fresh_until: freshness::fresh_until(response.headers(), now).or_else(|| freshness::heuristic_fresh_until(response.headers(), now, heuristic?)),
+            no_cache,
+            status: Some(response.status().as_u16()),
+            fetched_at: Some(now),
+            validated_at: Some(now),
+            declared_length: content_length(response.headers()),
+            response_date: freshness::response_date(response.headers()),
+            compressed: self.options.compress_at_rest,
+        };
+        (record, headers)
+    }
+
+    /// Make sure the content of the given URL is cached, without opening it.
+    ///
+    /// This performs the same checks and downloads as [`Cache::get`], so it's a cheaper way to pre-populate the cache.
+    ///
+    /// # Errors
+    /// The same as [`Cache::get`].
+    #[throws] pub fn warm(&mut self, url: impl IntoUrl) { self.retrieve(url.into_url()?)?; }
+
+    #[throws] fn retrieve(&mut self, mut url: reqwest::Url) -> (path::PathBuf, CacheStatus) {
+        self.strip_fragment(&mut url);
+        let key = self.key(&url);
+        let retrieved = self.fetch(url, key.clone())?;
+        if !self.options.read_only {
+            match self.options.access_debounce {
+                Some(interval) => self.db.record_access_debounced(key, self.clock().now(), interval)?,
+                None => self.db.record_access(key, self.clock().now())?,
+            }
+        }
+        retrieved
+    }
+
+    /// Like [`Cache::retrieve`], but returns the path of uncompressed content, decompressing it if it's stored compressed.
+    #[throws] fn retrieve_uncompressed(&mut self, mut url: reqwest::Url) -> (path::PathBuf, CacheStatus) {
+        self.strip_fragment(&mut url);
+        let key = self.key(&url);
+        let (path, status) = self.retrieve(url)?;
+        // Content that wasn't stored, like a no-store response, is never compressed.
+        let compressed = match self.db.get(key) {
+            Ok(record) => record.compressed && self.root.join(&record.path) == path,
+            Err(db::DbError::NotFound(_)) => false,
+            Err(err) => Err(err)?,
+        };
+        (self.uncompressed(path, compressed)?, status)
+    }
+
+    /// Returns the path of an uncompressed copy of the content at `path`, decompressing it into the scratch directory if it's `compressed`.
+    ///
+    /// Complete content files never change, so a copy decompressed earlier is reused.
+    #[throws] fn uncompressed(&mut self, path: path::PathBuf, compressed: bool) -> path::PathBuf {
+        if !compressed { return path }
+        if self.scratch.is_none() { self.scratch = Some(tempfile::tempdir()?); }
+        let scratch = self.scratch.as_ref().unwrap().path();
+        let copy = scratch.join(path.file_name().unwrap());
+        if self.storage.len(&copy).is_err() {
+            let (mut handle, partial) = make_random_file(&self.storage, scratch, false)?;
+            io::copy(&mut flate2::read::MultiGzDecoder::new(self.storage.open(&path)?), &mut handle)?;
+            self.storage.rename(&partial, &copy)?;
+        }
+        copy
+    }
+
+    /// Retrieve the content of the given URL into memory.
+    ///
+    /// This behaves exactly like [`Cache::get`], but reads the whole local copy instead of returning a file-handle,
+    /// which is handy for small resources like configuration files.
+    ///
+    /// ```no_run
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// let mut cache = static_http_cache::Cache::new(
+    ///     std::env::temp_dir().join("static_http_cache"),
+    ///     reqwest::blocking::Client::new(),
+    /// )?;
+    /// let bytes = cache.get_bytes("https://static.rust-lang.org/dist/channel-rust-stable.toml")?;
+    /// println!("{} bytes", bytes.len());
+    /// # Ok(())
+    /// # }
+    /// ```
+    ///
+    /// # Errors
+    /// The same as [`Cache::get`], plus any error reading the local copy.
+    #[throws] pub fn get_bytes(&mut self, url: impl IntoUrl) -> Vec<u8> {
+        let path = self.retrieve_uncompressed(url.into_url()?)?.0;
+        let mut bytes = Vec::new();
+        io::Read::read_to_end(&mut open(&self.storage, &path)?, &mut bytes)?;
+        bytes
+    }
+
+    /// Retrieve the content of the given URL into memory as text.
+    ///
+    /// This behaves exactly like [`Cache::get_bytes`], but also checks that the content is valid UTF-8.
+    ///
+    /// ```no_run
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// let mut cache = static_http_cache::Cache::new(
+    ///     std::env::temp_dir().join("static_http_cache"),
+    ///     reqwest::blocking::Client::new(),
+    /// )?;
+    /// let text = cache.get_string("https://static.rust-lang.org/dist/channel-rust-stable.toml")?;
+    /// print!("{}", text);
+    /// # Ok(())
+    /// # }
+    /// ```
+    ///
+    /// # Errors
+    /// The same as [`Cache::get_bytes`], plus the content not being valid UTF-8.
+    #[throws] pub fn get_string(&mut self, url: impl IntoUrl) -> String {
+        let url = url.into_url()?;
+        let bytes = self.get_bytes(url.clone())?;
+        String::from_utf8(bytes).map_err(|err| anyhow::anyhow!("Content of {} is not valid UTF-8: {}", url, err.utf8_error()))?
+    }
+
+    /// Retrieve the bytes of the given URL's content from `start` up to, but not including, `end`, or to the end if `end` is `None`.
+    ///
+    /// This behaves exactly like [`Cache::get`], then seeks the local copy to `start` and stops reading at `end`,
+    /// which is handy to answer a client's `Range` request from the cache.
+    /// The range is only taken from the local copy: the whole content is still downloaded as usual if it isn't cached.
+    ///
+    /// # Errors
+    /// The same as [`Cache::get`], plus:
+    ///   - [`CacheError::InvalidRange`] if `start` is past `end`, or either is past the end of the content
+    ///   - we can't seek the local copy
+    #[throws] pub fn get_range(&mut self, url: impl IntoUrl, start: u64, end: Option<u64>) -> io::Take<S::Reader> {
+        let url = url.into_url()?;
+        let path = self.retrieve_uncompressed(url.clone())?.0;
+        let mut file = open(&self.storage, &path)?;
+        let len = self.storage.len(&path)?;
+        let end = end.unwrap_or(len);
+        if start > end || end > len { throw!(CacheError::InvalidRange{url, start, end, len}) }
+        io::Seek::seek(&mut file, io::SeekFrom::Start(start))?;
+        io::Read::take(file, end - start)
+    }
+
+    /// Adds a local file to the cache as the content of `url`, without any network access.
+    ///
+    /// `src` is copied into the cache, and recorded with the given `Last-Modified` and `ETag` header values,
+    /// which later retrievals will use to revalidate it with the server as usual.
+    /// Any existing entry for `url` is replaced.
+    ///
+    /// # Errors
+    ///   - the cache is read-only
+    ///   - `src` cannot be read, or copied into the cache
+    ///   - we can't update the cache metadata
+    #[throws] pub fn insert_local(&mut self, url: impl IntoUrl, src: &path::Path, last_modified: Option<String>, etag: Option<String>) {
+        self.check_writable()?;
+        let mut url = url.into_url()?;
+        self.strip_fragment(&mut url);
+        let key = self.key(&url);
+        let _lock = lock_url(&self.root, &key)?;
+        let replaced = match self.db.get(key.clone()) {
+            Ok(record) => Some(record),
+            Err(db::DbError::NotFound(_)) => None,
+            Err(err) => Err(err)?,
+        };
+        let (mut handle, path) = self.create_file("content")?;
+        let count = io::copy(&mut fs::File::open(src)?, &mut handle)?;
+        info!("Seeded {} with {} bytes from {:?}", url, count, src);
+        self.db.set(key.clone(), db::CacheRecord {
+            path: path.strip_prefix(&self.root)?.to_str().unwrap().into(),
+            last_modified,
+            etags: etag.as_deref().map(db::ETag::parse_list).unwrap_or_default(),
+            complete: true,
+            fresh_until: None,
+            no_cache: false,
+            status: None,
+            fetched_at: None,
+            validated_at: None,
+            declared_length: None,
+            response_date: None,
+            compressed: false,
+        })?.commit()?;
+        if let Some(record) = replaced {
+            let old = self.root.join(&record.path);
+            self.storage.remove(&old).unwrap_or_else(|err| warn!("Failed to remove {:?}: {}", old, err));
+        }
+        self.db.set_source(key, &url)?;
+    }
+
+    /// Moves the cached entry for `old` to `new`, keeping its content and validators, so `new` can be retrieved without downloading it.
+    ///
+    /// Afterwards, `old` is no longer cached.
+    /// If `new` is already cached, this fails with [`CacheError::AlreadyCached`]; use [`Cache::replace_url`] to overwrite it instead.
+    ///
+    /// # Errors
+    ///   - the cache is read-only
+    ///   - `old` isn't cached
+    ///   - `new` is already cached
+    ///   - we can't update the cache metadata
+    #[throws] pub fn rename_url(&mut self, old: impl IntoUrl, new: impl IntoUrl) { self.move_entry(old.into_url()?, new.into_url()?, false)? }
+
+    /// Moves the cached entry for `old` to `new`, like [`Cache::rename_url`], replacing any entry `new` already has.
+    ///
+    /// # Errors
+    /// The same as [`Cache::rename_url`], except that `new` may already be cached.
+    #[throws] pub fn replace_url(&mut self, old: impl IntoUrl, new: impl IntoUrl) { self.move_entry(old.into_url()?, new.into_url()?, true)? }
+
+    #[throws] fn move_entry(&mut self, mut old: reqwest::Url, mut new: reqwest::Url, replace: bool) {
+        self.check_writable()?;
+        self.strip_fragment(&mut old);
+        self.strip_fragment(&mut new);
+        let (old_key, new_key) = (self.key(&old), self.key(&new));
+        let _lock = lock_url(&self.root, &new_key)?;
+        let (replaced, transaction) = self.db.rename(old_key, new_key)?;
+        if let Some(replaced) = replaced {
+            if !replace { throw!(CacheError::AlreadyCached{url: new}) }
+            transaction.commit()?;
+            self.storage.remove(&self.root.join(&replaced.path)).unwrap_or_else(|err| warn!("Failed to remove {:?}: {}", replaced.path, err));
+        } else {
+            transaction.commit()?;
+        }
+        info!("Moved the cached entry for {} to {}", old, new);
+    }
+
+    /// Revalidates every cached entry with the server, downloading new content for those that changed.
+    ///
+    /// Each entry is checked with a conditional `GET` request, whatever the [`RevalidationPolicy`].
+    /// If an entry can't be checked or updated, the error is logged and counted as `failed`, and the sweep carries on with the rest.
+    ///
+    /// # Errors
+    ///   - the cache is read-only
+    ///   - the cache metadata is corrupt
+    #[throws] pub fn refresh_all(&mut self) -> RefreshSummary {
+        self.check_writable()?;
+        let mut summary = RefreshSummary::default();
+        for (key, record) in self.db.records()? {
+            // Interrupted downloads are resumed by the next retrieval instead.
+            if !record.complete { continue }
+            match self.db.source(key.clone()).map_err(Error::from).and_then(|source| self.refresh(source, key.clone(), &record)) {
+                Ok(true) => summary.updated += 1,
+                Ok(false) => summary.unchanged += 1,
+                Err(err) => { warn!("Could not refresh {}: {}", key, err); summary.failed += 1 },
+            }
+        }
+        summary
+    }
+
+    /// Revalidates `url`'s cached entry with the server, downloading new content if it changed, without opening it.
+    ///
+    /// Like [`Cache::refresh_all`] for every entry, this sends a conditional `GET` request whatever the [`RevalidationPolicy`],
+    /// and returns [`CacheStatus::Revalidated`] if the server confirmed the cached content, or [`CacheStatus::Downloaded`] if it sent new content.
+    ///
+    /// # Errors
+    ///   - the cache is read-only
+    ///   - `url` isn't cached, or only partly downloaded
+    ///   - the request fails, or the server answers with neither new content nor `304 Not Modified`
+    ///   - we can't store new content or update the cache metadata
+    #[throws] pub fn revalidate(&mut self, url: impl IntoUrl) -> CacheStatus {
+        self.check_writable()?;
+        let mut url = url.into_url()?;
+        self.strip_fragment(&mut url);
+        let key = self.key(&url);
+        let record = self.db.get(key.clone())?;
+        if !record.complete { anyhow::bail!("{} is only partly downloaded", url) }
+        if self.refresh(url, key, &record)? { CacheStatus::Downloaded } else { CacheStatus::Revalidated }
+    }
+
+    /// Revalidates `record`, stored under `key`, with a request for `url`, downloading new content if it changed; returns whether it did.
+    #[throws] fn refresh(&mut self, url: reqwest::Url, key: reqwest::Url, record: &db::CacheRecord) -> bool {
+        use reqwest_mock::HttpResponse;
+        let mut request = reqwest::blocking::Request::new(reqwest::Method::GET, url.clone());
+        *request.headers_mut() = self.request_headers();
+        make_conditional(&mut request, record, self.options.validator)?;
+        self.stats.revalidation();
+        let response = self.execute(request)?;
+        let changed = response.status() != reqwest::StatusCode::NOT_MODIFIED;
+        if changed && !replaces_content(response.status()) { anyhow::bail!("Unexpected status {} revalidating {}", response.status(), url) }
+        self.emit(CacheEvent::Revalidated{url: url.clone(), changed});
+        if changed { self.download(url, key, response, None)?; } else { self.not_modified(key, response.headers())?; }
+        changed
+    }
+
+    /// Returns the status code of the most recent response from the server about `url`, if it's cached.
+    ///
+    /// This is the status of the download that produced the cached content,
+    /// or `304 Not Modified` if the server has since confirmed it's unchanged.
+    /// Entries added with [`Cache::insert_local`] or [`Cache::import`] have no status until the server is asked about them.
+    #[throws] pub fn last_status(&self, url: impl IntoUrl) -> Option<reqwest::StatusCode> {
+        self.metadata(url)?.and_then(|record| record.status).map(reqwest::StatusCode::from_u16).transpose()?
+    }
+
+    /// Returns what the cache knows about `url`, if it's cached, without opening its content or using the network.
+    ///
+    /// The record's `path` is relative to [`Cache::root`].
+    /// It may describe an interrupted download, whose `complete` field is `false`.
+    ///
+    /// # Errors
+    /// Returns an error if the cache metadata is corrupt or can't be read.
+    #[throws] pub fn metadata(&self, url: impl IntoUrl) -> Option<CacheRecord> {
+        let mut url = url.into_url()?;
+        self.strip_fragment(&mut url);
+        match self.db.get(self.key(&url)) {
+            Ok(record) => Some(record),
+            Err(db::DbError::NotFound(_)) => None,
+            Err(err) => Err(err)?,
+        }
+    }
+
+    /// Returns the response headers stored with the cached copy of `url`, so it can be re-served as it was originally.
+    ///
+    /// Only the headers chosen with [`CacheBuilder::store_headers`] are stored, from the response that was downloaded;
+    /// revalidating the entry doesn't change them.
+    /// Entries added with [`Cache::insert_local`] or [`Cache::import`], or copied from `file:` URLs, have none.
+    ///
+    /// # Errors
+    ///   - `url` isn't cached
+    ///   - the cache metadata is corrupt or can't be read
+    #[throws] pub fn get_headers(&self, url: impl IntoUrl) -> HeaderMap {
+        let mut url = url.into_url()?;
+        self.strip_fragment(&mut url);
+        self.db.headers(self.key(&url))?
+    }
+
+    /// Returns the URL whose cached content is stored at `path`, if any.
+    ///
+    /// `path` may be relative to [`Cache::root`], like [`CacheRecord::path`], or an absolute path under it, like [`Cache::get_path`] returns.
+    /// This is handy for finding out what a file in the cache directory belongs to, or whether it belongs to anything at all.
+    ///
+    /// # Errors
+    /// Returns an error if the cache metadata is corrupt or can't be read.
+    #[throws] pub fn url_for_path(&self, path: &path::Path) -> Option<reqwest::Url> {
+        let relative = path.strip_prefix(&self.root).unwrap_or(path);
+        match relative.to_str() { Some(relative) => self.db.url_for_path(relative)?, None => None }
+    }
+
+    /// Returns whether `url` is completely cached, and its local copy is still on disk.
+    ///
+    /// Unlike [`Cache::metadata`], this also checks the file the metadata refers to,
+    /// so it notices when something else has deleted it.
+    ///
+    /// # Errors
+    /// Returns an error if the cache metadata is corrupt or can't be read.
+    #[throws] pub fn is_present_on_disk(&self, url: impl IntoUrl) -> bool {
+        match self.metadata(url)? {
+            Some(record) => record.complete && self.storage.len(&self.root.join(&record.path)).is_ok(),
+            None => false,
+        }
+    }
+
+    /// Returns every entry whose content file no longer exists, with the URL it's stored under.
+    ///
+    /// Something else may have deleted the files, say a partial copy of the cache, or a disk failure.
+    /// Interrupted downloads are included if their partial file is gone, though the next retrieval just starts them over.
+    /// See [`Cache::repair`] to fix them.
+    ///
+    /// # Errors
+    /// Returns an error if the cache metadata is corrupt or can't be read.
+    #[throws] pub fn find_missing_files(&self) -> Vec<(reqwest::Url, CacheRecord)> {
+        self.db.records()?.into_iter().filter(|(_, record)| self.storage.len(&self.root.join(&record.path)).is_err()).collect()
+    }
+
+    /// Fixes the entries [`Cache::find_missing_files`] returns as `policy` says, and returns how many were fixed.
+    ///
+    /// With [`RepairPolicy::Download`], an entry whose content can't be downloaded again is left as it is,
+    /// and the error is logged; it's found again by the next repair.
+    /// Removed entries are reported to the [eviction handler](Cache::set_eviction_handler).
+    ///
+    /// # Errors
+    ///   - the cache is read-only
+    ///   - the cache metadata is corrupt, or we can't update it
+    #[throws] pub fn repair(&mut self, policy: RepairPolicy) -> usize {
+        self.check_writable()?;
+        let mut repaired = 0;
+        for (key, record) in self.find_missing_files()? {
+            match policy {
+                RepairPolicy::Download => {
+                    // Without validators, the request isn't conditional, so the server sends the content again.
+                    let record = db::CacheRecord{etags: vec![], last_modified: None, ..record};
+                    match self.db.source(key.clone()).map_err(Error::from).and_then(|source| self.refresh(source, key.clone(), &record)) {
+                        Ok(_) => repaired += 1,
+                        Err(err) => warn!("Could not download {} again: {}", key, err),
+                    }
+                },
+                RepairPolicy::Remove => {
+                    info!("Removing {}, whose content is missing", key);
+                    if let Some(handler) = &mut self.eviction_handler { handler(&key, &record) }
+                    self.db.remove(key)?.commit()?;
+                    repaired += 1;
+                },
+            }
+        }
+        repaired
+    }
+
+    /// Returns whether retrieving `url` now would need the network.
+    ///
+    /// That's the case when it isn't cached, its download was interrupted, or it must be revalidated,
+    /// following the revalidation policy, freshness information, and [`Cache::set_max_stale`] just as [`Cache::get`] does.
+    /// This only looks at what's stored and the cache's clock, and never contacts the server.
+    ///
+    /// # Errors
+    /// Returns an error if the cache metadata is corrupt or can't be read, or the local copy is missing.
+    #[throws] pub fn is_stale(&self, url: impl IntoUrl) -> bool {
+        let mut url = url.into_url()?;
+        self.strip_fragment(&mut url);
+        match self.db.get(self.key(&url)) {
+            Ok(record) if record.complete => self.must_revalidate(&record, &self.root.join(&record.path), self.clock().now())?,
+            Ok(_) | Err(db::DbError::NotFound(_)) => true,
+            Err(err) => Err(err)?,
+        }
+    }
+
+    /// Returns how many URLs are cached, without reading their entries.
+    ///
+    /// This includes interrupted downloads, which [`Cache::metadata`] also describes.
+    ///
+    /// # Errors
+    /// Returns an error if the cache metadata is corrupt or can't be read.
+    #[throws] pub fn len(&self) -> usize { self.db.len()? }
+
+    /// Returns whether no URLs are cached.
+    ///
+    /// # Errors
+    /// Returns an error if the cache metadata is corrupt or can't be read.
+    #[throws] pub fn is_empty(&self) -> bool { self.len()? == 0 }
+
+    /// Returns the `n` most frequently retrieved URLs, most popular first, with their access counts.
+    ///
+    /// Every successful [`Cache::get`] counts as an access, whether or not it needed the network.
+    /// Counts are stored in the metadata database, so they persist across restarts.
+    #[throws] pub fn most_accessed(&self, n: usize) -> Vec<(reqwest::Url, u64)> { self.db.most_accessed(n)? }
+
+    /// Records that `url`'s cached entry was just used, without contacting the server or opening its content.
+    ///
+    /// Use this when reading a local copy through a path obtained earlier, say from [`Cache::get_path`],
+    /// so that [`Cache::trim`] and [`Cache::prune_expired`] still see the entry as recently used.
+    /// Unlike a retrieval, this doesn't count towards [`Cache::most_accessed`].
+    ///
+    /// # Errors
+    ///   - the cache is read-only
+    ///   - `url` isn't cached
+    ///   - we can't update the cache metadata
+    #[throws] pub fn touch(&mut self, url: impl IntoUrl) {
+        self.check_writable()?;
+        let mut url = url.into_url()?;
+        self.strip_fragment(&mut url);
+        let key = self.key(&url);
+        self.db.get(key.clone())?;
+        self.db.touch(key, self.clock().now())?;
+    }
+
+    /// Pins `url`'s cached entry, so that [`Cache::trim`] and [`Cache::prune_expired`] never remove it.
+    ///
+    /// The entry stays pinned when its content is updated, until [`Cache::unpin`] is called.
+    /// It can still be removed explicitly, for instance with [`Cache::invalidate_prefix`],
+    /// or because the server says it's gone and [`CacheBuilder::evict_when_gone`] is set.
+    ///
+    /// Returns whether `url` is cached; if it isn't, there's nothing to pin.
+    ///
+    /// # Errors
+    /// Returns an error if the cache is read-only, or we can't update the cache metadata.
+    #[throws] pub fn pin(&mut self, url: impl IntoUrl) -> bool { self.set_pinned(url.into_url()?, true)? }
+
+    /// Unpins `url`'s cached entry, so that it may be removed to make room again.
+    ///
+    /// Returns whether `url` is cached.
+    ///
+    /// # Errors
+    /// Returns an error if the cache is read-only, or we can't update the cache metadata.
+    #[throws] pub fn unpin(&mut self, url: impl IntoUrl) -> bool { self.set_pinned(url.into_url()?, false)? }
+
+    #[throws] fn set_pinned(&mut self, mut url: reqwest::Url, pinned: bool) -> bool {
+        self.check_writable()?;
+        self.strip_fragment(&mut url);
+        if self.metadata(url.clone())?.is_none() { return false }
+        self.db.set_pinned(self.key(&url), pinned)?;
+        true
+    }
+
+    /// Removes every cached entry whose URL starts with `prefix`, returning how many were removed.
+    ///
+    /// Both the metadata and the content file of each entry are removed, and the metadata in a single transaction.
+    /// `prefix` is compared with the URLs as the cache stores them: without fragments, and normalized if so configured.
+    ///
+    /// # Errors
+    /// Returns an error if the cache is read-only, or we can't update the cache metadata.
+    #[throws] pub fn invalidate_prefix(&mut self, prefix: &str) -> usize {
+        self.check_writable()?;
+        let (removed, transaction) = self.db.remove_with_prefix(prefix)?;
+        if let Some(handler) = &mut self.eviction_handler { for (url, record) in &removed { handler(url, record) } }
+        transaction.commit()?;
+        for (url, record) in &removed {
+            info!("Invalidating {}", url);
+            self.storage.remove(&self.root.join(&record.path)).unwrap_or_else(|err| warn!("Failed to remove {:?}: {}", record.path, err));
+        }
+        removed.len()
+    }
+
+    /// Compacts the metadata database, and updates the statistics it uses to plan queries.
+    ///
+    /// After many entries have been replaced or removed, this shrinks the database file.
+    /// It rewrites the whole database, so other instances sharing it wait until it's done.
+    ///
+    /// # Errors
+    /// Returns an error if the cache is read-only, or the database can't be rewritten.
+    #[throws] pub fn optimize(&mut self) {
+        self.check_writable()?;
+        self.db.optimize()?;
+    }
+
+    /// Returns whether the metadata database is free of corruption, logging any problems found.
+    ///
+    /// # Errors
+    /// Returns an error if the check itself can't be run.
+    #[throws] pub fn check_integrity(&self) -> bool { self.db.check_integrity()? }
+
+    /// Removes cached entries that haven't been retrieved for at least `older_than`, and are no longer fresh.
+    ///
+    /// An entry is still fresh until the freshness lifetime the server gave it, with `max-age` or `Expires`, is over.
+    /// Both the metadata and the content file of each entry are removed.
+    /// Entries pinned with [`Cache::pin`] are kept.
+    /// Returns the number of entries pruned.
+    #[throws] pub fn prune_expired(&mut self, older_than: std::time::Duration) -> usize {
+        self.check_writable()?;
+        let now = self.clock().now();
+        // Nothing was retrieved that long ago.
+        let since = match now.checked_sub(older_than) { Some(since) => since, None => return 0 };
+        let (removed, transaction) = self.db.remove_accessed_before(since, now)?;
+        if let Some(handler) = &mut self.eviction_handler { for (url, record) in &removed { handler(url, record) } }
+        transaction.commit()?;
+        for (url, record) in &removed {
+            info!("Pruning {} from the cache", url);
+            self.storage.remove(&self.root.join(&record.path)).unwrap_or_else(|err| warn!("Failed to remove {:?}: {}", record.path, err));
+        }
+        removed.len()
+    }
+
+    /// Removes cached entries, least recently retrieved first, as `policy` says, and returns what was removed.
+    ///
+    /// Both the metadata and the content file of each entry are removed, and the metadata in a single transaction.
+    /// [`TrimPolicy::OlderThan`] removes the same entries as [`Cache::prune_expired`].
+    /// Entries pinned with [`Cache::pin`] are never removed; if they alone exceed the limit,
+    /// every other entry is removed, and a warning is logged.
+    ///
+    /// # Errors
+    /// Returns an error if the cache is read-only, or we can't update the cache metadata.
+    #[throws] pub fn trim(&mut self, policy: TrimPolicy) -> TrimSummary {
+        self.check_writable()?;
+        let (root, storage) = (&self.root, &self.storage);
+        let size = |record: &db::CacheRecord| storage.len(&root.join(&record.path)).unwrap_or(0);
+        let pinned = self.db.pinned()?;
+        let pinned_bytes: u64 = pinned.iter().map(|(_, record)| size(record)).sum();
+        let (removed, transaction) = match policy {
+            TrimPolicy::OlderThan(age) => match self.clock().now().checked_sub(age) {
+                Some(since) => self.db.remove_accessed_before(since, self.clock().now())?,
+                // Nothing was retrieved that long ago.
+                None => return TrimSummary::default(),
+            },
+            TrimPolicy::MaxEntries(max) => self.db.remove_least_recently_accessed(|entries| std::cmp::min((entries.len() + pinned.len()).saturating_sub(max), entries.len()))?,
+            TrimPolicy::MaxBytes(max) => self.db.remove_least_recently_accessed(|entries| {
+                let mut total: u64 = pinned_bytes + entries.iter().map(|(_, record)| size(record)).sum::<u64>();
+                entries.iter().take_while(|(_, record)| { let over = total > max; total = total.saturating_sub(size(record)); over }).count()
+            })?,
+        };
+        if let Some(handler) = &mut self.eviction_handler { for (url, record) in &removed { handler(url, record) } }
+        transaction.commit()?;
+        let over = match policy { TrimPolicy::MaxEntries(max) => pinned.len() > max, TrimPolicy::MaxBytes(max) => pinned_bytes > max, TrimPolicy::OlderThan(_) => false };
+        if over { warn!("Pinned entries alone exceed {:?}, so the cache can't be trimmed to fit", policy) }
+        let mut summary = TrimSummary::default();
+        for (url, record) in &removed {
+            info!("Trimming {} from the cache", url);
+            summary.entries += 1;
+            summary.bytes += size(record);
+            self.storage.remove(&self.root.join(&record.path)).unwrap_or_else(|err| warn!("Failed to remove {:?}: {}", record.path, err));
+        }
+        summary
+    }
+
+    /// Asks the server, with a `HEAD` request, whether `record` still describes the resource at `url`.
+    ///
+    /// Returns `false` if the answer is "no" or inconclusive, in which case the caller should fall back to a conditional `GET`.
+    fn head_confirms(&self, url: reqwest::Url, record: &db::CacheRecord, path: &path::Path) -> bool {
+        use reqwest_mock::HttpResponse;
+        let mut request = reqwest::blocking::Request::new(reqwest::Method::HEAD, url);
+        *request.headers_mut() = self.request_headers();
+        let response = match execute(&self.client, self.intercept(request), self.clock(), self.options.max_redirects, &[]) {
+            Ok(response) => response,
+            Err(err) => { info!("HEAD request failed, falling back to GET: {}", err); return false }
+        };
+        let headers = response.headers();
+        let etags: Vec<_> = headers.get_all(ETAG).iter().filter_map(|etag| etag.to_str().ok()).map(db::ETag::parse).collect();
+        let last_modified = headers.get(LAST_MODIFIED).and_then(|date| date.to_str().ok());
+        let length = headers.get(CONTENT_LENGTH).and_then(|length| length.to_str().ok()?.parse::<u64>().ok());
+        let validators = [
+            if etags.is_empty() || record.etags.is_empty() { None } else { Some(etags == record.etags) },
+            match (last_modified, &record.last_modified) {
+                (Some(new), Some(old)) => Some(match (freshness::parse_http_date(new), freshness::parse_http_date(old)) { (Some(new), Some(old)) => new == old, _ => new == old }),
+                _ => None,
+            },
+        ];
+        // Compressed content is smaller than the body it holds.
+        let stored = if record.compressed { record.declared_length } else { self.storage.len(path).ok() };
+        let length = match (length, stored) { (Some(new), Some(old)) => Some(new == old), _ => None };
+        // Content-Length on its own can't tell us the content is unchanged.
+        validators.iter().any(Option::is_some) && validators.iter().chain(Some(&length)).all(|check| check != &Some(false))
+    }
+
+    #[throws] fn check_writable(&self) { if self.options.read_only { Err(CacheError::ReadOnly)? } }
+
+    /// Removes `url`'s fragment, unless [`CacheBuilder::keep_fragment`] says otherwise.
+    fn strip_fragment(&self, url: &mut reqwest::Url) { if !self.options.db.keep_fragment { url.set_fragment(None) } }
+
+    /// Returns the URL that `url`'s content is stored under.
+    ///
+    /// `url` should already have had its fragment removed.
+    fn key(&self, url: &reqwest::Url) -> reqwest::Url {
+        match &self.key_fn { Some(key_fn) => key_fn(url), None => default_key(&self.options, url) }
+    }
+
+    /// Sends `request` with [`execute`], waiting and retrying once if the server is unavailable and we're allowed to.
+    ///
+    /// See [`CacheBuilder::retry_unavailable`].
+    #[throws] fn execute(&self, request: reqwest::blocking::Request) -> C::Response { self.execute_accepting(request, &[])? }
+
+    /// Like [`Cache::execute`], but returns a response with any of the error statuses in `accept` rather than failing.
+    #[throws] fn execute_accepting(&self, request: reqwest::blocking::Request, accept: &[reqwest::StatusCode]) -> C::Response {
+        let max_wait = match self.options.max_retry_wait { Some(max_wait) => max_wait, None => return execute(&self.client, self.intercept(request), self.clock(), self.options.max_redirects, accept)? };
+        // Our requests have no body, so they're easy to copy.
+        // Copy before intercepting, so that a signature can be computed afresh for the retry.
+        let mut retry = reqwest::blocking::Request::new(request.method().clone(), request.url().clone());
+        *retry.headers_mut() = request.headers().clone();
+        match execute(&self.client, self.intercept(request), self.clock(), self.options.max_redirects, accept) {
+            Ok(response) => response,
+            Err(err) => match err.downcast_ref::<CacheError>() {
+                Some(CacheError::Unavailable{url, retry_after: Some(delay)}) => {
+                    let delay = std::cmp::min(*delay, max_wait);
+                    info!("{} is unavailable, retrying in {:?}", url, delay);
+                    std::thread::sleep(delay);
+                    execute(&self.client, self.intercept(retry), self.clock(), self.options.max_redirects, accept)?
+                },
+                _ => Err(err)?,
+            },
+        }
+    }
+
+    /// Makes sure an up-to-date copy of `url` is cached under `key`, and returns its path and how we got it.
+    #[throws] fn fetch(&mut self, url: reqwest::Url, key: reqwest::Url) -> (path::PathBuf, CacheStatus) {
+        use {reqwest::StatusCode, reqwest_mock::HttpResponse};
+        if url.scheme() == "file" { return self.fetch_file(url, key)? }
+        let mut request = reqwest::blocking::Request::new(reqwest::Method::GET, url.clone());
+        *request.headers_mut() = self.request_headers();
+        let mut download_lock = None;
+        let response = match self.db.get(key.clone()) {
+            Ok(record) if !record.complete && self.options.read_only => {
+                // We can't resume an interrupted download without writing to the cache.
+                self.stats.miss();
+                self.emit(CacheEvent::Miss{url: url.clone()});
+                self.execute(request)?
+            },
+            Ok(record) if !record.complete => {
+                // Someone else may be resuming this download right now.
+                download_lock = Some(lock_url(&self.root, &key)?);
+                let record = self.db.get(key.clone())?;
+                let path = self.root.join(&record.path);
+                if record.complete { self.hit(url); return (path, CacheStatus::Fresh) }
+                let offset = self.storage.len(&path).unwrap_or(0);
+                // Weak entity tags can't be used with If-Range.
+                let validator = record.etags.iter().find(|etag| !etag.weak).map(ToString::to_string).or_else(|| record.last_modified.clone());
+                // The length of compressed content isn't how much of the body we received, so it can't be resumed.
+                if let (Some(validator), true) = (validator, offset > 0 && !record.compressed) {
+                    request.headers_mut().insert(RANGE, HeaderValue::from_str(&format!("bytes={}-", offset))?);
+                    request.headers_mut().insert(IF_RANGE, HeaderValue::from_str(&validator)?);
+                }
+                let mut response = self.execute_accepting(request, &[StatusCode::RANGE_NOT_SATISFIABLE])?;
+                if response.status() == StatusCode::RANGE_NOT_SATISFIABLE && record.declared_length == Some(offset) {
+                    // We received the whole body, but stopped before moving it into place, and the server confirms it hasn't changed.
+                    info!("Already downloaded all of {}", url);
+                    let path = self.finish_download(key, &path)?;
+                    drop(download_lock);
+                    self.stats.revalidation();
+                    self.emit(CacheEvent::Revalidated{url: url.clone(), changed: false});
+                    self.hit(url);
+                    return (path, CacheStatus::Revalidated)
+                }
+                let range = response.headers().get(CONTENT_RANGE).and_then(|range| range.to_str().ok()).unwrap_or_default().to_owned();
+                if response.status() == StatusCode::RANGE_NOT_SATISFIABLE || (response.status() == StatusCode::PARTIAL_CONTENT && !range.starts_with(&format!("bytes {}-", offset))) {
+                    warn!("Asked for {} from byte {}, but got {} with range {:?}, starting over", url, offset, response.status(), range);
+                    let mut request = reqwest::blocking::Request::new(reqwest::Method::GET, url.clone());
+                    *request.headers_mut() = self.request_headers();
+                    response = self.execute(request)?;
+                } else if response.status() == StatusCode::PARTIAL_CONTENT {
+                    self.stats.download();
+                    let count = match self.check_size(&url, response.headers(), offset).and_then(|()| {
+                        self.copy_body(&url, &mut response, &mut self.storage.append(&path)?, offset)
+                    }) {
+                        Ok(count) => count,
+                        Err(err) => { if err.is::<CacheError>() { self.evict(&key, &path)?; } Err(err)? },
+                    };
+                    info!("Resumed download at byte {}, and downloaded {} more bytes", offset, count);
+                    if let Err(err) = self.check_length(&url, record.declared_length, offset.saturating_add(count)) { self.evict(&key, &path)?; throw!(err); }
+                    self.db.set_status(key.clone(), response.status().as_u16())?;
+                    let path = self.finish_download(key, &path)?;
+                    drop(download_lock);
+                    self.emit(CacheEvent::Download{url, bytes: count});
+                    return (path, CacheStatus::Downloaded)
+                }
+                // The resource changed since we started downloading it, or the server couldn't send the rest of it.
+                info!("Could not resume download of {}, starting over", url);
+                self.storage.remove(&path).unwrap_or_else(|err| warn!("Failed to remove {:?}: {}", path, err));
+                response
+            },
+            Ok(record) => {
+                let path = self.root.join(&record.path);
+                let now = self.clock().now();
+                if !self.must_revalidate(&record, &path, now)? { self.hit(url); return (path, CacheStatus::Fresh) }
+                self.stats.revalidation();
+                if self.options.validate_with_head && self.head_confirms(url.clone(), &record, &path) {
+                    if !self.options.read_only { self.db.set(key, db::CacheRecord{validated_at: Some(now), ..record})?.commit()?; }
+                    self.emit(CacheEvent::Revalidated{url: url.clone(), changed: false});
+                    self.hit(url);
+                    return (path, CacheStatus::Revalidated)
+                }
+                make_conditional(&mut request, &record, self.options.validator)?;
+                let response = match send(&self.client, self.intercept(request), self.options.max_redirects) {
+                    Ok(response) => response,
+                    Err(err) => {
+                        warn!("Could not revalidate {}, using cached data: {}", url, err);
+                        self.emit(CacheEvent::FallbackToCache{url: url.clone(), error: err.to_string()});
+                        self.hit(url);
+                        return (path, CacheStatus::Fresh)
+                    }
+                };
+                let status = response.status();
+                if status == StatusCode::NOT_MODIFIED {
+                    self.emit(CacheEvent::Revalidated{url: url.clone(), changed: false});
+                    if !self.options.read_only { self.not_modified(key, response.headers())?; }
+                    self.hit(url);
+                    return (path, CacheStatus::Revalidated)
+                }
+                if !replaces_content(status) {
+                    if (status == StatusCode::NOT_FOUND || status == StatusCode::GONE) && self.options.evict_when_gone && !self.options.read_only {
+                        warn!("{} is gone ({}), removing it from the cache", url, status);
+                        self.evict(&key, &path)?;
+                        throw!(CacheError::Gone{url, status});
+                    }
+                    // Redirects are followed by the client, if it's configured to, or by us, so we don't expect to see them here either.
+                    warn!("Unexpected status {} revalidating {}, using cached data", status, url);
+                    self.emit(CacheEvent::FallbackToCache{url: url.clone(), error: format!("Unexpected status {}", status)});
+                    self.hit(url);
+                    return (path, CacheStatus::Fresh)
+                }
+                // A full response replaces our copy even if its Last-Modified is the same as ours:
+                // that has one-second granularity, so the content may have changed within the second.
+                self.emit(CacheEvent::Revalidated{url: url.clone(), changed: true});
+                response
+            },
+            Err(db::DbError::NotFound(_)) => {
+                // Another instance may be downloading this URL right now;
+                // if so, wait for it and use its copy.
+                if !self.options.read_only { download_lock = Some(lock_url(&self.root, &key)?); }
+                if let Ok(db::CacheRecord{path, complete: true, ..}) = self.db.get(key.clone()) { self.hit(url); return (self.root.join(path), CacheStatus::Fresh) }
+                self.stats.miss();
+                self.emit(CacheEvent::Miss{url: url.clone()});
+                self.execute(request)?
+            },
+            Err(err) => Err(err)?,
+        };
+        (self.download(url, key, response, download_lock)?, CacheStatus::Downloaded)
+    }
+
+    /// Makes sure an up-to-date copy of the local file named by the `file:` URL `url` is cached under `key`, without the HTTP client.
+    ///
+    /// The file's modification time and length serve as its entity tag, so it's copied again whenever it changes.
+    #[throws] fn fetch_file(&mut self, url: reqwest::Url, key: reqwest::Url) -> (path::PathBuf, CacheStatus) {
+        let src = url.to_file_path().map_err(|()| anyhow::anyhow!("{} does not name a local file", url))?;
+        let cached = match self.db.get(key.clone()) {
+            Ok(record) if record.complete => Some(record),
+            Ok(_) | Err(db::DbError::NotFound(_)) => None,
+            Err(err) => Err(err)?,
+        };
+        let metadata = match (fs::metadata(&src), &cached) {
+            (Ok(metadata), _) => metadata,
+            (Err(err), Some(record)) => {
+                warn!("Could not read {:?}, using the cached copy: {}", src, err);
+                self.emit(CacheEvent::FallbackToCache{url: url.clone(), error: err.to_string()});
+                self.hit(url);
+                return (self.root.join(&record.path), CacheStatus::Fresh)
+            },
+            (Err(err), None) => throw!(err),
+        };
+        let modified = metadata.modified()?;
+        let since_epoch = modified.duration_since(std::time::UNIX_EPOCH).unwrap_or_default();
+        let etag = db::ETag{tag: format!("\"{}.{:09}-{}\"", since_epoch.as_secs(), since_epoch.subsec_nanos(), metadata.len()), weak: false};
+        let now = self.clock().now();
+        match &cached {
+            Some(record) => {
+                self.stats.revalidation();
+                let path = self.root.join(&record.path);
+                let changed = record.etags != [etag.clone()];
+                self.emit(CacheEvent::Revalidated{url: url.clone(), changed});
+                if !changed {
+                    if !self.options.read_only { self.db.set(key, db::CacheRecord{validated_at: Some(now), ..record.clone()})?.commit()?; }
+                    self.hit(url);
+                    return (path, CacheStatus::Revalidated)
+                }
+            },
+            None => {
+                self.stats.miss();
+                self.emit(CacheEvent::Miss{url: url.clone()});
+            },
+        }
+        self.stats.download();
+        // There's nowhere to record a copy, so make one in the scratch directory, where the storage can find it.
+        if self.options.read_only {
+            if self.scratch.is_none() { self.scratch = Some(tempfile::tempdir()?); }
+            let (mut handle, path) = make_random_file(&self.storage, self.scratch.as_ref().unwrap().path(), false)?;
+            io::copy(&mut fs::File::open(&src)?, &mut handle)?;
+            return (path, CacheStatus::Downloaded)
+        }
+        let _lock = lock_url(&self.root, &key)?;
+        let (mut handle, path) = self.create_file("content")?;
+        let count = io::copy(&mut fs::File::open(&src)?, &mut handle)?;
+        info!("Copied {} bytes from {:?}", count, src);
+        self.db.set(key, db::CacheRecord {
+            path: path.strip_prefix(&self.root)?.to_str().unwrap().into(),
+            last_modified: Some(httpdate::fmt_http_date(modified)),
+            etags: vec![etag],
+            complete: true,
+            fresh_until: None,
+            no_cache: false,
+            status: None,
+            fetched_at: Some(now),
+            validated_at: Some(now),
+            declared_length: Some(count),
+            response_date: None,
+            compressed: false,
+        })?.commit()?;
+        if let Some(record) = cached {
+            let old = self.root.join(&record.path);
+            self.storage.remove(&old).unwrap_or_else(|err| warn!("Failed to remove {:?}: {}", old, err));
+        }
+        self.emit(CacheEvent::Download{url, bytes: count});
+        (path, CacheStatus::Downloaded)
+    }
+
+    /// Whether `record` was last validated longer ago than [`Cache::set_max_stale`] allows.
+    fn too_old(&self, record: &db::CacheRecord, now: std::time::SystemTime) -> bool {
+        match (self.options.max_stale, record.validated_at) {
+            (Some(max_stale), Some(validated_at)) => now.duration_since(validated_at).unwrap_or_default() > max_stale,
+            _ => false,
+        }
+    }
+
+    /// Whether the complete entry `record`, whose content is at `path`, must be revalidated before it's used at `now`.
+    #[throws] fn must_revalidate(&self, record: &db::CacheRecord, path: &path::Path, now: std::time::SystemTime) -> bool {
+        let too_old = self.too_old(record, now);
+        // A response marked no-cache must be revalidated before every use, whatever our policy, and so must one validated too long ago.
+        let fresh = !(record.no_cache || too_old) && match (&self.options.freshness, self.options.revalidation) {
+            (Some(policy), _) => policy.is_fresh(record, now),
+            (None, RevalidationPolicy::Always) => false,
+            (None, RevalidationPolicy::WhenStale) => DefaultPolicy.is_fresh(record, now),
+            (None, RevalidationPolicy::Never) => true,
+        };
+        if fresh { return false }
+        let day = std::time::Duration::new(24*60*60, 0);
+        record.no_cache || too_old || now.duration_since(self.storage.modified(path)?).unwrap_or_default() <= day
+    }
+
+    /// Saves the body of `response` to `url` as the new content for `key`, and returns its path.
+    ///
+    /// `download_lock` is the lock on downloading `key`, if the caller already took it.
+    #[throws] fn download(&mut self, url: reqwest::Url, key: reqwest::Url, mut response: impl reqwest_mock::HttpResponse, mut download_lock: Option<fs::File>) -> path::PathBuf {
+        self.stats.download();
+        self.check_size(&url, response.headers(), 0)?;
+        let no_store = freshness::cache_control(response.headers(), "no-store").is_some();
+        // A `204 No Content` or `206 Partial Content` isn't the whole resource, so it mustn't be served as if it were.
+        let incomplete = !replaces_content(response.status());
+        if incomplete { warn!("Not caching the {} response for {}", response.status(), url) }
+        if self.options.read_only || no_store || incomplete {
+            if self.scratch.is_none() { self.scratch = Some(tempfile::tempdir()?); }
+            let (mut handle, path) = make_random_file(&self.storage, self.scratch.as_ref().unwrap().path(), false)?;
+            let count = match self.copy_body(&url, &mut response, &mut handle, 0) {
+                Ok(count) => count,
+                Err(err) => { self.storage.remove(&path).unwrap_or_else(|err| warn!("Failed to remove {:?}: {}", path, err)); Err(err)? },
+            };
+            if let Err(err) = self.check_length(&url, content_length(response.headers()), count) {
+                self.storage.remove(&path).unwrap_or_else(|err| warn!("Failed to remove {:?}: {}", path, err));
+                throw!(err);
+            }
+            info!("Downloaded {} bytes, without recording them", count);
+            self.emit(CacheEvent::Download{url, bytes: count});
+            return path
+        }
+        // Other instances that find our half-written entry should wait for us rather than try to resume it.
+        if download_lock.is_none() { download_lock = Some(lock_url(&self.root, &key)?); }
+        // Content we already have stays in place until its replacement is complete, so a failed download doesn't lose it.
+        let replacing = match self.db.get(key.clone()) {
+            Ok(record) if record.complete => Some(self.root.join(record.path)),
+            _ => None,
+        };
+        let (mut handle, partial) = self.create_file("partial")?;
+        let (record, headers) = self.response_record(&partial, &response)?;
+        // Otherwise, record the entry before downloading, so that an interrupted download can be resumed.
+        if replacing.is_none() { self.db.set_response(key.clone(), &url, record.clone(), &headers)?.commit()?; }
+        let compressed = self.options.compress_at_rest;
+        let copied = if compressed {
+            let mut encoder = flate2::write::GzEncoder::new(handle, flate2::Compression::default());
+            self.copy_body(&url, &mut response, &mut encoder, 0).and_then(|count| { encoder.finish()?; Ok(count) })
+        } else {
+            self.copy_body(&url, &mut response, &mut handle, 0)
+        };
+        let count = match copied {
+            Ok(count) => count,
+            Err(err) => {
+                if replacing.is_some() {
+                    self.storage.remove(&partial).unwrap_or_else(|err| warn!("Failed to remove {:?}: {}", partial, err));
+                } else if err.is::<CacheError>() || compressed {
+                    // Keep an interrupted download to resume later, but not one we'd refuse again or couldn't store, nor a compressed one we couldn't resume.
+                    self.evict(&key, &partial)?;
+                }
+                Err(err)?
+            },
+        };
+        info!("Downloaded {} bytes", count);
+        if let Err(err) = self.check_length(&url, content_length(response.headers()), count) {
+            if replacing.is_some() {
+                self.storage.remove(&partial).unwrap_or_else(|err| warn!("Failed to remove {:?}: {}", partial, err));
+            } else {
+                self.evict(&key, &partial)?;
+            }
+            throw!(err);
+        }
+        let path = match replacing {
+            None => self.finish_download(key, &partial)?,
+            Some(old) => {
+                let (_, path) = self.create_file("content")?;
+                self.storage.rename(&partial, &path)?;
+                let record = db::CacheRecord{path: path.strip_prefix(&self.root)?.to_str().unwrap().into(), complete: true, ..record};
+                self.db.set_response(key, &url, record, &headers)?.commit()?;
+                match self.storage.remove(&old) {
+                    // It may be missing already, if we're repairing the entry.
+                    Err(err) if err.kind() != io::ErrorKind::NotFound => warn!("Failed to remove {:?}: {}", old, err),
+                    _ => {},
+                }
+                path
+            },
+        };
+        drop(download_lock);
+        self.emit(CacheEvent::Download{url, bytes: count});
+        path
+    }
+
+    /// Fails with [`CacheError::TooLarge`] if `headers` announce a body that would make a file already holding `offset` bytes too large,
+    /// or with [`CacheError::OutOfSpace`] if there's no room for it and we were asked to check.
+    #[throws] fn check_size(&self, url: &reqwest::Url, headers: &HeaderMap, offset: u64) {
+        if let (Some(limit), Some(length)) = (self.options.max_file_size, content_length(headers)) {
+            if offset.checked_add(length).map_or(true, |total| total > limit) { throw!(CacheError::TooLarge{url: url.clone(), limit}) }
+        }
+        if let (Some(reserve), Some(needed)) = (self.options.free_space_reserve, content_length(headers)) {
+            let (room, available) = room_for(&self.root, needed, reserve)?;
+            if !room { throw!(CacheError::OutOfSpace{url: url.clone(), needed, available}) }
+        }
+    }
+
+    /// Warns if we `received` a different number of bytes than the response `declared`, which usually means the transfer was cut short.
+    ///
+    /// With [`CacheBuilder::strict_length`], this is an error instead.
+    #[throws] fn check_length(&self, url: &reqwest::Url, declared: Option<u64>, received: u64) {
+        if let Some(declared) = declared.filter(|&declared| declared != received) {
+            if self.options.strict_length { throw!(CacheError::LengthMismatch{url: url.clone(), declared, received}) }
+            warn!("{} declared a length of {} bytes, but we received {}", url, declared, received);
+        }
+    }
+
+    /// Copies the body of `response` to `file`, which already holds `offset` bytes, and returns how many bytes were copied.
+    ///
+    /// Fails with [`CacheError::TooLarge`] as soon as `file` would grow beyond the maximum size, whatever the response announced,
+    /// and with [`CacheError::StorageFailed`], in front of the underlying error, if writing to `file` fails, say because the disk is full.
+    #[throws] fn copy_body(&self, url: &reqwest::Url, response: &mut impl reqwest_mock::HttpResponse, file: &mut impl io::Write, offset: u64) -> u64 {
+        let mut storage = TrackedWriter{inner: file, failed: false};
+        let copied = match self.options.max_file_size {
+            // Read one byte more than allowed, to tell a body that exactly fits from one that doesn't.
+            Some(limit) => io::copy(&mut io::Read::take(response, limit.saturating_sub(offset).saturating_add(1)), &mut storage),
+            None => io::copy(response, &mut storage),
+        };
+        let count = match copied {
+            Ok(count) => count,
+            Err(err) if storage.failed => throw!(anyhow::Error::new(err).context(CacheError::StorageFailed{url: url.clone()})),
+            Err(err) => throw!(err),
+        };
+        if let Some(limit) = self.options.max_file_size.filter(|&limit| offset.checked_add(count).map_or(true, |total| total > limit)) { throw!(CacheError::TooLarge{url: url.clone(), limit}) }
+        count
+    }
+
+    /// Moves the completely downloaded content for `key` from `partial` into place, and returns its new path.
+    ///
+    /// An entry only ever refers to a file in `content` once that file is complete.
+    #[throws] fn finish_download(&mut self, key: reqwest::Url, partial: &path::Path) -> path::PathBuf {
+        let (_, path) = self.create_file("content")?;
+        self.storage.rename(partial, &path)?;
+        self.db.set_complete(key, path.strip_prefix(&self.root)?.to_str().unwrap())?;
+        path
+    }
+
+    /// Removes the entry for `key`, whose content is at `path`.
+    #[throws] fn evict(&mut self, key: &reqwest::Url, path: &path::Path) {
+        if let (Some(handler), Ok(record)) = (&mut self.eviction_handler, self.db.get(key.clone())) { handler(key, &record) }
+        self.db.remove(key.clone())?.commit()?;
+        self.storage.remove(path).unwrap_or_else(|err| warn!("Failed to remove {:?}: {}", path, err));
+    }
+
+    /// Updates what we know about `key` from the headers of a `304 Not Modified` response.
+    ///
+    /// Validators and freshness information the response includes replace the stored ones; anything it leaves out is kept.
+    #[throws] fn not_modified(&mut self, key: reqwest::Url, headers: &HeaderMap) {
+        let mut record = self.db.get(key.clone())?;
+        if let Some(last_modified) = headers.get(LAST_MODIFIED) { record.last_modified = Some(last_modified.to_str()?.to_owned()); }
+        if headers.contains_key(ETAG) { record.etags = headers.get_all(ETAG).iter().map(|etag| etag.to_str().map(db::ETag::parse)).collect::<Result<_, _>>()?; }
+        if let Some(fresh_until) = freshness::fresh_until(headers, self.clock().now()) { record.fresh_until = Some(fresh_until); }
+        record.status = Some(reqwest::StatusCode::NOT_MODIFIED.as_u16());
+        record.validated_at = Some(self.clock().now());
+        self.db.set(key, record)?.commit()?;
+    }
+
+    /// Returns the local copy of `url` if it must be revalidated, but went stale less than `window` ago,
+    /// so it may be served without waiting for the server.
+    ///
+    /// If `since_validation`, an entry without freshness information went stale when it was last validated;
+    /// otherwise, it's never served stale.
+    /// A `window` too long to add to any time has no end.
+    /// See [`CacheBuilder::stale_while_revalidate`] and [`Cache::get_max_stale`].
+    #[throws] pub(crate) fn stale_copy(&mut self, mut url: reqwest::Url, window: std::time::Duration, since_validation: bool) -> Option<path::PathBuf> {
+        self.strip_fragment(&mut url);
+        let key = self.key(&url);
+        let record = match self.db.get(key.clone()) {
+            Ok(record) => record,
+            Err(db::DbError::NotFound(_)) => return None,
+            Err(err) => Err(err)?,
+        };
+        let (path, now) = (self.root.join(&record.path), self.clock().now());
+        if !record.complete || record.no_cache || self.too_old(&record, now) || !self.must_revalidate(&record, &path, now)? { return None }
+        match record.fresh_until.or(record.validated_at.filter(|_| since_validation)) {
+            Some(stale_since) if stale_since.checked_add(window).map_or(true, |until| now < until) => {
+                if !self.options.read_only { self.db.record_access(key, now)?; }
+                self.hit(url);
+                Some(self.uncompressed(path, record.compressed)?)
+            },
+            _ => None,
+        }
+    }
+
+    fn hit(&mut self, url: reqwest::Url) {
+        self.stats.hit();
+        self.emit(CacheEvent::Hit{url});
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate env_logger;
+    extern crate tempdir;
+
+    use reqwest::header as rh;
+    use reqwest::header::*;
+
+    use std::io;
+
+    use std::io::Read;
+
+    use super::reqwest_mock::testing as rmt;
+    use super::Storage;
+
+    const DATE_ZERO: &str = "Thu, 01 Jan 1970 00:00:00 GMT";
+    const DATE_ONE: &str = "Thu, 01 Jan 1970 00:00:01 GMT";
+
+    fn make_test_cache<C: super::reqwest_mock::Client>(
+        client: C,
+    ) -> super::Cache<C> {
+        super::Cache::new(
+            tempdir::TempDir::new("http-cache-test")
+                .unwrap()
+                .into_path(),
+            client,
+        )
+        .unwrap()
+    }
+
+    fn make_test_cache_in<C: super::reqwest_mock::Client, S: super::Storage>(
+        client: C,
+        storage: S,
+    ) -> super::Cache<C, S> {
+        super::Cache::builder(
+            tempdir::TempDir::new("http-cache-test")
+                .unwrap()
+                .into_path(),
+            client,
+        )
+        .build_with_storage(storage)
+        .unwrap()
+    }
+
+    /// Returns what `c` stores at `path`, if anything.
+    fn stored<C: super::reqwest_mock::Client, S: super::Storage>(
+        c: &super::Cache<C, S>,
+        path: &std::path::Path,
+    ) -> Option<Vec<u8>> {
+        let mut reader = c.storage.open(path).ok()?;
+        let mut buf = vec![];
+        reader.read_to_end(&mut buf).unwrap();
+        Some(buf)
+    }
+
+    /// Runs each of the given tests, which take the storage to use,
+    /// against both a `FsStorage` and a `MemoryStorage`.
+    macro_rules! with_each_storage {
+        ($($test:ident),* $(,)?) => {
+            mod fs_storage {
+                $(#[test] fn $test() { super::$test(crate::FsStorage) })*
+            }
+            mod memory_storage {
+                $(
+                    #[test]
+                    fn $test() { super::$test(crate::MemoryStorage::new()) }
+                )*
+            }
+        };
+    }
+
+    with_each_storage!(
+        initial_request_success,
+        use_cache_data_if_not_modified_since,
+        update_cache_if_none_match,
+        get_range_reads_part_of_the_content,
+        resume_interrupted_download,
+        restart_interrupted_download_of_changed_resource,
+        interrupted_download_is_not_referenced_as_complete,
+        replacement_removes_the_old_content,
+        missing_files_are_found_and_removed,
+        trim_to_max_bytes,
+    );
+
+    #[test]
+    fn root_and_db_path_are_exposed() {
+        let root = tempdir::TempDir::new("http-cache-test")
+            .unwrap()
+            .into_path();
+
+        let c = super::Cache::new(
+            root.clone(),
+            rmt::FakeClient::new(
+                "http://example.com/".parse().unwrap(),
+                HeaderMap::new(),
+                rmt::FakeResponse {
+                    status: reqwest::StatusCode::OK,
+                    headers: HeaderMap::new(),
+                    body: io::Cursor::new(vec![]),
+                },
+            ),
+        )
+        .unwrap();
+
+        assert_eq!(c.root(), root.canonicalize().unwrap());
+        assert_eq!(c.db_path(), c.root().join("cache.db"));
+    }
+
+    fn initial_request_success<S: super::Storage>(storage: S) {
+        let _ = env_logger::try_init();
+
+        let url_text = "http://example.com/";
+        let url: reqwest::Url = url_text.parse().unwrap();
+
+        let body = b"hello world";
+
+        let mut c = make_test_cache_in(
+            rmt::FakeClient::new(
+                url.clone(),
+                HeaderMap::new(),
+                rmt::FakeResponse {
+                    status: reqwest::StatusCode::OK,
+                    headers: HeaderMap::new(),
+                    body: io::Cursor::new(body.as_ref().into()),
+                },
+            ),
+            storage,
+        );
+
+        // We should get the body bytes.
+        assert_eq!(c.get_bytes(url).unwrap(), body);
+        c.client.assert_called();
+    }
+
+    #[test]
+    fn initial_request_failure() {
+        let _ = env_logger::try_init();
+
+        let url: reqwest::Url = "http://example.com/".parse().unwrap();
+        let mut c = make_test_cache(rmt::FakeClient::new(
+            url.clone(),
+            HeaderMap::new(),
+            rmt::FakeResponse {
+                status: reqwest::StatusCode::INTERNAL_SERVER_ERROR,
+                headers: HeaderMap::new(),
+                body: io::Cursor::new(vec![]),
+            },
+        ));
+
+        let err = c.get(url).expect_err("Got a response??");
+        assert_eq!(format!("{}", err), "FakeError");
+        c.client.assert_called();
+    }
+
+    #[test]
+    fn ignore_fragment_in_url() {
+        let _ = env_logger::try_init();
+
+        let url_fragment: reqwest::Url =
+            "http://example.com/#frag".parse().unwrap();
+
+        let mut network_url = url_fragment.clone();
+        network_url.set_fragment(None);
+
+        let mut c = make_test_cache(rmt::FakeClient::new(
+            // We expect the cache to request the URL without the fragment.
+            network_url,
+            HeaderMap::new(),
+            rmt::FakeResponse {
+                status: reqwest::StatusCode::OK,
+                headers: HeaderMap::new(),
+                body: io::Cursor::new(b"hello world"[..].into()),
+            },
+        ));
+
+        // Ask for the URL with the fragment.
+        c.get(url_fragment).unwrap();
+    }
+
+    fn use_cache_data_if_not_modified_since<S: super::Storage>(storage: S) {
+        let _ = env_logger::try_init();
+
+        let url: reqwest::Url = "http://example.com/".parse().unwrap();
+        let body = b"hello world";
+
+        // We send a request, and the server responds with the data,
+        // and a "Last-Modified" header.
+        let mut response_headers = HeaderMap::new();
+        response_headers
+            .append(LAST_MODIFIED, HeaderValue::from_static(DATE_ZERO));
+
+        let mut c = make_test_cache_in(
+            rmt::FakeClient::new(
+                url.clone(),
+                HeaderMap::new(),
+                rmt::FakeResponse {
+                    status: reqwest::StatusCode::OK,
+                    headers: response_headers.clone(),
+                    body: io::Cursor::new(body.as_ref().into()),
+                },
+            ),
+            storage,
+        );
+
+        // The response and its last-modified date should now be recorded
+        // in the cache.
+        c.get_bytes(url.clone()).unwrap();
+        c.client.assert_called();
+
+        // For the next request, we expect the request to include the
+        // modified date in the "if modified since" header, and we'll give
+        // the "no, it hasn't been modified" response.
+        let mut second_request = HeaderMap::new();
+        second_request.append(
+            IF_MODIFIED_SINCE,
+            HeaderValue::from_static(DATE_ZERO),
+        );
+
+        c.client = rmt::FakeClient::new(
+            url.clone(),
+            second_request,
+            rmt::FakeResponse {
+                status: reqwest::StatusCode::NOT_MODIFIED,
+                headers: response_headers,
+                body: io::Cursor::new(b""[..].into()),
+            },
+        );
+
+        // Now when we make the request, even though the actual response
+        // did not include a body, we should get the complete body from
+        // the local cache.
+        assert_eq!(c.get_bytes(url).unwrap(), body);
+        c.client.assert_called();
+    }
+
+    #[test]
+    fn update_cache_if_modified_since() {
+        let _ = env_logger::try_init();
+
+        let url: reqwest::Url = "http://example.com/".parse().unwrap();
+
+        // We send a request, and the server responds with the data,
+        // and a "Last-Modified" header.
+        let request_1_headers = HeaderMap::new();
+        let mut response_1_headers = HeaderMap::new();
+        response_1_headers
+            .append(LAST_MODIFIED, HeaderValue::from_static(DATE_ZERO));
+
+        let mut c = make_test_cache(rmt::FakeClient::new(
+            url.clone(),
+            request_1_headers,
+            rmt::FakeResponse {
+                status: reqwest::StatusCode::OK,
+                headers: response_1_headers,
+                body: io::Cursor::new(b"hello".as_ref().into()),
+            },
+        ));
+
+        // The response and its last-modified date should now be recorded
+        // in the cache.
+        c.get(url.clone()).unwrap();
+        c.client.assert_called();
+
+        // For the next request, we expect the request to include the
+        // modified date in the "if modified since" header, and we'll give
+        // the "yes, it has been modified" response with a new Last-Modified.
+        let mut request_2_headers = HeaderMap::new();
+        request_2_headers.append(
+            IF_MODIFIED_SINCE,
+            HeaderValue::from_static(DATE_ZERO),
+        );
+        let mut response_2_headers = HeaderMap::new();
+        response_2_headers
+            .append(LAST_MODIFIED, HeaderValue::from_static(DATE_ONE));
+
+        c.client = rmt::FakeClient::new(
+            url.clone(),
+            request_2_headers,
+            rmt::FakeResponse {
+                status: reqwest::StatusCode::OK,
+                headers: response_2_headers,
+                body: io::Cursor::new(b"world".as_ref().into()),
+            },
+        );
+
+        // Now when we make the request, we should get the new body and
+        // ignore what's in the cache.
+        let mut res = c.get(url.clone()).unwrap();
+        let mut buf = vec![];
+        res.read_to_end(&mut buf).unwrap();
+        assert_eq!(&buf, b"world");
+        c.client.assert_called();
+
+        // If we make another request, we should set If-Modified-Since
+        // to match the second response, and be able to return the data from
+        // the second response.
+        let mut request_3_headers = HeaderMap::new();
+        request_3_headers.append(
+            IF_MODIFIED_SINCE,
+            HeaderValue::from_static(DATE_ONE),
+        );
+        let response_3_headers = HeaderMap::new();
+
+        c.client = rmt::FakeClient::new(
+            url.clone(),
+            request_3_headers,
+            rmt::FakeResponse {
+                status: reqwest::StatusCode::NOT_MODIFIED,
+                headers: response_3_headers,
+                body: io::Cursor::new(b"".as_ref().into()),
+            },
+        );
+
+        // Now when we make the request, we should get updated info from the
+        // cache.
+        let mut res = c.get(url).unwrap();
+        let mut buf = vec![];
+        res.read_to_end(&mut buf).unwrap();
+        assert_eq!(&buf, b"world");
+        c.client.assert_called();
+    }
+
+    #[test]
+    fn update_cache_within_the_same_second() {
+        let _ = env_logger::try_init();
+
+        let url: reqwest::Url = "http://example.com/".parse().unwrap();
+
+        let mut response_headers = HeaderMap::new();
+        response_headers
+            .append(LAST_MODIFIED, HeaderValue::from_static(DATE_ZERO));
+
+        let mut c = make_test_cache(rmt::FakeClient::new(
+            url.clone(),
+            HeaderMap::new(),
+            rmt::FakeResponse {
+                status: reqwest::StatusCode::OK,
+                headers: response_headers.clone(),
+                body: io::Cursor::new(b"hello".as_ref().into()),
+            },
+        ));
+        c.get(url.clone()).unwrap();
+        c.client.assert_called();
+
+        // The resource changed again within the same second, so the server
+        // sends new content with the same Last-Modified date: Last-Modified
+        // only has second granularity, so that's all it can tell us.
+        let mut request_headers = HeaderMap::new();
+        request_headers.append(
+            IF_MODIFIED_SINCE,
+            HeaderValue::from_static(DATE_ZERO),
+        );
+        c.client = rmt::FakeClient::new(
+            url.clone(),
+            request_headers,
+            rmt::FakeResponse {
+                status: reqwest::StatusCode::OK,
+                headers: response_headers,
+                body: io::Cursor::new(b"world".as_ref().into()),
+            },
+        );
+
+        // The body is what counts, so we should get the new one.
+        let mut res = c.get(url.clone()).unwrap();
+        let mut buf = vec![];
+        res.read_to_end(&mut buf).unwrap();
+        assert_eq!(&buf, b"world");
+        c.client.assert_called();
+
+        let record = c.metadata(url).unwrap().unwrap();
+        assert_eq!(record.last_modified.as_deref(), Some(DATE_ZERO));
+    }
+
+    #[test]
+    fn return_existing_data_on_connection_refused() {
+        let _ = env_logger::try_init();
+
+        let temp_path = tempdir::TempDir::new("http-cache-test")
+            .unwrap()
+            .into_path();
+
+        let url: reqwest::Url = "http://example.com/".parse().unwrap();
+
+        // We send a request, and the server responds with the data,
+        // and a "Last-Modified" header.
+        let request_1_headers = HeaderMap::new();
+        let mut response_1_headers = HeaderMap::new();
+        response_1_headers
+            .append(LAST_MODIFIED, HeaderValue::from_static(DATE_ZERO));
+
+        let mut c = super::Cache::new(
+            temp_path.clone(),
+            rmt::FakeClient::new(
+                url.clone(),
+                request_1_headers,
+                rmt::FakeResponse {
+                    status: reqwest::StatusCode::OK,
+                    headers: response_1_headers,
+                    body: io::Cursor::new(b"hello".as_ref().into()),
+                },
+            ),
+        )
+        .unwrap();
+
+        // The response and its last-modified date should now be recorded
+        // in the cache.
+        c.get(url.clone()).unwrap();
+        c.client.assert_called();
+
+        // If we make second request, we should set If-Modified-Since
+        // to match the first response's Last-Modified.
+        let mut request_2_headers = HeaderMap::new();
+        request_2_headers.append(
+            IF_MODIFIED_SINCE,
+            HeaderValue::from_static(DATE_ZERO),
+        );
+
+        // This time, however, the request will return an error.
+        let mut c = super::Cache::new(
+            temp_path.clone(),
+            rmt::BrokenClient::new(url.clone(), request_2_headers, || {
+                rmt::FakeError
+            }),
+        )
+        .unwrap();
+
+        // Now when we request a URL, we should get the cached result.
+        let mut res = c.get(url.clone()).unwrap();
+        let mut buf = vec![];
+        res.read_to_end(&mut buf).unwrap();
+        assert_eq!(&buf, b"hello");
+        c.client.assert_called();
+    }
+
+    #[test]
+    fn use_cache_data_if_some_match() {
+        let _ = env_logger::try_init();
+
+        let url: reqwest::Url = "http://example.com/".parse().unwrap();
+        let body = b"hello world";
+
+        // We send a request, and the server responds with the data,
+        // and an "Etag" header.
+        let mut response_headers = HeaderMap::new();
+        response_headers.append(ETAG, HeaderValue::from_static("abcd"));
+
+        let mut c = make_test_cache(rmt::FakeClient::new(
+            url.clone(),
+            HeaderMap::new(),
+            rmt::FakeResponse {
+                status: reqwest::StatusCode::OK,
+                headers: response_headers.clone(),
+                body: io::Cursor::new(body.as_ref().into()),
+            },
+        ));
+
+        // The response and its etag should now be recorded
+        // in the cache.
+        c.get(url.clone()).unwrap();
+        c.client.assert_called();
+
+        // For the next request, we expect the request to include the
+        // etag in the "if none match" header, and we'll give
+        // the "no, it hasn't been modified" response.
+        let mut second_request = HeaderMap::new();
+        second_request
+            .append(IF_NONE_MATCH, HeaderValue::from_static("abcd"));
+
+        c.client = rmt::FakeClient::new(
+            url.clone(),
+            second_request,
+            rmt::FakeResponse {
+                status: reqwest::StatusCode::NOT_MODIFIED,
+                headers: response_headers,
+                body: io::Cursor::new(b""[..].into()),
+            },
+        );
+
+        // Now when we make the request, even though the actual response
+        // did not include a body, we should get the complete body from
+        // the local cache.
+        let mut res = c.get(url).unwrap();
+        let mut buf = vec![];
+        res.read_to_end(&mut buf).unwrap();
+        assert_eq!(&buf, body);
+        c.client.assert_called();
+    }
+
+    fn update_cache_if_none_match<S: super::Storage>(storage: S) {
+        let _ = env_logger::try_init();
+
+        let url: reqwest::Url = "http://example.com/".parse().unwrap();
+
+        // We send a request, and the server responds with the data,
+        // and an "ETag" header.
+        let request_1_headers = HeaderMap::new();
+        let mut response_1_headers = HeaderMap::new();
+        response_1_headers
+            .append(ETAG, HeaderValue::from_static("abcd"));
+
+        let mut c = make_test_cache_in(
+            rmt::FakeClient::new(
+                url.clone(),
+                request_1_headers,
+                rmt::FakeResponse {
+                    status: reqwest::StatusCode::OK,
+                    headers: response_1_headers,
+                    body: io::Cursor::new(b"hello".as_ref().into()),
+                },
+            ),
+            storage,
+        );
+
+        // The response and its etag should now be recorded in the cache.
+        c.get_bytes(url.clone()).unwrap();
+        c.client.assert_called();
+
+        // For the next request, we expect the request to include the
+        // etag in the "if none match" header, and we'll give
+        // the "yes, it has been modified" response with a new etag.
+        let mut request_2_headers = HeaderMap::new();
+        request_2_headers
+            .append(IF_NONE_MATCH, HeaderValue::from_static("abcd"));
+        let mut response_2_headers = HeaderMap::new();
+        response_2_headers
+            .append(ETAG, HeaderValue::from_static("efgh"));
+
+        c.client = rmt::FakeClient::new(
+            url.clone(),
+            request_2_headers,
+            rmt::FakeResponse {
+                status: reqwest::StatusCode::OK,
+                headers: response_2_headers,
+                body: io::Cursor::new(b"world".as_ref().into()),
+            },
+        );
+
+        // Now when we make the request, we should get the new body and
+        // ignore what's in the cache.
+        assert_eq!(c.get_bytes(url.clone()).unwrap(), b"world");
+        c.client.assert_called();
+
+        // If we make another request, we should set If-None-Match
+        // to match the second response, and be able to return the data from
+        // the second response.
+        let mut request_3_headers = HeaderMap::new();
+        request_3_headers
+            .append(IF_NONE_MATCH, HeaderValue::from_static("efgh"));
+        let response_3_headers = HeaderMap::new();
+
+        c.client = rmt::FakeClient::new(
+            url.clone(),
+            request_3_headers,
+            rmt::FakeResponse {
+                status: reqwest::StatusCode::NOT_MODIFIED,
+                headers: response_3_headers,
+                body: io::Cursor::new(b"".as_ref().into()),
+            },
+        );
+
+        // Now when we make the request, we should get updated info from the
+        // cache.
+        assert_eq!(c.get_bytes(url).unwrap(), b"world");
+        c.client.assert_called();
+    }
+
+    #[test]
+    fn stats_count_misses_hits_and_revalidations() {
+        let _ = env_logger::try_init();
+
+        let url: reqwest::Url = "http://example.com/".parse().unwrap();
+
+        let mut response_headers = HeaderMap::new();
+        response_headers.append(ETAG, HeaderValue::from_static("abcd"));
+
+        let mut c = make_test_cache(rmt::FakeClient::new(
+            url.clone(),
+            HeaderMap::new(),
+            rmt::FakeResponse {
+                status: reqwest::StatusCode::OK,
+                headers: response_headers.clone(),
+                body: io::Cursor::new(b"hello".as_ref().into()),
+            },
+        ));
+
+        // A never-seen URL is a miss that downloads.
+        c.get(url.clone()).unwrap();
+        assert_eq!(
+            c.stats(),
+            super::CacheStats {
+                hits: 0,
+                misses: 1,
+                revalidations: 0,
+                downloads: 1,
+            }
+        );
+
+        let mut request_headers = HeaderMap::new();
+        request_headers.append(IF_NONE_MATCH, HeaderValue::from_static("abcd"));
+
+        // A 304 is a hit that required a revalidation.
+        c.client = rmt::FakeClient::new(
+            url.clone(),
+            request_headers.clone(),
+            rmt::FakeResponse {
+                status: reqwest::StatusCode::NOT_MODIFIED,
+                headers: response_headers.clone(),
+                body: io::Cursor::new(vec![]),
+            },
+        );
+        c.get(url.clone()).unwrap();
+        assert_eq!(
+            c.stats(),
+            super::CacheStats {
+                hits: 1,
+                misses: 1,
+                revalidations: 1,
+                downloads: 1,
+            }
+        );
+
+        // A changed resource is a revalidation that downloads.
+        c.client = rmt::FakeClient::new(
+            url.clone(),
+            request_headers,
+            rmt::FakeResponse {
+                status: reqwest::StatusCode::OK,
+                headers: response_headers,
+                body: io::Cursor::new(b"world".as_ref().into()),
+            },
+        );
+        c.get(url).unwrap();
+        assert_eq!(
+            c.stats(),
+            super::CacheStats {
+                hits: 1,
+                misses: 1,
+                revalidations: 2,
+                downloads: 2,
+            }
+        );
+
+        c.reset_stats();
+        assert_eq!(c.stats(), super::CacheStats::default());
+    }
+
+    #[test]
+    fn stats_count_fresh_data_as_a_plain_hit() {
+        let _ = env_logger::try_init();
+
+        let url: reqwest::Url = "http://example.com/".parse().unwrap();
+
+        let mut response_headers = HeaderMap::new();
+        response_headers
+            .append(CACHE_CONTROL, HeaderValue::from_static("max-age=3600"));
+        let response = rmt::FakeResponse {
+            status: reqwest::StatusCode::OK,
+            headers: response_headers,
+            body: io::Cursor::new(b"hello".as_ref().into()),
+        };
+
+        let mut c = make_test_cache(rmt::FakeClient::new(
+            url.clone(),
+            HeaderMap::new(),
+            response.clone(),
+        ));
+        c.set_revalidation(super::RevalidationPolicy::WhenStale);
+        c.get(url.clone()).unwrap();
+
+        // Fresh data is served without asking the server at all.
+        c.client = rmt::FakeClient::new(url.clone(), HeaderMap::new(), response);
+        c.get(url).unwrap();
+        assert!(!c.client.was_called());
+        assert_eq!(
+            c.stats(),
+            super::CacheStats {
+                hits: 1,
+                misses: 1,
+                revalidations: 0,
+                downloads: 1,
+            }
+        );
+    }
+
+    #[test]
+    fn default_headers_are_sent_with_every_request() {
+        let _ = env_logger::try_init();
+
+        let url: reqwest::Url = "http://example.com/".parse().unwrap();
+
+        let mut default_headers = HeaderMap::new();
+        default_headers.append(
+            AUTHORIZATION,
+            HeaderValue::from_static("Bearer secret"),
+        );
+        // This collides with the header the cache adds on revalidation.
+        default_headers
+            .append(IF_NONE_MATCH, HeaderValue::from_static("bogus"));
+
+        let mut response_headers = HeaderMap::new();
+        response_headers.append(ETAG, HeaderValue::from_static("abcd"));
+
+        let mut c = make_test_cache(rmt::FakeClient::new(
+            url.clone(),
+            default_headers.clone(),
+            rmt::FakeResponse {
+                status: reqwest::StatusCode::OK,
+                headers: response_headers.clone(),
+                body: io::Cursor::new(b"hello".as_ref().into()),
+            },
+        ));
+        c.set_default_headers(default_headers);
+
+        c.get(url.clone()).unwrap();
+        c.client.assert_called();
+
+        // On revalidation, the custom header is still sent, but the
+        // cache's own If-None-Match takes precedence.
+        let mut second_request = HeaderMap::new();
+        second_request.append(
+            AUTHORIZATION,
+            HeaderValue::from_static("Bearer secret"),
+        );
+        second_request
+            .append(IF_NONE_MATCH, HeaderValue::from_static("abcd"));
+
+        c.client = rmt::FakeClient::new(
+            url.clone(),
+            second_request,
+            rmt::FakeResponse {
+                status: reqwest::StatusCode::NOT_MODIFIED,
+                headers: response_headers,
+                body: io::Cursor::new(vec![]),
+            },
+        );
+
+        c.get(url).unwrap();
+        c.client.assert_called();
+    }
+
+    #[test]
+    fn header_provider_is_called_for_each_request() {
+        let _ = env_logger::try_init();
+
+        let url: reqwest::Url = "http://example.com/".parse().unwrap();
+
+        let mut response_headers = HeaderMap::new();
+        response_headers.append(ETAG, HeaderValue::from_static("abcd"));
+
+        let mut first_request = HeaderMap::new();
+        first_request
+            .append(AUTHORIZATION, HeaderValue::from_static("Bearer one"));
+
+        let mut c = make_test_cache(rmt::FakeClient::new(
+            url.clone(),
+            first_request,
+            rmt::FakeResponse {
+                status: reqwest::StatusCode::OK,
+                headers: response_headers.clone(),
+                body: io::Cursor::new(b"hello".as_ref().into()),
+            },
+        ));
+
+        let token = std::rc::Rc::new(std::cell::RefCell::new("one"));
+        let provider_token = token.clone();
+        c.set_header_provider(move || {
+            let mut headers = HeaderMap::new();
+            headers.append(
+                AUTHORIZATION,
+                HeaderValue::from_str(&format!(
+                    "Bearer {}",
+                    provider_token.borrow()
+                ))
+                .unwrap(),
+            );
+            headers
+        });
+
+        c.get(url.clone()).unwrap();
+        c.client.assert_called();
+
+        // The token expires, and the revalidation request should carry
+        // the new one.
+        *token.borrow_mut() = "two";
+
+        let mut second_request = HeaderMap::new();
+        second_request
+            .append(AUTHORIZATION, HeaderValue::from_static("Bearer two"));
+        second_request
+            .append(IF_NONE_MATCH, HeaderValue::from_static("abcd"));
+
+        c.client = rmt::FakeClient::new(
+            url.clone(),
+            second_request,
+            rmt::FakeResponse {
+                status: reqwest::StatusCode::NOT_MODIFIED,
+                headers: response_headers,
+                body: io::Cursor::new(vec![]),
+            },
+        );
+
+        c.get(url).unwrap();
+        c.client.assert_called();
+    }
+
+    #[test]
+    fn access_count_includes_revalidations() {
+        let _ = env_logger::try_init();
+
+        let url: reqwest::Url = "http://example.com/".parse().unwrap();
+
+        let mut response_headers = HeaderMap::new();
+        response_headers.append(ETAG, HeaderValue::from_static("abcd"));
+
+        let mut c = make_test_cache(rmt::FakeClient::new(
+            url.clone(),
+            HeaderMap::new(),
+            rmt::FakeResponse {
+                status: reqwest::StatusCode::OK,
+                headers: response_headers.clone(),
+                body: io::Cursor::new(b"hello".as_ref().into()),
+            },
+        ));
+        c.get(url.clone()).unwrap();
+
+        let mut request_headers = HeaderMap::new();
+        request_headers.append(IF_NONE_MATCH, HeaderValue::from_static("abcd"));
+
+        c.client = rmt::FakeClient::new(
+            url.clone(),
+            request_headers,
+            rmt::FakeResponse {
+                status: reqwest::StatusCode::NOT_MODIFIED,
+                headers: response_headers,
+                body: io::Cursor::new(vec![]),
+            },
+        );
+        c.get(url.clone()).unwrap();
+        c.get(url.clone()).unwrap();
+
+        assert_eq!(c.most_accessed(10).unwrap(), vec![(url, 3)]);
+    }
+
+    #[test]
+    fn prune_expired_removes_only_old_entries() {
+        let _ = env_logger::try_init();
+
+        let old_url: reqwest::Url = "http://example.com/old".parse().unwrap();
+        let new_url: reqwest::Url = "http://example.com/new".parse().unwrap();
+
+        let mut c = make_test_cache(rmt::FakeClient::new(
+            old_url.clone(),
+            HeaderMap::new(),
+            rmt::FakeResponse {
+                status: reqwest::StatusCode::OK,
+                headers: HeaderMap::new(),
+                body: io::Cursor::new(b"old".as_ref().into()),
+            },
+        ));
+        c.get(old_url.clone()).unwrap();
+
+        c.client = rmt::FakeClient::new(
+            new_url.clone(),
+            HeaderMap::new(),
+            rmt::FakeResponse {
+                status: reqwest::StatusCode::OK,
+                headers: HeaderMap::new(),
+                body: io::Cursor::new(b"new".as_ref().into()),
+            },
+        );
+        c.get(new_url.clone()).unwrap();
+
+        // Pretend the first URL was last used two days ago.
+        let day = std::time::Duration::from_secs(24 * 60 * 60);
+        c.db.record_access(old_url.clone(), std::time::SystemTime::now() - 2 * day)
+            .unwrap();
+        let old_path = c.root.join(c.db.get(old_url.clone()).unwrap().path);
+        let new_path = c.root.join(c.db.get(new_url.clone()).unwrap().path);
+
+        assert_eq!(c.prune_expired(day).unwrap(), 1);
+
+        assert!(c.db.get(old_url).is_err());
+        assert!(!old_path.exists());
+        assert!(c.db.get(new_url).is_ok());
+        assert!(new_path.exists());
+    }
+
+    #[test]
+    fn prune_expired_keeps_fresh_entries() {
+        let _ = env_logger::try_init();
+
+        let url: reqwest::Url = "http://example.com/fresh".parse().unwrap();
+        let mut response_headers = HeaderMap::new();
+        response_headers.append(
+            CACHE_CONTROL,
+            HeaderValue::from_static("max-age=604800"),
+        );
+        let mut c = make_test_cache(rmt::FakeClient::new(
+            url.clone(),
+            HeaderMap::new(),
+            rmt::FakeResponse {
+                status: reqwest::StatusCode::OK,
+                headers: response_headers,
+                body: io::Cursor::new(b"fresh".as_ref().into()),
+            },
+        ));
+        c.get(url.clone()).unwrap();
+
+        // Last used two days ago, but still fresh for a week.
+        let day = std::time::Duration::from_secs(24 * 60 * 60);
+        c.db.record_access(url.clone(), std::time::SystemTime::now() - 2 * day)
+            .unwrap();
+
+        assert_eq!(c.prune_expired(day).unwrap(), 0);
+        assert!(c.is_present_on_disk(url.clone()).unwrap());
+
+        // Nothing was used before the start of time.
+        assert_eq!(c.prune_expired(std::time::Duration::MAX).unwrap(), 0);
+        assert!(c.is_present_on_disk(url).unwrap());
+    }
+
+    fn revalidate_with_etag(etag: &'static str) {
+        let url: reqwest::Url = "http://example.com/".parse().unwrap();
+
+        let mut response_headers = HeaderMap::new();
+        response_headers.append(ETAG, HeaderValue::from_static(etag));
+
+        let mut c = make_test_cache(rmt::FakeClient::new(
+            url.clone(),
+            HeaderMap::new(),
+            rmt::FakeResponse {
+                status: reqwest::StatusCode::OK,
+                headers: response_headers.clone(),
+                body: io::Cursor::new(b"hello".as_ref().into()),
+            },
+        ));
+        c.get(url.clone()).unwrap();
+        assert_eq!(
+            c.db.get(url.clone()).unwrap().etags[0].weak,
+            etag.starts_with("W/")
+        );
+
+        // The revalidation request should echo the tag exactly,
+        // including any weakness prefix.
+        let mut second_request = HeaderMap::new();
+        second_request.append(IF_NONE_MATCH, HeaderValue::from_static(etag));
+
+        c.client = rmt::FakeClient::new(
+            url.clone(),
+            second_request,
+            rmt::FakeResponse {
+                status: reqwest::StatusCode::NOT_MODIFIED,
+                headers: response_headers,
+                body: io::Cursor::new(vec![]),
+            },
+        );
+
+        let mut res = c.get(url).unwrap();
+        let mut buf = vec![];
+        res.read_to_end(&mut buf).unwrap();
+        assert_eq!(&buf, b"hello");
+        c.client.assert_called();
+    }
+
+    #[test]
+    fn revalidate_with_weak_etag() {
+        let _ = env_logger::try_init();
+        revalidate_with_etag("W/\"abcd\"");
+    }
+
+    #[test]
+    fn revalidate_with_strong_etag() {
+        let _ = env_logger::try_init();
+        revalidate_with_etag("\"abcd\"");
+    }
+
+    #[test]
+    fn revalidate_with_several_etags() {
+        let _ = env_logger::try_init();
+
+        let url: reqwest::Url = "http://example.com/".parse().unwrap();
+
+        // The server identifies the response with two entity tags.
+        let mut response_1_headers = HeaderMap::new();
+        response_1_headers.append(ETAG, HeaderValue::from_static("\"a\""));
+        response_1_headers.append(ETAG, HeaderValue::from_static("W/\"b\""));
+
+        let mut c = make_test_cache(rmt::FakeClient::new(
+            url.clone(),
+            HeaderMap::new(),
+            rmt::FakeResponse {
+                status: reqwest::StatusCode::OK,
+                headers: response_1_headers,
+                body: io::Cursor::new(b"hello".as_ref().into()),
+            },
+        ));
+        c.get(url.clone()).unwrap();
+        c.client.assert_called();
+
+        // Both should be offered on revalidation; a 304 keeps them.
+        let mut request_2_headers = HeaderMap::new();
+        request_2_headers.append(
+            IF_NONE_MATCH,
+            HeaderValue::from_static("\"a\", W/\"b\""),
+        );
+
+        for _ in 0..2 {
+            c.client = rmt::FakeClient::new(
+                url.clone(),
+                request_2_headers.clone(),
+                rmt::FakeResponse {
+                    status: reqwest::StatusCode::NOT_MODIFIED,
+                    headers: HeaderMap::new(),
+                    body: io::Cursor::new(vec![]),
+                },
+            );
+            c.get(url.clone()).unwrap();
+            c.client.assert_called();
+        }
+
+        // A 200 with a single tag replaces the set.
+        let mut response_3_headers = HeaderMap::new();
+        response_3_headers.append(ETAG, HeaderValue::from_static("\"c\""));
+
+        c.client = rmt::FakeClient::new(
+            url.clone(),
+            request_2_headers,
+            rmt::FakeResponse {
+                status: reqwest::StatusCode::OK,
+                headers: response_3_headers,
+                body: io::Cursor::new(b"world".as_ref().into()),
+            },
+        );
+        c.get(url.clone()).unwrap();
+        c.client.assert_called();
+
+        assert_eq!(c.db.get(url).unwrap().etags, vec!["\"c\"".into()]);
+    }
+
+    fn make_head_test_cache(
+        url: &reqwest::Url,
+        next_requests: Vec<rmt::FakeClient>,
+    ) -> super::Cache<rmt::SequenceClient> {
+        let mut response_headers = HeaderMap::new();
+        response_headers.append(ETAG, HeaderValue::from_static("\"abcd\""));
+
+        // The first request caches the URL; the rest are up to the test.
+        let mut requests = vec![rmt::FakeClient::new(
+            url.clone(),
+            HeaderMap::new(),
+            rmt::FakeResponse {
+                status: reqwest::StatusCode::OK,
+                headers: response_headers,
+                body: io::Cursor::new(b"hello".as_ref().into()),
+            },
+        )];
+        requests.extend(next_requests);
+
+        let mut c = super::Cache::builder(
+            tempdir::TempDir::new("http-cache-test")
+                .unwrap()
+                .into_path(),
+            rmt::SequenceClient::new(requests),
+        )
+        .validate_with_head(true)
+        .build()
+        .unwrap();
+        c.get(url.clone()).unwrap();
+        c
+    }
+
+    #[test]
+    fn matching_head_response_skips_get() {
+        let _ = env_logger::try_init();
+
+        let url: reqwest::Url = "http://example.com/".parse().unwrap();
+
+        let mut head_headers = HeaderMap::new();
+        head_headers.append(ETAG, HeaderValue::from_static("\"abcd\""));
+        head_headers.append(CONTENT_LENGTH, HeaderValue::from_static("5"));
+
+        let head = rmt::FakeClient::new(
+            url.clone(),
+            HeaderMap::new(),
+            rmt::FakeResponse {
+                status: reqwest::StatusCode::OK,
+                headers: head_headers,
+                body: io::Cursor::new(vec![]),
+            },
+        )
+        .with_method(reqwest::Method::HEAD);
+
+        let mut c = make_head_test_cache(&url, vec![head]);
+
+        let mut res = c.get(url).unwrap();
+        let mut buf = vec![];
+        res.read_to_end(&mut buf).unwrap();
+        assert_eq!(&buf, b"hello");
+        c.client.assert_called();
+    }
+
+    #[test]
+    fn inconclusive_head_response_falls_back_to_get() {
+        let _ = env_logger::try_init();
+
+        let url: reqwest::Url = "http://example.com/".parse().unwrap();
+
+        // The HEAD response has no validators to compare...
+        let head = rmt::FakeClient::new(
+            url.clone(),
+            HeaderMap::new(),
+            rmt::FakeResponse {
+                status: reqwest::StatusCode::OK,
+                headers: HeaderMap::new(),
+                body: io::Cursor::new(vec![]),
+            },
+        )
+        .with_method(reqwest::Method::HEAD);
+
+        // ...so we expect a conditional GET.
+        let mut get_headers = HeaderMap::new();
+        get_headers.append(IF_NONE_MATCH, HeaderValue::from_static("\"abcd\""));
+        let get = rmt::FakeClient::new(
+            url.clone(),
+            get_headers,
+            rmt::FakeResponse {
+                status: reqwest::StatusCode::NOT_MODIFIED,
+                headers: HeaderMap::new(),
+                body: io::Cursor::new(vec![]),
+            },
+        );
+
+        let mut c = make_head_test_cache(&url, vec![head, get]);
+
+        let mut res = c.get(url).unwrap();
+        let mut buf = vec![];
+        res.read_to_end(&mut buf).unwrap();
+        assert_eq!(&buf, b"hello");
+        c.client.assert_called();
+    }
+
+    #[test]
+    fn wait_for_concurrent_download_of_same_url() {
+        let _ = env_logger::try_init();
+
+        let root = tempdir::TempDir::new("http-cache-test")
+            .unwrap()
+            .into_path();
+        let url: reqwest::Url = "http://example.com/".parse().unwrap();
+
+        // Pretend another instance is busy downloading the URL.
+        let mut other = super::Cache::new(
+            root.clone(),
+            rmt::FakeClient::new(
+                url.clone(),
+                HeaderMap::new(),
+                rmt::FakeResponse {
+                    status: reqwest::StatusCode::OK,
+                    headers: HeaderMap::new(),
+                    body: io::Cursor::new(vec![]),
+                },
+            ),
+        )
+        .unwrap();
+        let lock = super::lock_url(&other.root, &url).unwrap();
+
+        let thread_root = root.clone();
+        let thread_url = url.clone();
+        let (looking_up, about_to_look_up) = std::sync::mpsc::channel();
+        let waiter = std::thread::spawn(move || {
+            let mut c = super::Cache::new(
+                thread_root,
+                rmt::FakeClient::new(
+                    thread_url.clone(),
+                    HeaderMap::new(),
+                    rmt::FakeResponse {
+                        status: reqwest::StatusCode::OK,
+                        headers: HeaderMap::new(),
+                        body: io::Cursor::new(b"duplicate".as_ref().into()),
+                    },
+                ),
+            )
+            .unwrap();
+            // Headers are built just before the URL is looked up.
+            c.set_header_provider(move || {
+                let _ = looking_up.send(());
+                HeaderMap::new()
+            });
+            let mut buf = vec![];
+            c.get(thread_url).unwrap().read_to_end(&mut buf).unwrap();
+            (buf, c.client.was_called())
+        });
+
+        // Once the waiter is about to miss and block on the lock, finish the
+        // other download. The content is fresh, so even if the waiter only
+        // looks after we're done, it can use it without a request.
+        about_to_look_up.recv().unwrap();
+        let mut response = other.client.response.clone();
+        response
+            .headers
+            .insert(CACHE_CONTROL, HeaderValue::from_static("max-age=3600"));
+        let (mut handle, partial, transaction) =
+            other.record_response(url.clone(), &url, &response).unwrap();
+        transaction.commit().unwrap();
+        io::copy(&mut io::Cursor::new(b"hello"), &mut handle).unwrap();
+        other.finish_download(url, &partial).unwrap();
+        drop(lock);
+
+        // The waiter should have used the other download, without making
+        // a request of its own.
+        let (buf, called) = waiter.join().unwrap();
+        assert_eq!(&buf, b"hello");
+        assert!(!called);
+    }
+
+    #[test]
+    fn read_only_cache_records_nothing() {
+        let _ = env_logger::try_init();
+
+        let root = tempdir::TempDir::new("http-cache-test")
+            .unwrap()
+            .into_path();
+        let url: reqwest::Url = "http://example.com/".parse().unwrap();
+
+        // Some other process created the cache.
+        super::Cache::new(
+            root.clone(),
+            rmt::FakeClient::new(
+                url.clone(),
+                HeaderMap::new(),
+                rmt::FakeResponse {
+                    status: reqwest::StatusCode::OK,
+                    headers: HeaderMap::new(),
+                    body: io::Cursor::new(vec![]),
+                },
+            ),
+        )
+        .unwrap();
+
+        let mut c = super::Cache::open_read_only(
+            root,
+            rmt::FakeClient::new(
+                url.clone(),
+                HeaderMap::new(),
+                rmt::FakeResponse {
+                    status: reqwest::StatusCode::OK,
+                    headers: HeaderMap::new(),
+                    body: io::Cursor::new(b"hello".as_ref().into()),
+                },
+            ),
+        )
+        .unwrap();
+
+        // We still get the data...
+        let mut res = c.get(url.clone()).unwrap();
+        let mut buf = vec![];
+        res.read_to_end(&mut buf).unwrap();
+        assert_eq!(&buf, b"hello");
+
+        // ...but it wasn't recorded.
+        assert!(matches!(
+            c.db.get(url),
+            Err(super::DbError::NotFound(_))
+        ));
+        assert!(!c.root.join("content").exists());
+
+        let err = c.prune_expired(std::time::Duration::from_secs(0));
+        assert_eq!(
+            err.unwrap_err().downcast_ref(),
+            Some(&super::CacheError::ReadOnly)
+        );
+    }
+
+    #[test]
+    fn ignored_query_params_share_an_entry() {
+        let _ = env_logger::try_init();
+
+        let url_1: reqwest::Url =
+            "http://example.com/?v=1&utm_source=a".parse().unwrap();
+        let url_2: reqwest::Url =
+            "http://example.com/?utm_source=b&v=1#frag".parse().unwrap();
+        let url_3: reqwest::Url =
+            "http://example.com/?v=2&utm_source=a".parse().unwrap();
+
+        let mut response_headers = HeaderMap::new();
+        response_headers.append(ETAG, HeaderValue::from_static("\"abcd\""));
+        let mut revalidate_headers = HeaderMap::new();
+        revalidate_headers
+            .append(IF_NONE_MATCH, HeaderValue::from_static("\"abcd\""));
+
+        let mut c = super::Cache::builder(
+            tempdir::TempDir::new("http-cache-test")
+                .unwrap()
+                .into_path(),
+            rmt::SequenceClient::new(vec![
+                rmt::FakeClient::new(
+                    url_1.clone(),
+                    HeaderMap::new(),
+                    rmt::FakeResponse {
+                        status: reqwest::StatusCode::OK,
+                        headers: response_headers.clone(),
+                        body: io::Cursor::new(b"hello".as_ref().into()),
+                    },
+                ),
+                // The second URL is the same resource, so we revalidate
+                // the first one's data (but still ask for the URL we were
+                // given, minus its fragment).
+                rmt::FakeClient::new(
+                    "http://example.com/?utm_source=b&v=1".parse().unwrap(),
+                    revalidate_headers,
+                    rmt::FakeResponse {
+                        status: reqwest::StatusCode::NOT_MODIFIED,
+                        headers: HeaderMap::new(),
+                        body: io::Cursor::new(vec![]),
+                    },
+                ),
+                // Other parameters still matter.
+                rmt::FakeClient::new(
+                    url_3.clone(),
+                    HeaderMap::new(),
+                    rmt::FakeResponse {
+                        status: reqwest::StatusCode::OK,
+                        headers: HeaderMap::new(),
+                        body: io::Cursor::new(b"world".as_ref().into()),
+                    },
+                ),
+            ]),
+        )
+        .ignore_query_params(vec!["utm_*".into()])
+        .build()
+        .unwrap();
+
+        c.get(url_1).unwrap();
+        let mut buf = vec![];
+        c.get(url_2).unwrap().read_to_end(&mut buf).unwrap();
+        assert_eq!(&buf, b"hello");
+        c.get(url_3).unwrap();
+        c.client.assert_called();
+
+        assert!(c.db.get("http://example.com/?v=1".parse().unwrap()).is_ok());
+        assert!(c.db.get("http://example.com/?v=2".parse().unwrap()).is_ok());
+    }
+
+    #[test]
+    fn query_params_are_part_of_the_key_by_default() {
+        let _ = env_logger::try_init();
+
+        let url_1: reqwest::Url =
+            "http://example.com/?utm_source=a".parse().unwrap();
+        let url_2: reqwest::Url =
+            "http://example.com/?utm_source=b".parse().unwrap();
+
+        let mut c = make_test_cache(rmt::SequenceClient::new(
+            vec![url_1.clone(), url_2.clone()]
+                .into_iter()
+                .map(|url| {
+                    rmt::FakeClient::new(
+                        url,
+                        HeaderMap::new(),
+                        rmt::FakeResponse {
+                            status: reqwest::StatusCode::OK,
+                            headers: HeaderMap::new(),
+                            body: io::Cursor::new(b"hello".as_ref().into()),
+                        },
+                    )
+                })
+                .collect(),
+        ));
+
+        c.get(url_1).unwrap();
+        c.get(url_2).unwrap();
+        c.client.assert_called();
+    }
+
+    #[test]
+    fn normalize_percent_encoding() {
+        assert_eq!(
+            super::normalize_percent_encoding("/%7euser/a%2fb%3F%zz%4"),
+            "/~user/a%2Fb%3F%zz%4"
+        );
+    }
+
+    #[test]
+    fn normalized_urls_share_an_entry() {
+        let _ = env_logger::try_init();
+
+        // Parsing already takes care of the host, port and dot-segments.
+        let url_1: reqwest::Url =
+            "HTTP://Example.COM:80/a/../%7euser/?q=%7e".parse().unwrap();
+        let url_2: reqwest::Url =
+            "http://example.com/~user/?q=~".parse().unwrap();
+
+        let mut response_headers = HeaderMap::new();
+        response_headers.append(ETAG, HeaderValue::from_static("\"abcd\""));
+        let mut revalidate_headers = HeaderMap::new();
+        revalidate_headers
+            .append(IF_NONE_MATCH, HeaderValue::from_static("\"abcd\""));
+
+        let mut c = super::Cache::builder(
+            tempdir::TempDir::new("http-cache-test")
+                .unwrap()
+                .into_path(),
+            rmt::SequenceClient::new(vec![
+                rmt::FakeClient::new(
+                    url_1.clone(),
+                    HeaderMap::new(),
+                    rmt::FakeResponse {
+                        status: reqwest::StatusCode::OK,
+                        headers: response_headers,
+                        body: io::Cursor::new(b"hello".as_ref().into()),
+                    },
+                ),
+                rmt::FakeClient::new(
+                    url_2.clone(),
+                    revalidate_headers,
+                    rmt::FakeResponse {
+                        status: reqwest::StatusCode::NOT_MODIFIED,
+                        headers: HeaderMap::new(),
+                        body: io::Cursor::new(vec![]),
+                    },
+                ),
+            ]),
+        )
+        .normalize_urls(true)
+        .build()
+        .unwrap();
+
+        c.get(url_1).unwrap();
+        let mut buf = vec![];
+        c.get(url_2).unwrap().read_to_end(&mut buf).unwrap();
+        assert_eq!(&buf, b"hello");
+        c.client.assert_called();
+    }
+
+    #[test]
+    fn get_bytes_and_string() {
+        let _ = env_logger::try_init();
+
+        let url: reqwest::Url = "http://example.com/hello".parse().unwrap();
+        let response = || rmt::FakeResponse {
+            status: reqwest::StatusCode::OK,
+            headers: HeaderMap::new(),
+            body: io::Cursor::new(b"hello".as_ref().into()),
+        };
+
+        let mut c = make_test_cache(rmt::FakeClient::new(
+            url.clone(),
+            HeaderMap::new(),
+            response(),
+        ));
+        assert_eq!(c.get_bytes(url.clone()).unwrap(), b"hello");
+
+        c.client =
+            rmt::FakeClient::new(url.clone(), HeaderMap::new(), response());
+        assert_eq!(c.get_string(url).unwrap(), "hello");
+    }
+
+    #[test]
+    fn get_string_rejects_invalid_utf8() {
+        let _ = env_logger::try_init();
+
+        let url: reqwest::Url = "http://example.com/binary".parse().unwrap();
+        let mut c = make_test_cache(rmt::FakeClient::new(
+            url.clone(),
+            HeaderMap::new(),
+            rmt::FakeResponse {
+                status: reqwest::StatusCode::OK,
+                headers: HeaderMap::new(),
+                body: io::Cursor::new(vec![0xff, 0xfe]),
+            },
+        ));
+
+        let err = c.get_string(url).unwrap_err();
+        assert!(err.to_string().contains("not valid UTF-8"), "{}", err);
+    }
+
+    fn get_range_reads_part_of_the_content<S: super::Storage>(storage: S) {
+        let _ = env_logger::try_init();
+
+        let url: reqwest::Url = "http://example.com/video".parse().unwrap();
+        let mut c = make_test_cache_in(
+            rmt::FakeClient::new(
+                url.clone(),
+                HeaderMap::new(),
+                rmt::FakeResponse {
+                    status: reqwest::StatusCode::OK,
+                    headers: HeaderMap::new(),
+                    body: io::Cursor::new(b"0123456789".as_ref().into()),
+                },
+            ),
+            storage,
+        );
+
+        let mut buf = vec![];
+        c.get_range(url.clone(), 3, Some(7))
+            .unwrap()
+            .read_to_end(&mut buf)
+            .unwrap();
+        assert_eq!(&buf, b"3456");
+        c.client.assert_called();
+
+        // The rest comes from the cache.
+        c.client = rmt::FakeClient::new(
+            url.clone(),
+            HeaderMap::new(),
+            rmt::FakeResponse {
+                status: reqwest::StatusCode::NOT_MODIFIED,
+                headers: HeaderMap::new(),
+                body: io::Cursor::new(b"".as_ref().into()),
+            },
+        );
+        let mut buf = vec![];
+        c.get_range(url, 7, None).unwrap().read_to_end(&mut buf).unwrap();
+        assert_eq!(&buf, b"789");
+    }
+
+    #[test]
+    fn get_range_rejects_ranges_past_the_end() {
+        let _ = env_logger::try_init();
+
+        let url: reqwest::Url = "http://example.com/video".parse().unwrap();
+        let mut c = make_test_cache(rmt::FakeClient::new(
+            url.clone(),
+            HeaderMap::new(),
+            rmt::FakeResponse {
+                status: reqwest::StatusCode::OK,
+                headers: HeaderMap::new(),
+                body: io::Cursor::new(b"0123456789".as_ref().into()),
+            },
+        ));
+
+        let err = c.get_range(url.clone(), 5, Some(20)).unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<super::CacheError>(),
+            Some(&super::CacheError::InvalidRange {
+                url: url.clone(),
+                start: 5,
+                end: 20,
+                len: 10
+            })
+        );
+
+        let err = c.get_range(url.clone(), 8, Some(2)).unwrap_err();
+        assert!(err.downcast_ref::<super::CacheError>().is_some());
+    }
+
+    #[test]
+    fn get_with_headers_adds_headers_to_one_call() {
+        let _ = env_logger::try_init();
+
+        let url: reqwest::Url = "http://example.com/".parse().unwrap();
+        let mut extra = HeaderMap::new();
+        extra.insert("x-request-id", HeaderValue::from_static("1234"));
+        let mut response_headers = HeaderMap::new();
+        response_headers.insert(ETAG, HeaderValue::from_static("\"abcd\""));
+
+        let mut c = make_test_cache(rmt::FakeClient::new(
+            url.clone(),
+            extra.clone(),
+            rmt::FakeResponse {
+                status: reqwest::StatusCode::OK,
+                headers: response_headers,
+                body: io::Cursor::new(b"hello".as_ref().into()),
+            },
+        ));
+        c.get_with_headers(url.clone(), extra).unwrap();
+        c.client.assert_called();
+
+        // The next plain request doesn't send it again.
+        let mut request_headers = HeaderMap::new();
+        request_headers
+            .insert(IF_NONE_MATCH, HeaderValue::from_static("\"abcd\""));
+        c.client = rmt::FakeClient::new(
+            url.clone(),
+            request_headers,
+            rmt::FakeResponse {
+                status: reqwest::StatusCode::NOT_MODIFIED,
+                headers: HeaderMap::new(),
+                body: io::Cursor::new(b"".as_ref().into()),
+            },
+        );
+        let mut buf = vec![];
+        c.get(url).unwrap().read_to_end(&mut buf).unwrap();
+        c.client.assert_called();
+        assert_eq!(&buf, b"hello");
+    }
+
+    #[test]
+    fn conditional_headers_take_precedence_over_extra_headers() {
+        let _ = env_logger::try_init();
+
+        let url: reqwest::Url = "http://example.com/".parse().unwrap();
+        let mut response_headers = HeaderMap::new();
+        response_headers.insert(ETAG, HeaderValue::from_static("\"abcd\""));
+        let mut c = make_test_cache(rmt::FakeClient::new(
+            url.clone(),
+            HeaderMap::new(),
+            rmt::FakeResponse {
+                status: reqwest::StatusCode::OK,
+                headers: response_headers,
+                body: io::Cursor::new(b"hello".as_ref().into()),
+            },
+        ));
+        c.get(url.clone()).unwrap();
+
+        let mut extra = HeaderMap::new();
+        extra.insert(IF_NONE_MATCH, HeaderValue::from_static("\"other\""));
+        extra.insert(ACCEPT_LANGUAGE, HeaderValue::from_static("fr"));
+        let mut request_headers = HeaderMap::new();
+        request_headers
+            .insert(IF_NONE_MATCH, HeaderValue::from_static("\"abcd\""));
+        request_headers.insert(ACCEPT_LANGUAGE, HeaderValue::from_static("fr"));
+        c.client = rmt::FakeClient::new(
+            url.clone(),
+            request_headers,
+            rmt::FakeResponse {
+                status: reqwest::StatusCode::NOT_MODIFIED,
+                headers: HeaderMap::new(),
+                body: io::Cursor::new(b"".as_ref().into()),
+            },
+        );
+        c.get_with_headers(url, extra).unwrap();
+        c.client.assert_called();
+    }
+
+    /// Caches "hello" with an ETag, and expects it to be revalidated next.
+    fn cached_for_revalidation(
+        response: rmt::FakeResponse,
+    ) -> (super::Cache<rmt::FakeClient>, reqwest::Url) {
+        cached_for_revalidation_in(response, super::FsStorage)
+    }
+
+    fn cached_for_revalidation_in<S: super::Storage>(
+        response: rmt::FakeResponse,
+        storage: S,
+    ) -> (super::Cache<rmt::FakeClient, S>, reqwest::Url) {
+        let url: reqwest::Url = "http://example.com/".parse().unwrap();
+        let mut response_headers = HeaderMap::new();
+        response_headers.insert(ETAG, HeaderValue::from_static("\"abcd\""));
+        let mut c = make_test_cache_in(
+            rmt::FakeClient::new(
+                url.clone(),
+                HeaderMap::new(),
+                rmt::FakeResponse {
+                    status: reqwest::StatusCode::OK,
+                    headers: response_headers,
+                    body: io::Cursor::new(b"hello".as_ref().into()),
+                },
+            ),
+            storage,
+        );
+        c.warm(url.clone()).unwrap();
+
+        let mut request_headers = HeaderMap::new();
+        request_headers
+            .insert(IF_NONE_MATCH, HeaderValue::from_static("\"abcd\""));
+        c.client = rmt::FakeClient::new(url.clone(), request_headers, response);
+        (c, url)
+    }
+
+    #[test]
+    fn revalidate_confirms_unchanged_content() {
+        use super::CacheStatus;
+        let _ = env_logger::try_init();
+
+        let mut response_headers = HeaderMap::new();
+        response_headers
+            .insert(CACHE_CONTROL, HeaderValue::from_static("max-age=60"));
+        let (mut c, url) = cached_for_revalidation(rmt::FakeResponse {
+            status: reqwest::StatusCode::NOT_MODIFIED,
+            headers: response_headers,
+            body: io::Cursor::new(b"".as_ref().into()),
+        });
+        let path = c.root.join(c.metadata(url.clone()).unwrap().unwrap().path);
+
+        assert_eq!(
+            c.revalidate(url.clone()).unwrap(),
+            CacheStatus::Revalidated
+        );
+        c.client.assert_called();
+        let record = c.metadata(url).unwrap().unwrap();
+        assert_eq!(c.root.join(&record.path), path);
+        assert!(record.fresh_until.is_some());
+    }
+
+    #[test]
+    fn revalidate_downloads_changed_content() {
+        use super::CacheStatus;
+        let _ = env_logger::try_init();
+
+        let (mut c, url) = cached_for_revalidation(rmt::FakeResponse {
+            status: reqwest::StatusCode::OK,
+            headers: HeaderMap::new(),
+            body: io::Cursor::new(b"world".as_ref().into()),
+        });
+
+        assert_eq!(c.revalidate(url.clone()).unwrap(), CacheStatus::Downloaded);
+        c.client.assert_called();
+        let record = c.metadata(url.clone()).unwrap().unwrap();
+        assert_eq!(std::fs::read(c.root.join(record.path)).unwrap(), b"world");
+
+        // Only cached URLs can be revalidated.
+        let other: reqwest::Url = "http://example.com/other".parse().unwrap();
+        assert!(c.revalidate(other).is_err());
+    }
+
+    #[test]
+    fn get_path_returns_the_cached_file() {
+        let _ = env_logger::try_init();
+
+        let url: reqwest::Url = "http://example.com/dataset".parse().unwrap();
+        let mut c = make_test_cache(rmt::FakeClient::new(
+            url.clone(),
+            HeaderMap::new(),
+            rmt::FakeResponse {
+                status: reqwest::StatusCode::OK,
+                headers: HeaderMap::new(),
+                body: io::Cursor::new(b"lots of data".as_ref().into()),
+            },
+        ));
+
+        let path = c.get_path(url).unwrap();
+        c.client.assert_called();
+
+        assert!(path.is_absolute());
+        assert!(path.starts_with(c.root()));
+        assert_eq!(std::fs::read(&path).unwrap(), b"lots of data");
+    }
+
+    #[test]
+    fn events_for_miss_then_hit() {
+        use super::CacheEvent;
+        use std::{cell::RefCell, rc::Rc};
+
+        let _ = env_logger::try_init();
+
+        let url: reqwest::Url = "http://example.com/".parse().unwrap();
+
+        let mut response_headers = HeaderMap::new();
+        response_headers.append(ETAG, HeaderValue::from_static("\"abcd\""));
+        let mut c = make_test_cache(rmt::FakeClient::new(
+            url.clone(),
+            HeaderMap::new(),
+            rmt::FakeResponse {
+                status: reqwest::StatusCode::OK,
+                headers: response_headers,
+                body: io::Cursor::new(b"hello".as_ref().into()),
+            },
+        ));
+
+        let events = Rc::new(RefCell::new(vec![]));
+        let sink = events.clone();
+        c.set_event_handler(move |event| sink.borrow_mut().push(event));
+
+        c.get(url.clone()).unwrap();
+        c.client.assert_called();
+
+        let mut revalidate_headers = HeaderMap::new();
+        revalidate_headers
+            .append(IF_NONE_MATCH, HeaderValue::from_static("\"abcd\""));
+        c.client = rmt::FakeClient::new(
+            url.clone(),
+            revalidate_headers,
+            rmt::FakeResponse {
+                status: reqwest::StatusCode::NOT_MODIFIED,
+                headers: HeaderMap::new(),
+                body: io::Cursor::new(vec![]),
+            },
+        );
+        c.get(url.clone()).unwrap();
+        c.client.assert_called();
+
+        assert_eq!(
+            *events.borrow(),
+            vec![
+                CacheEvent::Miss { url: url.clone() },
+                CacheEvent::Download {
+                    url: url.clone(),
+                    bytes: 5
+                },
+                CacheEvent::Revalidated {
+                    url: url.clone(),
+                    changed: false
+                },
+                CacheEvent::Hit { url },
+            ]
+        );
+    }
+
+    /// Records the first bytes of an interrupted download of `url` in `c`.
+    fn make_partial_entry<C: super::reqwest_mock::Client, S: super::Storage>(
+        c: &mut super::Cache<C, S>,
+        url: &reqwest::Url,
+        body: &[u8],
+    ) -> std::path::PathBuf {
+        let path = c.root.join("partial").join("download");
+        io::Write::write_all(&mut c.storage.create_new(&path).unwrap(), body)
+            .unwrap();
+        c.db.set(
+            url.clone(),
+            super::db::CacheRecord {
+                path: "partial/download".into(),
+                last_modified: None,
+                etags: vec!["\"abcd\"".into()],
+                complete: false,
+                fresh_until: None,
+                no_cache: false,
+                status: None,
+                fetched_at: None,
+                validated_at: None,
+                declared_length: None,
+                response_date: None,
+                compressed: false,
+            },
+        )
+        .unwrap()
+        .commit()
+        .unwrap();
+        path
+    }
+
+    fn resume_interrupted_download<S: super::Storage>(storage: S) {
+        let _ = env_logger::try_init();
+
+        let url: reqwest::Url = "http://example.com/big".parse().unwrap();
+
+        // We should ask for the rest of the content, as long as it's still
+        // the same content.
+        let mut request_headers = HeaderMap::new();
+        request_headers.append(RANGE, HeaderValue::from_static("bytes=3-"));
+        request_headers.append(IF_RANGE, HeaderValue::from_static("\"abcd\""));
+        let mut response_headers = HeaderMap::new();
+        response_headers
+            .append(CONTENT_RANGE, HeaderValue::from_static("bytes 3-4/5"));
+        let mut c = make_test_cache_in(
+            rmt::FakeClient::new(
+                url.clone(),
+                request_headers,
+                rmt::FakeResponse {
+                    status: reqwest::StatusCode::PARTIAL_CONTENT,
+                    headers: response_headers,
+                    body: io::Cursor::new(b"lo".as_ref().into()),
+                },
+            ),
+            storage,
+        );
+        let path = make_partial_entry(&mut c, &url, b"hel");
+
+        assert_eq!(c.get_bytes(url.clone()).unwrap(), b"hello");
+        c.client.assert_called();
+
+        // The completed download should have moved into place.
+        let record = c.db.get(url).unwrap();
+        assert!(record.complete);
+        assert!(record.path.starts_with("content"));
+        assert_eq!(stored(&c, &path), None);
+    }
+
+    fn restart_interrupted_download_of_changed_resource<S: super::Storage>(
+        storage: S,
+    ) {
+        let _ = env_logger::try_init();
+
+        let url: reqwest::Url = "http://example.com/big".parse().unwrap();
+
+        let mut request_headers = HeaderMap::new();
+        request_headers.append(RANGE, HeaderValue::from_static("bytes=3-"));
+        request_headers.append(IF_RANGE, HeaderValue::from_static("\"abcd\""));
+        // The server sends the whole of the new content instead.
+        let mut response_headers = HeaderMap::new();
+        response_headers.append(ETAG, HeaderValue::from_static("\"efgh\""));
+        let mut c = make_test_cache_in(
+            rmt::FakeClient::new(
+                url.clone(),
+                request_headers,
+                rmt::FakeResponse {
+                    status: reqwest::StatusCode::OK,
+                    headers: response_headers,
+                    body: io::Cursor::new(b"world".as_ref().into()),
+                },
+            ),
+            storage,
+        );
+        let path = make_partial_entry(&mut c, &url, b"hel");
+
+        assert_eq!(c.get_bytes(url.clone()).unwrap(), b"world");
+        c.client.assert_called();
+
+        // The stale prefix should be gone, replaced by a complete entry.
+        assert_eq!(stored(&c, &path), None);
+        let record = c.db.get(url).unwrap();
+        assert!(record.complete);
+        assert_eq!(record.etags, vec![super::db::ETag::parse("\"efgh\"")]);
+    }
+
+    /// Returns the headers of a request resuming the download recorded by
+    /// `make_partial_entry`, after its first 3 bytes.
+    fn resume_headers() -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.append(RANGE, HeaderValue::from_static("bytes=3-"));
+        headers.append(IF_RANGE, HeaderValue::from_static("\"abcd\""));
+        headers
+    }
+
+    #[test]
+    fn finish_interrupted_download_that_was_already_complete() {
+        use super::CacheStatus;
+        let _ = env_logger::try_init();
+
+        let url: reqwest::Url = "http://example.com/big".parse().unwrap();
+        let mut response_headers = HeaderMap::new();
+        response_headers
+            .append(CONTENT_RANGE, HeaderValue::from_static("bytes */3"));
+        let mut c = make_test_cache(rmt::FakeClient::new(
+            url.clone(),
+            resume_headers(),
+            rmt::FakeResponse {
+                status: reqwest::StatusCode::RANGE_NOT_SATISFIABLE,
+                headers: response_headers,
+                body: io::Cursor::new(b"".as_ref().into()),
+            },
+        ));
+        // We crashed after receiving the whole body.
+        let path = make_partial_entry(&mut c, &url, b"hel");
+        let record = c.db.get(url.clone()).unwrap();
+        c.db.set(
+            url.clone(),
+            super::db::CacheRecord {
+                declared_length: Some(3),
+                ..record
+            },
+        )
+        .unwrap()
+        .commit()
+        .unwrap();
+
+        let (mut file, status) = c.get_with_status(url.clone()).unwrap();
+        c.client.assert_called();
+        let mut buf = vec![];
+        file.read_to_end(&mut buf).unwrap();
+        assert_eq!(&buf, b"hel");
+        assert_eq!(status, CacheStatus::Revalidated);
+
+        let record = c.db.get(url).unwrap();
+        assert!(record.complete);
+        assert!(record.path.starts_with("content"));
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn restart_interrupted_download_whose_range_is_not_satisfiable() {
+        let _ = env_logger::try_init();
+
+        let url: reqwest::Url = "http://example.com/big".parse().unwrap();
+        let mut c = make_test_cache(rmt::SequenceClient::new(vec![
+            rmt::FakeClient::new(
+                url.clone(),
+                resume_headers(),
+                rmt::FakeResponse {
+                    status: reqwest::StatusCode::RANGE_NOT_SATISFIABLE,
+                    headers: HeaderMap::new(),
+                    body: io::Cursor::new(b"".as_ref().into()),
+                },
+            ),
+            // We don't know how long the content is, so we ask for all of it.
+            rmt::FakeClient::new(
+                url.clone(),
+                HeaderMap::new(),
+                rmt::FakeResponse {
+                    status: reqwest::StatusCode::OK,
+                    headers: HeaderMap::new(),
+                    body: io::Cursor::new(b"hello".as_ref().into()),
+                },
+            ),
+        ]));
+        let path = make_partial_entry(&mut c, &url, b"hel");
+
+        assert_eq!(c.get_bytes(url.clone()).unwrap(), b"hello");
+        assert!(c.db.get(url).unwrap().complete);
+        assert!(!path.exists());
+        c.client.assert_called();
+    }
+
+    #[test]
+    fn restart_interrupted_download_given_the_wrong_range() {
+        let _ = env_logger::try_init();
+
+        let url: reqwest::Url = "http://example.com/big".parse().unwrap();
+        let mut response_headers = HeaderMap::new();
+        response_headers
+            .append(CONTENT_RANGE, HeaderValue::from_static("bytes 0-4/5"));
+        let mut c = make_test_cache(rmt::SequenceClient::new(vec![
+            rmt::FakeClient::new(
+                url.clone(),
+                resume_headers(),
+                rmt::FakeResponse {
+                    status: reqwest::StatusCode::PARTIAL_CONTENT,
+                    headers: response_headers,
+                    body: io::Cursor::new(b"hello".as_ref().into()),
+                },
+            ),
+            rmt::FakeClient::new(
+                url.clone(),
+                HeaderMap::new(),
+                rmt::FakeResponse {
+                    status: reqwest::StatusCode::OK,
+                    headers: HeaderMap::new(),
+                    body: io::Cursor::new(b"hello".as_ref().into()),
+                },
+            ),
+        ]));
+        let path = make_partial_entry(&mut c, &url, b"hel");
+
+        assert_eq!(c.get_bytes(url.clone()).unwrap(), b"hello");
+        assert!(c.db.get(url).unwrap().complete);
+        assert!(!path.exists());
+        c.client.assert_called();
+    }
+
+    #[test]
+    fn get_with_status_says_how_content_was_obtained() {
+        use super::CacheStatus;
+
+        let _ = env_logger::try_init();
+
+        let url: reqwest::Url = "http://example.com/".parse().unwrap();
+        let response = |status, etag, body: &[u8]| {
+            let mut headers = HeaderMap::new();
+            headers.append(ETAG, HeaderValue::from_static(etag));
+            rmt::FakeResponse {
+                status,
+                headers,
+                body: io::Cursor::new(body.into()),
+            }
+        };
+        let if_none_match = |etag| {
+            let mut headers = HeaderMap::new();
+            headers.append(IF_NONE_MATCH, HeaderValue::from_static(etag));
+            headers
+        };
+
+        // The first request has to download the content.
+        let mut c = make_test_cache(rmt::FakeClient::new(
+            url.clone(),
+            HeaderMap::new(),
+            response(reqwest::StatusCode::OK, "\"abcd\"", b"hello"),
+        ));
+        let (_, status) = c.get_with_status(url.clone()).unwrap();
+        c.client.assert_called();
+        assert_eq!(status, CacheStatus::Downloaded);
+
+        // If the server says it's unchanged, we revalidated our copy.
+        c.client = rmt::FakeClient::new(
+            url.clone(),
+            if_none_match("\"abcd\""),
+            response(reqwest::StatusCode::NOT_MODIFIED, "\"abcd\"", b""),
+        );
+        let (_, status) = c.get_with_status(url.clone()).unwrap();
+        c.client.assert_called();
+        assert_eq!(status, CacheStatus::Revalidated);
+
+        // If the server has new content, we download it.
+        c.client = rmt::FakeClient::new(
+            url.clone(),
+            if_none_match("\"abcd\""),
+            response(reqwest::StatusCode::OK, "\"efgh\"", b"world"),
+        );
+        let (mut file, status) = c.get_with_status(url).unwrap();
+        c.client.assert_called();
+        assert_eq!(status, CacheStatus::Downloaded);
+        let mut buf = vec![];
+        file.read_to_end(&mut buf).unwrap();
+        assert_eq!(&buf, b"world");
+    }
+
+    /// Caches `url` with the given `Cache-Control` header, then retrieves it
+    /// again under `policy`, returning whether that needed the network.
+    fn revalidates_under_policy(
+        policy: super::RevalidationPolicy,
+        cache_control: &'static str,
+    ) -> bool {
+        let _ = env_logger::try_init();
+
+        let url: reqwest::Url = "http://example.com/".parse().unwrap();
+
+        let mut response_headers = HeaderMap::new();
+        response_headers
+            .append(CACHE_CONTROL, HeaderValue::from_static(cache_control));
+        response_headers.append(ETAG, HeaderValue::from_static("\"abcd\""));
+        let mut c = make_test_cache(rmt::FakeClient::new(
+            url.clone(),
+            HeaderMap::new(),
+            rmt::FakeResponse {
+                status: reqwest::StatusCode::OK,
+                headers: response_headers,
+                body: io::Cursor::new(b"hello".as_ref().into()),
+            },
+        ));
+        c.set_revalidation(policy);
+        c.get(url.clone()).unwrap();
+        c.client.assert_called();
+
+        let mut revalidate_headers = HeaderMap::new();
+        revalidate_headers
+            .append(IF_NONE_MATCH, HeaderValue::from_static("\"abcd\""));
+        c.client = rmt::FakeClient::new(
+            url.clone(),
+            revalidate_headers,
+            rmt::FakeResponse {
+                status: reqwest::StatusCode::NOT_MODIFIED,
+                headers: HeaderMap::new(),
+                body: io::Cursor::new(vec![]),
+            },
+        );
+        let mut buf = vec![];
+        c.get(url).unwrap().read_to_end(&mut buf).unwrap();
+        assert_eq!(&buf, b"hello");
+        c.client.was_called()
+    }
+
+    #[test]
+    fn always_revalidate() {
+        use super::RevalidationPolicy::Always;
+        assert!(revalidates_under_policy(Always, "max-age=3600"));
+        assert!(revalidates_under_policy(Always, "no-transform"));
+    }
+
+    #[test]
+    fn revalidate_when_stale() {
+        use super::RevalidationPolicy::WhenStale;
+        assert!(!revalidates_under_policy(WhenStale, "max-age=3600"));
+        assert!(revalidates_under_policy(WhenStale, "max-age=0"));
+        assert!(revalidates_under_policy(WhenStale, "no-transform"));
+    }
+
+    #[test]
+    fn never_revalidate() {
+        use super::RevalidationPolicy::Never;
+        assert!(!revalidates_under_policy(Never, "max-age=0"));
+        assert!(!revalidates_under_policy(Never, "no-transform"));
+    }
+
+    #[test]
+    fn serve_seeded_entry_offline() {
+        let _ = env_logger::try_init();
+
+        let url: reqwest::Url = "http://example.com/asset".parse().unwrap();
+
+        // The server is unreachable, so we can only revalidate in vain.
+        let mut request_headers = HeaderMap::new();
+        request_headers
+            .append(IF_NONE_MATCH, HeaderValue::from_static("\"abcd\""));
+        let mut c = make_test_cache(rmt::BrokenClient::new(
+            url.clone(),
+            request_headers,
+            || rmt::FakeError,
+        ));
+
+        let src = c.root.join("asset");
+        std::fs::write(&src, b"seeded").unwrap();
+        c.insert_local(url.clone(), &src, None, Some("\"abcd\"".into()))
+            .unwrap();
+
+        let mut buf = vec![];
+        c.get(url).unwrap().read_to_end(&mut buf).unwrap();
+        c.client.assert_called();
+        assert_eq!(&buf, b"seeded");
+    }
+
+    #[test]
+    fn seeding_again_replaces_the_content() {
+        let _ = env_logger::try_init();
+
+        let url: reqwest::Url = "http://example.com/asset".parse().unwrap();
+        let mut c = make_test_cache(rmt::SequenceClient::new(vec![]));
+        let src = c.root.join("asset");
+        std::fs::write(&src, b"first").unwrap();
+        c.insert_local(url.clone(), &src, None, None).unwrap();
+        let old = c.root.join(c.metadata(url.clone()).unwrap().unwrap().path);
+
+        std::fs::write(&src, b"second").unwrap();
+        c.insert_local(url.clone(), &src, None, None).unwrap();
+
+        let record = c.metadata(url).unwrap().unwrap();
+        assert_eq!(
+            std::fs::read(c.root.join(&record.path)).unwrap(),
+            b"second"
+        );
+        assert!(!old.exists());
+        assert_eq!(content_files(&c), 1);
+    }
+
+    #[test]
+    fn export_and_import_round_trip() {
+        let _ = env_logger::try_init();
+
+        let url_1: reqwest::Url = "http://example.com/one".parse().unwrap();
+        let url_2: reqwest::Url = "http://example.com/two".parse().unwrap();
+        let response = |body: &[u8]| {
+            let mut headers = HeaderMap::new();
+            headers.append(ETAG, HeaderValue::from_static("\"abcd\""));
+            headers.append(LAST_MODIFIED, HeaderValue::from_static(DATE_ZERO));
+            rmt::FakeResponse {
+                status: reqwest::StatusCode::OK,
+                headers,
+                body: io::Cursor::new(body.into()),
+            }
+        };
+
+        let mut c = make_test_cache(rmt::SequenceClient::new(vec![
+            rmt::FakeClient::new(
+                url_1.clone(),
+                HeaderMap::new(),
+                response(b"one"),
+            ),
+            rmt::FakeClient::new(
+                url_2.clone(),
+                HeaderMap::new(),
+                response(b"two"),
+            ),
+        ]));
+        c.get(url_1.clone()).unwrap();
+        c.get(url_2.clone()).unwrap();
+
+        let mut archive = vec![];
+        c.export(&mut archive).unwrap();
+        c.client.assert_called();
+
+        // Import into a fresh cache that never talks to the network.
+        let mut c = make_test_cache(rmt::FakeClient::new(
+            url_1.clone(),
+            HeaderMap::new(),
+            response(b"network"),
+        ));
+        c.set_revalidation(super::RevalidationPolicy::Never);
+        c.import(&archive[..]).unwrap();
+
+        for (url, body) in [(url_1, b"one"), (url_2, b"two")] {
+            let record = c.db.get(url.clone()).unwrap();
+            assert_eq!(record.last_modified.as_deref(), Some(DATE_ZERO));
+            assert_eq!(
+                record.etags,
+                vec![super::db::ETag::parse("\"abcd\"")]
+            );
+
+            let (mut file, status) = c.get_with_status(url).unwrap();
+            assert_eq!(status, super::CacheStatus::Fresh);
+            let mut buf = vec![];
+            file.read_to_end(&mut buf).unwrap();
+            assert_eq!(&buf, body);
+        }
+        assert!(!c.client.was_called());
+    }
+
+    /// Returns an archive holding `index` and files with the given names and
+    /// contents, in order.
+    fn make_archive(index: &str, files: &[(&str, &[u8])]) -> Vec<u8> {
+        let mut archive = tar::Builder::new(vec![]);
+        for (name, content) in std::iter::once(("index.tsv", index.as_bytes()))
+            .chain(files.iter().copied())
+        {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(content.len() as u64);
+            header.set_mode(0o644);
+            header.set_cksum();
+            archive.append_data(&mut header, name, content).unwrap();
+        }
+        archive.into_inner().unwrap()
+    }
+
+    /// Returns how many content files `c` holds.
+    fn content_files<C: super::reqwest_mock::Client>(
+        c: &super::Cache<C>,
+    ) -> usize {
+        std::fs::read_dir(c.root.join("content"))
+            .map_or(0, |entries| entries.count())
+    }
+
+    #[test]
+    fn failed_import_leaves_nothing_behind() {
+        let _ = env_logger::try_init();
+
+        let mut c = make_test_cache(rmt::SequenceClient::new(vec![]));
+        for index in &[
+            // Too far in the future to represent.
+            "http://example.com/\tone\t\t\t18446744073709551615\t\n",
+            "http://example.com/\tone\tmalformed\n",
+        ] {
+            let archive = make_archive(index, &[("one", &b"one"[..])]);
+            assert!(c.import(&archive[..]).is_err());
+            assert_eq!(c.db.records().unwrap(), vec![]);
+            assert_eq!(content_files(&c), 0);
+        }
+    }
+
+    #[test]
+    fn import_keeps_only_the_content_it_uses() {
+        let _ = env_logger::try_init();
+
+        let url: reqwest::Url = "http://example.com/".parse().unwrap();
+        let mut c = make_test_cache(rmt::FakeClient::new(
+            url.clone(),
+            HeaderMap::new(),
+            rmt::FakeResponse {
+                status: reqwest::StatusCode::OK,
+                headers: HeaderMap::new(),
+                body: io::Cursor::new(b"old".as_ref().into()),
+            },
+        ));
+        c.get(url.clone()).unwrap();
+        let old = c.root.join(c.metadata(url.clone()).unwrap().unwrap().path);
+
+        // The second file named "one" replaces the first, and "unused"
+        // isn't in the index.
+        let archive = make_archive(
+            "http://example.com/\tone\t\t\t\t\n",
+            &[
+                ("one", &b"first"[..]),
+                ("one", &b"second"[..]),
+                ("unused", &b"unused"[..]),
+            ],
+        );
+        c.import(&archive[..]).unwrap();
+
+        let record = c.metadata(url).unwrap().unwrap();
+        assert_eq!(
+            std::fs::read(c.root.join(&record.path)).unwrap(),
+            b"second"
+        );
+        assert!(!old.exists());
+        assert_eq!(content_files(&c), 1);
+    }
+
+    #[test]
+    fn refresh_all_counts_each_outcome() {
+        let _ = env_logger::try_init();
+
+        let urls: Vec<reqwest::Url> = vec![
+            "http://example.com/a".parse().unwrap(),
+            "http://example.com/b".parse().unwrap(),
+            "http://example.com/c".parse().unwrap(),
+        ];
+        let response = |status, body: &[u8]| {
+            let mut headers = HeaderMap::new();
+            headers.append(ETAG, HeaderValue::from_static("\"abcd\""));
+            rmt::FakeResponse {
+                status,
+                headers,
+                body: io::Cursor::new(body.into()),
+            }
+        };
+        let mut revalidate_headers = HeaderMap::new();
+        revalidate_headers
+            .append(IF_NONE_MATCH, HeaderValue::from_static("\"abcd\""));
+
+        let mut c = make_test_cache(rmt::SequenceClient::new(
+            urls.iter()
+                .map(|url| {
+                    rmt::FakeClient::new(
+                        url.clone(),
+                        HeaderMap::new(),
+                        response(reqwest::StatusCode::OK, b"old"),
+                    )
+                })
+                .collect(),
+        ));
+        for url in &urls {
+            c.get(url.clone()).unwrap();
+        }
+        c.client.assert_called();
+
+        // Entries are refreshed in URL order: the first is unchanged,
+        // the second has new content, and the third fails.
+        c.client = rmt::SequenceClient::new(vec![
+            rmt::FakeClient::new(
+                urls[0].clone(),
+                revalidate_headers.clone(),
+                response(reqwest::StatusCode::NOT_MODIFIED, b""),
+            ),
+            rmt::FakeClient::new(
+                urls[1].clone(),
+                revalidate_headers.clone(),
+                response(reqwest::StatusCode::OK, b"new"),
+            ),
+            rmt::FakeClient::new(
+                urls[2].clone(),
+                revalidate_headers,
+                response(reqwest::StatusCode::INTERNAL_SERVER_ERROR, b""),
+            ),
+        ]);
+        let summary = c.refresh_all().unwrap();
+        c.client.assert_called();
+        assert_eq!(
+            summary,
+            super::RefreshSummary {
+                unchanged: 1,
+                updated: 1,
+                failed: 1,
+            }
+        );
+
+        let mut buf = vec![];
+        let record = c.db.get(urls[1].clone()).unwrap();
+        std::fs::File::open(c.root.join(record.path))
+            .unwrap()
+            .read_to_end(&mut buf)
+            .unwrap();
+        assert_eq!(&buf, b"new");
+    }
+
+    #[test]
+    fn refreshes_ask_for_the_url_that_was_retrieved() {
+        let _ = env_logger::try_init();
+
+        let url: reqwest::Url =
+            "http://example.com/data?token=secret".parse().unwrap();
+        let key: reqwest::Url = "http://example.com/data".parse().unwrap();
+        let mut response_headers = HeaderMap::new();
+        response_headers.append(ETAG, HeaderValue::from_static("\"abcd\""));
+        let response = rmt::FakeResponse {
+            status: reqwest::StatusCode::OK,
+            headers: response_headers,
+            body: io::Cursor::new(b"hello".as_ref().into()),
+        };
+        let mut c = super::Cache::builder(
+            tempdir::TempDir::new("http-cache-test")
+                .unwrap()
+                .into_path(),
+            rmt::FakeClient::new(url.clone(), HeaderMap::new(), response.clone()),
+        )
+        .ignore_query_params(vec!["token".into()])
+        .build()
+        .unwrap();
+        c.get(url.clone()).unwrap();
+        assert_eq!(c.db.get(key).unwrap().etags.len(), 1);
+
+        // Refreshing asks for the URL with its token, not the key.
+        let mut expected = HeaderMap::new();
+        expected.insert(IF_NONE_MATCH, HeaderValue::from_static("\"abcd\""));
+        c.client = rmt::FakeClient::new(url, expected, response);
+        assert_eq!(c.refresh_all().unwrap().updated, 1);
+        c.client.assert_called();
+    }
+
+    #[test]
+    fn no_cache_always_revalidates() {
+        use super::RevalidationPolicy::{Never, WhenStale};
+        assert!(revalidates_under_policy(WhenStale, "no-cache, max-age=3600"));
+        assert!(revalidates_under_policy(Never, "no-cache"));
+    }
+
+    #[test]
+    fn no_store_response_is_not_recorded() {
+        let _ = env_logger::try_init();
+
+        let url: reqwest::Url = "http://example.com/secret".parse().unwrap();
+        let mut response_headers = HeaderMap::new();
+        response_headers
+            .append(CACHE_CONTROL, HeaderValue::from_static("no-store"));
+        let mut c = make_test_cache(rmt::FakeClient::new(
+            url.clone(),
+            HeaderMap::new(),
+            rmt::FakeResponse {
+                status: reqwest::StatusCode::OK,
+                headers: response_headers,
+                body: io::Cursor::new(b"hello".as_ref().into()),
+            },
+        ));
+
+        let mut buf = vec![];
+        c.get(url.clone()).unwrap().read_to_end(&mut buf).unwrap();
+        c.client.assert_called();
+        assert_eq!(&buf, b"hello");
+
+        assert!(c.db.records().unwrap().is_empty());
+        assert!(!c.root.join("content").exists());
+    }
+
+    fn interrupted_download_is_not_referenced_as_complete<S: super::Storage>(
+        storage: S,
+    ) {
+        let _ = env_logger::try_init();
+
+        let url: reqwest::Url = "http://example.com/big".parse().unwrap();
+        let mut c = make_test_cache_in(
+            rmt::TruncatingClient(rmt::FakeClient::new(
+                url.clone(),
+                HeaderMap::new(),
+                rmt::FakeResponse {
+                    status: reqwest::StatusCode::OK,
+                    headers: HeaderMap::new(),
+                    body: io::Cursor::new(b"hel".as_ref().into()),
+                },
+            )),
+            storage,
+        );
+
+        assert!(c.get_bytes(url.clone()).is_err());
+
+        // The partial download is kept aside, so it can be resumed,
+        // but nothing refers to a file in the content directory.
+        let record = c.db.get(url).unwrap();
+        assert!(!record.complete);
+        assert!(record.path.starts_with("partial"));
+        assert_eq!(stored(&c, &c.root.join(record.path)).unwrap(), b"hel");
+        assert!(!c.root.join("content").exists());
+    }
+
+    #[test]
+    fn interrupted_replacement_keeps_cached_content() {
+        let _ = env_logger::try_init();
+
+        let (c, url) = cached_for_revalidation(rmt::FakeResponse {
+            status: reqwest::StatusCode::NOT_MODIFIED,
+            headers: HeaderMap::new(),
+            body: io::Cursor::new(b"".as_ref().into()),
+        });
+        let old = c.metadata(url.clone()).unwrap().unwrap();
+
+        // The content changed, but the connection drops while we download
+        // the new version.
+        let mut c = super::Cache::new(
+            c.root.clone(),
+            rmt::TruncatingClient(rmt::FakeClient::new(
+                url.clone(),
+                c.client.expected_headers.clone(),
+                rmt::FakeResponse {
+                    status: reqwest::StatusCode::OK,
+                    headers: HeaderMap::new(),
+                    body: io::Cursor::new(b"hel".as_ref().into()),
+                },
+            )),
+        )
+        .unwrap();
+        assert!(c.get(url.clone()).is_err());
+        c.client.0.assert_called();
+
+        // The entry still refers to the old content, which is still there,
+        // and the partial download was thrown away.
+        assert_eq!(c.metadata(url).unwrap().unwrap(), old);
+        assert_eq!(std::fs::read(c.root.join(&old.path)).unwrap(), b"hello");
+        let partials = std::fs::read_dir(c.root.join("partial"))
+            .map_or(0, |entries| entries.count());
+        assert_eq!(partials, 0);
+    }
+
+    fn replacement_removes_the_old_content<S: super::Storage>(storage: S) {
+        let _ = env_logger::try_init();
+
+        let (mut c, url) = cached_for_revalidation_in(
+            rmt::FakeResponse {
+                status: reqwest::StatusCode::OK,
+                headers: HeaderMap::new(),
+                body: io::Cursor::new(b"world".as_ref().into()),
+            },
+            storage,
+        );
+        let old = c.root.join(c.metadata(url.clone()).unwrap().unwrap().path);
+
+        assert_eq!(c.get_bytes(url.clone()).unwrap(), b"world");
+        c.client.assert_called();
+        assert_eq!(stored(&c, &old), None);
+    }
+
+    #[test]
+    fn shared_cache_across_threads() {
+        let _ = env_logger::try_init();
+
+        let shared_url: reqwest::Url =
+            "http://example.com/shared".parse().unwrap();
+        let distinct_urls: Vec<reqwest::Url> = (0..4)
+            .map(|i| format!("http://example.com/{}", i).parse().unwrap())
+            .collect();
+
+        let mut client = rmt::SharedClient::default();
+        for url in distinct_urls.iter().chain(Some(&shared_url)) {
+            client.responses.insert(
+                url.clone(),
+                rmt::FakeResponse {
+                    status: reqwest::StatusCode::OK,
+                    headers: HeaderMap::new(),
+                    body: io::Cursor::new(url.path().as_bytes().into()),
+                },
+            );
+        }
+        let requests = client.requests.clone();
+
+        let cache = std::sync::Arc::new(
+            super::Cache::builder(
+                tempdir::TempDir::new("http-cache-test")
+                    .unwrap()
+                    .into_path(),
+                client,
+            )
+            .revalidation(super::RevalidationPolicy::Never)
+            .build_shared()
+            .unwrap(),
+        );
+
+        let threads: Vec<_> = distinct_urls
+            .iter()
+            .cloned()
+            .map(|url| {
+                let cache = cache.clone();
+                let shared_url = shared_url.clone();
+                std::thread::spawn(move || {
+                    for url in [shared_url, url] {
+                        let mut buf = vec![];
+                        cache
+                            .get(url.clone())
+                            .unwrap()
+                            .read_to_end(&mut buf)
+                            .unwrap();
+                        assert_eq!(buf, url.path().as_bytes());
+                    }
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+
+        // Every URL, including the one all the threads wanted, should have
+        // been downloaded exactly once.
+        let mut requests = requests.lock().unwrap().clone();
+        requests.sort();
+        let mut expected = distinct_urls.clone();
+        expected.push(shared_url);
+        expected.sort();
+        assert_eq!(requests, expected);
+    }
+
+    #[test]
+    fn shared_cache_reuses_connections() {
+        let _ = env_logger::try_init();
+
+        let mut client = rmt::SharedClient::default();
+        let urls: Vec<reqwest::Url> = (0..16)
+            .map(|i| format!("http://example.com/{}", i).parse().unwrap())
+            .collect();
+        for url in &urls {
+            client.responses.insert(
+                url.clone(),
+                rmt::FakeResponse {
+                    status: reqwest::StatusCode::OK,
+                    headers: HeaderMap::new(),
+                    body: io::Cursor::new(b"hello".as_ref().into()),
+                },
+            );
+        }
+        let cache = std::sync::Arc::new(
+            super::SharedCache::new(
+                tempdir::TempDir::new("http-cache-test")
+                    .unwrap()
+                    .into_path(),
+                client,
+            )
+            .unwrap(),
+        );
+
+        // Each thread fetches its own URLs several times over, but should
+        // only ever need one connection to the metadata DB.
+        let threads: Vec<_> = urls
+            .chunks(4)
+            .map(|chunk| {
+                let cache = cache.clone();
+                let chunk = chunk.to_vec();
+                std::thread::spawn(move || {
+                    for _ in 0..3 {
+                        for url in &chunk {
+                            cache.get(url.clone()).unwrap();
+                        }
+                    }
+                    cache.idle_connections()
+                })
+            })
+            .collect();
+        for thread in threads {
+            assert_eq!(thread.join().unwrap(), 1);
+        }
+
+        // That's one per thread, after the one the builder checked the
+        // database with.
+        let opened = super::db::OPENED.lock().unwrap().as_ref().unwrap()
+            [&cache.root().join("cache.db")];
+        assert_eq!(opened, 1 + urls.len() / 4);
+    }
+
+    #[test]
+    fn shared_caches_keep_their_own_connections() {
+        let _ = env_logger::try_init();
+
+        let url: reqwest::Url = "http://example.com/".parse().unwrap();
+        let mut client = rmt::SharedClient::default();
+        client.responses.insert(
+            url.clone(),
+            rmt::FakeResponse {
+                status: reqwest::StatusCode::OK,
+                headers: HeaderMap::new(),
+                body: io::Cursor::new(b"hello".as_ref().into()),
+            },
+        );
+        let root = tempdir::TempDir::new("http-cache-test")
+            .unwrap()
+            .into_path();
+        let one = super::SharedCache::new(root.clone(), client.clone()).unwrap();
+        let other = super::Cache::builder(root, client)
+            .read_only(true)
+            .build_shared()
+            .unwrap();
+
+        // The same database, but opened with different settings, so they
+        // mustn't share connections.
+        one.get(url.clone()).unwrap();
+        assert_eq!(one.idle_connections(), 1);
+        assert_eq!(other.idle_connections(), 0);
+        other.get(url).unwrap();
+        assert_eq!(one.idle_connections(), 1);
+        assert_eq!(other.idle_connections(), 1);
+
+        // Every handle to a cache shares its pool, which keeps the
+        // connections until the last of them is gone.
+        let pool = one.pool.0;
+        let handle = one.pool.clone();
+        drop(one);
+        assert_eq!(super::shared::idle_connections(pool), 1);
+        drop(handle);
+        assert_eq!(super::shared::idle_connections(pool), 0);
+    }
+
+    #[test]
+    fn shared_cache_gets_distinct_urls_in_parallel() {
+        let _ = env_logger::try_init();
+
+        let delay = std::time::Duration::from_millis(100);
+        let mut client = rmt::SharedClient::default();
+        let urls: Vec<reqwest::Url> = (0..8)
+            .map(|i| format!("http://example.com/{}", i).parse().unwrap())
+            .collect();
+        for url in &urls {
+            client.responses.insert(
+                url.clone(),
+                rmt::FakeResponse {
+                    status: reqwest::StatusCode::OK,
+                    headers: HeaderMap::new(),
+                    body: io::Cursor::new(b"hello".as_ref().into()),
+                },
+            );
+        }
+        client.delay = delay;
+        let cache = std::sync::Arc::new(
+            super::SharedCache::new(
+                tempdir::TempDir::new("http-cache-test")
+                    .unwrap()
+                    .into_path(),
+                client,
+            )
+            .unwrap(),
+        );
+
+        // One after the other, these would take at least 800ms; with a
+        // connection each, the threads shouldn't wait for one another.
+        let start = std::time::Instant::now();
+        let threads: Vec<_> = urls
+            .iter()
+            .map(|url| {
+                let (cache, url) = (cache.clone(), url.clone());
+                std::thread::spawn(move || {
+                    let mut buf = vec![];
+                    cache.get(url).unwrap().read_to_end(&mut buf).unwrap();
+                    buf
+                })
+            })
+            .collect();
+        for thread in threads {
+            assert_eq!(thread.join().unwrap(), b"hello");
+        }
+        assert!(
+            start.elapsed() < delay * urls.len() as u32 / 2,
+            "took {:?}",
+            start.elapsed()
+        );
+    }
+
+    #[test]
+    fn last_status_is_recorded() {
+        let _ = env_logger::try_init();
+
+        let url: reqwest::Url = "http://example.com/".parse().unwrap();
+        let mut response_headers = HeaderMap::new();
+        response_headers.append(ETAG, HeaderValue::from_static("\"abcd\""));
+        let mut c = make_test_cache(rmt::FakeClient::new(
+            url.clone(),
+            HeaderMap::new(),
+            rmt::FakeResponse {
+                status: reqwest::StatusCode::NON_AUTHORITATIVE_INFORMATION,
+                headers: response_headers,
+                body: io::Cursor::new(b"hello".as_ref().into()),
+            },
+        ));
+        assert_eq!(c.last_status(url.clone()).unwrap(), None);
+
+        c.get(url.clone()).unwrap();
+        c.client.assert_called();
+        assert_eq!(
+            c.last_status(url.clone()).unwrap(),
+            Some(reqwest::StatusCode::NON_AUTHORITATIVE_INFORMATION)
+        );
+
+        let mut revalidate_headers = HeaderMap::new();
+        revalidate_headers
+            .append(IF_NONE_MATCH, HeaderValue::from_static("\"abcd\""));
+        c.client = rmt::FakeClient::new(
+            url.clone(),
+            revalidate_headers,
+            rmt::FakeResponse {
+                status: reqwest::StatusCode::NOT_MODIFIED,
+                headers: HeaderMap::new(),
+                body: io::Cursor::new(vec![]),
+            },
+        );
+        c.get(url.clone()).unwrap();
+        c.client.assert_called();
+        assert_eq!(
+            c.last_status(url).unwrap(),
+            Some(reqwest::StatusCode::NOT_MODIFIED)
+        );
+    }
+
+    #[test]
+    fn not_modified_response_updates_validators_and_freshness() {
+        let _ = env_logger::try_init();
+
+        let url: reqwest::Url = "http://example.com/".parse().unwrap();
+
+        // The original response must be revalidated straight away.
+        let mut response_headers = HeaderMap::new();
+        response_headers.append(ETAG, HeaderValue::from_static("\"abcd\""));
+        response_headers
+            .append(CACHE_CONTROL, HeaderValue::from_static("max-age=0"));
+        let mut c = make_test_cache(rmt::FakeClient::new(
+            url.clone(),
+            HeaderMap::new(),
+            rmt::FakeResponse {
+                status: reqwest::StatusCode::OK,
+                headers: response_headers,
+                body: io::Cursor::new(b"hello".as_ref().into()),
+            },
+        ));
+        c.set_revalidation(super::RevalidationPolicy::WhenStale);
+        c.get(url.clone()).unwrap();
+        c.client.assert_called();
+
+        // The server confirms it's unchanged, with a new ETag, and says
+        // it's good for an hour.
+        let mut revalidate_headers = HeaderMap::new();
+        revalidate_headers
+            .append(IF_NONE_MATCH, HeaderValue::from_static("\"abcd\""));
+        let mut not_modified_headers = HeaderMap::new();
+        not_modified_headers
+            .append(ETAG, HeaderValue::from_static("\"efgh\""));
+        not_modified_headers
+            .append(CACHE_CONTROL, HeaderValue::from_static("max-age=3600"));
+        c.client = rmt::FakeClient::new(
+            url.clone(),
+            revalidate_headers,
+            rmt::FakeResponse {
+                status: reqwest::StatusCode::NOT_MODIFIED,
+                headers: not_modified_headers,
+                body: io::Cursor::new(vec![]),
+            },
+        );
+        c.get(url.clone()).unwrap();
+        c.client.assert_called();
+
+        let record = c.db.get(url.clone()).unwrap();
+        assert_eq!(record.etags, vec![super::db::ETag::parse("\"efgh\"")]);
+
+        // So the next retrieval doesn't need the network at all.
+        c.client = rmt::FakeClient::new(
+            url.clone(),
+            HeaderMap::new(),
+            rmt::FakeResponse {
+                status: reqwest::StatusCode::OK,
+                headers: HeaderMap::new(),
+                body: io::Cursor::new(vec![]),
+            },
+        );
+        let (mut file, status) = c.get_with_status(url).unwrap();
+        assert!(!c.client.was_called());
+        assert_eq!(status, super::CacheStatus::Fresh);
+        let mut buf = vec![];
+        file.read_to_end(&mut buf).unwrap();
+        assert_eq!(&buf, b"hello");
+    }
+
+    /// Caches "hello" for a URL, then revalidates it getting `status`.
+    fn revalidate_with_status(
+        status: reqwest::StatusCode,
+        evict_when_gone: bool,
+    ) -> (
+        super::Cache<rmt::FakeClient>,
+        reqwest::Url,
+        Result<Vec<u8>, anyhow::Error>,
+    ) {
+        let url: reqwest::Url = "http://example.com/".parse().unwrap();
+        let mut response_headers = HeaderMap::new();
+        response_headers.append(ETAG, HeaderValue::from_static("\"abcd\""));
+        let mut c = super::Cache::builder(
+            tempdir::TempDir::new("http-cache-test")
+                .unwrap()
+                .into_path(),
+            rmt::FakeClient::new(
+                url.clone(),
+                HeaderMap::new(),
+                rmt::FakeResponse {
+                    status: reqwest::StatusCode::OK,
+                    headers: response_headers,
+                    body: io::Cursor::new(b"hello".as_ref().into()),
+                },
+            ),
+        )
+        .evict_when_gone(evict_when_gone)
+        .build()
+        .unwrap();
+        c.get(url.clone()).unwrap();
+        c.client.assert_called();
+
+        let mut revalidate_headers = HeaderMap::new();
+        revalidate_headers
+            .append(IF_NONE_MATCH, HeaderValue::from_static("\"abcd\""));
+        c.client = rmt::FakeClient::new(
+            url.clone(),
+            revalidate_headers,
+            rmt::FakeResponse {
+                status,
+                headers: HeaderMap::new(),
+                body: io::Cursor::new(vec![]),
+            },
+        );
+        let result = c.get_bytes(url.clone());
+        (c, url, result)
+    }
+
+    #[test]
+    fn gone_resource_keeps_cached_copy_by_default() {
+        let _ = env_logger::try_init();
+
+        let (c, url, result) =
+            revalidate_with_status(reqwest::StatusCode::GONE, false);
+        assert_eq!(result.unwrap(), b"hello");
+        assert!(c.db.get(url).is_ok());
+        c.client.assert_called();
+    }
+
+    #[test]
+    fn gone_resource_is_evicted_when_configured() {
+        let _ = env_logger::try_init();
+
+        let (c, url, result) =
+            revalidate_with_status(reqwest::StatusCode::NOT_FOUND, true);
+        assert_eq!(
+            result.unwrap_err().downcast::<super::CacheError>().unwrap(),
+            super::CacheError::Gone {
+                url: url.clone(),
+                status: reqwest::StatusCode::NOT_FOUND,
+            }
+        );
+        assert!(c.db.get(url).is_err());
+        assert_eq!(
+            std::fs::read_dir(c.root.join("content")).unwrap().count(),
+            0
+        );
+        c.client.assert_called();
+    }
+
+    #[test]
+    fn unexpected_revalidation_status_keeps_cached_copy() {
+        let _ = env_logger::try_init();
+
+        // A 204 has an empty body, which must not replace our data.
+        let (c, _, result) =
+            revalidate_with_status(reqwest::StatusCode::NO_CONTENT, true);
+        assert_eq!(result.unwrap(), b"hello");
+        c.client.assert_called();
+
+        let (c, _, result) = revalidate_with_status(
+            reqwest::StatusCode::INTERNAL_SERVER_ERROR,
+            true,
+        );
+        assert_eq!(result.unwrap(), b"hello");
+        c.client.assert_called();
+    }
+
+    #[test]
+    fn metadata_describes_cached_entries() {
+        let _ = env_logger::try_init();
+
+        let url: reqwest::Url = "http://example.com/".parse().unwrap();
+        let mut response_headers = HeaderMap::new();
+        response_headers.append(ETAG, HeaderValue::from_static("\"abcd\""));
+        response_headers.append(
+            LAST_MODIFIED,
+            HeaderValue::from_static("Thu, 01 Jan 1970 00:00:00 GMT"),
+        );
+        let mut c = make_test_cache(rmt::FakeClient::new(
+            url.clone(),
+            HeaderMap::new(),
+            rmt::FakeResponse {
+                status: reqwest::StatusCode::OK,
+                headers: response_headers,
+                body: io::Cursor::new(b"hello".as_ref().into()),
+            },
+        ));
+        assert_eq!(c.metadata(url.clone()).unwrap(), None);
+
+        c.get(url.clone()).unwrap();
+        c.client.assert_called();
+
+        // The fragment is ignored, as it is when retrieving.
+        let record = c
+            .metadata("http://example.com/#top")
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            record.last_modified.as_deref(),
+            Some("Thu, 01 Jan 1970 00:00:00 GMT")
+        );
+        assert_eq!(record.etags, vec![super::ETag::parse("\"abcd\"")]);
+        assert!(record.complete);
+        assert_eq!(record.status, Some(200));
+        assert_eq!(std::fs::read(c.root.join(&record.path)).unwrap(), b"hello");
+
+        // And the entry can be found from its content.
+        assert_eq!(
+            c.url_for_path(&c.root.join(&record.path)).unwrap(),
+            Some(url.clone())
+        );
+        assert_eq!(
+            c.url_for_path(std::path::Path::new(&record.path)).unwrap(),
+            Some(url)
+        );
+    }
+
+    #[test]
+    fn stored_headers_round_trip() {
+        let _ = env_logger::try_init();
+
+        let url: reqwest::Url = "http://example.com/".parse().unwrap();
+        let mut response_headers = HeaderMap::new();
+        response_headers.append(ETAG, HeaderValue::from_static("\"abcd\""));
+        response_headers
+            .append(CONTENT_TYPE, HeaderValue::from_static("text/plain"));
+        response_headers.append(
+            CONTENT_DISPOSITION,
+            HeaderValue::from_static("attachment; filename=\"hello.txt\""),
+        );
+        response_headers
+            .append(CONNECTION, HeaderValue::from_static("keep-alive"));
+        let mut c = make_test_cache(rmt::FakeClient::new(
+            url.clone(),
+            HeaderMap::new(),
+            rmt::FakeResponse {
+                status: reqwest::StatusCode::OK,
+                headers: response_headers,
+                body: io::Cursor::new(b"hello".as_ref().into()),
+            },
+        ));
+        assert!(c.get_headers(url.clone()).is_err());
+
+        c.get(url.clone()).unwrap();
+        c.client.assert_called();
+
+        // Only the allowed headers are stored, so Connection isn't.
+        let mut expected = HeaderMap::new();
+        expected.append(CONTENT_TYPE, HeaderValue::from_static("text/plain"));
+        expected.append(
+            CONTENT_DISPOSITION,
+            HeaderValue::from_static("attachment; filename=\"hello.txt\""),
+        );
+        assert_eq!(c.get_headers(url.clone()).unwrap(), expected);
+
+        // Revalidating the entry keeps them.
+        let mut revalidate_headers = HeaderMap::new();
+        revalidate_headers
+            .append(IF_NONE_MATCH, HeaderValue::from_static("\"abcd\""));
+        c.client = rmt::FakeClient::new(
+            url.clone(),
+            revalidate_headers,
+            rmt::FakeResponse {
+                status: reqwest::StatusCode::NOT_MODIFIED,
+                headers: HeaderMap::new(),
+                body: io::Cursor::new(vec![]),
+            },
+        );
+        c.get(url.clone()).unwrap();
+        c.client.assert_called();
+        assert_eq!(c.get_headers(url).unwrap(), expected);
+    }
+
+    #[test]
+    fn stored_headers_can_be_chosen() {
+        let _ = env_logger::try_init();
+
+        let url: reqwest::Url = "http://example.com/".parse().unwrap();
+        let mut response_headers = HeaderMap::new();
+        response_headers
+            .append(CONTENT_TYPE, HeaderValue::from_static("text/plain"));
+        response_headers.append("x-version", HeaderValue::from_static("1"));
+        response_headers.append("x-version", HeaderValue::from_static("2"));
+        let mut c = super::Cache::builder(
+            tempdir::TempDir::new("http-cache-test")
+                .unwrap()
+                .into_path(),
+            rmt::FakeClient::new(
+                url.clone(),
+                HeaderMap::new(),
+                rmt::FakeResponse {
+                    status: reqwest::StatusCode::OK,
+                    headers: response_headers,
+                    body: io::Cursor::new(b"hello".as_ref().into()),
+                },
+            ),
+        )
+        .store_headers(vec![HeaderName::from_static("x-version")])
+        .build()
+        .unwrap();
+        c.get(url.clone()).unwrap();
+        c.client.assert_called();
+
+        let headers = c.get_headers(url).unwrap();
+        assert!(!headers.contains_key(CONTENT_TYPE));
+        assert_eq!(
+            headers.get_all("x-version").iter().collect::<Vec<_>>(),
+            vec!["1", "2"]
+        );
+    }
+
+    fn unavailable_response(retry_after: &str) -> rmt::FakeResponse {
+        let mut headers = HeaderMap::new();
+        headers.append(RETRY_AFTER, HeaderValue::from_str(retry_after).unwrap());
+        rmt::FakeResponse {
+            status: reqwest::StatusCode::SERVICE_UNAVAILABLE,
+            headers,
+            body: io::Cursor::new(vec![]),
+        }
+    }
+
+    /// Returns how long the error for a 503 with `retry_after` asks to wait.
+    fn retry_after_error(retry_after: &str) -> Option<std::time::Duration> {
+        let url: reqwest::Url = "http://example.com/".parse().unwrap();
+        let mut c = make_test_cache(rmt::FakeClient::new(
+            url.clone(),
+            HeaderMap::new(),
+            unavailable_response(retry_after),
+        ));
+        let err = c.get(url.clone()).unwrap_err();
+        c.client.assert_called();
+        match err.downcast::<super::CacheError>().unwrap() {
+            super::CacheError::Unavailable {
+                url: unavailable,
+                retry_after,
+            } => {
+                assert_eq!(unavailable, url);
+                retry_after
+            }
+            err => panic!("Unexpected error: {}", err),
+        }
+    }
+
+    #[test]
+    fn unavailable_error_has_retry_after_seconds() {
+        let _ = env_logger::try_init();
+
+        assert_eq!(
+            retry_after_error("30"),
+            Some(std::time::Duration::from_secs(30))
+        );
+    }
+
+    #[test]
+    fn unavailable_error_has_retry_after_date() {
+        let _ = env_logger::try_init();
+
+        let date = httpdate::fmt_http_date(
+            std::time::SystemTime::now() + std::time::Duration::from_secs(120),
+        );
+        // HTTP dates only have a resolution of one second.
+        let delay = retry_after_error(&date).unwrap();
+        assert!(delay > std::time::Duration::from_secs(115), "{:?}", delay);
+        assert!(delay <= std::time::Duration::from_secs(120), "{:?}", delay);
+
+        // A date in the past means we can retry right away.
+        assert_eq!(
+            retry_after_error("Thu, 01 Jan 1970 00:00:00 GMT"),
+            Some(std::time::Duration::from_secs(0))
+        );
+    }
+
+    #[test]
+    fn retry_unavailable_waits_at_most_max_wait() {
+        let _ = env_logger::try_init();
+
+        let url: reqwest::Url = "http://example.com/".parse().unwrap();
+        let mut c = super::Cache::builder(
+            tempdir::TempDir::new("http-cache-test")
+                .unwrap()
+                .into_path(),
+            rmt::SequenceClient::new(vec![
+                rmt::FakeClient::new(
+                    url.clone(),
+                    HeaderMap::new(),
+                    unavailable_response("3600"),
+                ),
+                rmt::FakeClient::new(
+                    url.clone(),
+                    HeaderMap::new(),
+                    rmt::FakeResponse {
+                        status: reqwest::StatusCode::OK,
+                        headers: HeaderMap::new(),
+                        body: io::Cursor::new(b"hello".as_ref().into()),
+                    },
+                ),
+            ]),
+        )
+        .retry_unavailable(Some(std::time::Duration::from_millis(10)))
+        .build()
+        .unwrap();
+
+        let start = std::time::Instant::now();
+        assert_eq!(c.get_bytes(url).unwrap(), b"hello");
+        assert!(start.elapsed() < std::time::Duration::from_secs(60));
+        c.client.assert_called();
+    }
+
+    #[test]
+    fn test_clock_controls_freshness() {
+        let _ = env_logger::try_init();
+
+        let url: reqwest::Url = "http://example.com/".parse().unwrap();
+        let clock = super::TestClock::default();
+        let mut response_headers = HeaderMap::new();
+        response_headers
+            .append(CACHE_CONTROL, HeaderValue::from_static("max-age=60"));
+        response_headers.append(ETAG, HeaderValue::from_static("\"abcd\""));
+        let mut c = super::Cache::builder(
+            tempdir::TempDir::new("http-cache-test")
+                .unwrap()
+                .into_path(),
+            rmt::FakeClient::new(
+                url.clone(),
+                HeaderMap::new(),
+                rmt::FakeResponse {
+                    status: reqwest::StatusCode::OK,
+                    headers: response_headers,
+                    body: io::Cursor::new(b"hello".as_ref().into()),
+                },
+            ),
+        )
+        .revalidation(super::RevalidationPolicy::WhenStale)
+        .clock(clock.clone())
+        .build()
+        .unwrap();
+        c.get(url.clone()).unwrap();
+        c.client.assert_called();
+
+        let mut revalidate_headers = HeaderMap::new();
+        revalidate_headers
+            .append(IF_NONE_MATCH, HeaderValue::from_static("\"abcd\""));
+        let revalidation = || {
+            rmt::FakeClient::new(
+                url.clone(),
+                revalidate_headers.clone(),
+                rmt::FakeResponse {
+                    status: reqwest::StatusCode::NOT_MODIFIED,
+                    headers: HeaderMap::new(),
+                    body: io::Cursor::new(vec![]),
+                },
+            )
+        };
+
+        // Still fresh just before max-age runs out.
+        clock.advance(std::time::Duration::from_secs(59));
+        c.client = revalidation();
+        c.get(url.clone()).unwrap();
+        assert!(!c.client.was_called());
+
+        clock.advance(std::time::Duration::from_secs(2));
+        c.client = revalidation();
+        c.get(url.clone()).unwrap();
+        c.client.assert_called();
+    }
+
+    #[test]
+    fn memory_storage_uses_the_cache_clock() {
+        let _ = env_logger::try_init();
+
+        let url: reqwest::Url = "http://example.com/".parse().unwrap();
+        let clock = super::TestClock::new(
+            std::time::UNIX_EPOCH + std::time::Duration::from_secs(1000),
+        );
+        let mut c = super::Cache::builder(
+            tempdir::TempDir::new("http-cache-test")
+                .unwrap()
+                .into_path(),
+            rmt::FakeClient::new(
+                url.clone(),
+                HeaderMap::new(),
+                rmt::FakeResponse {
+                    status: reqwest::StatusCode::OK,
+                    headers: HeaderMap::new(),
+                    body: io::Cursor::new(b"hello".as_ref().into()),
+                },
+            ),
+        )
+        .revalidation(super::RevalidationPolicy::Always)
+        .clock(clock.clone())
+        .build_with_storage(super::MemoryStorage::with_clock(clock.clone()))
+        .unwrap();
+        c.get_bytes(url.clone()).unwrap();
+        c.client.assert_called();
+
+        // Content written more than a day ago by the cache's clock isn't
+        // revalidated, which it would be if it were timed by the system's.
+        clock.advance(std::time::Duration::from_secs(2 * 24 * 60 * 60));
+        c.client = rmt::FakeClient::new(
+            url.clone(),
+            HeaderMap::new(),
+            rmt::FakeResponse {
+                status: reqwest::StatusCode::OK,
+                headers: HeaderMap::new(),
+                body: io::Cursor::new(b"changed".as_ref().into()),
+            },
+        );
+        assert_eq!(c.get_bytes(url).unwrap(), b"hello");
+        assert!(!c.client.was_called());
+    }
+
+    #[test]
+    fn fetched_at_is_recorded() {
+        let _ = env_logger::try_init();
+
+        let url: reqwest::Url = "http://example.com/".parse().unwrap();
+        // Whole seconds, as that's all the cache stores.
+        let now = std::time::UNIX_EPOCH + std::time::Duration::from_secs(1000);
+        let clock = super::TestClock::new(now);
+        let mut c = super::Cache::builder(
+            tempdir::TempDir::new("http-cache-test")
+                .unwrap()
+                .into_path(),
+            rmt::FakeClient::new(
+                url.clone(),
+                HeaderMap::new(),
+                rmt::FakeResponse {
+                    status: reqwest::StatusCode::OK,
+                    headers: HeaderMap::new(),
+                    body: io::Cursor::new(b"hello".as_ref().into()),
+                },
+            ),
+        )
+        .clock(clock.clone())
+        .build()
+        .unwrap();
+        c.get(url.clone()).unwrap();
+        c.client.assert_called();
+
+        clock.advance(std::time::Duration::from_secs(60));
+        let record = c.metadata(url).unwrap().unwrap();
+        assert_eq!(record.fetched_at, Some(now));
+    }
+
+    #[test]
+    fn heuristic_freshness_without_validators() {
+        use super::Clock;
+        let _ = env_logger::try_init();
+
+        let url: reqwest::Url = "http://example.com/".parse().unwrap();
+        let clock = super::TestClock::default();
+        let hour = std::time::Duration::from_secs(60 * 60);
+        // A bare response, sent five hours ago by the server's reckoning.
+        let response = || {
+            let mut headers = HeaderMap::new();
+            headers.append(
+                DATE,
+                HeaderValue::from_str(&httpdate::fmt_http_date(
+                    clock.now() - 5 * hour,
+                ))
+                .unwrap(),
+            );
+            rmt::FakeClient::new(
+                url.clone(),
+                HeaderMap::new(),
+                rmt::FakeResponse {
+                    status: reqwest::StatusCode::OK,
+                    headers,
+                    body: io::Cursor::new(b"hello".as_ref().into()),
+                },
+            )
+        };
+        let mut c = super::Cache::builder(
+            tempdir::TempDir::new("http-cache-test")
+                .unwrap()
+                .into_path(),
+            response(),
+        )
+        .revalidation(super::RevalidationPolicy::WhenStale)
+        .heuristic_freshness(Some(0.1))
+        .clock(clock.clone())
+        .build()
+        .unwrap();
+        c.get(url.clone()).unwrap();
+        c.client.assert_called();
+
+        // Fresh for 10% of five hours, that is half an hour.
+        clock.advance(hour / 4);
+        c.client = response();
+        c.get(url.clone()).unwrap();
+        assert!(!c.client.was_called());
+
+        clock.advance(hour / 2);
+        c.client = response();
+        c.get(url.clone()).unwrap();
+        c.client.assert_called();
+    }
+
+    #[test]
+    fn heuristic_freshness_must_be_a_fraction() {
+        for fraction in &[f64::NAN, -0.1, 1.5, f64::INFINITY] {
+            let result = super::Cache::builder(
+                tempdir::TempDir::new("http-cache-test")
+                    .unwrap()
+                    .into_path(),
+                rmt::SharedClient::default(),
+            )
+            .heuristic_freshness(Some(*fraction))
+            .build();
+            assert!(result.is_err(), "{}", fraction);
+        }
+    }
+
+    #[test]
+    fn fixture_client_serves_and_revalidates() {
+        use super::CacheStatus;
+        let _ = env_logger::try_init();
+
+        let fixtures = tempdir::TempDir::new("http-cache-fixtures").unwrap();
+        let dir = fixtures.path().join("example.com").join("data");
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("file.txt"), "hello").unwrap();
+        std::fs::write(
+            dir.join("file.txt.headers"),
+            "ETag: \"abcd\"\nContent-Type: text/plain\n",
+        )
+        .unwrap();
+        std::fs::write(dir.join("gone"), "").unwrap();
+        std::fs::write(dir.join("gone.headers"), "Status: 410 Gone\n").unwrap();
+
+        let mut c = make_test_cache(super::reqwest_mock::FixtureClient::new(
+            fixtures.path(),
+        ));
+        let url: reqwest::Url =
+            "http://example.com/data/file.txt".parse().unwrap();
+
+        let (mut file, status) = c.get_with_status(url.clone()).unwrap();
+        assert_eq!(status, CacheStatus::Downloaded);
+        let mut buf = vec![];
+        file.read_to_end(&mut buf).unwrap();
+        assert_eq!(&buf, b"hello");
+
+        // The fixture's ETag matches the cached one.
+        let (_, status) = c.get_with_status(url).unwrap();
+        assert_eq!(status, CacheStatus::Revalidated);
+
+        assert!(c.get("http://example.com/data/gone").is_err());
+        assert!(c.get("http://example.com/data/missing").is_err());
+    }
+
+    #[test]
+    fn user_agent_is_sent() {
+        let _ = env_logger::try_init();
+
+        let url: reqwest::Url = "http://example.com/".parse().unwrap();
+        let mut expected_headers = HeaderMap::new();
+        expected_headers
+            .append(USER_AGENT, HeaderValue::from_static("my-app/1.0"));
+        let mut c = make_test_cache(rmt::FakeClient::new(
+            url.clone(),
+            expected_headers,
+            rmt::FakeResponse {
+                status: reqwest::StatusCode::OK,
+                headers: HeaderMap::new(),
+                body: io::Cursor::new(b"hello".as_ref().into()),
+            },
+        ));
+        assert!(c.set_user_agent("bad\nagent").is_err());
+        c.set_user_agent("my-app/1.0").unwrap();
+
+        c.get(url).unwrap();
+        c.client.assert_called();
+    }
+
+    /// Accepts `room` bytes, then fails as if the disk were full.
+    struct FullDisk {
+        room: usize,
+    }
+
+    impl std::io::Write for FullDisk {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            let count = std::cmp::min(self.room, buf.len());
+            self.room -= count;
+            Ok(count)
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn storage_failures_are_reported_as_such() {
+        let _ = env_logger::try_init();
+
+        let url: reqwest::Url = "http://example.com/big".parse().unwrap();
+        let mut response = rmt::FakeResponse {
+            status: reqwest::StatusCode::OK,
+            headers: HeaderMap::new(),
+            body: io::Cursor::new(vec![0; 20]),
+        };
+        let c = make_test_cache(rmt::SharedClient::default());
+
+        let err = c
+            .copy_body(&url, &mut response, &mut FullDisk { room: 10 }, 0)
+            .unwrap_err();
+        // Failing to store the content evicts the entry, as for any
+        // `CacheError`, so nothing is left behind to resume.
+        assert!(err.is::<super::CacheError>());
+        assert_eq!(
+            err.downcast_ref::<super::CacheError>(),
+            Some(&super::CacheError::StorageFailed { url })
+        );
+        assert_eq!(
+            err.downcast_ref::<io::Error>().map(io::Error::kind),
+            Some(io::ErrorKind::WriteZero)
+        );
+    }
+
+    /// Downloads a 20-byte body into a cache limited to 10-byte files.
+    fn download_too_large(response_headers: HeaderMap) {
+        let _ = env_logger::try_init();
+
+        let url: reqwest::Url = "http://example.com/huge".parse().unwrap();
+        let mut c = make_test_cache(rmt::FakeClient::new(
+            url.clone(),
+            HeaderMap::new(),
+            rmt::FakeResponse {
+                status: reqwest::StatusCode::OK,
+                headers: response_headers,
+                body: io::Cursor::new(vec![0; 20]),
+            },
+        ));
+        c.set_max_file_size(10);
+
+        let err = c.get(url.clone()).unwrap_err();
+        assert_eq!(
+            err.downcast::<super::CacheError>().unwrap(),
+            super::CacheError::TooLarge {
+                url: url.clone(),
+                limit: 10,
+            }
+        );
+        c.client.assert_called();
+
+        // Nothing is left behind.
+        assert_eq!(c.metadata(url).unwrap(), None);
+        for dir in &["partial", "content"] {
+            let count = std::fs::read_dir(c.root.join(dir))
+                .map_or(0, |entries| entries.count());
+            assert_eq!(count, 0, "{}", dir);
+        }
+    }
+
+    #[test]
+    fn too_large_content_length_fails_up_front() {
+        let mut response_headers = HeaderMap::new();
+        response_headers.append(CONTENT_LENGTH, HeaderValue::from_static("20"));
+        download_too_large(response_headers);
+    }
+
+    #[test]
+    fn too_large_body_fails_while_streaming() {
+        download_too_large(HeaderMap::new());
+    }
+
+    #[test]
+    fn largest_max_file_size_allows_any_download() {
+        let _ = env_logger::try_init();
+
+        let url: reqwest::Url = "http://example.com/".parse().unwrap();
+        let mut c = make_test_cache(rmt::FakeClient::new(
+            url.clone(),
+            HeaderMap::new(),
+            rmt::FakeResponse {
+                status: reqwest::StatusCode::OK,
+                headers: HeaderMap::new(),
+                body: io::Cursor::new(b"hello".as_ref().into()),
+            },
+        ));
+        c.set_max_file_size(u64::MAX);
+
+        assert_eq!(c.get_bytes(url).unwrap(), b"hello");
+        c.client.assert_called();
+    }
+
+    #[test]
+    fn resumed_download_of_unrepresentable_length_is_too_large() {
+        let _ = env_logger::try_init();
+
+        let url: reqwest::Url = "http://example.com/big".parse().unwrap();
+        let mut response_headers = HeaderMap::new();
+        response_headers
+            .append(CONTENT_RANGE, HeaderValue::from_static("bytes 3-4/5"));
+        // Added to the 3 bytes we have, this doesn't fit in a u64.
+        response_headers.append(
+            CONTENT_LENGTH,
+            HeaderValue::from_static("18446744073709551615"),
+        );
+        let mut c = make_test_cache(rmt::FakeClient::new(
+            url.clone(),
+            resume_headers(),
+            rmt::FakeResponse {
+                status: reqwest::StatusCode::PARTIAL_CONTENT,
+                headers: response_headers,
+                body: io::Cursor::new(b"lo".as_ref().into()),
+            },
+        ));
+        c.set_max_file_size(u64::MAX);
+        let path = make_partial_entry(&mut c, &url, b"hel");
+
+        let err = c.get(url.clone()).unwrap_err();
+        assert_eq!(
+            err.downcast::<super::CacheError>().unwrap(),
+            super::CacheError::TooLarge {
+                url: url.clone(),
+                limit: u64::MAX,
+            }
+        );
+        c.client.assert_called();
+        assert_eq!(c.metadata(url).unwrap(), None);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn custom_freshness_policy_decides_revalidation() {
+        let _ = env_logger::try_init();
+
+        #[derive(Debug)]
+        struct AlwaysFresh;
+        impl super::FreshnessPolicy for AlwaysFresh {
+            fn is_fresh(
+                &self,
+                _: &super::CacheRecord,
+                _: std::time::SystemTime,
+            ) -> bool {
+                true
+            }
+        }
+
+        let url: reqwest::Url = "http://example.com/".parse().unwrap();
+        let mut response_headers = HeaderMap::new();
+        response_headers.append(ETAG, HeaderValue::from_static("\"abcd\""));
+        // The response says it's already stale, but the policy disagrees.
+        response_headers
+            .append(CACHE_CONTROL, HeaderValue::from_static("max-age=0"));
+        let mut c = super::Cache::builder(
+            tempdir::TempDir::new("http-cache-test")
+                .unwrap()
+                .into_path(),
+            rmt::FakeClient::new(
+                url.clone(),
+                HeaderMap::new(),
+                rmt::FakeResponse {
+                    status: reqwest::StatusCode::OK,
+                    headers: response_headers,
+                    body: io::Cursor::new(b"hello".as_ref().into()),
+                },
+            ),
+        )
+        .freshness_policy(AlwaysFresh)
+        .build()
+        .unwrap();
+        c.get(url.clone()).unwrap();
+        c.client.assert_called();
+
+        let mut revalidate_headers = HeaderMap::new();
+        revalidate_headers
+            .append(IF_NONE_MATCH, HeaderValue::from_static("\"abcd\""));
+        c.client = rmt::FakeClient::new(
+            url.clone(),
+            revalidate_headers,
+            rmt::FakeResponse {
+                status: reqwest::StatusCode::NOT_MODIFIED,
+                headers: HeaderMap::new(),
+                body: io::Cursor::new(vec![]),
+            },
+        );
+        let (mut file, status) = c.get_with_status(url).unwrap();
+        assert!(!c.client.was_called());
+        assert_eq!(status, super::CacheStatus::Fresh);
+        let mut buf = vec![];
+        file.read_to_end(&mut buf).unwrap();
+        assert_eq!(&buf, b"hello");
+    }
+
+    #[test]
+    fn max_stale_forces_revalidation_of_fresh_data() {
+        let _ = env_logger::try_init();
+
+        let url: reqwest::Url = "http://example.com/".parse().unwrap();
+        let clock = super::TestClock::default();
+        let minute = std::time::Duration::from_secs(60);
+        let mut response_headers = HeaderMap::new();
+        response_headers
+            .append(CACHE_CONTROL, HeaderValue::from_static("max-age=3600"));
+        response_headers.append(ETAG, HeaderValue::from_static("\"abcd\""));
+        let mut c = super::Cache::builder(
+            tempdir::TempDir::new("http-cache-test")
+                .unwrap()
+                .into_path(),
+            rmt::FakeClient::new(
+                url.clone(),
+                HeaderMap::new(),
+                rmt::FakeResponse {
+                    status: reqwest::StatusCode::OK,
+                    headers: response_headers,
+                    body: io::Cursor::new(b"hello".as_ref().into()),
+                },
+            ),
+        )
+        .revalidation(super::RevalidationPolicy::Never)
+        .clock(clock.clone())
+        .build()
+        .unwrap();
+        c.set_max_stale(10 * minute);
+        c.get(url.clone()).unwrap();
+        c.client.assert_called();
+
+        let mut revalidate_headers = HeaderMap::new();
+        revalidate_headers
+            .append(IF_NONE_MATCH, HeaderValue::from_static("\"abcd\""));
+        let revalidation = || {
+            rmt::FakeClient::new(
+                url.clone(),
+                revalidate_headers.clone(),
+                rmt::FakeResponse {
+                    status: reqwest::StatusCode::NOT_MODIFIED,
+                    headers: HeaderMap::new(),
+                    body: io::Cursor::new(vec![]),
+                },
+            )
+        };
+
+        clock.advance(5 * minute);
+        c.client = revalidation();
+        c.get(url.clone()).unwrap();
+        assert!(!c.client.was_called());
+
+        // Still fresh according to max-age, but validated too long ago.
+        clock.advance(6 * minute);
+        c.client = revalidation();
+        c.get(url.clone()).unwrap();
+        c.client.assert_called();
+
+        // The revalidation restarts the countdown.
+        clock.advance(5 * minute);
+        c.client = revalidation();
+        c.get(url.clone()).unwrap();
+        assert!(!c.client.was_called());
+    }
+
+    #[test]
+    fn optimize_and_check_integrity() {
+        let _ = env_logger::try_init();
+
+        let url: reqwest::Url = "http://example.com/".parse().unwrap();
+        let mut c = make_test_cache(rmt::FakeClient::new(
+            url.clone(),
+            HeaderMap::new(),
+            rmt::FakeResponse {
+                status: reqwest::StatusCode::OK,
+                headers: HeaderMap::new(),
+                body: io::Cursor::new(b"hello".as_ref().into()),
+            },
+        ));
+        c.get(url.clone()).unwrap();
+        c.client.assert_called();
+
+        assert!(c.check_integrity().unwrap());
+        c.optimize().unwrap();
+        assert!(c.check_integrity().unwrap());
+        assert!(c.metadata(url).unwrap().is_some());
+    }
+
+    #[test]
+    fn rename_url_moves_the_entry() {
+        let _ = env_logger::try_init();
+
+        let old: reqwest::Url = "http://example.com/v1/app.js".parse().unwrap();
+        let new: reqwest::Url = "http://example.com/app.js".parse().unwrap();
+        let mut c = make_test_cache(rmt::FakeClient::new(
+            old.clone(),
+            HeaderMap::new(),
+            rmt::FakeResponse {
+                status: reqwest::StatusCode::OK,
+                headers: HeaderMap::new(),
+                body: io::Cursor::new(b"hello".as_ref().into()),
+            },
+        ));
+        c.set_revalidation(super::RevalidationPolicy::Never);
+        c.get(old.clone()).unwrap();
+        c.client.assert_called();
+
+        let missing: reqwest::Url = "http://example.com/missing".parse().unwrap();
+        assert!(c.rename_url(missing, new.clone()).is_err());
+
+        c.rename_url(old.clone(), new.clone()).unwrap();
+        assert_eq!(c.metadata(old.clone()).unwrap(), None);
+        assert!(c.metadata(new.clone()).unwrap().is_some());
+        // The content is served without asking the server.
+        assert_eq!(c.get_bytes(new.clone()).unwrap(), b"hello");
+
+        // The old URL isn't cached any more, so it's downloaded again.
+        c.client = rmt::FakeClient::new(
+            old.clone(),
+            HeaderMap::new(),
+            rmt::FakeResponse {
+                status: reqwest::StatusCode::NOT_FOUND,
+                headers: HeaderMap::new(),
+                body: io::Cursor::new(vec![]),
+            },
+        );
+        assert!(c.get(old.clone()).is_err());
+        c.client.assert_called();
+    }
+
+    #[test]
+    fn rename_url_only_replaces_when_asked() {
+        let _ = env_logger::try_init();
+
+        let old: reqwest::Url = "http://example.com/old".parse().unwrap();
+        let new: reqwest::Url = "http://example.com/new".parse().unwrap();
+        let mut c = make_test_cache(rmt::FakeClient::new(
+            old.clone(),
+            HeaderMap::new(),
+            rmt::FakeResponse {
+                status: reqwest::StatusCode::OK,
+                headers: HeaderMap::new(),
+                body: io::Cursor::new(b"old".as_ref().into()),
+            },
+        ));
+        c.get(old.clone()).unwrap();
+        c.client.assert_called();
+        let src = c.root.join("new-src");
+        std::fs::write(&src, "new").unwrap();
+        c.insert_local(new.clone(), &src, None, None).unwrap();
+
+        let err = c.rename_url(old.clone(), new.clone()).unwrap_err();
+        assert_eq!(
+            err.downcast::<super::CacheError>().unwrap(),
+            super::CacheError::AlreadyCached { url: new.clone() }
+        );
+        // Nothing changed.
+        assert!(c.metadata(old.clone()).unwrap().is_some());
+
+        c.replace_url(old.clone(), new.clone()).unwrap();
+        assert_eq!(c.metadata(old).unwrap(), None);
+        let record = c.metadata(new).unwrap().unwrap();
+        assert_eq!(std::fs::read(c.root.join(&record.path)).unwrap(), b"old");
+        assert_eq!(std::fs::read_dir(c.root.join("content")).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn moving_an_entry_onto_itself_leaves_it_alone() {
+        let _ = env_logger::try_init();
+
+        let url: reqwest::Url = "http://example.com/".parse().unwrap();
+        let mut c = make_test_cache(rmt::FakeClient::new(
+            url.clone(),
+            HeaderMap::new(),
+            rmt::FakeResponse {
+                status: reqwest::StatusCode::OK,
+                headers: HeaderMap::new(),
+                body: io::Cursor::new(b"hello".as_ref().into()),
+            },
+        ));
+        c.get(url.clone()).unwrap();
+        let record = c.metadata(url.clone()).unwrap().unwrap();
+
+        c.replace_url(url.clone(), url.clone()).unwrap();
+        // The fragment isn't part of the key, so this is the same entry too.
+        c.rename_url(url.clone(), "http://example.com/#top").unwrap();
+
+        assert_eq!(c.metadata(url).unwrap(), Some(record.clone()));
+        assert_eq!(std::fs::read(c.root.join(&record.path)).unwrap(), b"hello");
+    }
+
+    /// Downloads "hello" with the given `Content-Length` header.
+    fn download_with_length(
+        content_length: &'static str,
+        strict: bool,
+    ) -> (
+        super::Cache<rmt::FakeClient>,
+        reqwest::Url,
+        Result<Vec<u8>, anyhow::Error>,
+    ) {
+        let _ = env_logger::try_init();
+
+        let url: reqwest::Url = "http://example.com/".parse().unwrap();
+        let mut response_headers = HeaderMap::new();
+        response_headers
+            .append(CONTENT_LENGTH, HeaderValue::from_static(content_length));
+        let mut c = super::Cache::builder(
+            tempdir::TempDir::new("http-cache-test")
+                .unwrap()
+                .into_path(),
+            rmt::FakeClient::new(
+                url.clone(),
+                HeaderMap::new(),
+                rmt::FakeResponse {
+                    status: reqwest::StatusCode::OK,
+                    headers: response_headers,
+                    body: io::Cursor::new(b"hello".as_ref().into()),
+                },
+            ),
+        )
+        .strict_length(strict)
+        .build()
+        .unwrap();
+        let result = c.get_bytes(url.clone());
+        (c, url, result)
+    }
+
+    #[test]
+    fn declared_length_is_recorded() {
+        let (c, url, result) = download_with_length("5", true);
+        assert_eq!(result.unwrap(), b"hello");
+        assert!(c.client.was_called());
+        assert_eq!(c.metadata(url).unwrap().unwrap().declared_length, Some(5));
+    }
+
+    #[test]
+    fn length_mismatch_only_fails_when_strict() {
+        let (c, url, result) = download_with_length("10", false);
+        assert_eq!(result.unwrap(), b"hello");
+        assert!(c.client.was_called());
+        assert_eq!(c.metadata(url).unwrap().unwrap().declared_length, Some(10));
+
+        let (c, url, result) = download_with_length("10", true);
+        assert_eq!(
+            result.unwrap_err().downcast::<super::CacheError>().unwrap(),
+            super::CacheError::LengthMismatch {
+                url: url.clone(),
+                declared: 10,
+                received: 5,
+            }
+        );
+        assert!(c.client.was_called());
+        assert_eq!(c.metadata(url).unwrap(), None);
+    }
+
+    /// A client that knows `http://example.com/a` and `http://example.com/b`.
+    fn two_url_client() -> rmt::SharedClient {
+        let mut client = rmt::SharedClient::default();
+        for name in &["a", "b"] {
+            client.responses.insert(
+                format!("http://example.com/{}", name).parse().unwrap(),
+                rmt::FakeResponse {
+                    status: reqwest::StatusCode::OK,
+                    headers: HeaderMap::new(),
+                    body: io::Cursor::new(name.as_bytes().into()),
+                },
+            );
+        }
+        client
+    }
+
+    fn many_urls() -> Vec<reqwest::Url> {
+        vec!["b", "missing", "a"]
+            .into_iter()
+            .map(|name| format!("http://example.com/{}", name).parse().unwrap())
+            .collect()
+    }
+
+    /// Checks `get_many` results for the URLs in `many_urls`.
+    fn check_many(results: Vec<Result<std::fs::File, anyhow::Error>>) {
+        let bodies: Vec<_> = results
+            .into_iter()
+            .map(|result| {
+                result.ok().map(|mut file| {
+                    let mut buf = String::new();
+                    file.read_to_string(&mut buf).unwrap();
+                    buf
+                })
+            })
+            .collect();
+        assert_eq!(bodies, vec![Some("b".into()), None, Some("a".into())]);
+    }
+
+    #[test]
+    fn get_many_mixes_hits_and_misses() {
+        let _ = env_logger::try_init();
+
+        let client = two_url_client();
+        let requests = client.requests.clone();
+        let mut c = make_test_cache(client);
+        c.set_revalidation(super::RevalidationPolicy::Never);
+        let urls = many_urls();
+        c.get(urls[2].clone()).unwrap();
+
+        check_many(c.get_many(&urls));
+        // The cached URL wasn't requested again.
+        let mut requested = requests.lock().unwrap().clone();
+        requested[1..].sort();
+        assert_eq!(
+            requested,
+            vec![urls[2].clone(), urls[0].clone(), urls[1].clone()]
+        );
+    }
+
+    #[test]
+    fn get_many_retrieves_urls_concurrently() {
+        let _ = env_logger::try_init();
+
+        let delay = std::time::Duration::from_millis(500);
+        let client = rmt::SharedClient {
+            delay,
+            ..two_url_client()
+        };
+        let mut c = make_test_cache(client);
+        let urls = many_urls();
+
+        let start = std::time::Instant::now();
+        check_many(c.get_many(&urls));
+        // Retrieved one at a time, the three requests would take three delays.
+        assert!(start.elapsed() < delay * 2, "{:?}", start.elapsed());
+        assert_eq!(c.stats().downloads, 2);
+    }
+
+    #[test]
+    fn shared_cache_get_many_mixes_hits_and_misses() {
+        let _ = env_logger::try_init();
+
+        let client = two_url_client();
+        let requests = client.requests.clone();
+        let cache = super::Cache::builder(
+            tempdir::TempDir::new("http-cache-test")
+                .unwrap()
+                .into_path(),
+            client,
+        )
+        .revalidation(super::RevalidationPolicy::Never)
+        .build_shared()
+        .unwrap();
+        let urls = many_urls();
+        cache.get(urls[2].clone()).unwrap();
+
+        check_many(cache.get_many(&urls));
+        let mut requested = requests.lock().unwrap().clone();
+        requested.sort();
+        let mut expected = urls.clone();
+        expected.sort();
+        assert_eq!(requested, expected);
+    }
+
+    #[test]
+    fn invalidate_prefix_removes_only_that_subtree() {
+        let _ = env_logger::try_init();
+
+        let mut c = make_test_cache(rmt::SharedClient::default());
+        let src = c.root.join("src");
+        std::fs::write(&src, "hello").unwrap();
+        let urls: Vec<reqwest::Url> = vec![
+            "https://cdn.example.com/assets/app.js",
+            "https://cdn.example.com/assets/img/logo.png",
+            "https://cdn.example.com/fonts/sans.woff",
+        ]
+        .into_iter()
+        .map(|url| url.parse().unwrap())
+        .collect();
+        for url in &urls {
+            c.insert_local(url.clone(), &src, None, None).unwrap();
+        }
+
+        assert_eq!(
+            c.invalidate_prefix("https://cdn.example.com/assets/").unwrap(),
+            2
+        );
+        assert_eq!(c.metadata(urls[0].clone()).unwrap(), None);
+        assert_eq!(c.metadata(urls[1].clone()).unwrap(), None);
+        assert!(c.metadata(urls[2].clone()).unwrap().is_some());
+        assert_eq!(std::fs::read_dir(c.root.join("content")).unwrap().count(), 1);
+    }
+
+    /// Answers one connection on a local port with each of `responses` in
+    /// turn, returning the port and the head of each request received.
+    fn serve(
+        responses: Vec<&'static str>,
+    ) -> (std::net::SocketAddr, std::thread::JoinHandle<Vec<String>>) {
+        use std::io::Write;
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = std::thread::spawn(move || {
+            responses
+                .into_iter()
+                .map(|response| {
+                    let (mut stream, _) = listener.accept().unwrap();
+                    let mut head = vec![];
+                    while !head.ends_with(b"\r\n\r\n") {
+                        let mut byte = [0];
+                        stream.read_exact(&mut byte).unwrap();
+                        head.push(byte[0]);
+                    }
+                    stream.write_all(response.as_bytes()).unwrap();
+                    String::from_utf8(head).unwrap().to_lowercase()
+                })
+                .collect()
+        });
+        (addr, server)
+    }
+
+    const OK_RESPONSE: &str = "HTTP/1.1 200 OK\r\n\
+        ETag: \"abcd\"\r\n\
+        Content-Length: 5\r\n\
+        Connection: close\r\n\r\nhello";
+    const REDIRECT_RESPONSE: &str = "HTTP/1.1 307 Temporary Redirect\r\n\
+        Location: http://example.invalid/moved\r\n\
+        Content-Length: 0\r\n\
+        Connection: close\r\n\r\n";
+
+    fn proxied_client(
+        proxy: std::net::SocketAddr,
+    ) -> reqwest::blocking::ClientBuilder {
+        reqwest::blocking::Client::builder().proxy(
+            reqwest::Proxy::http(&format!("http://{}", proxy)).unwrap(),
+        )
+    }
+
+    #[test]
+    fn conditional_requests_use_the_client_configuration() {
+        use super::CacheStatus;
+        let _ = env_logger::try_init();
+
+        let (proxy, server) = serve(vec![
+            OK_RESPONSE,
+            REDIRECT_RESPONSE,
+            "HTTP/1.1 304 Not Modified\r\nConnection: close\r\n\r\n",
+        ]);
+        let mut c = make_test_cache(proxied_client(proxy).build().unwrap());
+        let url: reqwest::Url = "http://example.invalid/data".parse().unwrap();
+
+        let (_, status) = c.get_with_status(url.clone()).unwrap();
+        assert_eq!(status, CacheStatus::Downloaded);
+        let (_, status) = c.get_with_status(url).unwrap();
+        assert_eq!(status, CacheStatus::Revalidated);
+
+        // Every request went through the proxy, which is sent absolute URLs,
+        // and the redirect was followed with the conditional header intact.
+        let requests = server.join().unwrap();
+        assert!(requests[0].starts_with("get http://example.invalid/data "));
+        assert!(requests[1].starts_with("get http://example.invalid/data "));
+        assert!(requests[1].contains("if-none-match: \"abcd\"\r\n"));
+        assert!(requests[2].starts_with("get http://example.invalid/moved "));
+        assert!(requests[2].contains("if-none-match: \"abcd\"\r\n"));
+    }
+
+    #[test]
+    fn conditional_requests_use_the_client_redirect_policy() {
+        use super::CacheStatus;
+        let _ = env_logger::try_init();
+
+        let (proxy, server) = serve(vec![OK_RESPONSE, REDIRECT_RESPONSE]);
+        let mut c = make_test_cache(
+            proxied_client(proxy)
+                .redirect(reqwest::redirect::Policy::none())
+                .build()
+                .unwrap(),
+        );
+        let url: reqwest::Url = "http://example.invalid/data".parse().unwrap();
+
+        c.get(url.clone()).unwrap();
+        // The redirect isn't followed, so the cached copy is used.
+        let (mut file, status) = c.get_with_status(url).unwrap();
+        assert_eq!(status, CacheStatus::Fresh);
+        let mut buf = vec![];
+        file.read_to_end(&mut buf).unwrap();
+        assert_eq!(&buf, b"hello");
+        assert_eq!(server.join().unwrap().len(), 2);
+    }
+
+    fn redirect_to(location: &'static str) -> rmt::FakeResponse {
+        let mut headers = HeaderMap::new();
+        headers.insert(LOCATION, HeaderValue::from_static(location));
+        rmt::FakeResponse {
+            status: reqwest::StatusCode::FOUND,
+            headers,
+            body: io::Cursor::new(vec![]),
+        }
+    }
+
+    #[test]
+    fn max_redirects_follows_redirections() {
+        let _ = env_logger::try_init();
+
+        let mut client = rmt::SharedClient::default();
+        client.responses.insert(
+            "http://example.com/old".parse().unwrap(),
+            redirect_to("/new"),
+        );
+        client.responses.insert(
+            "http://example.com/new".parse().unwrap(),
+            rmt::FakeResponse {
+                status: reqwest::StatusCode::OK,
+                headers: HeaderMap::new(),
+                body: io::Cursor::new(b"hello".as_ref().into()),
+            },
+        );
+        let mut c = make_test_cache(client);
+        c.set_max_redirects(1);
+
+        assert_eq!(c.get_bytes("http://example.com/old").unwrap(), b"hello");
+        assert_eq!(c.client.requests.lock().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn max_redirects_stops_redirect_loops() {
+        let _ = env_logger::try_init();
+
+        let mut client = rmt::SharedClient::default();
+        client
+            .responses
+            .insert("http://example.com/a".parse().unwrap(), redirect_to("/b"));
+        client
+            .responses
+            .insert("http://example.com/b".parse().unwrap(), redirect_to("/a"));
+        let mut c = make_test_cache(client);
+        c.set_max_redirects(5);
+
+        let url: reqwest::Url = "http://example.com/a".parse().unwrap();
+        let err = c.get(url.clone()).unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<super::CacheError>(),
+            Some(&super::CacheError::TooManyRedirects {
+                url: url.clone(),
+                max: 5
+            })
+        );
+        // The request, and five redirections.
+        assert_eq!(c.client.requests.lock().unwrap().len(), 6);
+        assert_eq!(c.metadata(url).unwrap(), None);
+    }
+
+    #[test]
+    fn keep_fragment_gives_fragments_separate_entries() {
+        let _ = env_logger::try_init();
+
+        let mut client = rmt::SharedClient::default();
+        for name in &["a", "b"] {
+            client.responses.insert(
+                format!("http://example.com/#{}", name).parse().unwrap(),
+                rmt::FakeResponse {
+                    status: reqwest::StatusCode::OK,
+                    headers: HeaderMap::new(),
+                    body: io::Cursor::new(name.as_bytes().into()),
+                },
+            );
+        }
+        let mut c = super::Cache::builder(
+            tempdir::TempDir::new("http-cache-test")
+                .unwrap()
+                .into_path(),
+            client,
+        )
+        .keep_fragment(true)
+        .build()
+        .unwrap();
+
+        let a: reqwest::Url = "http://example.com/#a".parse().unwrap();
+        let b: reqwest::Url = "http://example.com/#b".parse().unwrap();
+        assert_eq!(c.get_bytes(a.clone()).unwrap(), b"a");
+        assert_eq!(c.get_bytes(b.clone()).unwrap(), b"b");
+
+        let a_path = c.metadata(a).unwrap().unwrap().path;
+        let b_path = c.metadata(b).unwrap().unwrap().path;
+        assert_ne!(a_path, b_path);
+        assert!(c.metadata("http://example.com/").unwrap().is_none());
+        assert_eq!(c.client.requests.lock().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn warm_caches_without_opening() {
+        let _ = env_logger::try_init();
+
+        let url: reqwest::Url = "http://example.com/".parse().unwrap();
+        let mut response_headers = HeaderMap::new();
+        response_headers.append(
+            CACHE_CONTROL,
+            HeaderValue::from_static("max-age=3600"),
+        );
+        let mut c = make_test_cache(rmt::FakeClient::new(
+            url.clone(),
+            HeaderMap::new(),
+            rmt::FakeResponse {
+                status: reqwest::StatusCode::OK,
+                headers: response_headers,
+                body: io::Cursor::new(b"hello world"[..].into()),
+            },
+        ));
+        c.set_revalidation(super::RevalidationPolicy::WhenStale);
+
+        c.warm(url.clone()).unwrap();
+        assert!(c.client.was_called());
+        assert!(c.metadata(url.clone()).unwrap().unwrap().complete);
+
+        // The entry is fresh, so the next request doesn't touch the network.
+        c.client = rmt::FakeClient::new(
+            url.clone(),
+            HeaderMap::new(),
+            rmt::FakeResponse {
+                status: reqwest::StatusCode::OK,
+                headers: HeaderMap::new(),
+                body: io::Cursor::new(b"changed"[..].into()),
+            },
+        );
+        assert_eq!(c.get_bytes(url).unwrap(), b"hello world");
+        assert!(!c.client.was_called());
+    }
+
+    #[test]
+    fn room_for_compares_against_available_space() {
+        let root = tempdir::TempDir::new("http-cache-test").unwrap();
+        let (room, available) = super::room_for(root.path(), 0, 0).unwrap();
+        assert!(room);
+        assert!(available > 0);
+        // Neither the download nor the reserve fits on any real disk.
+        assert!(!super::room_for(root.path(), 1, u64::MAX).unwrap().0);
+        assert!(!super::room_for(root.path(), u64::MAX, 0).unwrap().0);
+    }
+
+    #[test]
+    fn compressed_content_is_smaller_on_disk() {
+        let _ = env_logger::try_init();
+
+        let url: reqwest::Url = "http://example.com/log.txt".parse().unwrap();
+        let body = b"All is well.\n".repeat(10000);
+        let mut c = super::Cache::builder(
+            tempdir::TempDir::new("http-cache-test")
+                .unwrap()
+                .into_path(),
+            rmt::FakeClient::new(
+                url.clone(),
+                HeaderMap::new(),
+                rmt::FakeResponse {
+                    status: reqwest::StatusCode::OK,
+                    headers: HeaderMap::new(),
+                    body: io::Cursor::new(body.clone()),
+                },
+            ),
+        )
+        .compress_at_rest(true)
+        .revalidation(super::RevalidationPolicy::Never)
+        .build()
+        .unwrap();
+
+        assert_eq!(c.get_bytes(url.clone()).unwrap(), body);
+        c.client.assert_called();
+
+        let record = c.metadata(url.clone()).unwrap().unwrap();
+        assert!(record.compressed);
+        let stored = std::fs::metadata(c.root.join(&record.path)).unwrap().len();
+        assert!(stored < body.len() as u64 / 10, "{} bytes on disk", stored);
+
+        // Served from the cache, it's still decompressed.
+        c.client = rmt::FakeClient::new(
+            url.clone(),
+            HeaderMap::new(),
+            rmt::FakeResponse {
+                status: reqwest::StatusCode::OK,
+                headers: HeaderMap::new(),
+                body: io::Cursor::new(vec![]),
+            },
+        );
+        let path = c.get_path(url.clone()).unwrap();
+        assert!(!c.client.was_called());
+        assert!(!path.starts_with(&c.root));
+        assert_eq!(c.get_bytes(url).unwrap(), body);
+    }
+
+    #[test]
+    fn expected_size_is_checked_when_building() {
+        let _ = env_logger::try_init();
+
+        let client = || {
+            rmt::FakeClient::new(
+                "http://example.com/".parse().unwrap(),
+                HeaderMap::new(),
+                rmt::FakeResponse {
+                    status: reqwest::StatusCode::OK,
+                    headers: HeaderMap::new(),
+                    body: io::Cursor::new(vec![]),
+                },
+            )
+        };
+        let root = tempdir::TempDir::new("http-cache-test").unwrap();
+
+        // No real disk has room for this.
+        let err = super::Cache::builder(root.path().into(), client())
+            .expected_size(Some(u64::MAX))
+            .build()
+            .unwrap_err();
+        match err.downcast_ref::<super::CacheError>() {
+            Some(super::CacheError::InsufficientSpace {
+                root: full,
+                needed,
+                ..
+            }) => {
+                assert_eq!(full, &root.path().canonicalize().unwrap());
+                assert_eq!(*needed, u64::MAX);
+            }
+            other => panic!("Unexpected error {:?}", other),
+        }
+
+        super::Cache::builder(root.path().into(), client())
+            .expected_size(Some(0))
+            .build()
+            .unwrap();
+    }
+
+    #[test]
+    fn reserve_free_space_refuses_downloads_that_do_not_fit() {
+        let _ = env_logger::try_init();
+
+        let url: reqwest::Url = "http://example.com/".parse().unwrap();
+        let mut response_headers = HeaderMap::new();
+        response_headers
+            .append(CONTENT_LENGTH, HeaderValue::from_static("5"));
+        let mut c = super::Cache::builder(
+            tempdir::TempDir::new("http-cache-test")
+                .unwrap()
+                .into_path(),
+            rmt::FakeClient::new(
+                url.clone(),
+                HeaderMap::new(),
+                rmt::FakeResponse {
+                    status: reqwest::StatusCode::OK,
+                    headers: response_headers,
+                    body: io::Cursor::new(b"hello"[..].into()),
+                },
+            ),
+        )
+        .reserve_free_space(Some(u64::MAX))
+        .build()
+        .unwrap();
+
+        assert!(!c.has_room_for(5).unwrap());
+        let err = c.get(url.clone()).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<super::CacheError>(),
+            Some(super::CacheError::OutOfSpace { needed: 5, .. })
+        ));
+        assert!(c.metadata(url).unwrap().is_none());
+    }
+
+    /// Caches a response with both an ETag and a Last-Modified date,
+    /// then checks that revalidating it while preferring `validator` sends exactly `expected`.
+    fn revalidate_preferring(
+        validator: super::Validator,
+        expected: &[(HeaderName, &'static str)],
+    ) {
+        let _ = env_logger::try_init();
+
+        let url: reqwest::Url = "http://example.com/".parse().unwrap();
+        let mut response_headers = HeaderMap::new();
+        response_headers.append(ETAG, HeaderValue::from_static("\"abcd\""));
+        response_headers
+            .append(LAST_MODIFIED, HeaderValue::from_static(DATE_ZERO));
+        let mut c = super::Cache::builder(
+            tempdir::TempDir::new("http-cache-test")
+                .unwrap()
+                .into_path(),
+            rmt::FakeClient::new(
+                url.clone(),
+                HeaderMap::new(),
+                rmt::FakeResponse {
+                    status: reqwest::StatusCode::OK,
+                    headers: response_headers,
+                    body: io::Cursor::new(b"hello".as_ref().into()),
+                },
+            ),
+        )
+        .prefer_validator(validator)
+        .build()
+        .unwrap();
+        c.get(url.clone()).unwrap();
+        assert!(c.client.was_called());
+
+        let mut revalidate_headers = HeaderMap::new();
+        for (name, value) in expected {
+            revalidate_headers
+                .append(name.clone(), HeaderValue::from_static(value));
+        }
+        c.client = rmt::FakeClient::new(
+            url.clone(),
+            revalidate_headers,
+            rmt::FakeResponse {
+                status: reqwest::StatusCode::NOT_MODIFIED,
+                headers: HeaderMap::new(),
+                body: io::Cursor::new(vec![]),
+            },
+        );
+        assert_eq!(c.get_bytes(url).unwrap(), b"hello");
+        assert!(c.client.was_called());
+    }
+
+    #[test]
+    fn prefer_etag_validator() {
+        revalidate_preferring(
+            super::Validator::ETag,
+            &[(IF_NONE_MATCH, "\"abcd\"")],
+        );
+    }
+
+    #[test]
+    fn prefer_last_modified_validator() {
+        revalidate_preferring(
+            super::Validator::LastModified,
+            &[(IF_MODIFIED_SINCE, DATE_ZERO)],
+        );
+    }
+
+    #[test]
+    fn prefer_both_validators() {
+        revalidate_preferring(
+            super::Validator::Both,
+            &[(IF_MODIFIED_SINCE, DATE_ZERO), (IF_NONE_MATCH, "\"abcd\"")],
+        );
+    }
+
+    /// Caches a response with `max-age=600` and the given `Date`, as received at `now`,
+    /// and returns whether it's still fresh a minute later, along with the cached record.
+    fn fresh_a_minute_later(
+        now: std::time::SystemTime,
+        date: std::time::SystemTime,
+    ) -> (bool, super::CacheRecord) {
+        let _ = env_logger::try_init();
+
+        let url: reqwest::Url = "http://example.com/".parse().unwrap();
+        let clock = super::TestClock::new(now);
+        let mut response_headers = HeaderMap::new();
+        response_headers
+            .append(CACHE_CONTROL, HeaderValue::from_static("max-age=600"));
+        response_headers.append(
+            DATE,
+            HeaderValue::from_str(&httpdate::fmt_http_date(date)).unwrap(),
+        );
+        let response = rmt::FakeResponse {
+            status: reqwest::StatusCode::OK,
+            headers: response_headers,
+            body: io::Cursor::new(b"hello".as_ref().into()),
+        };
+        let mut c = super::Cache::builder(
+            tempdir::TempDir::new("http-cache-test")
+                .unwrap()
+                .into_path(),
+            rmt::FakeClient::new(
+                url.clone(),
+                HeaderMap::new(),
+                response.clone(),
+            ),
+        )
+        .revalidation(super::RevalidationPolicy::WhenStale)
+        .clock(clock.clone())
+        .build()
+        .unwrap();
+        c.get(url.clone()).unwrap();
+        assert!(c.client.was_called());
+
+        clock.advance(std::time::Duration::from_secs(60));
+        c.client = rmt::FakeClient::new(url.clone(), HeaderMap::new(), response);
+        c.get(url.clone()).unwrap();
+        (!c.client.was_called(), c.metadata(url).unwrap().unwrap())
+    }
+
+    #[test]
+    fn response_date_behind_our_clock_counts_as_age() {
+        // Whole seconds, as that's all HTTP dates hold.
+        let now =
+            std::time::UNIX_EPOCH + std::time::Duration::from_secs(100_000);
+        let date = now - std::time::Duration::from_secs(570);
+        let (fresh, record) = fresh_a_minute_later(now, date);
+        // 570 seconds old on arrival, so it's stale 30 seconds later.
+        assert!(!fresh);
+        assert_eq!(record.response_date, Some(date));
+    }
+
+    #[test]
+    fn response_date_ahead_of_our_clock_is_not_age() {
+        let now =
+            std::time::UNIX_EPOCH + std::time::Duration::from_secs(100_000);
+        let date = now + std::time::Duration::from_secs(3600);
+        let (fresh, record) = fresh_a_minute_later(now, date);
+        assert!(fresh);
+        assert_eq!(record.response_date, Some(date));
+        assert_eq!(record.fetched_at, Some(now));
+    }
+
+    /// Fetches a URL that isn't cached, when the server answers with `status`,
+    /// and checks that we get the body but nothing is cached.
+    fn miss_with_status(status: reqwest::StatusCode, body: &[u8]) {
+        let _ = env_logger::try_init();
+
+        let url: reqwest::Url = "http://example.com/".parse().unwrap();
+        let mut c = make_test_cache(rmt::FakeClient::new(
+            url.clone(),
+            HeaderMap::new(),
+            rmt::FakeResponse {
+                status,
+                headers: HeaderMap::new(),
+                body: io::Cursor::new(body.into()),
+            },
+        ));
+
+        assert_eq!(c.get_bytes(url.clone()).unwrap(), body);
+        assert!(c.client.was_called());
+        assert_eq!(c.metadata(url).unwrap(), None);
+    }
+
+    #[test]
+    fn no_content_is_not_cached() {
+        miss_with_status(reqwest::StatusCode::NO_CONTENT, b"");
+    }
+
+    #[test]
+    fn partial_content_is_not_cached() {
+        miss_with_status(reqwest::StatusCode::PARTIAL_CONTENT, b"hel");
+    }
+
+    #[test]
+    #[cfg(feature = "rustls-tls")]
+    fn rustls_client_is_a_client() {
+        let client = reqwest::blocking::Client::builder()
+            .use_rustls_tls()
+            .build()
+            .unwrap();
+        make_test_cache(client);
+    }
+
+    #[test]
+    fn sharded_content_is_stored_in_subdirectories() {
+        let _ = env_logger::try_init();
+
+        let root = tempdir::TempDir::new("http-cache-test")
+            .unwrap()
+            .into_path();
+        let response = |body: &[u8]| rmt::FakeResponse {
+            status: reqwest::StatusCode::OK,
+            headers: HeaderMap::new(),
+            body: io::Cursor::new(body.into()),
+        };
+
+        // An entry from before sharding was enabled.
+        let flat: reqwest::Url = "http://example.com/flat".parse().unwrap();
+        let mut c = super::Cache::new(
+            root.clone(),
+            rmt::FakeClient::new(
+                flat.clone(),
+                HeaderMap::new(),
+                response(b"flat"),
+            ),
+        )
+        .unwrap();
+        c.get(flat.clone()).unwrap();
+        assert!(c.client.was_called());
+        drop(c);
+
+        let sharded: reqwest::Url =
+            "http://example.com/sharded".parse().unwrap();
+        let mut c = super::Cache::builder(
+            root.clone(),
+            rmt::FakeClient::new(
+                sharded.clone(),
+                HeaderMap::new(),
+                response(b"sharded"),
+            ),
+        )
+        .sharded(true)
+        .revalidation(super::RevalidationPolicy::Never)
+        .build()
+        .unwrap();
+        assert_eq!(c.get_bytes(sharded.clone()).unwrap(), b"sharded");
+        assert!(c.client.was_called());
+
+        let record = c.metadata(sharded).unwrap().unwrap();
+        let components: Vec<_> =
+            std::path::Path::new(&record.path).components().collect();
+        assert_eq!(components.len(), 3);
+        assert_eq!(components[0].as_os_str(), "content");
+        assert_eq!(components[1].as_os_str().len(), 2);
+        assert!(root.join(&record.path).is_file());
+
+        // The flat entry is still served, without asking the server.
+        assert_eq!(c.get_bytes(flat).unwrap(), b"flat");
+    }
+
+    #[test]
+    fn access_debounce_limits_access_writes() {
+        let _ = env_logger::try_init();
+
+        let url: reqwest::Url = "http://example.com/".parse().unwrap();
+        let clock = super::TestClock::default();
+        let mut c = super::Cache::builder(
+            tempdir::TempDir::new("http-cache-test")
+                .unwrap()
+                .into_path(),
+            rmt::FakeClient::new(
+                url.clone(),
+                HeaderMap::new(),
+                rmt::FakeResponse {
+                    status: reqwest::StatusCode::OK,
+                    headers: HeaderMap::new(),
+                    body: io::Cursor::new(b"hello".as_ref().into()),
+                },
+            ),
+        )
+        .revalidation(super::RevalidationPolicy::Never)
+        .access_debounce(Some(std::time::Duration::from_secs(60)))
+        .clock(clock.clone())
+        .build()
+        .unwrap();
+        let access_count = |c: &super::Cache<rmt::FakeClient>| {
+            c.most_accessed(1).unwrap()[0].1
+        };
+
+        c.get(url.clone()).unwrap();
+        let before = access_count(&c);
+        for _ in 0..5 {
+            clock.advance(std::time::Duration::from_secs(1));
+            c.get(url.clone()).unwrap();
+        }
+        assert!(access_count(&c) <= before + 1);
+
+        clock.advance(std::time::Duration::from_secs(120));
+        let before = access_count(&c);
+        c.get(url.clone()).unwrap();
+        assert_eq!(access_count(&c), before + 1);
+    }
+
+    #[test]
+    fn is_stale_follows_freshness() {
+        let _ = env_logger::try_init();
+
+        let url: reqwest::Url = "http://example.com/".parse().unwrap();
+        let clock = super::TestClock::default();
+        let mut response_headers = HeaderMap::new();
+        response_headers
+            .append(CACHE_CONTROL, HeaderValue::from_static("max-age=60"));
+        let mut c = super::Cache::builder(
+            tempdir::TempDir::new("http-cache-test")
+                .unwrap()
+                .into_path(),
+            rmt::FakeClient::new(
+                url.clone(),
+                HeaderMap::new(),
+                rmt::FakeResponse {
+                    status: reqwest::StatusCode::OK,
+                    headers: response_headers,
+                    body: io::Cursor::new(b"hello".as_ref().into()),
+                },
+            ),
+        )
+        .revalidation(super::RevalidationPolicy::WhenStale)
+        .clock(clock.clone())
+        .build()
+        .unwrap();
+
+        // Absent.
+        assert!(c.is_stale(url.clone()).unwrap());
+        assert!(!c.client.was_called());
+
+        c.get(url.clone()).unwrap();
+        assert!(c.client.was_called());
+        // Fresh.
+        assert!(!c.is_stale(url.clone()).unwrap());
+
+        // Stale.
+        clock.advance(std::time::Duration::from_secs(61));
+        assert!(c.is_stale(url.clone()).unwrap());
+    }
+
+    #[test]
+    fn is_stale_honours_max_stale() {
+        let _ = env_logger::try_init();
+
+        let url: reqwest::Url = "http://example.com/".parse().unwrap();
+        let clock = super::TestClock::default();
+        let mut c = super::Cache::builder(
+            tempdir::TempDir::new("http-cache-test")
+                .unwrap()
+                .into_path(),
+            rmt::FakeClient::new(
+                url.clone(),
+                HeaderMap::new(),
+                rmt::FakeResponse {
+                    status: reqwest::StatusCode::OK,
+                    headers: HeaderMap::new(),
+                    body: io::Cursor::new(b"hello".as_ref().into()),
+                },
+            ),
+        )
+        .revalidation(super::RevalidationPolicy::Never)
+        .clock(clock.clone())
+        .build()
+        .unwrap();
+        c.set_max_stale(std::time::Duration::from_secs(30));
+        c.get(url.clone()).unwrap();
+        assert!(c.client.was_called());
+
+        assert!(!c.is_stale(url.clone()).unwrap());
+        clock.advance(std::time::Duration::from_secs(31));
+        assert!(c.is_stale(url).unwrap());
+    }
+
+    #[test]
+    fn stale_while_revalidate_serves_stale_data_then_refreshes() {
+        let _ = env_logger::try_init();
+
+        let url: reqwest::Url = "http://example.com/".parse().unwrap();
+        let response = |body: &[u8]| {
+            let mut headers = HeaderMap::new();
+            headers
+                .append(CACHE_CONTROL, HeaderValue::from_static("max-age=60"));
+            rmt::FakeResponse {
+                status: reqwest::StatusCode::OK,
+                headers,
+                body: io::Cursor::new(body.into()),
+            }
+        };
+        let mut client = rmt::SharedClient::default();
+        client.responses.insert(url.clone(), response(b"old"));
+        let requests = client.requests.clone();
+        let clock = super::TestClock::default();
+        let mut cache = super::Cache::builder(
+            tempdir::TempDir::new("http-cache-test")
+                .unwrap()
+                .into_path(),
+            client,
+        )
+        .revalidation(super::RevalidationPolicy::WhenStale)
+        .stale_while_revalidate(Some(std::time::Duration::from_secs(3600)))
+        .clock(clock.clone())
+        .build_shared()
+        .unwrap();
+        let read = |cache: &super::SharedCache<rmt::SharedClient>| {
+            let mut buf = vec![];
+            cache.get(url.clone()).unwrap().read_to_end(&mut buf).unwrap();
+            buf
+        };
+        assert_eq!(read(&cache), b"old");
+        assert_eq!(requests.lock().unwrap().len(), 1);
+
+        clock.advance(std::time::Duration::from_secs(120));
+        cache.client.responses.insert(url.clone(), response(b"new"));
+        // Both get the stale data, and only one revalidation is started.
+        assert_eq!(read(&cache), b"old");
+        let _ = read(&cache);
+
+        let start = std::time::Instant::now();
+        while !cache.refreshing.lock().unwrap().is_empty() {
+            assert!(start.elapsed() < std::time::Duration::from_secs(10));
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+        assert_eq!(requests.lock().unwrap().len(), 2);
+
+        // The refreshed data is fresh, so it's served without a request.
+        assert_eq!(read(&cache), b"new");
+        assert_eq!(requests.lock().unwrap().len(), 2);
+    }
 
-        // We send a request, and the server responds with the data,
-        // and a "Last-Modified" header.
-        let request_1_headers = HeaderMap::new();
-        let mut response_1_headers = HeaderMap::new();
-        response_1_headers
-            .append(LAST_MODIFIED, HeaderValue::from_static(DATE_ZERO));
+    #[test]
+    fn stale_while_revalidate_serves_compressed_content() {
+        let _ = env_logger::try_init();
+
+        let url: reqwest::Url = "http://example.com/".parse().unwrap();
+        let mut headers = HeaderMap::new();
+        headers.append(CACHE_CONTROL, HeaderValue::from_static("max-age=60"));
+        let mut client = rmt::SharedClient::default();
+        client.responses.insert(
+            url.clone(),
+            rmt::FakeResponse {
+                status: reqwest::StatusCode::OK,
+                headers,
+                body: io::Cursor::new(b"old".as_ref().into()),
+            },
+        );
+        let clock = super::TestClock::default();
+        let cache = super::Cache::builder(
+            tempdir::TempDir::new("http-cache-test")
+                .unwrap()
+                .into_path(),
+            client,
+        )
+        .revalidation(super::RevalidationPolicy::WhenStale)
+        .stale_while_revalidate(Some(std::time::Duration::from_secs(3600)))
+        .compress_at_rest(true)
+        .clock(clock.clone())
+        .build_shared()
+        .unwrap();
+        cache.get(url.clone()).unwrap();
+        clock.advance(std::time::Duration::from_secs(120));
+
+        // The stale copy is decompressed into a scratch directory that's
+        // removed along with the Cache that made it, so it must be opened
+        // first.
+        let mut buf = vec![];
+        cache.get(url).unwrap().read_to_end(&mut buf).unwrap();
+        assert_eq!(&buf, b"old");
+        wait_for_refreshes(&cache);
+    }
+
+    /// Answers like the wrapped client, but panics when asked for a URL it
+    /// doesn't know.
+    #[derive(Clone)]
+    struct PanickingClient(rmt::SharedClient);
+
+    impl super::reqwest_mock::Client for PanickingClient {
+        type Response = rmt::FakeResponse;
+        type Error = rmt::FakeError;
+
+        fn execute(
+            &self,
+            request: reqwest::blocking::Request,
+        ) -> Result<Self::Response, rmt::FakeError> {
+            if !self.0.responses.contains_key(request.url()) {
+                panic!("Unexpected request for {}", request.url());
+            }
+            super::reqwest_mock::Client::execute(&self.0, request)
+        }
+    }
+
+    /// A shared cache serving stale data while it revalidates it, whose
+    /// copy of `url` is stale.
+    fn stale_shared_cache(
+        url: &reqwest::Url,
+    ) -> super::SharedCache<PanickingClient> {
+        let mut headers = HeaderMap::new();
+        headers.append(CACHE_CONTROL, HeaderValue::from_static("max-age=60"));
+        let mut client = rmt::SharedClient::default();
+        client.responses.insert(
+            url.clone(),
+            rmt::FakeResponse {
+                status: reqwest::StatusCode::OK,
+                headers,
+                body: io::Cursor::new(b"old".as_ref().into()),
+            },
+        );
+        let clock = super::TestClock::default();
+        let cache = super::Cache::builder(
+            tempdir::TempDir::new("http-cache-test")
+                .unwrap()
+                .into_path(),
+            PanickingClient(client),
+        )
+        .revalidation(super::RevalidationPolicy::WhenStale)
+        .stale_while_revalidate(Some(std::time::Duration::from_secs(3600)))
+        .ignore_query_params(vec!["utm_*".into()])
+        .clock(clock.clone())
+        .build_shared()
+        .unwrap();
+        cache.get(url.clone()).unwrap();
+        clock.advance(std::time::Duration::from_secs(120));
+        cache
+    }
+
+    /// Waits for `cache` to finish revalidating in the background.
+    fn wait_for_refreshes<C: super::reqwest_mock::Client + Clone>(
+        cache: &super::SharedCache<C>,
+    ) {
+        let start = std::time::Instant::now();
+        while !cache.refreshing.lock().unwrap().is_empty() {
+            assert!(start.elapsed() < std::time::Duration::from_secs(10));
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    fn background_revalidations_are_coalesced_by_key() {
+        let _ = env_logger::try_init();
+
+        let url: reqwest::Url =
+            "http://example.com/?utm_source=a".parse().unwrap();
+        let mut cache = stale_shared_cache(&url);
+        let requests = cache.client.0.requests.clone();
+        cache.client.0.delay = std::time::Duration::from_millis(200);
+
+        // Different URLs, but stored under the same key, so only one
+        // revalidation is started.
+        cache.get(url.clone()).unwrap();
+        cache.get("http://example.com/?utm_source=b#top").unwrap();
+        wait_for_refreshes(&cache);
+        assert_eq!(requests.lock().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn background_revalidation_can_run_again_after_a_panic() {
+        let _ = env_logger::try_init();
+
+        let url: reqwest::Url = "http://example.com/".parse().unwrap();
+        let mut cache = stale_shared_cache(&url);
+        let requests = cache.client.0.requests.clone();
+
+        // The revalidation panics, but serving the stale data doesn't.
+        let response = cache.client.0.responses.remove(&url).unwrap();
+        let mut buf = vec![];
+        cache.get(url.clone()).unwrap().read_to_end(&mut buf).unwrap();
+        assert_eq!(&buf, b"old");
+        wait_for_refreshes(&cache);
+
+        // So the next stale retrieval tries again.
+        cache.client.0.responses.insert(url.clone(), response);
+        cache.get(url).unwrap();
+        wait_for_refreshes(&cache);
+        assert_eq!(requests.lock().unwrap().len(), 2);
+    }
+
+    fn missing_files_are_found_and_removed<S: super::Storage>(storage: S) {
+        let _ = env_logger::try_init();
+        let (mut c, urls) = three_cached_urls_in(storage);
+
+        let record = c.metadata(urls[1].clone()).unwrap().unwrap();
+        c.storage.remove(&c.root.join(&record.path)).unwrap();
+
+        assert_eq!(
+            c.find_missing_files().unwrap(),
+            vec![(urls[1].clone(), record)]
+        );
+        assert_eq!(c.repair(super::RepairPolicy::Remove).unwrap(), 1);
+        assert_eq!(still_cached(&c, &urls), vec![true, false, true]);
+        assert_eq!(c.find_missing_files().unwrap(), vec![]);
+    }
+
+    #[test]
+    fn missing_files_can_be_downloaded_again() {
+        let _ = env_logger::try_init();
+        let (mut c, urls) = three_cached_urls();
+
+        let record = c.metadata(urls[0].clone()).unwrap().unwrap();
+        std::fs::remove_file(c.root.join(&record.path)).unwrap();
+
+        assert_eq!(c.repair(super::RepairPolicy::Download).unwrap(), 1);
+        assert_eq!(c.find_missing_files().unwrap(), vec![]);
+        assert_eq!(c.get_bytes(urls[0].clone()).unwrap(), b"aaaaaaaaaa");
+    }
 
+    #[test]
+    fn is_present_on_disk_checks_the_file() {
+        let _ = env_logger::try_init();
+
+        let url: reqwest::Url = "http://example.com/".parse().unwrap();
         let mut c = make_test_cache(rmt::FakeClient::new(
             url.clone(),
-            request_1_headers,
+            HeaderMap::new(),
             rmt::FakeResponse {
                 status: reqwest::StatusCode::OK,
-                headers: response_1_headers,
+                headers: HeaderMap::new(),
                 body: io::Cursor::new(b"hello".as_ref().into()),
             },
         ));
 
-        // The response and its last-modified date should now be recorded
-        // in the cache.
-        c.get(url.clone()).unwrap();
+        // Absent.
+        assert!(!c.is_present_on_disk(url.clone()).unwrap());
+
+        // Present.
+        let path = c.get_path(url.clone()).unwrap();
+        assert!(c.is_present_on_disk(url.clone()).unwrap());
+
+        // Deleted behind our back.
+        std::fs::remove_file(path).unwrap();
+        assert!(!c.is_present_on_disk(url.clone()).unwrap());
+        assert!(c.metadata(url).unwrap().is_some());
+    }
+
+    /// Caches `a`, `b` and `c`, ten bytes each, retrieving each ten seconds after the last.
+    fn three_cached_urls(
+    ) -> (super::Cache<rmt::SharedClient>, Vec<reqwest::Url>) {
+        three_cached_urls_in(super::FsStorage)
+    }
+
+    fn three_cached_urls_in<S: super::Storage>(
+        storage: S,
+    ) -> (super::Cache<rmt::SharedClient, S>, Vec<reqwest::Url>) {
+        let urls: Vec<reqwest::Url> = vec!["a", "b", "c"]
+            .into_iter()
+            .map(|name| format!("http://example.com/{}", name).parse().unwrap())
+            .collect();
+        let mut client = rmt::SharedClient::default();
+        for url in &urls {
+            client.responses.insert(
+                url.clone(),
+                rmt::FakeResponse {
+                    status: reqwest::StatusCode::OK,
+                    headers: HeaderMap::new(),
+                    body: io::Cursor::new(url.path()[1..].repeat(10).into()),
+                },
+            );
+        }
+        let clock = super::TestClock::default();
+        let mut c = super::Cache::builder(
+            tempdir::TempDir::new("http-cache-test")
+                .unwrap()
+                .into_path(),
+            client,
+        )
+        .clock(clock.clone())
+        .build_with_storage(storage)
+        .unwrap();
+        for url in &urls {
+            c.get_bytes(url.clone()).unwrap();
+            clock.advance(std::time::Duration::from_secs(10));
+        }
+        (c, urls)
+    }
+
+    /// Returns which of `urls` are still cached.
+    fn still_cached<S: super::Storage>(
+        c: &super::Cache<rmt::SharedClient, S>,
+        urls: &[reqwest::Url],
+    ) -> Vec<bool> {
+        urls.iter()
+            .map(|url| c.metadata(url.clone()).unwrap().is_some())
+            .collect()
+    }
+
+    #[test]
+    fn trim_to_max_entries() {
+        let _ = env_logger::try_init();
+        let (mut c, urls) = three_cached_urls();
+
+        let summary = c.trim(super::TrimPolicy::MaxEntries(2)).unwrap();
+        assert_eq!(
+            summary,
+            super::TrimSummary {
+                entries: 1,
+                bytes: 10
+            }
+        );
+        assert_eq!(still_cached(&c, &urls), vec![false, true, true]);
+    }
+
+    #[test]
+    fn eviction_handler_sees_each_removed_entry() {
+        let _ = env_logger::try_init();
+        let (mut c, urls) = three_cached_urls();
+
+        let evicted = std::rc::Rc::new(std::cell::RefCell::new(vec![]));
+        let sink = evicted.clone();
+        let root = c.root.clone();
+        c.set_eviction_handler(move |url, record| {
+            // The content is still there when we're told.
+            assert!(root.join(&record.path).exists());
+            sink.borrow_mut().push(url.clone());
+        });
+
+        c.trim(super::TrimPolicy::MaxEntries(1)).unwrap();
+        let mut evicted = evicted.borrow().clone();
+        evicted.sort();
+        assert_eq!(evicted, urls[..2]);
+        assert_eq!(still_cached(&c, &urls), vec![false, false, true]);
+    }
+
+    #[test]
+    fn touch_marks_entries_as_recently_used() {
+        let _ = env_logger::try_init();
+        let (mut c, urls) = three_cached_urls();
+
+        // `a` was retrieved first, but is now the most recently used.
+        c.touch(urls[0].clone()).unwrap();
+        c.trim(super::TrimPolicy::MaxEntries(2)).unwrap();
+        assert_eq!(still_cached(&c, &urls), vec![true, false, true]);
+
+        assert!(c.touch("http://example.com/d").is_err());
+    }
+
+    #[test]
+    fn len_counts_cached_urls() {
+        let _ = env_logger::try_init();
+        let mut c = make_test_cache(rmt::SharedClient::default());
+        assert_eq!(c.len().unwrap(), 0);
+        assert!(c.is_empty().unwrap());
+
+        let dir = tempdir::TempDir::new("http-cache-test").unwrap();
+        let src = dir.path().join("data");
+        std::fs::write(&src, b"hello").unwrap();
+        for url in &["http://example.com/a", "http://example.com/b"] {
+            c.insert_local(*url, &src, None, None).unwrap();
+        }
+        assert_eq!(c.len().unwrap(), 2);
+        assert!(!c.is_empty().unwrap());
+    }
+
+    fn trim_to_max_bytes<S: super::Storage>(storage: S) {
+        let _ = env_logger::try_init();
+        let (mut c, urls) = three_cached_urls_in(storage);
+        let paths: Vec<_> = urls
+            .iter()
+            .map(|url| {
+                c.root.join(c.metadata(url.clone()).unwrap().unwrap().path)
+            })
+            .collect();
+
+        let summary = c.trim(super::TrimPolicy::MaxBytes(15)).unwrap();
+        assert_eq!(
+            summary,
+            super::TrimSummary {
+                entries: 2,
+                bytes: 20
+            }
+        );
+        assert_eq!(still_cached(&c, &urls), vec![false, false, true]);
+        assert_eq!(
+            paths
+                .iter()
+                .map(|path| stored(&c, path).is_some())
+                .collect::<Vec<_>>(),
+            vec![false, false, true]
+        );
+
+        // Already within the budget.
+        assert_eq!(
+            c.trim(super::TrimPolicy::MaxBytes(15)).unwrap(),
+            super::TrimSummary::default()
+        );
+    }
+
+    #[test]
+    fn trim_older_than() {
+        let _ = env_logger::try_init();
+        let (mut c, urls) = three_cached_urls();
+
+        // The clock is now 30 seconds after `a` was retrieved.
+        let summary = c
+            .trim(super::TrimPolicy::OlderThan(std::time::Duration::from_secs(
+                25,
+            )))
+            .unwrap();
+        assert_eq!(summary.entries, 1);
+        assert_eq!(still_cached(&c, &urls), vec![false, true, true]);
+
+        assert_eq!(
+            c.trim(super::TrimPolicy::OlderThan(std::time::Duration::MAX))
+                .unwrap(),
+            super::TrimSummary::default()
+        );
+        assert_eq!(still_cached(&c, &urls), vec![false, true, true]);
+    }
+
+    #[test]
+    fn file_urls_are_copied_and_revalidated_by_modification_time() {
+        let _ = env_logger::try_init();
+
+        let dir = tempdir::TempDir::new("http-cache-test").unwrap();
+        let src = dir.path().join("manifest.toml");
+        std::fs::write(&src, b"version = 1").unwrap();
+        let url = reqwest::Url::from_file_path(&src).unwrap();
+        // The client is never used for file URLs.
+        let mut c = make_test_cache(rmt::FakeClient::new(
+            "http://example.com/".parse().unwrap(),
+            HeaderMap::new(),
+            rmt::FakeResponse {
+                status: reqwest::StatusCode::OK,
+                headers: HeaderMap::new(),
+                body: io::Cursor::new(vec![]),
+            },
+        ));
+
+        let (_, status) = c.get_with_status(url.clone()).unwrap();
+        assert_eq!(status, super::CacheStatus::Downloaded);
+        assert_eq!(c.get_bytes(url.clone()).unwrap(), b"version = 1");
+        let path = c.get_path(url.clone()).unwrap();
+        assert!(path.starts_with(c.root.join("content")));
+
+        // Unchanged.
+        let (_, status) = c.get_with_status(url.clone()).unwrap();
+        assert_eq!(status, super::CacheStatus::Revalidated);
+
+        // Modified, with a different length so the change shows even if the clock is coarse.
+        std::fs::write(&src, b"version = 22").unwrap();
+        let (_, status) = c.get_with_status(url.clone()).unwrap();
+        assert_eq!(status, super::CacheStatus::Downloaded);
+        assert_eq!(c.get_bytes(url.clone()).unwrap(), b"version = 22");
+        assert!(!path.exists());
+
+        // Gone, so the cached copy is used.
+        std::fs::remove_file(&src).unwrap();
+        assert_eq!(c.get_bytes(url).unwrap(), b"version = 22");
+        assert!(!c.client.was_called());
+    }
+
+    #[test]
+    fn client_errors_keep_their_type() {
+        let _ = env_logger::try_init();
+
+        let url: reqwest::Url = "http://example.com/".parse().unwrap();
+        let mut c = make_test_cache(rmt::BrokenClient::new(
+            url.clone(),
+            HeaderMap::new(),
+            || rmt::FakeError,
+        ));
+
+        let err = c.get(url).expect_err("Got a response??");
+        assert_eq!(err.downcast_ref::<rmt::FakeError>(), Some(&rmt::FakeError));
+        c.client.assert_called();
+    }
+
+    #[test]
+    fn error_statuses_keep_their_type() {
+        let _ = env_logger::try_init();
+
+        let url: reqwest::Url = "http://example.com/".parse().unwrap();
+        let mut c = make_test_cache(rmt::FakeClient::new(
+            url.clone(),
+            HeaderMap::new(),
+            rmt::FakeResponse {
+                status: reqwest::StatusCode::NOT_FOUND,
+                headers: HeaderMap::new(),
+                body: io::Cursor::new(vec![]),
+            },
+        ));
+
+        let err = c.get(url).expect_err("Got a response??");
+        assert_eq!(err.downcast::<rmt::FakeError>().unwrap(), rmt::FakeError);
         c.client.assert_called();
+    }
+
+    #[test]
+    fn recent_hit_ratio_covers_the_latest_requests() {
+        let _ = env_logger::try_init();
+
+        let url: reqwest::Url = "http://example.com/".parse().unwrap();
+        let mut response_headers = HeaderMap::new();
+        response_headers.append(ETAG, HeaderValue::from_static("abcd"));
+        let mut c = make_test_cache(rmt::FakeClient::new(
+            url.clone(),
+            HeaderMap::new(),
+            rmt::FakeResponse {
+                status: reqwest::StatusCode::OK,
+                headers: response_headers.clone(),
+                body: io::Cursor::new(b"hello".as_ref().into()),
+            },
+        ));
+        assert_eq!(c.recent_hit_ratio(10), 0.0);
+
+        // Download, then three hits that revalidate.
+        c.get(url.clone()).unwrap();
+        let mut request_headers = HeaderMap::new();
+        request_headers.append(IF_NONE_MATCH, HeaderValue::from_static("abcd"));
+        c.client = rmt::FakeClient::new(
+            url.clone(),
+            request_headers.clone(),
+            rmt::FakeResponse {
+                status: reqwest::StatusCode::NOT_MODIFIED,
+                headers: response_headers.clone(),
+                body: io::Cursor::new(vec![]),
+            },
+        );
+        for _ in 0..3 {
+            c.get(url.clone()).unwrap();
+        }
+        assert_eq!(c.recent_hit_ratio(4), 0.75);
+        assert_eq!(c.recent_hit_ratio(100), 0.75);
+        assert_eq!(c.recent_hit_ratio(2), 1.0);
+
+        // Then a changed resource is downloaded again.
+        c.client = rmt::FakeClient::new(
+            url.clone(),
+            request_headers,
+            rmt::FakeResponse {
+                status: reqwest::StatusCode::OK,
+                headers: response_headers,
+                body: io::Cursor::new(b"world".as_ref().into()),
+            },
+        );
+        c.get(url).unwrap();
+        assert_eq!(c.recent_hit_ratio(2), 0.5);
+        assert_eq!(c.recent_hit_ratio(5), 0.6);
+
+        c.reset_stats();
+        assert_eq!(c.recent_hit_ratio(10), 0.0);
+    }
+
+    #[test]
+    fn key_fn_can_fold_urls_into_one_entry() {
+        let _ = env_logger::try_init();
+
+        let mirror_1: reqwest::Url =
+            "http://mirror-1.example.com/pkg.tar".parse().unwrap();
+        let mirror_2: reqwest::Url =
+            "http://mirror-2.example.com/pkg.tar".parse().unwrap();
+        let mut response_headers = HeaderMap::new();
+        response_headers
+            .append(CACHE_CONTROL, HeaderValue::from_static("max-age=3600"));
+        // Only the first mirror is ever asked for the content.
+        let mut c = make_test_cache(rmt::FakeClient::new(
+            mirror_1.clone(),
+            HeaderMap::new(),
+            rmt::FakeResponse {
+                status: reqwest::StatusCode::OK,
+                headers: response_headers,
+                body: io::Cursor::new(b"package".as_ref().into()),
+            },
+        ));
+        c.set_revalidation(super::RevalidationPolicy::WhenStale);
+        c.set_key_fn(|url| {
+            let mut key = url.clone();
+            key.set_host(Some("mirror.example.com")).unwrap();
+            key
+        });
+
+        assert_eq!(c.get_bytes(mirror_1).unwrap(), b"package");
+        let (_, status) = c.get_with_status(mirror_2.clone()).unwrap();
+        assert_eq!(status, super::CacheStatus::Fresh);
+        assert_eq!(c.get_bytes(mirror_2.clone()).unwrap(), b"package");
+        assert!(c.metadata(mirror_2).unwrap().is_some());
+        let key: reqwest::Url =
+            "http://mirror.example.com/pkg.tar".parse().unwrap();
+        assert_eq!(c.most_accessed(10).unwrap(), vec![(key, 3)]);
+    }
+
+    #[test]
+    fn obsolete_last_modified_formats_are_sent_back_canonically() {
+        let _ = env_logger::try_init();
+
+        for last_modified in
+            &["Thursday, 01-Jan-70 00:00:00 GMT", "Thu Jan  1 00:00:00 1970"]
+        {
+            let url: reqwest::Url = "http://example.com/".parse().unwrap();
+            let mut response_headers = HeaderMap::new();
+            response_headers.append(
+                LAST_MODIFIED,
+                HeaderValue::from_static(last_modified),
+            );
+            let mut c = make_test_cache(rmt::FakeClient::new(
+                url.clone(),
+                HeaderMap::new(),
+                rmt::FakeResponse {
+                    status: reqwest::StatusCode::OK,
+                    headers: response_headers,
+                    body: io::Cursor::new(b"hello".as_ref().into()),
+                },
+            ));
+            c.get(url.clone()).unwrap();
+            // The date is stored as sent.
+            assert_eq!(
+                c.metadata(url.clone()).unwrap().unwrap().last_modified,
+                Some(last_modified.to_string())
+            );
+
+            let mut request_headers = HeaderMap::new();
+            request_headers
+                .append(IF_MODIFIED_SINCE, HeaderValue::from_static(DATE_ZERO));
+            c.client = rmt::FakeClient::new(
+                url.clone(),
+                request_headers,
+                rmt::FakeResponse {
+                    status: reqwest::StatusCode::NOT_MODIFIED,
+                    headers: HeaderMap::new(),
+                    body: io::Cursor::new(vec![]),
+                },
+            );
+            assert_eq!(c.get_bytes(url).unwrap(), b"hello");
+            assert!(c.client.was_called());
+        }
+    }
 
-        // For the next request, we expect the request to include the
-        // modified date in the "if modified since" header, and we'll give
-        // the "yes, it has been modified" response with a new Last-Modified.
-        let mut request_2_headers = HeaderMap::new();
-        request_2_headers.append(
-            IF_MODIFIED_SINCE,
-            HeaderValue::from_static(DATE_ZERO),
-        );
-        let mut response_2_headers = HeaderMap::new();
-        response_2_headers
-            .append(LAST_MODIFIED, HeaderValue::from_static(DATE_ONE));
+    #[test]
+    fn interceptor_sees_the_final_request() {
+        let _ = env_logger::try_init();
 
-        c.client = rmt::FakeClient::new(
+        let url: reqwest::Url = "http://example.com/".parse().unwrap();
+        let signature = rh::HeaderName::from_static("x-signature");
+        let mut response_headers = HeaderMap::new();
+        response_headers.append(ETAG, HeaderValue::from_static("\"abcd\""));
+        let mut request_headers = HeaderMap::new();
+        request_headers.append(&signature, HeaderValue::from_static("1"));
+        let mut c = make_test_cache(rmt::FakeClient::new(
             url.clone(),
-            request_2_headers,
+            request_headers,
             rmt::FakeResponse {
                 status: reqwest::StatusCode::OK,
-                headers: response_2_headers,
-                body: io::Cursor::new(b"world".as_ref().into()),
+                headers: response_headers,
+                body: io::Cursor::new(b"hello".as_ref().into()),
             },
-        );
-
-        // Now when we make the request, we should get the new body and
-        // ignore what's in the cache.
-        let mut res = c.get(url.clone()).unwrap();
-        let mut buf = vec![];
-        res.read_to_end(&mut buf).unwrap();
-        assert_eq!(&buf, b"world");
-        c.client.assert_called();
-
-        // If we make another request, we should set If-Modified-Since
-        // to match the second response, and be able to return the data from
-        // the second response.
-        let mut request_3_headers = HeaderMap::new();
-        request_3_headers.append(
-            IF_MODIFIED_SINCE,
-            HeaderValue::from_static(DATE_ONE),
-        );
-        let response_3_headers = HeaderMap::new();
+        ));
+        // Sign over the headers the cache added.
+        let name = signature.clone();
+        c.set_interceptor(move |request| {
+            let count = request.headers().len() + 1;
+            request
+                .headers_mut()
+                .insert(&name, HeaderValue::from(count as u64));
+        });
+        c.get(url.clone()).unwrap();
+        assert!(c.client.was_called());
 
+        let mut request_headers = HeaderMap::new();
+        request_headers
+            .append(IF_NONE_MATCH, HeaderValue::from_static("\"abcd\""));
+        request_headers.append(&signature, HeaderValue::from_static("2"));
         c.client = rmt::FakeClient::new(
             url.clone(),
-            request_3_headers,
+            request_headers,
             rmt::FakeResponse {
                 status: reqwest::StatusCode::NOT_MODIFIED,
-                headers: response_3_headers,
-                body: io::Cursor::new(b"".as_ref().into()),
+                headers: HeaderMap::new(),
+                body: io::Cursor::new(vec![]),
             },
         );
-
-        // Now when we make the request, we should get updated info from the
-        // cache.
-        let mut res = c.get(url).unwrap();
-        let mut buf = vec![];
-        res.read_to_end(&mut buf).unwrap();
-        assert_eq!(&buf, b"world");
-        c.client.assert_called();
+        assert_eq!(c.get_bytes(url).unwrap(), b"hello");
+        assert!(c.client.was_called());
     }
 
     #[test]
-    fn return_existing_data_on_connection_refused() {
+    fn get_max_stale_tolerates_slightly_stale_copies() {
         let _ = env_logger::try_init();
 
-        let temp_path = tempdir::TempDir::new("http-cache-test")
-            .unwrap()
-            .into_path();
-
         let url: reqwest::Url = "http://example.com/".parse().unwrap();
-
-        // We send a request, and the server responds with the data,
-        // and a "Last-Modified" header.
-        let request_1_headers = HeaderMap::new();
-        let mut response_1_headers = HeaderMap::new();
-        response_1_headers
-            .append(LAST_MODIFIED, HeaderValue::from_static(DATE_ZERO));
-
-        let mut c = super::Cache::new(
-            temp_path.clone(),
+        let mut response_headers = HeaderMap::new();
+        response_headers
+            .append(CACHE_CONTROL, HeaderValue::from_static("max-age=60"));
+        let response = rmt::FakeResponse {
+            status: reqwest::StatusCode::OK,
+            headers: response_headers,
+            body: io::Cursor::new(b"hello".as_ref().into()),
+        };
+        let clock = super::TestClock::default();
+        let mut c = super::Cache::builder(
+            tempdir::TempDir::new("http-cache-test")
+                .unwrap()
+                .into_path(),
             rmt::FakeClient::new(
                 url.clone(),
-                request_1_headers,
-                rmt::FakeResponse {
-                    status: reqwest::StatusCode::OK,
-                    headers: response_1_headers,
-                    body: io::Cursor::new(b"hello".as_ref().into()),
-                },
+                HeaderMap::new(),
+                response.clone(),
             ),
         )
+        .revalidation(super::RevalidationPolicy::WhenStale)
+        .clock(clock.clone())
+        .build()
         .unwrap();
-
-        // The response and its last-modified date should now be recorded
-        // in the cache.
         c.get(url.clone()).unwrap();
-        c.client.assert_called();
+        assert!(c.client.was_called());
 
-        // If we make second request, we should set If-Modified-Since
-        // to match the first response's Last-Modified.
-        let mut request_2_headers = HeaderMap::new();
-        request_2_headers.append(
-            IF_MODIFIED_SINCE,
-            HeaderValue::from_static(DATE_ZERO),
+        // Stale by ten seconds.
+        clock.advance(std::time::Duration::from_secs(70));
+        c.client = rmt::FakeClient::new(
+            url.clone(),
+            HeaderMap::new(),
+            response.clone(),
         );
+        let mut buf = vec![];
+        c.get_max_stale(url.clone(), std::time::Duration::from_secs(30))
+            .unwrap()
+            .read_to_end(&mut buf)
+            .unwrap();
+        assert_eq!(&buf, b"hello");
+        assert!(!c.client.was_called());
 
-        // This time, however, the request will return an error.
-        let mut c = super::Cache::new(
-            temp_path.clone(),
-            rmt::BrokenClient::new(url.clone(), request_2_headers, || {
-                rmt::FakeError.into()
-            }),
+        c.get_max_stale(url.clone(), std::time::Duration::from_secs(5))
+            .unwrap();
+        assert!(c.client.was_called());
+
+        // The longest tolerance accepts a copy however stale it is.
+        clock.advance(std::time::Duration::from_secs(1_000_000));
+        c.client =
+            rmt::FakeClient::new(url.clone(), HeaderMap::new(), response);
+        c.get_max_stale(url, std::time::Duration::MAX).unwrap();
+        assert!(!c.client.was_called());
+    }
+
+    #[test]
+    fn stale_while_revalidate_needs_a_freshness_lifetime() {
+        let _ = env_logger::try_init();
+
+        let url: reqwest::Url = "http://example.com/".parse().unwrap();
+        let response = |body: &[u8]| rmt::FakeResponse {
+            status: reqwest::StatusCode::OK,
+            headers: HeaderMap::new(),
+            body: io::Cursor::new(body.into()),
+        };
+        let mut client = rmt::SharedClient::default();
+        client.responses.insert(url.clone(), response(b"old"));
+        let requests = client.requests.clone();
+        let clock = super::TestClock::default();
+        let mut cache = super::Cache::builder(
+            tempdir::TempDir::new("http-cache-test")
+                .unwrap()
+                .into_path(),
+            client,
         )
+        .stale_while_revalidate(Some(std::time::Duration::MAX))
+        .clock(clock.clone())
+        .build_shared()
         .unwrap();
+        cache.get(url.clone()).unwrap();
 
-        // Now when we request a URL, we should get the cached result.
-        let mut res = c.get(url.clone()).unwrap();
+        // Without a freshness lifetime, the data is revalidated before it's
+        // served, however long the window.
+        clock.advance(std::time::Duration::from_secs(10));
+        cache.client.responses.insert(url.clone(), response(b"new"));
         let mut buf = vec![];
-        res.read_to_end(&mut buf).unwrap();
-        assert_eq!(&buf, b"hello");
-        c.client.assert_called();
+        cache.get(url).unwrap().read_to_end(&mut buf).unwrap();
+        assert_eq!(&buf, b"new");
+        assert_eq!(requests.lock().unwrap().len(), 2);
+        assert!(cache.refreshing.lock().unwrap().is_empty());
     }
 
     #[test]
-    fn use_cache_data_if_some_match() {
+    fn filesystem_errors_name_the_path() {
         let _ = env_logger::try_init();
 
         let url: reqwest::Url = "http://example.com/".parse().unwrap();
-        let body = b"hello world";
-
-        // We send a request, and the server responds with the data,
-        // and an "Etag" header.
-        let mut response_headers = HeaderMap::new();
-        response_headers.append(ETAG, HeaderValue::from_static("abcd"));
-
         let mut c = make_test_cache(rmt::FakeClient::new(
             url.clone(),
             HeaderMap::new(),
             rmt::FakeResponse {
                 status: reqwest::StatusCode::OK,
-                headers: response_headers.clone(),
-                body: io::Cursor::new(body.as_ref().into()),
+                headers: HeaderMap::new(),
+                body: io::Cursor::new(b"hello".as_ref().into()),
             },
         ));
+        // A file where the directory for downloads should be.
+        let partial = c.root.join("partial");
+        std::fs::write(&partial, b"").unwrap();
 
-        // The response and its etag should now be recorded
-        // in the cache.
-        c.get(url.clone()).unwrap();
-        c.client.assert_called();
+        let err = c.get(url).expect_err("Created a directory over a file??");
+        assert!(
+            format!("{}", err).contains(&format!("{:?}", partial)),
+            "{:#}",
+            err
+        );
+        assert!(err.downcast_ref::<io::Error>().is_some());
+    }
 
-        // For the next request, we expect the request to include the
-        // etag in the "if none match" header, and we'll give
-        // the "no, it hasn't been modified" response.
-        let mut second_request = HeaderMap::new();
-        second_request
-            .append(IF_NONE_MATCH, HeaderValue::from_static("abcd"));
+    #[test]
+    fn pinned_entries_survive_trimming() {
+        let _ = env_logger::try_init();
+        let (mut c, urls) = three_cached_urls();
 
-        c.client = rmt::FakeClient::new(
+        // `a` is the least recently retrieved, so it would go first.
+        assert!(c.pin(urls[0].clone()).unwrap());
+        let summary = c.trim(super::TrimPolicy::MaxEntries(2)).unwrap();
+        assert_eq!(summary.entries, 1);
+        assert_eq!(still_cached(&c, &urls), vec![true, false, true]);
+
+        // Pinned entries count towards the budget, even if they can't be removed.
+        assert_eq!(
+            c.trim(super::TrimPolicy::MaxBytes(15)).unwrap(),
+            super::TrimSummary {
+                entries: 1,
+                bytes: 10
+            }
+        );
+        assert_eq!(still_cached(&c, &urls), vec![true, false, false]);
+        assert_eq!(
+            c.trim(super::TrimPolicy::MaxBytes(5)).unwrap(),
+            super::TrimSummary::default()
+        );
+        assert_eq!(
+            c.prune_expired(std::time::Duration::from_secs(0)).unwrap(),
+            0
+        );
+        assert_eq!(still_cached(&c, &urls), vec![true, false, false]);
+
+        assert!(c.unpin(urls[0].clone()).unwrap());
+        assert_eq!(c.trim(super::TrimPolicy::MaxBytes(5)).unwrap().entries, 1);
+        assert_eq!(still_cached(&c, &urls), vec![false, false, false]);
+        assert!(!c.pin(urls[0].clone()).unwrap());
+    }
+
+    #[test]
+    fn in_memory_cache_forgets_everything_when_dropped() {
+        let _ = env_logger::try_init();
+
+        let url: reqwest::Url = "http://example.com/".parse().unwrap();
+        let mut headers = HeaderMap::new();
+        headers.append(CACHE_CONTROL, HeaderValue::from_static("max-age=60"));
+        let mut client = rmt::SharedClient::default();
+        client.responses.insert(
             url.clone(),
-            second_request,
             rmt::FakeResponse {
-                status: reqwest::StatusCode::NOT_MODIFIED,
-                headers: response_headers,
-                body: io::Cursor::new(b""[..].into()),
+                status: reqwest::StatusCode::OK,
+                headers,
+                body: io::Cursor::new(b"hello".as_ref().into()),
             },
         );
+        let requests = client.requests.clone();
+        let mut c = super::Cache::in_memory(client).unwrap();
+        c.set_revalidation(super::RevalidationPolicy::WhenStale);
 
-        // Now when we make the request, even though the actual response
-        // did not include a body, we should get the complete body from
-        // the local cache.
-        let mut res = c.get(url).unwrap();
-        let mut buf = vec![];
-        res.read_to_end(&mut buf).unwrap();
-        assert_eq!(&buf, body);
-        c.client.assert_called();
+        assert_eq!(c.get_bytes(url.clone()).unwrap(), b"hello");
+        assert_eq!(c.get_bytes(url.clone()).unwrap(), b"hello");
+        assert_eq!(*requests.lock().unwrap(), vec![url]);
+
+        let root = c.root().to_owned();
+        assert!(!root.join("cache.db").exists());
+        drop(c);
+        assert!(!root.exists());
     }
 
     #[test]
-    fn update_cache_if_none_match() {
+    fn in_memory_cache_gets_many_on_one_thread() {
         let _ = env_logger::try_init();
 
-        let url: reqwest::Url = "http://example.com/".parse().unwrap();
+        // Another thread couldn't open its in-memory metadata, so it would record the downloads elsewhere.
+        let mut c = super::Cache::in_memory(two_url_client()).unwrap();
+        let urls = many_urls();
+        check_many(c.get_many(&urls));
+        assert!(c.metadata(urls[0].clone()).unwrap().is_some());
+    }
 
-        // We send a request, and the server responds with the data,
-        // and an "ETag" header.
-        let request_1_headers = HeaderMap::new();
-        let mut response_1_headers = HeaderMap::new();
-        response_1_headers
-            .append(ETAG, HeaderValue::from_static("abcd"));
+    #[test]
+    fn stored_paths_cannot_escape_the_root() {
+        let _ = env_logger::try_init();
 
+        let url: reqwest::Url = "http://example.com/".parse().unwrap();
         let mut c = make_test_cache(rmt::FakeClient::new(
             url.clone(),
-            request_1_headers,
+            HeaderMap::new(),
             rmt::FakeResponse {
                 status: reqwest::StatusCode::OK,
-                headers: response_1_headers,
+                headers: HeaderMap::new(),
                 body: io::Cursor::new(b"hello".as_ref().into()),
             },
         ));
-
-        // The response and its etag should now be recorded in the cache.
         c.get(url.clone()).unwrap();
-        c.client.assert_called();
 
-        // For the next request, we expect the request to include the
-        // etag in the "if none match" header, and we'll give
-        // the "yes, it has been modified" response with a new etag.
-        let mut request_2_headers = HeaderMap::new();
-        request_2_headers
-            .append(IF_NONE_MATCH, HeaderValue::from_static("abcd"));
-        let mut response_2_headers = HeaderMap::new();
-        response_2_headers
-            .append(ETAG, HeaderValue::from_static("efgh"));
+        // Something else poisons the database.
+        sqlite::open(c.db_path())
+            .unwrap()
+            .execute("UPDATE urls SET path = '../../etc/passwd';")
+            .unwrap();
 
-        c.client = rmt::FakeClient::new(
+        let err = c.get(url).expect_err("Escaped the cache root??");
+        assert!(matches!(
+            err.downcast_ref::<super::DbError>(),
+            Some(super::DbError::InvalidPath(_))
+        ));
+    }
+
+    #[test]
+    fn urls_can_be_given_as_strings() {
+        let _ = env_logger::try_init();
+
+        let url: reqwest::Url = "http://example.com/".parse().unwrap();
+        let mut c = make_test_cache(rmt::FakeClient::new(
             url.clone(),
-            request_2_headers,
+            HeaderMap::new(),
             rmt::FakeResponse {
                 status: reqwest::StatusCode::OK,
-                headers: response_2_headers,
-                body: io::Cursor::new(b"world".as_ref().into()),
+                headers: HeaderMap::new(),
+                body: io::Cursor::new(b"hello".as_ref().into()),
             },
-        );
+        ));
 
-        // Now when we make the request, we should get the new body and
-        // ignore what's in the cache.
-        let mut res = c.get(url.clone()).unwrap();
         let mut buf = vec![];
-        res.read_to_end(&mut buf).unwrap();
-        assert_eq!(&buf, b"world");
-        c.client.assert_called();
+        c.get("http://example.com/")
+            .unwrap()
+            .read_to_end(&mut buf)
+            .unwrap();
+        assert_eq!(&buf, b"hello");
+        assert!(c.metadata(&url).unwrap().is_some());
+        let owned = String::from("http://example.com/");
+        assert!(c.metadata(owned).unwrap().is_some());
 
-        // If we make another request, we should set If-None-Match
-        // to match the second response, and be able to return the data from
-        // the second response.
-        let mut request_3_headers = HeaderMap::new();
-        request_3_headers
-            .append(IF_NONE_MATCH, HeaderValue::from_static("efgh"));
-        let response_3_headers = HeaderMap::new();
+        let err = c.get("not a URL").expect_err("Parsed nonsense??");
+        assert!(err.to_string().contains("\"not a URL\""), "{}", err);
+    }
 
-        c.client = rmt::FakeClient::new(
+    #[test]
+    fn concurrent_gets_share_one_download() {
+        let _ = env_logger::try_init();
+
+        let url: reqwest::Url = "http://example.com/".parse().unwrap();
+        let mut client = rmt::SharedClient::default();
+        client.responses.insert(
             url.clone(),
-            request_3_headers,
             rmt::FakeResponse {
-                status: reqwest::StatusCode::NOT_MODIFIED,
-                headers: response_3_headers,
-                body: io::Cursor::new(b"".as_ref().into()),
+                status: reqwest::StatusCode::OK,
+                headers: HeaderMap::new(),
+                body: io::Cursor::new(b"hello".as_ref().into()),
             },
         );
+        client.delay = std::time::Duration::from_millis(200);
+        let requests = client.requests.clone();
+        let cache = std::sync::Arc::new(
+            super::SharedCache::new(
+                tempdir::TempDir::new("http-cache-test")
+                    .unwrap()
+                    .into_path(),
+                client,
+            )
+            .unwrap(),
+        );
 
-        // Now when we make the request, we should get updated info from the
-        // cache.
-        let mut res = c.get(url).unwrap();
-        let mut buf = vec![];
-        res.read_to_end(&mut buf).unwrap();
-        assert_eq!(&buf, b"world");
-        c.client.assert_called();
+        let threads: Vec<_> = (0..8)
+            .map(|_| {
+                let (cache, url) = (cache.clone(), url.clone());
+                std::thread::spawn(move || {
+                    let mut buf = vec![];
+                    cache.get(url).unwrap().read_to_end(&mut buf).unwrap();
+                    buf
+                })
+            })
+            .collect();
+        for thread in threads {
+            assert_eq!(thread.join().unwrap(), b"hello");
+        }
+        assert_eq!(*requests.lock().unwrap(), vec![url]);
+        assert!(cache.in_flight.lock().unwrap().is_empty());
     }
 
     // See also: https://developer.mozilla.org/en-US/docs/Web/HTTP/Caching
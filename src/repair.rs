@@ -0,0 +1,10 @@
+//! Fixing entries whose content file has gone missing.
+
+/// What [`Cache::repair`](../struct.Cache.html#method.repair) does with entries whose content file is missing.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RepairPolicy {
+    /// Download each entry's content again, unconditionally.
+    Download,
+    /// Remove each entry from the cache.
+    Remove,
+}
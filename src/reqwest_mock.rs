@@ -2,8 +2,9 @@
 //! them in tests.
 //!
 //! You do not need to care about this module
-//! if you just want to use this crate.
-use std::{fmt, io};
+//! if you just want to use this crate,
+//! though [`FixtureClient`] may help test code that uses it.
+use std::{fmt, fs, io, path};
 
 /// Represents the result of sending an HTTP request.
 ///
@@ -52,20 +53,153 @@ impl Client for reqwest::blocking::Client {
     fn execute(
         &self,
         request: reqwest::blocking::Request,
-    ) -> Result<Self::Response, Self::Error> { reqwest::blocking::Client::execute(&self, request) }
+    ) -> Result<Self::Response, Self::Error> { reqwest::blocking::Client::execute(self, request) }
 }
 
-#[cfg(test)]
-pub mod tests {
+/// A [`Client`] that serves canned responses from files under a fixture directory, for testing without a server.
+///
+/// The body for `http://example.com/a/b` is read from `<root>/example.com/a/b`,
+/// and URLs whose path ends in `/` are read from a file named `index` in that directory.
+/// The scheme, port, query and fragment are ignored.
+/// URLs without a matching file get an empty `404 Not Found` response.
+///
+/// Response headers are read from a sidecar file next to the body, with `.headers` appended to its name,
+/// holding one `Name: value` header per line.
+/// A `Status` pseudo-header sets the response status, as in CGI; it defaults to `200 OK`.
+///
+/// Conditional requests are answered with `304 Not Modified` when their `If-None-Match` header
+/// matches the fixture's `ETag` header, or when they have no `If-None-Match` header
+/// and their `If-Modified-Since` date is no earlier than the fixture's `Last-Modified` date.
+#[derive(Clone, Debug)]
+pub struct FixtureClient {
+    root: path::PathBuf,
+}
+
+impl FixtureClient {
+    /// Returns a client serving fixtures from under `root`.
+    pub fn new(root: impl Into<path::PathBuf>) -> FixtureClient { FixtureClient{root: root.into()} }
+
+    /// Returns the path of the file holding the body served for `url`.
+    pub fn fixture_path(&self, url: &reqwest::Url) -> path::PathBuf {
+        let mut path = self.root.join(url.host_str().unwrap_or_default());
+        path.extend(url.path_segments().into_iter().flatten().filter(|segment| !segment.is_empty()));
+        if url.path().ends_with('/') { path.push("index") }
+        path
+    }
+
+    fn read_headers(path: &path::Path) -> io::Result<(reqwest::StatusCode, reqwest::header::HeaderMap)> {
+        let invalid = |err: &dyn fmt::Display| io::Error::new(io::ErrorKind::InvalidData, format!("{:?}: {}", path, err));
+        let mut status = reqwest::StatusCode::OK;
+        let mut headers = reqwest::header::HeaderMap::new();
+        let mut name = path.as_os_str().to_owned();
+        name.push(".headers");
+        let text = match fs::read_to_string(name) {
+            Ok(text) => text,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok((status, headers)),
+            Err(err) => return Err(err),
+        };
+        for line in text.lines().filter(|line| !line.trim().is_empty()) {
+            let mut parts = line.splitn(2, ':');
+            let (name, value) = match (parts.next(), parts.next()) {
+                (Some(name), Some(value)) => (name.trim(), value.trim()),
+                _ => return Err(invalid(&format!("Malformed header line {:?}", line))),
+            };
+            if name.eq_ignore_ascii_case("status") {
+                let code = value.split_whitespace().next().unwrap_or_default().parse().map_err(|err| invalid(&err))?;
+                status = reqwest::StatusCode::from_u16(code).map_err(|err| invalid(&err))?;
+            } else {
+                headers.append(
+                    reqwest::header::HeaderName::from_bytes(name.as_bytes()).map_err(|err| invalid(&err))?,
+                    reqwest::header::HeaderValue::from_str(value).map_err(|err| invalid(&err))?,
+                );
+            }
+        }
+        Ok((status, headers))
+    }
+}
+
+/// Whether the resource described by `headers` is the version `request` already has.
+fn not_modified(request: &reqwest::blocking::Request, headers: &reqwest::header::HeaderMap) -> bool {
+    use reqwest::header::*;
+    let text = |headers: &HeaderMap, name| headers.get(name).and_then(|value: &HeaderValue| value.to_str().ok()).map(str::to_owned);
+    if let Some(if_none_match) = text(request.headers(), IF_NONE_MATCH) {
+        // Compare weakly, ignoring any W/ prefix, as RFC 7232 requires for If-None-Match.
+        let opaque = |tag: &str| tag.trim().trim_start_matches("W/").to_owned();
+        return text(headers, ETAG).map_or(false, |etag| if_none_match.split(',').any(|tag| tag.trim() == "*" || opaque(tag) == opaque(&etag)))
+    }
+    let date = |headers: &HeaderMap, name| httpdate::parse_http_date(&text(headers, name)?).ok();
+    match (date(request.headers(), IF_MODIFIED_SINCE), date(headers, LAST_MODIFIED)) {
+        (Some(since), Some(last_modified)) => last_modified <= since,
+        _ => false,
+    }
+}
+
+/// A response from a [`FixtureClient`].
+#[derive(Debug)]
+pub struct FixtureResponse {
+    status: reqwest::StatusCode,
+    headers: reqwest::header::HeaderMap,
+    body: io::Cursor<Vec<u8>>,
+}
+
+impl HttpResponse for FixtureResponse {
+    type Error = io::Error;
+
+    fn headers(&self) -> &reqwest::header::HeaderMap { &self.headers }
+    fn status(&self) -> reqwest::StatusCode { self.status }
+    fn error_for_status(self) -> Result<Self, Self::Error> {
+        if self.status.is_client_error() || self.status.is_server_error() {
+            Err(io::Error::new(io::ErrorKind::Other, format!("HTTP status {}", self.status)))
+        } else {
+            Ok(self)
+        }
+    }
+}
+
+impl io::Read for FixtureResponse {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> { self.body.read(buf) }
+}
+
+impl Client for FixtureClient {
+    type Response = FixtureResponse;
+    type Error = io::Error;
+
+    fn execute(&self, request: reqwest::blocking::Request) -> Result<Self::Response, Self::Error> {
+        let path = self.fixture_path(request.url());
+        let body = match fs::read(&path) {
+            Ok(body) => body,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                return Ok(FixtureResponse{status: reqwest::StatusCode::NOT_FOUND, headers: Default::default(), body: Default::default()})
+            },
+            Err(err) => return Err(err),
+        };
+        let (status, headers) = FixtureClient::read_headers(&path)?;
+        if status.is_success() && not_modified(&request, &headers) {
+            return Ok(FixtureResponse{status: reqwest::StatusCode::NOT_MODIFIED, headers, body: Default::default()})
+        }
+        let body = if *request.method() == reqwest::Method::HEAD { vec![] } else { body };
+        Ok(FixtureResponse{status, headers, body: io::Cursor::new(body)})
+    }
+}
+
+/// Fake clients and responses, for testing code that uses a [`Cache`](../../struct.Cache.html) without a network.
+///
+/// This module is only available with this crate's `testing` feature enabled.
+/// Most of these clients check each request against what they expect, and panic if it doesn't match.
+#[cfg(any(test, feature = "testing"))]
+pub mod testing {
     use reqwest;
 
     use std::cell;
+    use std::collections;
     use std::fmt;
     use std::io;
+    use std::sync;
 
     use std::error::Error;
     use std::io::Read;
 
+    /// The error returned by the fake clients.
     #[derive(Debug, Eq, PartialEq, Hash)]
     pub struct FakeError;
 
@@ -79,15 +213,9 @@ pub mod tests {
         }
     }
 
-    impl Error for FakeError {
-        fn description(&self) -> &str {
-            "Something Ooo occurred"
-        }
-        fn cause(&self) -> Option<&Error> {
-            None
-        }
-    }
+    impl Error for FakeError {}
 
+    /// A canned response.
     #[derive(Clone, Debug)]
     pub struct FakeResponse {
         pub status: reqwest::StatusCode,
@@ -96,18 +224,20 @@ pub mod tests {
     }
 
     impl super::HttpResponse for FakeResponse {
+        type Error = FakeError;
+
         fn headers(&self) -> &reqwest::header::HeaderMap {
             &self.headers
         }
         fn status(&self) -> reqwest::StatusCode {
             self.status
         }
-        fn error_for_status(self) -> Result<Self, Box<Error>> {
+        fn error_for_status(self) -> Result<Self, FakeError> {
             if !self.status.is_client_error() && !self.status.is_server_error()
             {
                 Ok(self)
             } else {
-                Err(Box::new(FakeError))
+                Err(FakeError)
             }
         }
     }
@@ -118,7 +248,10 @@ pub mod tests {
         }
     }
 
+    /// Expects a single kind of request, and answers it with a copy of
+    /// `response`.
     pub struct FakeClient {
+        pub expected_method: reqwest::Method,
         pub expected_url: reqwest::Url,
         pub expected_headers: reqwest::header::HeaderMap,
         pub response: FakeResponse,
@@ -133,6 +266,7 @@ pub mod tests {
         ) -> FakeClient {
             let called = cell::Cell::new(false);
             FakeClient {
+                expected_method: reqwest::Method::GET,
                 expected_url,
                 expected_headers,
                 response,
@@ -140,19 +274,30 @@ pub mod tests {
             }
         }
 
-        pub fn assert_called(self) {
-            assert_eq!(self.called.get(), true);
+        /// Expect a request with the given method, rather than `GET`.
+        pub fn with_method(mut self, method: reqwest::Method) -> FakeClient {
+            self.expected_method = method;
+            self
+        }
+
+        pub fn was_called(&self) -> bool {
+            self.called.get()
+        }
+
+        pub fn assert_called(&self) {
+            assert!(self.called.get());
         }
     }
 
     impl super::Client for FakeClient {
         type Response = FakeResponse;
+        type Error = FakeError;
 
         fn execute(
             &self,
-            request: reqwest::Request,
-        ) -> Result<Self::Response, Box<Error>> {
-            assert_eq!(request.method(), &reqwest::Method::GET);
+            request: reqwest::blocking::Request,
+        ) -> Result<Self::Response, FakeError> {
+            assert_eq!(request.method(), &self.expected_method);
             assert_eq!(request.url(), &self.expected_url);
             assert_eq!(request.headers(), &self.expected_headers);
 
@@ -162,10 +307,48 @@ pub mod tests {
         }
     }
 
+    /// Expects a series of requests, checking each against the next
+    /// `FakeClient` in line.
+    pub struct SequenceClient {
+        clients: cell::RefCell<collections::VecDeque<FakeClient>>,
+    }
+
+    impl SequenceClient {
+        pub fn new(clients: Vec<FakeClient>) -> SequenceClient {
+            SequenceClient {
+                clients: cell::RefCell::new(clients.into()),
+            }
+        }
+
+        pub fn assert_called(self) {
+            assert_eq!(self.clients.into_inner().len(), 0);
+        }
+    }
+
+    impl super::Client for SequenceClient {
+        type Response = FakeResponse;
+        type Error = FakeError;
+
+        fn execute(
+            &self,
+            request: reqwest::blocking::Request,
+        ) -> Result<Self::Response, FakeError> {
+            let client = self
+                .clients
+                .borrow_mut()
+                .pop_front()
+                .expect("Unexpected request");
+            super::Client::execute(&client, request)
+        }
+    }
+
+    /// Expects a single kind of request, and fails it with the error
+    /// returned by `make_error`, as if the network were down.
     pub struct BrokenClient<F>
     where
-        F: Fn() -> Box<Error>,
+        F: Fn() -> FakeError,
     {
+        pub expected_method: reqwest::Method,
         pub expected_url: reqwest::Url,
         pub expected_headers: reqwest::header::HeaderMap,
         pub make_error: F,
@@ -174,7 +357,7 @@ pub mod tests {
 
     impl<F> BrokenClient<F>
     where
-        F: Fn() -> Box<Error>,
+        F: Fn() -> FakeError,
     {
         pub fn new(
             expected_url: reqwest::Url,
@@ -183,6 +366,7 @@ pub mod tests {
         ) -> BrokenClient<F> {
             let called = cell::Cell::new(false);
             BrokenClient {
+                expected_method: reqwest::Method::GET,
                 expected_url,
                 expected_headers,
                 make_error,
@@ -190,22 +374,29 @@ pub mod tests {
             }
         }
 
-        pub fn assert_called(self) {
-            assert_eq!(self.called.get(), true);
+        /// Expect a request with the given method, rather than `GET`.
+        pub fn with_method(mut self, method: reqwest::Method) -> BrokenClient<F> {
+            self.expected_method = method;
+            self
+        }
+
+        pub fn assert_called(&self) {
+            assert!(self.called.get());
         }
     }
 
     impl<F> super::Client for BrokenClient<F>
     where
-        F: Fn() -> Box<Error>,
+        F: Fn() -> FakeError,
     {
         type Response = FakeResponse;
+        type Error = FakeError;
 
         fn execute(
             &self,
-            request: reqwest::Request,
-        ) -> Result<Self::Response, Box<Error>> {
-            assert_eq!(request.method(), &reqwest::Method::GET);
+            request: reqwest::blocking::Request,
+        ) -> Result<Self::Response, FakeError> {
+            assert_eq!(request.method(), &self.expected_method);
             assert_eq!(request.url(), &self.expected_url);
             assert_eq!(request.headers(), &self.expected_headers);
 
@@ -215,4 +406,74 @@ pub mod tests {
         }
     }
 
+    /// A response whose connection drops after the body of the
+    /// wrapped `FakeResponse` has been read.
+    #[derive(Debug)]
+    pub struct TruncatedResponse(pub FakeResponse);
+
+    impl super::HttpResponse for TruncatedResponse {
+        type Error = FakeError;
+
+        fn headers(&self) -> &reqwest::header::HeaderMap {
+            &self.0.headers
+        }
+        fn status(&self) -> reqwest::StatusCode {
+            self.0.status
+        }
+        fn error_for_status(self) -> Result<Self, FakeError> {
+            Ok(self)
+        }
+    }
+
+    impl Read for TruncatedResponse {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            match self.0.read(buf)? {
+                0 => Err(io::Error::new(
+                    io::ErrorKind::ConnectionReset,
+                    "connection reset",
+                )),
+                count => Ok(count),
+            }
+        }
+    }
+
+    /// Checks requests like the wrapped `FakeClient`, but truncates its
+    /// response.
+    pub struct TruncatingClient(pub FakeClient);
+
+    impl super::Client for TruncatingClient {
+        type Response = TruncatedResponse;
+        type Error = FakeError;
+
+        fn execute(
+            &self,
+            request: reqwest::blocking::Request,
+        ) -> Result<Self::Response, FakeError> {
+            super::Client::execute(&self.0, request).map(TruncatedResponse)
+        }
+    }
+
+    /// Answers requests for any of a set of URLs, recording each request;
+    /// unlike the other fake clients, it can be shared between threads.
+    #[derive(Clone, Default)]
+    pub struct SharedClient {
+        pub responses: collections::HashMap<reqwest::Url, FakeResponse>,
+        pub requests: sync::Arc<sync::Mutex<Vec<reqwest::Url>>>,
+        /// How long to take over each response, as if the network were slow.
+        pub delay: std::time::Duration,
+    }
+
+    impl super::Client for SharedClient {
+        type Response = FakeResponse;
+        type Error = FakeError;
+
+        fn execute(
+            &self,
+            request: reqwest::blocking::Request,
+        ) -> Result<Self::Response, FakeError> {
+            self.requests.lock().unwrap().push(request.url().clone());
+            std::thread::sleep(self.delay);
+            self.responses.get(request.url()).cloned().ok_or(FakeError)
+        }
+    }
 }
@@ -0,0 +1,223 @@
+//! A cache that can be used from several threads at once.
+use {fehler::throws, anyhow::Error, std::{cell::RefCell, cmp, collections::{HashMap, HashSet}, fs, path, sync::{atomic::{AtomicUsize, Ordering}, mpsc, Arc, Condvar, Mutex, Weak}, thread}, crate::{builder, db, reqwest_mock, Cache, CacheStatus, FsStorage, IntoUrl}};
+
+/// How many URLs [`SharedCache::get_many`] and [`Cache::get_many`] retrieve at once.
+pub(crate) const MAX_WORKERS: usize = 8;
+
+thread_local! {
+    /// Connections to metadata databases that no call on this thread is using right now, by the [`Pool`] they belong to.
+    ///
+    /// SQLite connections can't move between threads, so each thread keeps its own pool.
+    /// Each connection was opened with its cache's settings, so caches don't share them, even with the same root.
+    static IDLE_DBS: RefCell<HashMap<usize, (Weak<Pool>, Vec<db::CacheDB>)>> = Default::default();
+}
+
+/// Identifies the connections of one [`SharedCache`], shared by all its handles, in each thread's [`IDLE_DBS`].
+#[derive(Debug)]
+pub(crate) struct Pool(usize);
+
+impl Pool {
+    pub(crate) fn new() -> Arc<Pool> {
+        static NEXT: AtomicUsize = AtomicUsize::new(0);
+        Arc::new(Pool(NEXT.fetch_add(1, Ordering::Relaxed)))
+    }
+}
+
+impl Drop for Pool {
+    fn drop(&mut self) {
+        // Other threads close their connections the next time they use their pool.
+        let _ = IDLE_DBS.try_with(|idle| idle.borrow_mut().remove(&self.0));
+    }
+}
+
+/// A local cache of HTTP resources that can be shared between threads, for example in an `Arc`.
+///
+/// Unlike [`Cache`], its methods take `&self`.
+/// Each call uses its own connection to the metadata database, so calls from different threads run concurrently,
+/// coordinating exactly like separate [`Cache`] instances sharing the same root:
+/// SQLite serializes their writes, and only one of them downloads a given URL at a time.
+/// Connections are kept open between calls, and reused by later calls to the same cache on the same thread.
+///
+/// Create one with [`SharedCache::new`] or [`CacheBuilder::build_shared`](struct.CacheBuilder.html#method.build_shared).
+/// The `client` is cloned for each call, which is cheap for `reqwest::blocking::Client`.
+#[derive(Debug)]
+pub struct SharedCache<C: reqwest_mock::Client + Clone> {
+    pub(crate) root: path::PathBuf,
+    pub(crate) client: C,
+    pub(crate) options: builder::Options,
+    /// The URLs being revalidated in the background, by the key they're stored under, shared with every handle to this cache.
+    pub(crate) refreshing: Arc<Mutex<HashSet<reqwest::Url>>>,
+    /// The retrievals in progress, by the key their URL is stored under, shared with every handle to this cache.
+    pub(crate) in_flight: Arc<Mutex<HashMap<reqwest::Url, Arc<InFlight>>>>,
+    /// Where each thread keeps this cache's idle connections, shared with every handle to this cache.
+    pub(crate) pool: Arc<Pool>,
+}
+
+/// A retrieval in progress on one thread, which other threads wanting the same URL wait for instead of repeating it.
+#[derive(Debug, Default)]
+pub(crate) struct InFlight {
+    /// `None` until the retrieval finishes, then the path of the content it stored in the cache, if it succeeded.
+    result: Mutex<Option<Option<path::PathBuf>>>,
+    done: Condvar,
+}
+
+/// Publishes the result of a retrieval registered in [`SharedCache::in_flight`] when dropped, even by a panic.
+struct Finish<'a, C: reqwest_mock::Client + Clone> {
+    cache: &'a SharedCache<C>,
+    key: reqwest::Url,
+    slot: Arc<InFlight>,
+    path: Option<path::PathBuf>,
+}
+
+impl<C: reqwest_mock::Client + Clone> Drop for Finish<'_, C> {
+    fn drop(&mut self) {
+        self.cache.in_flight.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).remove(&self.key);
+        *self.slot.result.lock().unwrap_or_else(|poisoned| poisoned.into_inner()) = Some(self.path.take());
+        self.slot.done.notify_all();
+    }
+}
+
+/// Removes a key from [`SharedCache::refreshing`] when its background revalidation is over, even if it panicked.
+struct Refreshing<C: reqwest_mock::Client + Clone> {
+    cache: SharedCache<C>,
+    key: reqwest::Url,
+}
+
+impl<C: reqwest_mock::Client + Clone> Drop for Refreshing<C> {
+    fn drop(&mut self) {
+        self.cache.refreshing.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).remove(&self.key);
+    }
+}
+
+impl<C: reqwest_mock::Client + Clone> SharedCache<C> {
+    /// Returns a SharedCache that wraps `client` and caches data in `root`.
+    ///
+    /// See [`Cache::new`] for the meaning of the arguments.
+    ///
+    /// # Errors
+    /// The same as [`Cache::new`].
+    #[throws] pub fn new(root: path::PathBuf, client: C) -> SharedCache<C> { Cache::builder(root, client).build_shared()? }
+
+    /// Returns the directory where this cache stores its data.
+    pub fn root(&self) -> &path::Path { &self.root }
+
+    /// Retrieve the content of the given URL.
+    ///
+    /// See [`Cache::get`].
+    /// With [`CacheBuilder::stale_while_revalidate`](struct.CacheBuilder.html#method.stale_while_revalidate),
+    /// this may return slightly stale data, and revalidate it on another thread.
+    ///
+    /// Calls on several threads for the same URL at once share a single retrieval:
+    /// the first one asks the server, and the others wait for it and use the content it stored.
+    /// If it fails, or its content can't be shared, each of the others tries for itself.
+    ///
+    /// The client must be `Send + 'static`, as `reqwest::blocking::Client` is,
+    /// so that it can be used from the thread that revalidates stale data in the background.
+    #[throws] pub fn get(&self, url: impl IntoUrl) -> fs::File where C: Send + 'static {
+        let mut url = url.into_url()?;
+        if !self.options.db.keep_fragment { url.set_fragment(None) }
+        let key = crate::default_key(&self.options, &url);
+        if let Some(window) = self.options.stale_while_revalidate {
+            // Open it before the Cache is dropped: content stored compressed is decompressed into its scratch directory.
+            if let Some(file) = self.with_cache(|cache| Ok(cache.stale_copy(url.clone(), window, false)?.map(fs::File::open).transpose()?))? {
+                self.revalidate_in_background(url, key);
+                return file
+            }
+        }
+        let (slot, leading) = {
+            let mut in_flight = self.in_flight.lock().unwrap();
+            match in_flight.get(&key) {
+                Some(slot) => (slot.clone(), false),
+                None => { let slot = Arc::new(InFlight::default()); in_flight.insert(key.clone(), slot.clone()); (slot, true) }
+            }
+        };
+        if !leading {
+            let mut result = slot.result.lock().unwrap();
+            while result.is_none() { result = slot.done.wait(result).unwrap(); }
+            if let Some(Some(path)) = &*result {
+                if let Ok(file) = fs::File::open(path) { return file }
+            }
+            drop(result);
+            return self.with_cache(|cache| cache.get(url))?
+        }
+        let mut finish = Finish{cache: self, key, slot, path: None};
+        let (file, path) = self.with_cache(|cache| { let path = cache.get_path(url)?; Ok((fs::File::open(&path)?, path)) })?;
+        // Content that wasn't stored in the cache, like a no-store response, is removed along with the Cache that downloaded it.
+        if path.starts_with(&self.root) { finish.path = Some(path) }
+        file
+    }
+
+    /// Retrieve the content of the given URL, and say whether it needed the network.
+    ///
+    /// See [`Cache::get_with_status`].
+    #[throws] pub fn get_with_status(&self, url: impl IntoUrl) -> (fs::File, CacheStatus) {
+        let url = url.into_url()?;
+        self.with_cache(|cache| cache.get_with_status(url))?
+    }
+
+    /// Retrieve the content of each of the given URLs, several at a time, returning the results in the same order.
+    ///
+    /// Each URL is retrieved as by [`Cache::get`] on a pool of up to 8 threads,
+    /// and failing to retrieve one doesn't stop the others.
+    pub fn get_many<U: IntoUrl>(&self, urls: impl IntoIterator<Item = U>) -> Vec<Result<fs::File, Error>> where C: Send + 'static {
+        let urls: Vec<_> = urls.into_iter().map(IntoUrl::into_url).collect();
+        let valid: Vec<_> = urls.iter().enumerate().filter_map(|(index, url)| Some((index, url.as_ref().ok()?.clone()))).collect();
+        let next = Arc::new(Mutex::new(valid.clone().into_iter()));
+        let (sender, receiver) = mpsc::channel();
+        let workers: Vec<_> = (0..cmp::min(valid.len(), MAX_WORKERS)).map(|_| {
+            let (next, sender) = (next.clone(), sender.clone());
+            let cache = self.handle();
+            thread::spawn(move || loop {
+                let item = next.lock().unwrap().next();
+                match item { Some((index, url)) => sender.send((index, cache.get(url))).unwrap(), None => break }
+            })
+        }).collect();
+        drop(sender);
+        // Invalid URLs fail right away.
+        let mut results: Vec<Option<Result<fs::File, Error>>> = urls.into_iter().map(|url| url.err().map(Err)).collect();
+        for (index, result) in receiver { results[index] = Some(result); }
+        for worker in workers { worker.join().unwrap_or_else(|_| log::warn!("A get_many worker thread panicked")); }
+        for (index, url) in valid { results[index].get_or_insert_with(|| Err(anyhow::anyhow!("Retrieving {} panicked", url))); }
+        results.into_iter().map(Option::unwrap).collect()
+    }
+
+    /// Retrieves `url`, stored under `key`, on another thread, unless that's already happening, so that its cached data is revalidated.
+    fn revalidate_in_background(&self, url: reqwest::Url, key: reqwest::Url) where C: Send + 'static {
+        if !self.refreshing.lock().unwrap().insert(key.clone()) { return }
+        let refreshing = Refreshing{cache: self.handle(), key};
+        thread::spawn(move || {
+            // Not `SharedCache::get`, which could just serve the stale data again.
+            if let Err(err) = refreshing.cache.with_cache(|cache| cache.get_path(url.clone())) { log::warn!("Could not revalidate {} in the background: {}", url, err) }
+        });
+    }
+
+    /// Returns another handle to this cache, for another thread.
+    fn handle(&self) -> SharedCache<C> {
+        SharedCache{root: self.root.clone(), client: self.client.clone(), options: self.options.clone(), refreshing: self.refreshing.clone(), in_flight: self.in_flight.clone(), pool: self.pool.clone()}
+    }
+
+    fn db_path(&self) -> path::PathBuf { self.root.join("cache.db") }
+
+    /// Calls `f` with a [`Cache`] using one of this thread's idle connections, or a new one.
+    #[throws] fn with_cache<T>(&self, f: impl FnOnce(&mut Cache<C>) -> Result<T, Error>) -> T {
+        let idle = IDLE_DBS.with(|idle| {
+            let mut idle = idle.borrow_mut();
+            idle.retain(|_, (pool, _)| pool.strong_count() > 0);
+            idle.get_mut(&self.pool.0).and_then(|(_, dbs)| dbs.pop())
+        });
+        let db = match idle { Some(db) => db, None => db::CacheDB::open(self.db_path(), &self.options.db)? };
+        let mut cache = Cache::from_parts(self.root.clone(), db, self.client.clone(), self.options.clone(), FsStorage);
+        let result = f(&mut cache);
+        IDLE_DBS.with(|idle| idle.borrow_mut().entry(self.pool.0).or_insert_with(|| (Arc::downgrade(&self.pool), vec![])).1.push(cache.db));
+        result?
+    }
+
+    #[cfg(test)]
+    pub(crate) fn idle_connections(&self) -> usize { idle_connections(self.pool.0) }
+}
+
+/// How many idle connections this thread has for the cache whose [`Pool`] is `pool`.
+#[cfg(test)]
+pub(crate) fn idle_connections(pool: usize) -> usize {
+    IDLE_DBS.with(|idle| idle.borrow().get(&pool).map_or(0, |(_, dbs)| dbs.len()))
+}
@@ -0,0 +1,72 @@
+//! Runtime counters describing how requests were satisfied.
+use std::{collections::VecDeque, sync::{Mutex, atomic::{AtomicU64, Ordering}}};
+
+/// A snapshot of the counters maintained by a [`Cache`](../struct.Cache.html).
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct CacheStats {
+    /// Requests answered with locally cached data.
+    pub hits: u64,
+    /// Requests for URLs that were not cached at all.
+    pub misses: u64,
+    /// Requests that asked the server whether cached data was still valid.
+    pub revalidations: u64,
+    /// Requests that downloaded a response body.
+    pub downloads: u64,
+}
+
+/// What [`Cache::refresh_all`](../struct.Cache.html#method.refresh_all) did with each cached entry.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct RefreshSummary {
+    /// Entries the server confirmed were still valid.
+    pub unchanged: u64,
+    /// Entries whose new content was downloaded.
+    pub updated: u64,
+    /// Entries that couldn't be checked or updated.
+    pub failed: u64,
+}
+
+/// How many of the latest outcomes [`Stats`] remembers for [`Stats::recent_hit_ratio`].
+const RECENT_OUTCOMES: usize = 1024;
+
+/// The live, atomically-updated counters behind [`CacheStats`].
+#[derive(Debug, Default)]
+pub(crate) struct Stats {
+    hits: AtomicU64,
+    misses: AtomicU64,
+    revalidations: AtomicU64,
+    downloads: AtomicU64,
+    /// Whether each of the latest requests was a hit (`true`) or a download (`false`), oldest first.
+    recent: Mutex<VecDeque<bool>>,
+}
+
+impl Stats {
+    pub fn hit(&self) { self.hits.fetch_add(1, Ordering::Relaxed); self.record(true); }
+    pub fn miss(&self) { self.misses.fetch_add(1, Ordering::Relaxed); }
+    pub fn revalidation(&self) { self.revalidations.fetch_add(1, Ordering::Relaxed); }
+    pub fn download(&self) { self.downloads.fetch_add(1, Ordering::Relaxed); self.record(false); }
+
+    fn record(&self, hit: bool) {
+        let mut recent = self.recent.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        if recent.len() == RECENT_OUTCOMES { recent.pop_front(); }
+        recent.push_back(hit);
+    }
+
+    /// Returns the fraction of the latest `window` hits and downloads that were hits, or `0.0` if there were none.
+    pub fn recent_hit_ratio(&self, window: usize) -> f64 {
+        let recent = self.recent.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        let window = std::cmp::min(window, recent.len());
+        if window == 0 { return 0. }
+        recent.iter().rev().take(window).filter(|&&hit| hit).count() as f64 / window as f64
+    }
+
+    pub fn snapshot(&self) -> CacheStats {
+        CacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            revalidations: self.revalidations.load(Ordering::Relaxed),
+            downloads: self.downloads.load(Ordering::Relaxed),
+        }
+    }
+}
@@ -0,0 +1,145 @@
+//! Where a cache keeps the content it downloads.
+use std::{collections::HashMap, fs, io, path, sync::{Arc, Mutex}, time};
+use crate::clock::{Clock, SystemClock};
+
+/// Somewhere to keep the content of cached responses, which a [`Cache`](../struct.Cache.html) reads and writes through.
+///
+/// Content is named by paths under the cache root, or under a scratch directory for content that isn't recorded,
+/// but a storage needn't keep it in files: the paths are just names.
+/// The metadata database and the download lock files are always files under the cache root.
+///
+/// See [`CacheBuilder::build_with_storage`](../struct.CacheBuilder.html#method.build_with_storage).
+pub trait Storage {
+    /// Reads stored content back.
+    type Reader: io::Read + io::Seek;
+    /// Writes new content.
+    type Writer: io::Write;
+
+    /// Creates empty content at `path`, failing with [`io::ErrorKind::AlreadyExists`] if there's some already.
+    fn create_new(&self, path: &path::Path) -> io::Result<Self::Writer>;
+
+    /// Opens the content at `path`, to write more at its end.
+    fn append(&self, path: &path::Path) -> io::Result<Self::Writer>;
+
+    /// Opens the content at `path` for reading.
+    ///
+    /// What was read must not change if the content is replaced or removed afterwards.
+    fn open(&self, path: &path::Path) -> io::Result<Self::Reader>;
+
+    /// Returns the length in bytes of the content at `path`.
+    fn len(&self, path: &path::Path) -> io::Result<u64>;
+
+    /// Returns when the content at `path` was last written to.
+    fn modified(&self, path: &path::Path) -> io::Result<time::SystemTime>;
+
+    /// Moves the content at `from` to `to`, replacing anything there.
+    fn rename(&self, from: &path::Path, to: &path::Path) -> io::Result<()>;
+
+    /// Removes the content at `path`.
+    fn remove(&self, path: &path::Path) -> io::Result<()>;
+}
+
+/// Keeps content in files, as caches do by default.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct FsStorage;
+
+impl Storage for FsStorage {
+    type Reader = fs::File;
+    type Writer = fs::File;
+
+    fn create_new(&self, path: &path::Path) -> io::Result<fs::File> {
+        if let Some(parent) = path.parent() { fs::DirBuilder::new().recursive(true).create(parent)?; }
+        fs::OpenOptions::new().create_new(true).write(true).open(path)
+    }
+    fn append(&self, path: &path::Path) -> io::Result<fs::File> { fs::OpenOptions::new().append(true).open(path) }
+    fn open(&self, path: &path::Path) -> io::Result<fs::File> { fs::File::open(path) }
+    fn len(&self, path: &path::Path) -> io::Result<u64> { Ok(fs::metadata(path)?.len()) }
+    fn modified(&self, path: &path::Path) -> io::Result<time::SystemTime> { fs::metadata(path)?.modified() }
+    fn rename(&self, from: &path::Path, to: &path::Path) -> io::Result<()> { fs::rename(from, to) }
+    fn remove(&self, path: &path::Path) -> io::Result<()> { fs::remove_file(path) }
+}
+
+type Items = Arc<Mutex<HashMap<path::PathBuf, (Vec<u8>, time::SystemTime)>>>;
+
+/// Keeps content in memory, for as long as the cache that uses it lasts.
+///
+/// Only the content is kept in memory; see [`Cache::in_memory`](../struct.Cache.html#method.in_memory) to keep the metadata there too.
+#[derive(Debug)]
+pub struct MemoryStorage {
+    items: Items,
+    clock: Arc<dyn Clock>,
+}
+
+fn not_found(path: &path::Path) -> io::Error { io::Error::new(io::ErrorKind::NotFound, format!("{:?} is not stored", path)) }
+
+impl MemoryStorage {
+    /// Returns an empty storage.
+    pub fn new() -> MemoryStorage { Default::default() }
+
+    /// Returns an empty storage that records when content was written using `clock`.
+    ///
+    /// Pass the same clock as to [`CacheBuilder::clock`](../struct.CacheBuilder.html#method.clock),
+    /// so that the cache compares times from a single clock when deciding whether to revalidate.
+    pub fn with_clock(clock: impl Clock + 'static) -> MemoryStorage { MemoryStorage{items: Default::default(), clock: Arc::new(clock)} }
+
+    fn writer(&self, path: &path::Path) -> MemoryWriter { MemoryWriter{items: self.items.clone(), clock: self.clock.clone(), path: path.to_owned()} }
+}
+
+impl Default for MemoryStorage {
+    /// Returns an empty storage using the system's clock.
+    fn default() -> Self { MemoryStorage::with_clock(SystemClock) }
+}
+
+impl Storage for MemoryStorage {
+    type Reader = io::Cursor<Vec<u8>>;
+    type Writer = MemoryWriter;
+
+    fn create_new(&self, path: &path::Path) -> io::Result<MemoryWriter> {
+        let mut items = self.items.lock().unwrap();
+        if items.contains_key(path) { return Err(io::Error::new(io::ErrorKind::AlreadyExists, format!("{:?} is already stored", path))) }
+        items.insert(path.to_owned(), (vec![], self.clock.now()));
+        Ok(self.writer(path))
+    }
+    fn append(&self, path: &path::Path) -> io::Result<MemoryWriter> {
+        if !self.items.lock().unwrap().contains_key(path) { return Err(not_found(path)) }
+        Ok(self.writer(path))
+    }
+    fn open(&self, path: &path::Path) -> io::Result<io::Cursor<Vec<u8>>> {
+        self.items.lock().unwrap().get(path).map(|(content, _)| io::Cursor::new(content.clone())).ok_or_else(|| not_found(path))
+    }
+    fn len(&self, path: &path::Path) -> io::Result<u64> {
+        self.items.lock().unwrap().get(path).map(|(content, _)| content.len() as u64).ok_or_else(|| not_found(path))
+    }
+    fn modified(&self, path: &path::Path) -> io::Result<time::SystemTime> {
+        self.items.lock().unwrap().get(path).map(|&(_, modified)| modified).ok_or_else(|| not_found(path))
+    }
+    fn rename(&self, from: &path::Path, to: &path::Path) -> io::Result<()> {
+        let mut items = self.items.lock().unwrap();
+        let item = items.remove(from).ok_or_else(|| not_found(from))?;
+        items.insert(to.to_owned(), item);
+        Ok(())
+    }
+    fn remove(&self, path: &path::Path) -> io::Result<()> { self.items.lock().unwrap().remove(path).map(drop).ok_or_else(|| not_found(path)) }
+}
+
+/// Writes to the end of some content in a [`MemoryStorage`].
+///
+/// Writes go straight into the storage, so there's nothing to flush.
+/// If the content is removed or renamed, writing to it fails.
+#[derive(Debug)]
+pub struct MemoryWriter {
+    items: Items,
+    clock: Arc<dyn Clock>,
+    path: path::PathBuf,
+}
+
+impl io::Write for MemoryWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut items = self.items.lock().unwrap();
+        let (content, modified) = items.get_mut(&self.path).ok_or_else(|| not_found(&self.path))?;
+        content.extend_from_slice(buf);
+        *modified = self.clock.now();
+        Ok(buf.len())
+    }
+    fn flush(&mut self) -> io::Result<()> { Ok(()) }
+}
@@ -0,0 +1,26 @@
+//! Removing cached entries to keep a cache within limits.
+use std::time;
+
+/// Which entries [`Cache::trim`](../struct.Cache.html#method.trim) removes.
+///
+/// Entries are removed in order of when they were last retrieved, least recently first.
+/// Pinned entries are never removed, but count towards the limits.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TrimPolicy {
+    /// Remove entries until their content files take up at most this many bytes in total.
+    MaxBytes(u64),
+    /// Remove entries until at most this many are left.
+    MaxEntries(usize),
+    /// Remove entries that haven't been retrieved for at least this long, and are no longer fresh.
+    OlderThan(time::Duration),
+}
+
+/// What [`Cache::trim`](../struct.Cache.html#method.trim) removed.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct TrimSummary {
+    /// How many entries were removed.
+    pub entries: u64,
+    /// The total size of their content files, in bytes.
+    pub bytes: u64,
+}
@@ -0,0 +1,175 @@
+//! Runs a real `Cache` and `reqwest::blocking::Client` against a local HTTP
+//! server, to exercise what the fake clients can't: real header parsing,
+//! redirects, and connections.
+extern crate static_http_cache;
+extern crate reqwest;
+extern crate tempdir;
+extern crate tiny_http;
+
+use static_http_cache::{Cache, CacheStatus, RevalidationPolicy, TestClock};
+use std::io::Read;
+use std::sync::{Arc, Mutex};
+use std::{thread, time};
+
+/// What the server is currently serving at `/file`.
+struct Resource {
+    body: Vec<u8>,
+    etag: String,
+    last_modified: String,
+}
+
+/// What the server has been asked, and what it answered.
+#[derive(Default)]
+struct Log {
+    /// The path and response status of each request, in order.
+    requests: Vec<(String, u16)>,
+}
+
+fn header(name: &str, value: &str) -> tiny_http::Header {
+    tiny_http::Header::from_bytes(name.as_bytes(), value.as_bytes()).unwrap()
+}
+
+/// Starts a server on a free local port, serving `resource` at `/file`
+/// and a redirect to it at `/moved`, and returns its base URL.
+fn serve(resource: Arc<Mutex<Resource>>, log: Arc<Mutex<Log>>) -> String {
+    let server = tiny_http::Server::http("127.0.0.1:0").unwrap();
+    let base = format!("http://{}", server.server_addr().to_ip().unwrap());
+    thread::spawn(move || {
+        for request in server.incoming_requests() {
+            let path = request.url().to_owned();
+            let resource = resource.lock().unwrap();
+            let if_none_match = request
+                .headers()
+                .iter()
+                .find(|header| header.field.equiv("If-None-Match"))
+                .map(|header| header.value.as_str().to_owned());
+            let unchanged =
+                if_none_match.as_deref() == Some(resource.etag.as_str());
+            let status = match path.as_str() {
+                "/moved" => 302,
+                "/file" if unchanged => 304,
+                "/file" => 200,
+                _ => 404,
+            };
+            // Logged before responding, so the client never sees a response
+            // that isn't logged yet.
+            log.lock().unwrap().requests.push((path, status));
+            let etag = header("ETag", &resource.etag);
+            let result = match status {
+                302 => request.respond(
+                    tiny_http::Response::empty(302)
+                        .with_header(header("Location", "/file")),
+                ),
+                304 => request
+                    .respond(tiny_http::Response::empty(304).with_header(etag)),
+                200 => request.respond(
+                    tiny_http::Response::from_data(resource.body.clone())
+                        .with_header(etag)
+                        .with_header(header(
+                            "Last-Modified",
+                            &resource.last_modified,
+                        ))
+                        .with_header(header("Cache-Control", "max-age=60")),
+                ),
+                _ => request.respond(tiny_http::Response::empty(404)),
+            };
+            result.unwrap();
+        }
+    });
+    base
+}
+
+fn read(mut file: std::fs::File) -> Vec<u8> {
+    let mut buf = vec![];
+    file.read_to_end(&mut buf).unwrap();
+    buf
+}
+
+#[test]
+fn cached_resource_lifecycle() {
+    let resource = Arc::new(Mutex::new(Resource {
+        body: b"version 1".to_vec(),
+        etag: "\"v1\"".into(),
+        last_modified: "Thu, 01 Jan 1970 00:00:00 GMT".into(),
+    }));
+    let log = Arc::new(Mutex::new(Log::default()));
+    let url = format!("{}/file", serve(resource.clone(), log.clone()));
+
+    let clock = TestClock::default();
+    let mut cache = Cache::builder(
+        tempdir::TempDir::new("http-cache-test").unwrap().into_path(),
+        reqwest::blocking::Client::new(),
+    )
+    .revalidation(RevalidationPolicy::WhenStale)
+    .clock(clock.clone())
+    .build()
+    .unwrap();
+
+    // A miss downloads the resource.
+    let (file, status) = cache.get_with_status(url.as_str()).unwrap();
+    assert_eq!(status, CacheStatus::Downloaded);
+    assert_eq!(read(file), b"version 1");
+
+    // While it's fresh, a hit doesn't ask the server.
+    let (file, status) = cache.get_with_status(url.as_str()).unwrap();
+    assert_eq!(status, CacheStatus::Fresh);
+    assert_eq!(read(file), b"version 1");
+    assert_eq!(log.lock().unwrap().requests.len(), 1);
+
+    // Once it's stale, the server confirms it's unchanged.
+    clock.advance(time::Duration::from_secs(61));
+    let (file, status) = cache.get_with_status(url.as_str()).unwrap();
+    assert_eq!(status, CacheStatus::Revalidated);
+    assert_eq!(read(file), b"version 1");
+
+    // Then it changes.
+    *resource.lock().unwrap() = Resource {
+        body: b"version 2".to_vec(),
+        etag: "\"v2\"".into(),
+        last_modified: "Fri, 02 Jan 1970 00:00:00 GMT".into(),
+    };
+    clock.advance(time::Duration::from_secs(61));
+    let (file, status) = cache.get_with_status(url.as_str()).unwrap();
+    assert_eq!(status, CacheStatus::Downloaded);
+    assert_eq!(read(file), b"version 2");
+    let record = cache.metadata(url.as_str()).unwrap().unwrap();
+    assert_eq!(
+        record.last_modified.as_deref(),
+        Some("Fri, 02 Jan 1970 00:00:00 GMT")
+    );
+    assert_eq!(record.status, Some(200));
+
+    assert_eq!(
+        log.lock().unwrap().requests,
+        vec![
+            ("/file".to_owned(), 200),
+            ("/file".to_owned(), 304),
+            ("/file".to_owned(), 200),
+        ]
+    );
+}
+
+#[test]
+fn redirects_are_followed() {
+    let resource = Arc::new(Mutex::new(Resource {
+        body: b"hello".to_vec(),
+        etag: "\"abcd\"".into(),
+        last_modified: "Thu, 01 Jan 1970 00:00:00 GMT".into(),
+    }));
+    let log = Arc::new(Mutex::new(Log::default()));
+    let url = format!("{}/moved", serve(resource, log.clone()));
+
+    let mut cache = Cache::new(
+        tempdir::TempDir::new("http-cache-test").unwrap().into_path(),
+        reqwest::blocking::Client::new(),
+    )
+    .unwrap();
+
+    assert_eq!(cache.get_bytes(url.as_str()).unwrap(), b"hello");
+    // The content is cached under the URL we asked for.
+    assert!(cache.metadata(url.as_str()).unwrap().is_some());
+    assert_eq!(
+        log.lock().unwrap().requests,
+        vec![("/moved".to_owned(), 302), ("/file".to_owned(), 200)]
+    );
+}